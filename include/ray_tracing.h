@@ -0,0 +1,62 @@
+/*
+ * C interface of the ray-tracing renderer, implemented in src/ffi.rs. Build the library
+ * without the SDL viewer:
+ *
+ *     cargo build --release --lib --no-default-features
+ *
+ * and link target/release/libray_tracing.so (.dylib, .dll). A renderer traces one more
+ * sample per pixel on every rt_render_frame call, so the image sharpens as long as it is
+ * called:
+ *
+ *     RtRenderer *r = rt_renderer_load("scene.json", 640, 480);
+ *     if (!r) { fprintf(stderr, "%s\n", rt_last_error()); return 1; }
+ *     uint8_t *rgba = malloc(640 * 480 * 4);
+ *     for (int i = 0; i < 64 && rt_render_frame(r, rgba, 640 * 480 * 4) == 1; i++) {}
+ *     rt_renderer_free(r);
+ *
+ * Failures return null or -1, with the reason in rt_last_error. A renderer must not be
+ * used from two threads at once; each call spreads its work over all the cores.
+ */
+#ifndef RAY_TRACING_H
+#define RAY_TRACING_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct RtRenderer RtRenderer;
+
+/* Why the last call failed on this thread, valid until the next failing call. */
+const char *rt_last_error(void);
+
+/* Renderer of the RON or JSON scene file at path, through the camera saved with it or a
+ * default one. */
+RtRenderer *rt_renderer_load(const char *path, size_t width, size_t height);
+
+/* Like rt_renderer_load, from the len bytes of a JSON scene file. */
+RtRenderer *rt_renderer_parse(const uint8_t *json, size_t len, size_t width, size_t height);
+
+/* Frees a renderer; null is ignored. */
+void rt_renderer_free(RtRenderer *renderer);
+
+/* Places the camera at (x, y, z) looking at (tx, ty, tz), with a vertical field of view
+ * of fov degrees. Restarts the accumulation. */
+void rt_set_camera(RtRenderer *renderer, float x, float y, float z, float tx, float ty,
+                   float tz, float fov);
+
+/* Changes the image size and restarts the accumulation. Returns 0, or -1 on failure. */
+int rt_resize(RtRenderer *renderer, size_t width, size_t height);
+
+/* Traces one more sample per pixel and writes width * height 8-bit RGBA pixels, rows from
+ * the top, to rgba. Returns 1 after a frame, 0 once the scene's frame limit is reached,
+ * leaving rgba as it was, and -1 when len is not width * height * 4. */
+int rt_render_frame(RtRenderer *renderer, uint8_t *rgba, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RAY_TRACING_H */
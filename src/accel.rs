@@ -0,0 +1,538 @@
+use glam::{Affine3A, BVec3, Vec3};
+
+use crate::objects::{Object3D, SphereBatch};
+use crate::ray::{Ray, RayHit, EPSILON};
+
+/// Objects per grid cell the grid resolution aims for.
+static GRID_DENSITY: f32 = 4.;
+static GRID_MAX_RESOLUTION: usize = 128;
+/// Most primitives a BVH leaf holds before it is split.
+static BVH_LEAF_SIZE: usize = 4;
+
+/// Acceleration structure used by `Scene::trace_ray`, picked per scene.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum Accel {
+    /// Test every object, spheres in SIMD-friendly packs.
+    Linear,
+    /// Uniform grid walked cell by cell along the ray. Suits dense, evenly
+    /// distributed objects.
+    Grid,
+    /// Bounding volume hierarchy over the object bounds. Scales to large scenes of
+    /// unevenly sized and placed objects.
+    #[default]
+    Bvh,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Default for Aabb {
+    fn default() -> Self {
+        Self {
+            min: Vec3::INFINITY,
+            max: Vec3::NEG_INFINITY,
+        }
+    }
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn extent(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    /// Box around this one moved by `transform`.
+    pub fn transformed(&self, transform: &Affine3A) -> Aabb {
+        (0..8)
+            .map(|i| {
+                let corner = Vec3::select(
+                    BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0),
+                    self.max,
+                    self.min,
+                );
+                let p = transform.transform_point3(corner);
+                Aabb::new(p, p)
+            })
+            .fold(Aabb::default(), |a, b| a.union(&b))
+    }
+
+    /// Slab test returning the distances where the ray enters and leaves the box,
+    /// clipped to `[0, t_max]`.
+    pub fn hit(&self, ray: &Ray, t_max: f32) -> Option<(f32, f32)> {
+        let inv_dir = ray.direction.recip();
+        let t0 = (self.min - ray.origin) * inv_dir;
+        let t1 = (self.max - ray.origin) * inv_dir;
+
+        let t_enter = t0.min(t1).max_element().max(0.);
+        let t_exit = t0.max(t1).min_element().min(t_max);
+
+        if t_enter <= t_exit {
+            Some((t_enter, t_exit))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Grid {
+    pub bounds: Aabb,
+    pub resolution: [usize; 3],
+    pub cell_size: Vec3,
+    /// Indices into `Scene::objects` of the objects overlapping each cell.
+    pub cells: Vec<Vec<usize>>,
+}
+
+impl Grid {
+    pub fn new(objects: &[Object3D]) -> Grid {
+        let bounds = objects
+            .iter()
+            .fold(Aabb::default(), |b, obj| b.union(&obj.bounds()));
+
+        let extent = bounds.extent().max(Vec3::splat(EPSILON));
+        let volume = extent.x * extent.y * extent.z;
+        let k = (GRID_DENSITY * objects.len() as f32 / volume).cbrt();
+
+        let resolution = (extent * k)
+            .ceil()
+            .to_array()
+            .map(|r| (r as usize).clamp(1, GRID_MAX_RESOLUTION));
+        let cell_size = extent
+            / Vec3::new(
+                resolution[0] as f32,
+                resolution[1] as f32,
+                resolution[2] as f32,
+            );
+
+        let mut grid = Grid {
+            bounds: Aabb::new(bounds.min, bounds.min + extent),
+            resolution,
+            cell_size,
+            cells: vec![vec![]; resolution[0] * resolution[1] * resolution[2]],
+        };
+
+        for (i, obj) in objects.iter().enumerate() {
+            let b = obj.bounds();
+            let lo = grid.cell_of(b.min);
+            let hi = grid.cell_of(b.max);
+            for z in lo[2]..=hi[2] {
+                for y in lo[1]..=hi[1] {
+                    for x in lo[0]..=hi[0] {
+                        let idx = grid.cell_index([x, y, z]);
+                        grid.cells[idx].push(i);
+                    }
+                }
+            }
+        }
+
+        grid
+    }
+
+    fn cell_of(&self, p: Vec3) -> [usize; 3] {
+        let c = ((p - self.bounds.min) / self.cell_size).floor().to_array();
+        [0, 1, 2].map(|a| (c[a].max(0.) as usize).min(self.resolution[a] - 1))
+    }
+
+    fn cell_index(&self, c: [usize; 3]) -> usize {
+        c[0] + self.resolution[0] * (c[1] + self.resolution[1] * c[2])
+    }
+
+    /// Walks the cells pierced by the ray in order (Amanatides & Woo) and calls `visit` on
+    /// each one together with the distance where the ray leaves it. Stops as soon as
+    /// `visit` returns false.
+    fn walk(&self, ray: &Ray, t_max: f32, mut visit: impl FnMut(&[usize], f32) -> bool) {
+        let Some((t_enter, t_exit)) = self.bounds.hit(ray, t_max) else {
+            return;
+        };
+
+        let mut cell = self.cell_of(ray.at(t_enter));
+        let origin = ray.origin.to_array();
+        let direction = ray.direction.to_array();
+        let min = self.bounds.min.to_array();
+        let size = self.cell_size.to_array();
+
+        let mut step = [0i64; 3];
+        let mut t_next = [f32::INFINITY; 3];
+        let mut t_delta = [f32::INFINITY; 3];
+
+        for a in 0..3 {
+            if direction[a] > 0. {
+                step[a] = 1;
+                let boundary = min[a] + (cell[a] + 1) as f32 * size[a];
+                t_next[a] = (boundary - origin[a]) / direction[a];
+                t_delta[a] = size[a] / direction[a];
+            } else if direction[a] < 0. {
+                step[a] = -1;
+                let boundary = min[a] + cell[a] as f32 * size[a];
+                t_next[a] = (boundary - origin[a]) / direction[a];
+                t_delta[a] = -size[a] / direction[a];
+            }
+        }
+
+        loop {
+            let axis = if t_next[0] < t_next[1] {
+                if t_next[0] < t_next[2] {
+                    0
+                } else {
+                    2
+                }
+            } else if t_next[1] < t_next[2] {
+                1
+            } else {
+                2
+            };
+            let t_leave = t_next[axis].min(t_exit);
+
+            if !visit(&self.cells[self.cell_index(cell)], t_leave) || t_next[axis] > t_exit {
+                return;
+            }
+
+            let next = cell[axis] as i64 + step[axis];
+            if next < 0 || next >= self.resolution[axis] as i64 {
+                return;
+            }
+            cell[axis] = next as usize;
+            t_next[axis] += t_delta[axis];
+        }
+    }
+
+    pub fn trace(&self, objects: &[Object3D], ray: Ray, t_max: f32) -> Option<RayHit> {
+        let mut closest_t = t_max;
+        let mut closest_hit: Option<RayHit> = None;
+
+        self.walk(&ray, t_max, |cell, t_leave| {
+            for i in cell {
+                if let Some(t) = ray.hit(&objects[*i]) {
+                    if t.distance > EPSILON && t.distance < closest_t {
+                        closest_hit = Some(RayHit {
+                            object_index: *i,
+                            ..t
+                        });
+                        closest_t = t.distance;
+                    }
+                }
+            }
+            // Objects span several cells, so a hit past this cell may still be beaten
+            // by an object in a later one.
+            closest_t > t_leave
+        });
+
+        closest_hit
+    }
+
+    pub fn occluded(&self, objects: &[Object3D], ray: Ray, t_max: f32) -> bool {
+        let mut occluded = false;
+
+        self.walk(&ray, t_max, |cell, _| {
+            occluded = cell.iter().any(|i| {
+                ray.hit(&objects[*i])
+                    .is_some_and(|t| t.distance > EPSILON && t.distance < t_max)
+            });
+            !occluded
+        });
+
+        occluded
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct BvhNode {
+    bounds: Aabb,
+    /// Leaves: first entry in `Bvh::indices`. Inner nodes: index of the left child, the
+    /// right one being next to it.
+    start: usize,
+    /// Number of primitives of a leaf, 0 for inner nodes.
+    count: usize,
+}
+
+/// Bounding volume hierarchy over any set of primitives given by their bounds, split at
+/// the median centroid along the widest axis.
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    /// Primitive indices, each leaf owning a contiguous range.
+    indices: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: vec![],
+            indices: (0..bounds.len()).collect(),
+        };
+
+        if !bounds.is_empty() {
+            let centroids: Vec<Vec3> = bounds.iter().map(|b| b.center()).collect();
+            bvh.nodes.push(BvhNode {
+                bounds: Aabb::default(),
+                start: 0,
+                count: bounds.len(),
+            });
+            bvh.build(0, bounds, &centroids);
+        }
+
+        bvh
+    }
+
+    fn build(&mut self, node: usize, bounds: &[Aabb], centroids: &[Vec3]) {
+        let BvhNode { start, count, .. } = self.nodes[node];
+        let range = start..start + count;
+
+        self.nodes[node].bounds = self.indices[range.clone()]
+            .iter()
+            .fold(Aabb::default(), |b, i| b.union(&bounds[*i]));
+
+        if count <= BVH_LEAF_SIZE {
+            return;
+        }
+
+        let centroid_bounds = self.indices[range.clone()]
+            .iter()
+            .fold(Aabb::default(), |b, i| {
+                b.union(&Aabb::new(centroids[*i], centroids[*i]))
+            });
+        let extent = centroid_bounds.extent();
+        if extent.max_element() <= 0. {
+            return;
+        }
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+
+        let mid = count / 2;
+        self.indices[range].select_nth_unstable_by(mid, |a, b| {
+            centroids[*a][axis].total_cmp(&centroids[*b][axis])
+        });
+
+        let left = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds: Aabb::default(),
+            start,
+            count: mid,
+        });
+        self.nodes.push(BvhNode {
+            bounds: Aabb::default(),
+            start: start + mid,
+            count: count - mid,
+        });
+        self.nodes[node].start = left;
+        self.nodes[node].count = 0;
+
+        self.build(left, bounds, centroids);
+        self.build(left + 1, bounds, centroids);
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::default(), |n| n.bounds)
+    }
+
+    /// Visits the primitives whose bounds the ray crosses before the closest distance found
+    /// so far, nearest nodes first. `visit` gets a primitive index and that closest
+    /// distance, which it shrinks on a closer hit, and returns false to stop the traversal.
+    pub fn traverse(&self, ray: &Ray, t_max: f32, mut visit: impl FnMut(usize, &mut f32) -> bool) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut closest = t_max;
+        let mut stack = [0usize; 64];
+        let mut top = 1;
+
+        while top > 0 {
+            top -= 1;
+            let node = self.nodes[stack[top]];
+            if node.bounds.hit(ray, closest).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for i in self.indices[node.start..node.start + node.count].iter() {
+                    if !visit(*i, &mut closest) {
+                        return;
+                    }
+                }
+                continue;
+            }
+
+            let (left, right) = (node.start, node.start + 1);
+            let t_left = self.nodes[left].bounds.hit(ray, closest).map(|h| h.0);
+            let t_right = self.nodes[right].bounds.hit(ray, closest).map(|h| h.0);
+
+            // Push the farther child first so the nearer one is visited first.
+            match (t_left, t_right) {
+                (Some(l), Some(r)) => {
+                    let (near, far) = if l <= r { (left, right) } else { (right, left) };
+                    stack[top] = far;
+                    stack[top + 1] = near;
+                    top += 2;
+                }
+                (Some(_), None) => {
+                    stack[top] = left;
+                    top += 1;
+                }
+                (None, Some(_)) => {
+                    stack[top] = right;
+                    top += 1;
+                }
+                (None, None) => {}
+            }
+        }
+    }
+
+    pub fn trace(&self, objects: &[Object3D], ray: Ray, t_max: f32) -> Option<RayHit> {
+        let mut closest_hit: Option<RayHit> = None;
+
+        self.traverse(&ray, t_max, |i, closest_t| {
+            if let Some(t) = ray.hit(&objects[i]) {
+                if t.distance > EPSILON && t.distance < *closest_t {
+                    closest_hit = Some(RayHit {
+                        object_index: i,
+                        ..t
+                    });
+                    *closest_t = t.distance;
+                }
+            }
+            true
+        });
+
+        closest_hit
+    }
+
+    pub fn occluded(&self, objects: &[Object3D], ray: Ray, t_max: f32) -> bool {
+        let mut occluded = false;
+
+        self.traverse(&ray, t_max, |i, _| {
+            occluded = ray
+                .hit(&objects[i])
+                .is_some_and(|t| t.distance > EPSILON && t.distance < t_max);
+            !occluded
+        });
+
+        occluded
+    }
+}
+
+/// Acceleration structure built by `Scene::compile` from the scene objects.
+#[derive(Debug, Clone)]
+pub enum AccelStructure {
+    Linear(SphereBatch),
+    Grid(Grid),
+    Bvh(Bvh),
+}
+
+impl Default for AccelStructure {
+    fn default() -> Self {
+        AccelStructure::Linear(SphereBatch::default())
+    }
+}
+
+impl AccelStructure {
+    pub fn new(accel: Accel, objects: &[Object3D]) -> AccelStructure {
+        match accel {
+            Accel::Linear => AccelStructure::Linear(SphereBatch::new(objects)),
+            Accel::Grid => AccelStructure::Grid(Grid::new(objects)),
+            Accel::Bvh => {
+                let bounds: Vec<Aabb> = objects.iter().map(|o| o.bounds()).collect();
+                AccelStructure::Bvh(Bvh::new(&bounds))
+            }
+        }
+    }
+
+    /// Closest hit whose distance lies in `(EPSILON, t_max)`.
+    pub fn trace(&self, objects: &[Object3D], ray: Ray, t_max: f32) -> Option<RayHit> {
+        match self {
+            AccelStructure::Linear(spheres) => Self::trace_linear(spheres, objects, ray, t_max),
+            AccelStructure::Grid(grid) => grid.trace(objects, ray, t_max),
+            AccelStructure::Bvh(bvh) => bvh.trace(objects, ray, t_max),
+        }
+    }
+
+    /// Whether anything lies in `(EPSILON, t_max)`, returning on the first occluder.
+    pub fn occluded(&self, objects: &[Object3D], ray: Ray, t_max: f32) -> bool {
+        match self {
+            AccelStructure::Linear(spheres) => Self::occluded_linear(spheres, objects, ray, t_max),
+            AccelStructure::Grid(grid) => grid.occluded(objects, ray, t_max),
+            AccelStructure::Bvh(bvh) => bvh.occluded(objects, ray, t_max),
+        }
+    }
+
+    fn trace_linear(
+        spheres: &SphereBatch,
+        objects: &[Object3D],
+        ray: Ray,
+        t_max: f32,
+    ) -> Option<RayHit> {
+        let mut closest_t = t_max;
+
+        let mut closest_hit: Option<RayHit> = None;
+
+        let batched = !spheres.is_empty();
+
+        for pack in spheres.packs.iter() {
+            if let k @ Some(t) = ray.hit_sphere_pack(pack, closest_t) {
+                if t.distance < closest_t {
+                    closest_hit = k;
+                    closest_t = t.distance;
+                }
+            }
+        }
+
+        for (i, obj) in objects.iter().enumerate() {
+            if batched && matches!(obj, Object3D::Sphere { .. }) {
+                continue;
+            }
+            if let Some(t) = ray.hit(obj) {
+                if t.distance > EPSILON && t.distance < closest_t {
+                    closest_hit = Some(RayHit {
+                        object_index: i,
+                        ..t
+                    });
+                    closest_t = t.distance;
+                }
+            }
+        }
+
+        closest_hit
+    }
+
+    fn occluded_linear(spheres: &SphereBatch, objects: &[Object3D], ray: Ray, t_max: f32) -> bool {
+        let batched = !spheres.is_empty();
+
+        if spheres
+            .packs
+            .iter()
+            .any(|pack| ray.hit_sphere_pack(pack, t_max).is_some())
+        {
+            return true;
+        }
+
+        objects.iter().any(|obj| {
+            if batched && matches!(obj, Object3D::Sphere { .. }) {
+                return false;
+            }
+            ray.hit(obj)
+                .is_some_and(|t| t.distance > EPSILON && t.distance < t_max)
+        })
+    }
+}
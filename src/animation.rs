@@ -0,0 +1,169 @@
+use glam::{Affine3A, Quat, Vec3};
+
+use crate::objects::Object3D;
+use crate::scene::Scene;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+}
+
+/// Values of one animated property at increasing times, in seconds.
+#[derive(Debug, Clone)]
+pub struct Keyframes<T> {
+    pub times: Vec<f32>,
+    pub values: Vec<T>,
+    pub interpolation: Interpolation,
+}
+
+impl<T: Copy> Keyframes<T> {
+    /// Value at `time`, holding the first and last keys outside their range. `mix`
+    /// blends two keys for linear interpolation.
+    pub fn sample(&self, time: f32, mix: impl Fn(T, T, f32) -> T) -> Option<T> {
+        let last = self.times.len().min(self.values.len()).checked_sub(1)?;
+        let next = self.times[..=last].partition_point(|t| *t <= time);
+
+        if next == 0 {
+            return Some(self.values[0]);
+        }
+        if next > last {
+            return Some(self.values[last]);
+        }
+
+        let (a, b) = (self.values[next - 1], self.values[next]);
+        Some(match self.interpolation {
+            Interpolation::Step => a,
+            Interpolation::Linear => {
+                let (t0, t1) = (self.times[next - 1], self.times[next]);
+                mix(a, b, (time - t0) / (t1 - t0).max(f32::EPSILON))
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Channel {
+    Translation(Keyframes<Vec3>),
+    Rotation(Keyframes<Quat>),
+    Scale(Keyframes<Vec3>),
+}
+
+/// A named set of channels, each animating one node of an `Animator`.
+#[derive(Debug, Clone, Default)]
+pub struct Clip {
+    pub name: String,
+    pub duration: f32,
+    pub channels: Vec<(usize, Channel)>,
+}
+
+/// A transform node. Its rest pose is used for every property no channel animates.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub parent: Option<usize>,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3,
+    /// Scene objects moved by this node, given by their index in `Scene::objects` and
+    /// their shape in the node space.
+    pub objects: Vec<(usize, Object3D)>,
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Self {
+            parent: None,
+            translation: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+            objects: vec![],
+        }
+    }
+}
+
+/// Plays a looping clip over a node hierarchy and moves the scene objects attached to
+/// the nodes. Call `update` from `App::run`.
+#[derive(Debug, Clone, Default)]
+pub struct Animator {
+    pub nodes: Vec<Node>,
+    pub clips: Vec<Clip>,
+    /// Index of the clip playing, if any.
+    pub clip: Option<usize>,
+    pub time: f32,
+}
+
+impl Animator {
+    /// Object-to-world transform of every node for the given local poses.
+    pub fn world_transforms(&self, pose: &[(Vec3, Quat, Vec3)]) -> Vec<Affine3A> {
+        let mut world: Vec<Option<Affine3A>> = vec![None; self.nodes.len()];
+        for i in 0..self.nodes.len() {
+            self.world_transform(i, pose, &mut world);
+        }
+        world.into_iter().map(|w| w.unwrap_or_default()).collect()
+    }
+
+    fn world_transform(
+        &self,
+        node: usize,
+        pose: &[(Vec3, Quat, Vec3)],
+        world: &mut [Option<Affine3A>],
+    ) -> Affine3A {
+        if let Some(w) = world[node] {
+            return w;
+        }
+        let (t, r, s) = pose[node];
+        let local = Affine3A::from_scale_rotation_translation(s, r, t);
+        let w = match self.nodes[node].parent {
+            Some(parent) => self.world_transform(parent, pose, world) * local,
+            None => local,
+        };
+        world[node] = Some(w);
+        w
+    }
+
+    pub fn rest_pose(&self) -> Vec<(Vec3, Quat, Vec3)> {
+        self.nodes
+            .iter()
+            .map(|n| (n.translation, n.rotation, n.scale))
+            .collect()
+    }
+
+    /// Advances the playing clip by `dt` seconds, backwards when negative, and moves the
+    /// attached objects. Returns whether anything moved.
+    pub fn update(&mut self, scene: &mut Scene, dt: f32) -> bool {
+        let Some(clip) = self.clip.and_then(|c| self.clips.get(c)) else {
+            return false;
+        };
+
+        self.time = if clip.duration > 0. {
+            (self.time + dt).rem_euclid(clip.duration)
+        } else {
+            0.
+        };
+
+        let mut pose = self.rest_pose();
+        for (node, channel) in clip.channels.iter() {
+            let (t, r, s) = &mut pose[*node];
+            match channel {
+                Channel::Translation(keys) => {
+                    *t = keys.sample(self.time, Vec3::lerp).unwrap_or(*t);
+                }
+                Channel::Rotation(keys) => {
+                    *r = keys.sample(self.time, Quat::slerp).unwrap_or(*r);
+                }
+                Channel::Scale(keys) => {
+                    *s = keys.sample(self.time, Vec3::lerp).unwrap_or(*s);
+                }
+            }
+        }
+
+        let world = self.world_transforms(&pose);
+        for (node, transform) in self.nodes.iter().zip(world.iter()) {
+            for (i, obj) in node.objects.iter() {
+                scene.objects[*i] = obj.transformed(transform);
+            }
+        }
+
+        true
+    }
+}
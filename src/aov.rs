@@ -0,0 +1,142 @@
+use glam::{Vec3, Vec4};
+
+/// Auxiliary per-pixel output, about the first surface seen through each pixel, rendered
+/// alongside the colour for compositing and denoising.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Aov {
+    /// World space normal, zero for the sky.
+    Normal,
+    /// Depth along the camera axis, infinite for the sky.
+    Depth,
+    /// Surface colour before lighting, zero for the sky.
+    Albedo,
+    /// Index in `Scene::materials`, -1 for the sky.
+    MaterialId,
+    /// Index in `Scene::objects`, -1 for the sky.
+    ObjectId,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 5] = [
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Albedo,
+        Aov::MaterialId,
+        Aov::ObjectId,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Albedo => "albedo",
+            Aov::MaterialId => "material_id",
+            Aov::ObjectId => "object_id",
+        }
+    }
+
+    /// The value `AovPixel::value` gives, brought into `[0, 1]` to be looked at: normals
+    /// from -1..1, depths compressed by `d / (1 + d)` with the sky white, and every id
+    /// in a colour of its own with the sky black.
+    pub fn display(self, value: Vec4) -> Vec4 {
+        let rgb = match self {
+            Aov::Normal => value.truncate() * 0.5 + 0.5,
+            Aov::Depth if value.x.is_finite() => Vec3::splat(value.x / (1. + value.x.abs())),
+            Aov::Depth => Vec3::ONE,
+            Aov::Albedo => value.truncate(),
+            Aov::MaterialId | Aov::ObjectId if value.x < 0. => Vec3::ZERO,
+            Aov::MaterialId | Aov::ObjectId => {
+                // Golden ratio hues keep neighbouring ids apart.
+                let hue = (value.x * 0.618_034).fract();
+                (Vec3::new(hue, hue + 2. / 3., hue + 1. / 3.) * std::f32::consts::TAU)
+                    .to_array()
+                    .map(|a| a.cos() * 0.4 + 0.55)
+                    .into()
+            }
+        };
+        rgb.clamp(Vec3::ZERO, Vec3::ONE).extend(1.)
+    }
+}
+
+/// First hits of the samples of a pixel. Albedo, normal and depth are summed with the
+/// sample weights, as `Renderer::accumulated` sums the colours; the ids are those of the
+/// first sample that hit something.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct AovPixel {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub depth: f32,
+    /// Weight of all the samples.
+    pub weight: f32,
+    /// Weight of the samples that hit something, which the depth is summed over.
+    pub hit_weight: f32,
+    pub material_index: Option<usize>,
+    pub object_index: Option<usize>,
+}
+
+/// What a sample saw first, for `AovPixel::add`.
+#[derive(Debug, Copy, Clone)]
+pub struct AovSample {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub depth: f32,
+    pub material_index: usize,
+    pub object_index: usize,
+}
+
+impl AovPixel {
+    /// Adds a sample that saw `sample`, or the sky when `None`.
+    pub fn add(&mut self, sample: Option<AovSample>, weight: f32) {
+        self.weight += weight;
+        let Some(sample) = sample else {
+            return;
+        };
+        self.albedo += sample.albedo * weight;
+        self.normal += sample.normal * weight;
+        self.depth += sample.depth * weight;
+        self.hit_weight += weight;
+        self.material_index.get_or_insert(sample.material_index);
+        self.object_index.get_or_insert(sample.object_index);
+    }
+
+    /// Average albedo of the samples, the sky counting as black.
+    pub fn albedo(&self) -> Vec3 {
+        if self.weight > 0. {
+            self.albedo / self.weight
+        } else {
+            Vec3::ZERO
+        }
+    }
+
+    /// Average normal of the samples, the sky counting as zero, so it is shorter than 1
+    /// on edges and over the sky.
+    pub fn normal(&self) -> Vec3 {
+        if self.weight > 0. {
+            self.normal / self.weight
+        } else {
+            Vec3::ZERO
+        }
+    }
+
+    /// Average depth of the samples that hit something, infinite when none did.
+    pub fn depth(&self) -> f32 {
+        if self.hit_weight > 0. {
+            self.depth / self.hit_weight
+        } else {
+            f32::INFINITY
+        }
+    }
+
+    /// The pixel value of `aov`: the vector in `xyz`, the depth and the ids in every
+    /// channel, and 1 in `w`.
+    pub fn value(&self, aov: Aov) -> Vec4 {
+        let id = |index: Option<usize>| Vec3::splat(index.map_or(-1., |i| i as f32)).extend(1.);
+        match aov {
+            Aov::Normal => self.normal().extend(1.),
+            Aov::Depth => Vec3::splat(self.depth()).extend(1.),
+            Aov::Albedo => self.albedo().extend(1.),
+            Aov::MaterialId => id(self.material_index),
+            Aov::ObjectId => id(self.object_index),
+        }
+    }
+}
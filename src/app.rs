@@ -2,19 +2,82 @@ use glam::Vec2;
 use sdl2::event::{Event, WindowEvent};
 
 use sdl2::keyboard::{Keycode, Mod};
-use sdl2::pixels::PixelFormatEnum;
-use sdl2::render::TextureQuery;
+use sdl2::mouse::MouseButton;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::{Point, Rect};
+use sdl2::render::{Canvas, TextureQuery};
+use sdl2::video::Window;
+use std::path::Path;
 use std::time::Instant;
 
-use crate::camera::{Camera, CameraEvent};
+use crate::camera::{Bookmarks, Camera, CameraEvent};
+use crate::changes::Changes;
+use crate::console::Console;
+use crate::gizmo;
+use crate::inspector::Inspector;
+use crate::turntable::Turntable;
 
-use crate::renderer::Renderer;
+use crate::renderer::{RenderSettings, Renderer};
+use crate::scene::Scene;
 use crate::utils::errors::AppError;
+use crate::utils::image::ImageUtils;
+
+/// Seconds between fixed update ticks.
+const TICK: f32 = 1. / 60.;
+
+/// Scene time given to the update function of `App::run`. Space pauses and
+/// resumes, the left and right arrows scrub one tick, `[` and `]` halve and double the
+/// speed.
+#[derive(Debug, Copy, Clone)]
+pub struct Clock {
+    /// Seconds of scene time elapsed.
+    pub time: f32,
+    /// Seconds since the previous update, negative when scrubbing back.
+    pub delta: f32,
+    pub speed: f32,
+    pub paused: bool,
+    /// Advances the scene time by this step every `samples_per_step` rendered frames
+    /// instead of following the wall clock, so animation renders are repeatable.
+    pub fixed_step: Option<f32>,
+    pub samples_per_step: u32,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self {
+            time: 0.,
+            delta: 0.,
+            speed: 1.,
+            paused: false,
+            fixed_step: None,
+            samples_per_step: 1,
+        }
+    }
+}
+
+impl Clock {
+    fn advance(&mut self, delta: f32) {
+        self.delta = delta;
+        self.time += delta;
+    }
+}
 
 pub struct App {}
 
 impl App {
-    pub fn run(camera: &mut Camera, renderer: &mut Renderer) -> Result<(), AppError> {
+    /// Opens a window rendering `scene` through `camera`. `update_func` is called with the
+    /// scene and the clock whenever the scene time advances, and returns whether it
+    /// changed the scene, which restarts the accumulation. Pass `|_, _| false` for a
+    /// static scene.
+    pub fn run(
+        camera: &mut Camera,
+        scene: Scene,
+        settings: RenderSettings,
+        mut clock: Clock,
+        mut update_func: impl FnMut(&mut Scene, &Clock) -> bool,
+    ) -> Result<(), AppError> {
+        let renderer = &mut Renderer::new_with_settings(scene, settings);
+
         let sdl_context = sdl2::init()?;
 
         let video_subsystem = sdl_context.video()?;
@@ -23,6 +86,7 @@ impl App {
             .window("Ray Tracing", 800, 600)
             .position_centered()
             .resizable()
+            .allow_highdpi()
             .build()
             .map_err(|e| e.to_string())?;
 
@@ -34,7 +98,10 @@ impl App {
 
         let texture_creator = canvas.texture_creator();
 
-        let size = canvas.output_size().unwrap();
+        let size = match renderer.settings.resolution {
+            Some((w, h)) => (w as u32, h as u32),
+            None => canvas.output_size()?,
+        };
 
         let mut texture = texture_creator
             .create_texture_streaming(PixelFormatEnum::ABGR8888, size.0, size.1)
@@ -63,19 +130,27 @@ impl App {
 
         let mut last_mouse_pos = Vec2::new(0., 0.);
         let mut mouse_pressed = false;
-        let mut updated = true;
+        let mut changes = Changes::all();
 
         let mut up = false;
         let mut down = false;
         let mut left = false;
         let mut right = false;
         let num_cores = num_cpus::get();
+        let mut bookmarks = Bookmarks::load("camera_bookmarks.txt");
+        let mut inspector: Option<Inspector> = None;
+        let mut cursor = (0, 0);
+        let mut console = Console::default();
+        let mut turntable: Option<Turntable> = None;
+        let mut show_gizmos = false;
+        video_subsystem.text_input().stop();
 
         'running: loop {
             let elapsed = frame_time.elapsed();
             let elapsed_nanos = elapsed.as_nanos() as f64;
             let ts = elapsed.as_secs_f32();
             let mut rotateXY: Option<Vec2> = None;
+            let mut scrub = 0.;
 
             for event in event_pump.poll_iter() {
                 match event {
@@ -90,14 +165,78 @@ impl App {
                         window_id: _,
                         keycode: Some(code),
                         scancode: _,
-                        keymod: _,
+                        keymod,
                         repeat: false,
                     } => {
+                        if code == Keycode::Backquote {
+                            console.toggle();
+                            if console.open {
+                                video_subsystem.text_input().start();
+                                set_title(&mut canvas, &console.prompt())?;
+                            } else {
+                                video_subsystem.text_input().stop();
+                            }
+                            continue;
+                        }
+
+                        if console.open {
+                            match code {
+                                Keycode::Return => match console.execute(renderer, camera, &img) {
+                                    Ok(changed) => changes |= changed,
+                                    Err(e) => log::warn!("Command failed: {}", e),
+                                },
+                                Keycode::Backspace => {
+                                    console.line.pop();
+                                }
+                                Keycode::Up => console.previous(),
+                                _ => {}
+                            }
+                            set_title(&mut canvas, &console.prompt())?;
+                            continue;
+                        }
+
+                        if let Some(slot) = bookmark_slot(code) {
+                            if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+                                if let Err(e) = bookmarks.store(slot, camera) {
+                                    log::error!("Saving bookmark failed: {}", e);
+                                }
+                            } else if bookmarks.recall(slot, camera) {
+                                changes.camera = true;
+                            }
+                        }
+
                         match code {
                             Keycode::W => up = true,
                             Keycode::S => down = true,
                             Keycode::A => left = true,
                             Keycode::D => right = true,
+                            Keycode::V => {
+                                renderer.settings.display = renderer.settings.display.next()
+                            }
+                            Keycode::Space => clock.paused = !clock.paused,
+                            Keycode::Left => scrub -= TICK,
+                            Keycode::Right => scrub += TICK,
+                            Keycode::LeftBracket => clock.speed *= 0.5,
+                            Keycode::RightBracket => clock.speed *= 2.,
+                            Keycode::T => {
+                                let t =
+                                    Turntable::new(camera, &renderer.scene, 120, 64, "turntable");
+                                t.place(camera);
+                                turntable = Some(t);
+                                changes |= Changes::all();
+                            }
+                            Keycode::G => show_gizmos = !show_gizmos,
+                            Keycode::I => {
+                                inspector = match inspector {
+                                    Some(_) => None,
+                                    None => Some(Inspector::open(&video_subsystem)?),
+                                }
+                            }
+                            Keycode::E => {
+                                if let Err(e) = renderer.save_exr("render.exr", camera) {
+                                    log::error!("Export failed: {}", e);
+                                }
+                            }
                             _ => {}
                         };
                     }
@@ -119,6 +258,19 @@ impl App {
                         };
                     }
 
+                    Event::MouseButtonDown {
+                        mouse_btn: MouseButton::Right,
+                        x,
+                        y,
+                        ..
+                    } => {
+                        let (x, y) = image_pixel(&canvas, camera, x, y)?;
+                        if let Some(distance) = renderer.scene.focus_distance(camera, x, y) {
+                            camera.focus_distance = distance;
+                            changes.camera = true;
+                        }
+                    }
+
                     Event::MouseButtonDown {
                         timestamp: _,
                         window_id: _,
@@ -132,6 +284,23 @@ impl App {
                         last_mouse_pos = Vec2::new(x as f32, y as f32);
                         sdl_context.mouse().show_cursor(false);
                     }
+                    Event::TextInput { text, .. } if console.open => {
+                        console
+                            .line
+                            .extend(text.chars().filter(|c| *c != '`' && *c != '~'));
+                        set_title(&mut canvas, &console.prompt())?;
+                    }
+
+                    Event::DropFile { filename, .. } => {
+                        let assign = sdl_context
+                            .keyboard()
+                            .mod_state()
+                            .intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                        match drop_file(&filename, renderer, camera, cursor, assign) {
+                            Ok(()) => changes |= Changes::all(),
+                            Err(e) => log::error!("Loading {} failed: {}", filename, e),
+                        }
+                    }
                     Event::MouseButtonUp { .. } => {
                         mouse_pressed = false;
                         rotateXY = None;
@@ -140,7 +309,7 @@ impl App {
 
                     Event::MouseMotion {
                         timestamp: _,
-                        window_id: _,
+                        window_id,
                         which: _,
                         mousestate: _,
                         x,
@@ -148,6 +317,9 @@ impl App {
                         xrel: _,
                         yrel: _,
                     } => {
+                        if window_id == canvas.window().id() {
+                            cursor = image_pixel(&canvas, camera, x, y)?;
+                        }
                         if mouse_pressed {
                             let mouse_pos = Vec2::new(x as f32, y as f32);
 
@@ -161,21 +333,30 @@ impl App {
                         }
                     }
                     Event::Window {
-                        timestamp: _,
-                        window_id: _,
+                        window_id,
                         win_event,
-                    } => match win_event {
-                        WindowEvent::SizeChanged(w, h) => {
-                            changed = Some((w as usize, h as usize));
-                            img = vec![0; (w * h * 4) as usize];
-                        }
-                        WindowEvent::Resized(w, h) => {
-                            changed = Some((w as usize, h as usize));
-                            img = vec![0; (w * h * 4) as usize];
+                        ..
+                    } if Some(window_id) != inspector.as_ref().map(|i| i.window_id()) => {
+                        match win_event {
+                            _ if renderer.settings.resolution.is_some() => {}
+                            // The event sizes are in window units, which are not pixels on
+                            // HiDPI displays, so the drawable size is queried instead.
+                            WindowEvent::SizeChanged(..)
+                            | WindowEvent::Resized(..)
+                            | WindowEvent::DisplayChanged(_) => {
+                                let (w, h) = canvas.output_size()?;
+                                changed = Some((w as usize, h as usize));
+                                img = vec![0; (w * h * 4) as usize];
+                            }
+                            WindowEvent::Close => break 'running,
+                            WindowEvent::Exposed => {}
+                            _ => {}
                         }
-                        WindowEvent::Exposed => {}
-                        _ => {}
-                    },
+                    }
+                    Event::Window {
+                        win_event: WindowEvent::Close,
+                        ..
+                    } => inspector = None,
                     _ => {}
                 }
             }
@@ -197,23 +378,32 @@ impl App {
                 events.push(CameraEvent::RotateXY { delta })
             }
 
-            if !events.is_empty() {
+            if !events.is_empty() || camera.is_moving() {
                 camera.update(events, ts);
-                updated = true;
+                changes.camera = true;
             }
 
             frame_time = Instant::now();
 
             delta += elapsed_nanos / nanos;
 
+            if scrub != 0. {
+                clock.advance(scrub);
+                changes |= renderer.update_scene(|scene| update_func(scene, &clock));
+            }
+
             while delta >= 1. {
                 // App state updates here.
+                if !clock.paused && clock.fixed_step.is_none() {
+                    clock.advance(TICK * clock.speed);
+                    changes |= renderer.update_scene(|scene| update_func(scene, &clock));
+                }
                 ups += 1;
                 delta -= 1.;
             }
 
             if let Some((w, h)) = changed {
-                updated = true;
+                changes |= Changes::all();
                 camera.update(
                     vec![CameraEvent::Resize {
                         w: w as usize,
@@ -230,20 +420,49 @@ impl App {
             }
 
             canvas.clear();
-            renderer.render_par(&mut texture, &mut img, &camera, updated, num_cores)?;
-            canvas.copy(&texture, None, None)?;
+            renderer.render_par(&mut texture, &mut img, &camera, &changes, num_cores)?;
+            let view = letterbox(canvas.output_size()?, camera);
+            canvas.copy(&texture, None, view)?;
+            if show_gizmos {
+                draw_gizmos(&mut canvas, view, &renderer.scene, camera)?;
+            }
             canvas.present();
+            changes = Changes::default();
+
+            if let Some(step) = clock.fixed_step {
+                let accumulated = renderer.frame_index.saturating_sub(1);
+                if !clock.paused && accumulated >= clock.samples_per_step {
+                    clock.advance(step * clock.speed);
+                    changes |= renderer.update_scene(|scene| update_func(scene, &clock));
+                }
+            }
+
+            if let Some(t) = turntable.as_mut() {
+                let accumulated = renderer.frame_index.saturating_sub(1);
+                match t.advance(camera, &img, accumulated)? {
+                    Some(true) => changes |= Changes::all(),
+                    Some(false) => {}
+                    None => turntable = None,
+                }
+            }
+
+            if let Some(inspector) = inspector.as_mut() {
+                let (x, y) = cursor;
+                let color = (x >= 0 && y >= 0 && (x as usize) < camera.width)
+                    .then(|| renderer.average(y as usize * camera.width + x as usize))
+                    .flatten();
+                inspector.draw(&img, camera.width, camera.height, cursor, color)?;
+            }
 
-            updated = false;
             fps += 1;
 
             let millis = timer.elapsed().as_millis();
 
-            if millis > 1000 {
+            if millis > 1000 && !console.open {
                 timer = Instant::now();
                 canvas
                     .window_mut()
-                    .set_title(format!("ups {} / fps {}", ups, fps).as_str())
+                    .set_title(format!("ups {} / fps {} / t {:.2}", ups, fps, clock.time).as_str())
                     .map_err(|e| e.to_string())?;
                 ups = 0;
                 fps = 0;
@@ -253,3 +472,131 @@ impl App {
         Ok(())
     }
 }
+
+/// Draws the light gizmos over the image shown in `view`.
+fn draw_gizmos(
+    canvas: &mut Canvas<Window>,
+    view: Rect,
+    scene: &Scene,
+    camera: &Camera,
+) -> Result<(), AppError> {
+    let scale = Vec2::new(
+        view.width() as f32 / camera.width.max(1) as f32,
+        view.height() as f32 / camera.height.max(1) as f32,
+    );
+    let point = |p: Vec2| {
+        let p = p * scale;
+        Point::new(view.x() + p.x as i32, view.y() + p.y as i32)
+    };
+
+    canvas.set_clip_rect(view);
+    canvas.set_draw_color(Color::RGB(255, 220, 40));
+    for (a, b) in gizmo::light_gizmos(scene, camera) {
+        canvas.draw_line(point(a), point(b))?;
+    }
+    canvas.set_clip_rect(None);
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+    Ok(())
+}
+
+fn set_title(canvas: &mut Canvas<Window>, title: &str) -> Result<(), AppError> {
+    canvas
+        .window_mut()
+        .set_title(title)
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Loads a file dropped on the window. Scene files replace the scene, and the camera when
+/// they have one. Images are added to the scene textures, and with `assign` also given to
+/// the material of the object under the cursor.
+fn drop_file(
+    path: &str,
+    renderer: &mut Renderer,
+    camera: &mut Camera,
+    (x, y): (i32, i32),
+    assign: bool,
+) -> Result<(), AppError> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    if extension == "ron" || extension == "json" {
+        let (loaded, loaded_camera) = Scene::load_with_camera(path)?;
+        renderer.update_scene(|scene| {
+            *scene = loaded;
+            true
+        });
+        // Keep the window size, which the saved camera may not match.
+        if let Some(loaded_camera) = loaded_camera {
+            let (w, h) = (camera.width, camera.height);
+            *camera = loaded_camera;
+            camera.update(vec![CameraEvent::Resize { w, h }], 0.);
+        }
+        return Ok(());
+    }
+
+    let texture = ImageUtils::load_image(path)?;
+    renderer.update_scene(|scene| {
+        scene.textures.push(texture);
+        if assign {
+            if let Some((_, hit)) = scene.pick(camera, x, y) {
+                scene.materials[hit.material_index].texture = Some(scene.textures.len() - 1);
+            }
+        }
+        true
+    });
+    Ok(())
+}
+
+/// Image pixel under a mouse position given in window units. The drawable may have more
+/// pixels than the window has units, and the image may be letterboxed.
+fn image_pixel(
+    canvas: &Canvas<Window>,
+    camera: &Camera,
+    x: i32,
+    y: i32,
+) -> Result<(i32, i32), AppError> {
+    let (w, h) = canvas.window().size();
+    let (pw, ph) = canvas.window().drawable_size();
+    let (x, y) = (
+        x * pw as i32 / w.max(1) as i32,
+        y * ph as i32 / h.max(1) as i32,
+    );
+    let view = letterbox(canvas.output_size()?, camera);
+    Ok((
+        (x - view.x()) * camera.width as i32 / view.width() as i32,
+        (y - view.y()) * camera.height as i32 / view.height() as i32,
+    ))
+}
+
+/// Largest area of the window showing the image with its aspect ratio, centered.
+fn letterbox(window: (u32, u32), camera: &Camera) -> Rect {
+    let (w, h) = (camera.width.max(1) as u32, camera.height.max(1) as u32);
+    let scale = (window.0 as f32 / w as f32).min(window.1 as f32 / h as f32);
+    let (dw, dh) = ((w as f32 * scale) as u32, (h as f32 * scale) as u32);
+    Rect::new(
+        ((window.0 - dw) / 2) as i32,
+        ((window.1 - dh) / 2) as i32,
+        dw.max(1),
+        dh.max(1),
+    )
+}
+
+/// Bookmark slot of a number key: Ctrl+number stores the view, number alone recalls it.
+fn bookmark_slot(code: Keycode) -> Option<usize> {
+    let keys = [
+        Keycode::Num0,
+        Keycode::Num1,
+        Keycode::Num2,
+        Keycode::Num3,
+        Keycode::Num4,
+        Keycode::Num5,
+        Keycode::Num6,
+        Keycode::Num7,
+        Keycode::Num8,
+        Keycode::Num9,
+    ];
+    keys.iter().position(|k| *k == code)
+}
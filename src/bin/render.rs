@@ -0,0 +1,220 @@
+use std::env;
+use std::time::{Duration, Instant};
+
+use ray_tracing::accel::Aabb;
+use ray_tracing::prelude::*;
+
+const USAGE: &str = "usage: render [--width <w>] [--height <h>] [--spp <n>] \
+                     [--target-error <e>] [--time-limit <seconds>] [--tile <size>] \
+                     [--dataset <samples>] [--seed <n>] \
+                     [--model <file.obj|file.gltf>] [--scene <file.json|file.ron>] \
+                     <output.png|output.exr|output directory>";
+
+/// Renders a scene without a window and saves it. Without `--model` or `--scene`, a few
+/// spheres on a floor are rendered. With `--target-error` or `--time-limit`, `--spp` is
+/// the most samples traced and the render stops as soon as either is reached. With
+/// `--tile`, the image is rendered straight into the file in square tiles of that size,
+/// for images too large to keep in memory, and always gets `--spp` samples. With
+/// `--dataset`, that many randomized variations of the scene are rendered into the output
+/// directory with their depth, normals, object masks and cameras, the floor and other
+/// objects too large to frame staying in place. With `--seed`, every run renders the same
+/// image.
+pub fn main() -> Result<(), AppError> {
+    ray_tracing::utils::logging::init_logging();
+
+    let mut width = 800;
+    let mut height = 600;
+    let mut spp = 64;
+    let mut target_error: Option<f32> = None;
+    let mut time_limit: Option<Duration> = None;
+    let mut tile: Option<usize> = None;
+    let mut dataset: Option<usize> = None;
+    let mut seed: Option<usize> = None;
+    let mut model: Option<String> = None;
+    let mut scene_file: Option<String> = None;
+    let mut output: Option<String> = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| AppError::Validation(format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "--width" => width = number(&value()?)?,
+            "--height" => height = number(&value()?)?,
+            "--spp" => spp = number(&value()?)?,
+            "--target-error" => target_error = Some(fraction(&value()?)?),
+            "--time-limit" => {
+                time_limit = Some(Duration::from_secs_f32(fraction(&value()?)?));
+            }
+            "--tile" => tile = Some(number(&value()?)?),
+            "--dataset" => dataset = Some(number(&value()?)?),
+            "--seed" => seed = Some(number(&value()?)?),
+            "--model" => model = Some(value()?),
+            "--scene" => scene_file = Some(value()?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if output.is_none() && !arg.starts_with('-') => output = Some(arg),
+            _ => return Err(AppError::Validation(USAGE.to_string())),
+        }
+    }
+    let output = output.ok_or_else(|| AppError::Validation(USAGE.to_string()))?;
+
+    let (scene, camera) = match (scene_file, model) {
+        (Some(path), _) => Scene::load_with_camera(path)?,
+        (None, Some(path)) => (model_scene(&path)?, None),
+        (None, None) => (demo_scene(), None),
+    };
+    let camera = camera.unwrap_or_else(|| frame_scene(&scene));
+
+    let start = Instant::now();
+    if let Some(samples) = dataset {
+        let fixed = (0..scene.objects.len())
+            .filter(|i| !frames(&scene.objects[*i]))
+            .collect();
+        Dataset::new(scene, camera)
+            .resolution(width, height)
+            .spp(spp as u32)
+            .samples(samples)
+            .fixed(fixed)
+            .seed(seed.unwrap_or(0) as u64)
+            .render(&output)?;
+        log::info!(
+            "Rendered {} samples into {} in {:?}",
+            samples,
+            output,
+            start.elapsed()
+        );
+        return Ok(());
+    }
+    let mut job = RenderJob::new(scene, camera)
+        .resolution(width, height)
+        .spp(spp as u32);
+    if let Some(error) = target_error {
+        job = job.target_error(error);
+    }
+    if let Some(limit) = time_limit {
+        job = job.time_limit(limit);
+    }
+    if let Some(seed) = seed {
+        job = job.seed(seed as u64);
+    }
+    if let Some(tile) = tile {
+        job.render_tiled(&output, tile)?;
+        log::info!("Rendered {} in {:?} tile by tile", output, start.elapsed());
+        return Ok(());
+    }
+    let frame = job.render()?;
+    frame.save(&output)?;
+    log::info!(
+        "Rendered {} in {:?}, {} samples per pixel",
+        output,
+        start.elapsed(),
+        frame.spp
+    );
+    Ok(())
+}
+
+fn number(value: &str) -> Result<usize, AppError> {
+    value
+        .parse()
+        .map_err(|_| AppError::Validation(format!("bad number {}", value)))
+}
+
+fn fraction(value: &str) -> Result<f32, AppError> {
+    value
+        .parse()
+        .ok()
+        .filter(|v: &f32| *v >= 0. && v.is_finite())
+        .ok_or_else(|| AppError::Validation(format!("bad value {}", value)))
+}
+
+fn model_scene(path: &str) -> Result<Scene, AppError> {
+    if path.to_lowercase().ends_with(".obj") {
+        let mesh = ObjUtils::load_obj(path)?;
+        let material = Material {
+            albedo: Vec3::splat(0.7),
+            kind: MaterialType::Reflective { roughness: 0.6 },
+            ..Default::default()
+        };
+        return Ok(Scene::new(
+            vec![Object3D::new_mesh(mesh, 0)],
+            vec![material],
+        ));
+    }
+
+    #[cfg(feature = "gltf")]
+    return Scene::from_gltf(path);
+    #[cfg(not(feature = "gltf"))]
+    Err(AppError::Validation(format!(
+        "{}: only .obj models are supported without the gltf feature",
+        path
+    )))
+}
+
+fn demo_scene() -> Scene {
+    Scene::new(
+        vec![
+            Object3D::sphere()
+                .position(vec3(-0.6, 0., 0.))
+                .radius(0.5)
+                .build(),
+            Object3D::sphere()
+                .position(vec3(0.6, 0., 0.))
+                .radius(0.5)
+                .material(1)
+                .build(),
+            Object3D::sphere()
+                .position(vec3(0., -100.5, 0.))
+                .radius(100.)
+                .material(2)
+                .build(),
+        ],
+        vec![
+            Material {
+                albedo: vec3(0.9, 0.3, 0.2),
+                kind: MaterialType::Reflective { roughness: 0.3 },
+                ..Default::default()
+            },
+            Material {
+                albedo: Vec3::ONE,
+                kind: MaterialType::Refractive {
+                    transparency: 1.,
+                    refraction_index: 1.5,
+                    reflectivity: 0.1,
+                    roughness: 0.,
+                },
+                ..Default::default()
+            },
+            Material {
+                albedo: Vec3::splat(0.5),
+                kind: MaterialType::Reflective { roughness: 0.8 },
+                ..Default::default()
+            },
+        ],
+    )
+}
+
+/// Camera looking at the scene bounds from the front and slightly above, far enough back
+/// to see all of them. The demo floor sphere is left out of the bounds.
+fn frame_scene(scene: &Scene) -> Camera {
+    let bounds = scene
+        .objects
+        .iter()
+        .filter(|o| frames(o))
+        .map(|o| o.bounds())
+        .reduce(|a, b| a.union(&b))
+        .unwrap_or(Aabb::new(Vec3::splat(-1.), Vec3::ONE));
+    let radius = bounds.extent().length() * 0.5;
+    let direction = vec3(0., 0.3, 1.).normalize();
+    let position = bounds.center() + direction * radius * 2.5;
+    Camera::new_with_pos(position, -direction)
+}
+
+/// Whether an object is small enough to be framed, unlike the demo floor sphere.
+fn frames(object: &Object3D) -> bool {
+    object.bounds().extent().max_element() < 50.
+}
@@ -1,6 +1,305 @@
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::sync::Arc;
+
 use glam::{Mat4, Vec2, Vec3, Vec4};
+use serde::{Deserialize, Serialize};
+
+use crate::objects::Texture;
+use crate::post;
+use crate::ray::{Ray, EPSILON};
+use crate::utils::errors::AppError;
+use crate::utils::image::ImageUtils;
+
+/// Smooths navigation: the camera speeds up and coasts to a stop instead of following
+/// the input instantly. The values are time constants in seconds, larger being smoother.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Damping {
+    pub position: f32,
+    pub rotation: f32,
+}
+
+impl Default for Damping {
+    fn default() -> Self {
+        Self {
+            position: 0.3,
+            rotation: 0.15,
+        }
+    }
+}
+
+/// Procedural hand-held shake added on top of the camera pose.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Shake {
+    /// Largest positional offset.
+    pub amplitude: f32,
+    /// Largest rotation, in degrees.
+    pub rotation: f32,
+    /// Main frequency, in Hz.
+    pub frequency: f32,
+}
+
+impl Default for Shake {
+    fn default() -> Self {
+        Self {
+            amplitude: 0.02,
+            rotation: 0.5,
+            frequency: 2.,
+        }
+    }
+}
+
+impl Shake {
+    /// Smooth pseudo-random value in `[-1, 1]`: a few incommensurate sines per channel.
+    fn noise(&self, time: f32, channel: f32) -> f32 {
+        let t = 2. * std::f32::consts::PI * self.frequency * time;
+        0.5 * (t + channel * 1.7).sin()
+            + 0.3 * (2.31 * t + channel * 3.1).sin()
+            + 0.2 * (4.57 * t + channel * 5.3).sin()
+    }
+}
+
+/// Lens and sensor of a real camera, used instead of `Camera::fov` when set. Lengths are
+/// in millimetres.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct PhysicalCamera {
+    pub focal_length: f32,
+    pub sensor_width: f32,
+    /// Lens shift as a fraction of the sensor size, positive values moving the frame right
+    /// and up without tilting the camera.
+    pub shift: Vec2,
+}
+
+impl Default for PhysicalCamera {
+    fn default() -> Self {
+        // 50mm lens on a full-frame sensor.
+        Self {
+            focal_length: 50.,
+            sensor_width: 36.,
+            shift: Vec2::ZERO,
+        }
+    }
+}
+
+impl PhysicalCamera {
+    /// Vertical field of view in radians, the sensor height following the image aspect.
+    pub fn fov_y(&self, aspect: f32) -> f32 {
+        let sensor_height = self.sensor_width / aspect;
+        2. * (sensor_height / (2. * self.focal_length)).atan()
+    }
+}
+
+/// How the pixels of the image map to rays.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum Projection {
+    /// A pinhole camera seeing `Camera::fov`, or through `Camera::physical`.
+    #[default]
+    Perspective,
+    /// Parallel rays along the view direction, for isometric views. `height` is the extent
+    /// of the view in world units.
+    Orthographic { height: f32 },
+    /// Equidistant fisheye: the angle from the view direction grows linearly away from the
+    /// image centre, reaching half of `fov`, in degrees, at the top and bottom edges.
+    Fisheye { fov: f32 },
+    /// 360° panorama, longitude across the image and latitude down it.
+    Equirectangular,
+}
+
+impl Projection {
+    /// Whether all the rays start from the eye. Orthographic rays start across the image
+    /// plane instead.
+    fn is_central(&self) -> bool {
+        !matches!(self, Projection::Orthographic { .. })
+    }
+}
+
+/// Shape of the lens aperture, which the out-of-focus highlights take.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Bokeh {
+    #[default]
+    Disk,
+    /// Aperture closed by `blades` straight blades into a regular polygon, turned by
+    /// `rotation` radians. Fewer than 3 blades leave a disk.
+    Polygon { blades: u32, rotation: f32 },
+    /// Aperture shaped by an image over the square around the lens, letting light through
+    /// in proportion to its luminance.
+    Mask(Arc<ApertureMask>),
+}
+
+impl Bokeh {
+    /// Point of the aperture picked by `sample`, in `[0, 1)²`, with the lens radius as unit.
+    /// Masks cover the whole square from -1 to 1.
+    pub fn sample(&self, sample: Vec2) -> Vec2 {
+        match self {
+            Bokeh::Polygon { blades, rotation } if *blades >= 3 => {
+                // A random triangle between the centre and an edge, then a point of it.
+                let n = *blades as f32;
+                let edge = (sample.x * n).floor().min(n - 1.);
+                let corner = |k: f32| Vec2::from_angle(rotation + 2. * PI * k / n);
+                let r = (sample.x * n - edge).sqrt();
+                corner(edge).lerp(corner(edge + 1.), sample.y) * r
+            }
+            Bokeh::Mask(mask) => mask.sample(sample),
+            _ => Vec2::from_angle(2. * PI * sample.y) * sample.x.sqrt(),
+        }
+    }
+}
 
+/// Image the light through the lens is shaped by, for `Bokeh::Mask`. Keeps the
+/// cumulative distribution of its texels by luminance. Scene files store the texture and
+/// the distribution is rebuilt on load.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "Texture", into = "Texture")]
+pub struct ApertureMask {
+    pub texture: Texture,
+    cdf: Vec<f32>,
+}
+
+impl From<Texture> for ApertureMask {
+    fn from(texture: Texture) -> Self {
+        let mut total = 0.;
+        let mut cdf: Vec<f32> = texture
+            .texels
+            .iter()
+            .map(|c| {
+                total += post::luminance(*c).max(0.);
+                total
+            })
+            .collect();
+        if total > 0. {
+            cdf.iter_mut().for_each(|c| *c /= total);
+        } else {
+            cdf.clear();
+        }
+        ApertureMask { texture, cdf }
+    }
+}
+
+impl From<ApertureMask> for Texture {
+    fn from(mask: ApertureMask) -> Self {
+        mask.texture
+    }
+}
+
+impl ApertureMask {
+    pub fn load(path: impl Into<String>) -> Result<ApertureMask, AppError> {
+        ImageUtils::load_image(path).map(ApertureMask::from)
+    }
+
+    /// Point of the mask drawn by its luminance, from -1 to 1 with the top of the image
+    /// up. The centre when the mask is all black.
+    fn sample(&self, sample: Vec2) -> Vec2 {
+        if self.cdf.is_empty() {
+            return Vec2::ZERO;
+        }
+        let texel = self
+            .cdf
+            .partition_point(|c| *c < sample.x)
+            .min(self.cdf.len() - 1);
+        // Where `sample.x` falls within the texel, so the point stays stratified.
+        let start = if texel > 0 { self.cdf[texel - 1] } else { 0. };
+        let u = ((sample.x - start) / (self.cdf[texel] - start).max(f32::EPSILON)).min(1.);
+        let w = self.texture.width.max(1) as usize;
+        let (x, y) = (texel % w, texel / w);
+        let size = Vec2::new(w as f32, self.texture.height.max(1) as f32);
+        let p = Vec2::new(x as f32 + u, y as f32 + sample.y) / size * 2. - 1.;
+        Vec2::new(p.x, -p.y)
+    }
+}
+
+/// A saved camera view.
+#[derive(Debug, Copy, Clone)]
+pub struct Bookmark {
+    pub position: Vec3,
+    pub forward_direction: Vec3,
+    pub fov: f32,
+}
+
+/// Ten camera bookmarks persisted to a small text file, one `slot x y z dx dy dz fov`
+/// line per saved view.
 #[derive(Debug, Clone)]
+pub struct Bookmarks {
+    pub path: String,
+    pub slots: [Option<Bookmark>; 10],
+}
+
+impl Bookmarks {
+    /// Reads the bookmarks saved at `path`. A missing file or bad lines give empty slots.
+    pub fn load(path: impl Into<String>) -> Bookmarks {
+        let mut bookmarks = Bookmarks {
+            path: path.into(),
+            slots: [None; 10],
+        };
+
+        let text = std::fs::read_to_string(&bookmarks.path).unwrap_or_default();
+        for line in text.lines() {
+            let v: Vec<f32> = line
+                .split_whitespace()
+                .filter_map(|w| w.parse().ok())
+                .collect();
+            if let [slot, x, y, z, dx, dy, dz, fov] = v[..] {
+                if let Some(s) = bookmarks.slots.get_mut(slot as usize) {
+                    *s = Some(Bookmark {
+                        position: Vec3::new(x, y, z),
+                        forward_direction: Vec3::new(dx, dy, dz),
+                        fov,
+                    });
+                }
+            }
+        }
+
+        bookmarks
+    }
+
+    pub fn save(&self) -> Result<(), AppError> {
+        let mut text = String::new();
+        for (slot, b) in self.slots.iter().enumerate() {
+            if let Some(b) = b {
+                let (p, d) = (b.position, b.forward_direction);
+                text += &format!(
+                    "{} {} {} {} {} {} {} {}\n",
+                    slot, p.x, p.y, p.z, d.x, d.y, d.z, b.fov
+                );
+            }
+        }
+        std::fs::write(&self.path, text)?;
+        Ok(())
+    }
+
+    /// Stores the current view of `camera` in `slot` and saves the file.
+    pub fn store(&mut self, slot: usize, camera: &Camera) -> Result<(), AppError> {
+        self.slots[slot] = Some(Bookmark {
+            position: camera.position,
+            forward_direction: camera.forward_direction,
+            fov: camera.fov,
+        });
+        self.save()
+    }
+
+    /// Moves `camera` to the view in `slot`, returning false if the slot is empty.
+    pub fn recall(&self, slot: usize, camera: &mut Camera) -> bool {
+        let Some(b) = self.slots[slot] else {
+            return false;
+        };
+        camera.position = b.position;
+        camera.forward_direction = b.forward_direction;
+        camera.fov = b.fov;
+        camera.velocity = Vec3::ZERO;
+        camera.angular_velocity = Vec2::ZERO;
+        camera.update(
+            vec![CameraEvent::Resize {
+                w: camera.width,
+                h: camera.height,
+            }],
+            0.,
+        );
+        true
+    }
+}
+
+/// Scene files keep the settings and the pose; the matrices and the ray directions are
+/// derived again by `update`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Camera {
     pub width: usize,
     pub height: usize,
@@ -10,11 +309,46 @@ pub struct Camera {
     pub position: Vec3,
     pub forward_direction: Vec3,
     pub up: Vec3,
+    #[serde(skip)]
     pub view: Mat4,
+    #[serde(skip)]
     pub inverse_view: Mat4,
+    #[serde(skip)]
     pub perspective: Mat4,
+    #[serde(skip)]
     pub inverse_perspective: Mat4,
+    #[serde(skip)]
     pub ray_directions: Vec<Vec3>,
+    pub damping: Option<Damping>,
+    pub shake: Option<Shake>,
+    #[serde(skip)]
+    pub velocity: Vec3,
+    /// Yaw and pitch speed, in degrees per second.
+    #[serde(skip)]
+    pub angular_velocity: Vec2,
+    /// Seconds of updates so far, driving the shake.
+    #[serde(skip)]
+    pub time: f32,
+    /// Distance along the view axis that is in focus.
+    pub focus_distance: f32,
+    /// Radius of the thin lens the rays start from. 0 keeps a pinhole camera with
+    /// everything in focus, larger values blur what is away from `focus_distance`.
+    pub aperture: f32,
+    pub bokeh: Bokeh,
+    /// Distance from the aperture to the rim of the lens barrel, in aperture radii. The
+    /// rim, as wide as the aperture, blocks part of the light of off-axis rays: out of
+    /// focus highlights turn into cat's eyes and the corners darken, more so the further
+    /// the rim. 0 leaves every ray through.
+    pub vignetting: f32,
+    pub physical: Option<PhysicalCamera>,
+    /// Keep a direction per pixel in `ray_directions`, rebuilt on every change. Otherwise
+    /// `rays` derives them on the fly, saving the memory and the rebuild on resize.
+    pub precomputed_rays: bool,
+    pub projection: Projection,
+    /// Part of the frame the shutter is open for, from 0 to 1: each sample traces its
+    /// rays at a random time in between, blurring the objects with a `Motion`. Equal
+    /// bounds freeze the motion at that time.
+    pub shutter: Vec2,
 }
 
 impl Default for Camera {
@@ -24,8 +358,8 @@ impl Default for Camera {
 
         let up = Vec3::new(0., 1., 0.);
         let fov: f32 = 45.0;
-        let near: f32 = -1.;
-        let far: f32 = -100.;
+        let near: f32 = 0.1;
+        let far: f32 = 100.;
         let view = Mat4::IDENTITY;
         let inverse_view = Mat4::IDENTITY;
         let perspective = Mat4::IDENTITY;
@@ -45,6 +379,19 @@ impl Default for Camera {
             perspective,
             inverse_perspective,
             ray_directions: Vec::new(),
+            damping: None,
+            shake: None,
+            velocity: Vec3::ZERO,
+            angular_velocity: Vec2::ZERO,
+            time: 0.,
+            focus_distance: 5.,
+            aperture: 0.,
+            bokeh: Bokeh::Disk,
+            vignetting: 0.,
+            physical: None,
+            precomputed_rays: true,
+            projection: Projection::Perspective,
+            shutter: Vec2::ZERO,
         }
     }
 }
@@ -82,80 +429,396 @@ impl Camera {
         Mat4::from_cols_array(&[1., 0., 0., 0., 0., c, -s, 0., 0., s, c, 0., 0., 0., 0., 1.])
     }
 
+    /// Whether the camera keeps moving without input, from damping or shake, so `update`
+    /// must still be called every frame.
+    pub fn is_moving(&self) -> bool {
+        self.shake.is_some()
+            || self.velocity.length_squared() > 1e-6
+            || self.angular_velocity.length_squared() > 1e-4
+    }
+
+    /// Sets the clipping planes, which must satisfy `0 < near < far`. Nothing closer than
+    /// `near` is drawn, and depth outputs are clamped to `[near, far]`.
+    pub fn set_clip_planes(&mut self, near: f32, far: f32) -> Result<(), AppError> {
+        if !(near > 0. && far > near && far.is_finite()) {
+            return Err(AppError::Validation(format!(
+                "Invalid clip planes near {} far {}, expected 0 < near < far",
+                near, far
+            )));
+        }
+        self.near = near;
+        self.far = far;
+        self.update(
+            vec![CameraEvent::Resize {
+                w: self.width,
+                h: self.height,
+            }],
+            0.,
+        );
+        Ok(())
+    }
+
+    /// Primary ray through `pixel`, starting on the near plane.
+    pub fn ray(&self, pixel: usize) -> Ray {
+        if self.precomputed_rays && self.projection.is_central() {
+            return self.ray_towards(self.ray_directions[pixel]);
+        }
+        let (x, y) = (pixel % self.width, pixel / self.width);
+        self.ray_at(x as f32, y as f32)
+    }
+
+    /// Primary rays of the `count` pixels starting at `first`, row by row.
+    pub fn rays(&self, first: usize, count: usize) -> Vec<Ray> {
+        if self.precomputed_rays && self.projection.is_central() {
+            return self.ray_directions[first..first + count]
+                .iter()
+                .map(|d| self.ray_towards(*d))
+                .collect();
+        }
+        if self.projection != Projection::Perspective {
+            return (first..first + count)
+                .map(|pixel| self.ray_at((pixel % self.width) as f32, (pixel / self.width) as f32))
+                .collect();
+        }
+
+        // The unnormalized direction is affine in the pixel coordinates, so each ray is
+        // two multiply-adds and a normalization away from the basis.
+        let [corner, dx, dy] = self.ray_basis();
+        (first..first + count)
+            .map(|pixel| {
+                let (x, y) = ((pixel % self.width) as f32, (pixel / self.width) as f32);
+                self.ray_towards((corner + dx * x + dy * y).normalize())
+            })
+            .collect()
+    }
+
+    /// Primary rays of the pixels starting at `first`, row by row, each through the point
+    /// shifted by its offset, in pixels, from the pixel position.
+    pub fn rays_offset(&self, first: usize, offsets: &[Vec2]) -> Vec<Ray> {
+        let [corner, dx, dy] = self.ray_basis();
+        offsets
+            .iter()
+            .enumerate()
+            .map(|(i, offset)| {
+                let pixel = first + i;
+                let x = (pixel % self.width) as f32 + offset.x;
+                let y = (pixel / self.width) as f32 + offset.y;
+                if self.projection == Projection::Perspective {
+                    self.ray_towards((corner + dx * x + dy * y).normalize())
+                } else {
+                    self.ray_at(x, y)
+                }
+            })
+            .collect()
+    }
+
+    /// Primary ray through the point `(x, y)` of the image, in pixels, for any projection.
+    pub fn ray_at(&self, x: f32, y: f32) -> Ray {
+        let Projection::Orthographic { height } = self.projection else {
+            return self.ray_towards(self.direction_at(x, y));
+        };
+        let (screen_x, screen_y) = self.screen(x, y);
+        let half = height * 0.5;
+        let aspect = self.width as f32 / self.height as f32;
+        let plane = Vec3::new(screen_x * half * aspect, screen_y * half, 0.);
+        let direction = self.inverse_view.transform_vector3(Vec3::NEG_Z).normalize();
+        Ray {
+            origin: self.inverse_view.transform_point3(plane) + direction * self.near,
+            direction,
+            width: height / self.height as f32,
+            spread: 0.,
+            time: self.shutter.x,
+            pdf: 0.,
+        }
+    }
+
+    /// Point `(x, y)` of the image, in pixels, in `[-1, 1]²` with y up.
+    fn screen(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            2.0 * x / self.width as f32 - 1.,
+            1. - 2.0 * y / self.height as f32,
+        )
+    }
+
+    /// World-space direction of the ray through the point `(x, y)` of the image, in pixels.
+    /// Orthographic rays all share the view direction.
+    fn direction_at(&self, x: f32, y: f32) -> Vec3 {
+        let (screen_x, screen_y) = self.screen(x, y);
+        let aspect = self.width as f32 / self.height as f32;
+        let view_direction = match self.projection {
+            Projection::Perspective => {
+                let target = self.inverse_perspective * Vec4::new(screen_x, screen_y, 1., 1.);
+                Vec3::new(target.x, target.y, target.z) / target.w
+            }
+            Projection::Orthographic { .. } => Vec3::NEG_Z,
+            Projection::Fisheye { fov } => {
+                let (x, y) = (screen_x * aspect, screen_y);
+                let theta = Vec2::new(x, y).length() * fov.to_radians() * 0.5;
+                let phi = y.atan2(x);
+                Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    -theta.cos(),
+                )
+            }
+            Projection::Equirectangular => {
+                let longitude = screen_x * PI;
+                let latitude = screen_y * FRAC_PI_2;
+                Vec3::new(
+                    latitude.cos() * longitude.sin(),
+                    latitude.sin(),
+                    -latitude.cos() * longitude.cos(),
+                )
+            }
+        };
+        self.inverse_view
+            .transform_vector3(view_direction)
+            .normalize()
+    }
+
+    /// Unnormalized world-space direction through pixel (0, 0), and its change per pixel
+    /// along x and y.
+    pub fn ray_basis(&self) -> [Vec3; 3] {
+        let direction = |x: f32, y: f32| {
+            let p_screen_x = 2.0 * x / self.width as f32 - 1.;
+            let p_screen_y = 1. - 2.0 * y / self.height as f32;
+            let target = self.inverse_perspective * Vec4::new(p_screen_x, p_screen_y, 1., 1.);
+            let v3 = Vec3::new(target.x, target.y, target.z) / target.w;
+            self.inverse_view.transform_vector3(v3)
+        };
+        let corner = direction(0., 0.);
+        [
+            corner,
+            direction(1., 0.) - corner,
+            direction(0., 1.) - corner,
+        ]
+    }
+
+    /// Turns a primary ray into one from a point of the lens picked by `sample`, in
+    /// `[0, 1)²` and shaped by `bokeh`, aimed at where the pinhole ray crosses the focus
+    /// plane. `None` when the lens barrel blocks it, under `vignetting`.
+    /// Panoramic projections have no focus plane and keep everything in focus.
+    pub fn lens_ray(&self, ray: Ray, sample: Vec2) -> Option<Ray> {
+        if self.aperture <= 0.
+            || matches!(
+                self.projection,
+                Projection::Fisheye { .. } | Projection::Equirectangular
+            )
+        {
+            return Some(ray);
+        }
+        let forward = self.forward_direction.normalize();
+        // Where the ray crosses the plane of the eye: the eye itself, unless orthographic.
+        let cos = ray.direction.dot(forward).max(EPSILON);
+        let center = ray.origin - ray.direction * (self.near / cos);
+        let focus = center + ray.direction * (self.focus_distance / cos);
+
+        let aperture_point = self.bokeh.sample(sample);
+        let p = aperture_point * self.aperture;
+        let right = self.inverse_view.x_axis.truncate();
+        let up = self.inverse_view.y_axis.truncate();
+        let lens = center + right * p.x + up * p.y;
+
+        let direction = (focus - lens).normalize();
+        let cos = direction.dot(forward).max(EPSILON);
+        if self.vignetting > 0. {
+            // Where the ray crosses the plane of the rim, in aperture radii from its centre.
+            let slope = Vec2::new(direction.dot(right), direction.dot(up)) / cos;
+            if (aperture_point + slope * self.vignetting).length_squared() > 1. {
+                return None;
+            }
+        }
+        Some(Ray {
+            origin: lens + direction * (self.near / cos),
+            direction,
+            ..ray
+        })
+    }
+
+    /// Angle one pixel spans at the centre of the image, the spread of primary ray cones.
+    pub fn pixel_spread(&self) -> f32 {
+        match self.projection {
+            Projection::Perspective => 2. / (self.perspective.y_axis.y * self.height as f32),
+            Projection::Orthographic { .. } => 0.,
+            Projection::Fisheye { fov } => fov.to_radians() / self.height as f32,
+            Projection::Equirectangular => PI / self.height as f32,
+        }
+    }
+
+    /// Ray from the eye along `direction`, starting on the near plane, or at `near` from
+    /// the eye for panoramic projections, which see behind it too.
+    fn ray_towards(&self, direction: Vec3) -> Ray {
+        let cos = if self.projection == Projection::Perspective {
+            direction
+                .dot(self.forward_direction.normalize())
+                .max(EPSILON)
+        } else {
+            1.
+        };
+        let spread = self.pixel_spread();
+        Ray {
+            origin: self.position + direction * (self.near / cos),
+            direction,
+            width: spread * self.near / cos,
+            spread,
+            time: self.shutter.x,
+            pdf: 0.,
+        }
+    }
+
+    /// Whether rays are traced at different times, blurring moving objects.
+    pub fn motion_blur(&self) -> bool {
+        self.shutter.x < self.shutter.y
+    }
+
+    /// Image pixel a world point projects to, or `None` when it is behind the near plane.
+    pub fn project(&self, point: Vec3) -> Option<Vec2> {
+        let aspect = self.width as f32 / self.height as f32;
+        let p = self.view.transform_point3(point);
+        let (screen_x, screen_y) = match self.projection {
+            Projection::Perspective => {
+                let clip = self.perspective * p.extend(1.);
+                if clip.w < self.near {
+                    return None;
+                }
+                (clip.x / clip.w, clip.y / clip.w)
+            }
+            Projection::Orthographic { height } => {
+                if -p.z < self.near {
+                    return None;
+                }
+                (p.x / (height * 0.5 * aspect), p.y / (height * 0.5))
+            }
+            Projection::Fisheye { fov } => {
+                if p.length() < self.near {
+                    return None;
+                }
+                let d = p.normalize();
+                let r = (-d.z).clamp(-1., 1.).acos() / (fov.to_radians() * 0.5);
+                let phi = d.y.atan2(d.x);
+                (r * phi.cos() / aspect, r * phi.sin())
+            }
+            Projection::Equirectangular => {
+                if p.length() < self.near {
+                    return None;
+                }
+                let d = p.normalize();
+                (d.x.atan2(-d.z) / PI, d.y.clamp(-1., 1.).asin() / FRAC_PI_2)
+            }
+        };
+        Some(Vec2::new(
+            (screen_x + 1.) * 0.5 * self.width as f32,
+            (1. - screen_y) * 0.5 * self.height as f32,
+        ))
+    }
+
+    /// Distance of the point at `distance` along `ray`: along the view axis, or from the
+    /// eye for panoramic projections.
+    pub fn depth(&self, ray: &Ray, distance: f32) -> f32 {
+        let offset = ray.at(distance) - self.position;
+        match self.projection {
+            Projection::Fisheye { .. } | Projection::Equirectangular => offset.length(),
+            _ => offset.dot(self.forward_direction.normalize()),
+        }
+    }
+
+    fn projection(&mut self, aspect: f32) -> Mat4 {
+        let Some(physical) = self.physical else {
+            return Mat4::perspective_rh(self.fov, aspect, self.near, self.far);
+        };
+
+        self.fov = physical.fov_y(aspect);
+        let mut perspective = Mat4::perspective_rh(self.fov, aspect, self.near, self.far);
+        // Off-axis frustum: offset the projected center by the shift, in NDC units.
+        perspective.z_axis.x += 2. * physical.shift.x;
+        perspective.z_axis.y += 2. * physical.shift.y;
+        perspective
+    }
+
+    fn rotate(&mut self, yaw_delta: f32, pitch_delta: f32) {
+        let rotation = Camera::rotate_x_mat(pitch_delta * std::f32::consts::PI / 180.)
+            * Camera::rotate_y_mat(yaw_delta * std::f32::consts::PI / 180.);
+
+        let fd = rotation
+            * Vec4::new(
+                self.forward_direction.x,
+                self.forward_direction.y,
+                self.forward_direction.z,
+                1.,
+            );
+
+        self.forward_direction = Vec3::new(fd.x, fd.y, fd.z);
+    }
+
     pub fn update(&mut self, events: Vec<CameraEvent>, ts: f32) {
         let right_direction = self.forward_direction.cross(self.up);
         let speed = 10.;
         let rotation_speed = 10.;
+
+        let mut movement = Vec3::ZERO;
+        let mut turn = Vec2::ZERO;
         for event in events {
             match event {
-                CameraEvent::Up => self.position += self.forward_direction * speed * ts,
-                CameraEvent::Down => self.position -= self.forward_direction * speed * ts,
-                CameraEvent::Left => self.position -= right_direction * speed * ts,
-                CameraEvent::Right => self.position += right_direction * speed * ts,
+                CameraEvent::Up => movement += self.forward_direction,
+                CameraEvent::Down => movement -= self.forward_direction,
+                CameraEvent::Left => movement -= right_direction,
+                CameraEvent::Right => movement += right_direction,
                 CameraEvent::Resize { w, h } => {
                     self.width = w;
                     self.height = h;
-                    self.perspective =
-                        Mat4::perspective_rh(self.fov, w as f32 / h as f32, self.near, self.far);
+                    self.perspective = self.projection(w as f32 / h as f32);
                     self.inverse_perspective = self.perspective.inverse();
                 }
 
-                CameraEvent::RotateXY { delta } => {
-                    let pitch_delta = delta.y * rotation_speed;
-                    let yaw_delta = delta.x * rotation_speed;
-
-                    let rotation =
-                        Camera::rotate_x_mat(pitch_delta as f32 * std::f32::consts::PI / 180.)
-                            * Camera::rotate_y_mat(yaw_delta as f32 * std::f32::consts::PI / 180.);
+                CameraEvent::RotateXY { delta } => turn += delta * rotation_speed,
+            }
+        }
 
-                    let fd = rotation
-                        * Vec4::new(
-                            self.forward_direction.x,
-                            self.forward_direction.y,
-                            self.forward_direction.z,
-                            1.,
-                        );
+        match self.damping {
+            Some(damping) if ts > 0. => {
+                let k = 1. - (-ts / damping.position.max(f32::EPSILON)).exp();
+                self.velocity = self.velocity.lerp(movement * speed, k);
+                self.position += self.velocity * ts;
 
-                    self.forward_direction = Vec3::new(fd.x, fd.y, fd.z);
-                }
+                let k = 1. - (-ts / damping.rotation.max(f32::EPSILON)).exp();
+                self.angular_velocity = self.angular_velocity.lerp(turn / ts, k);
+                let angles = self.angular_velocity * ts;
+                self.rotate(angles.x, angles.y);
+            }
+            _ => {
+                self.position += movement * speed * ts;
+                self.rotate(turn.x, turn.y);
             }
         }
 
-        self.view = Mat4::look_at_rh(
-            self.position,
-            self.position + self.forward_direction,
-            self.up,
-        );
+        self.time += ts;
+        let (mut eye, mut forward) = (self.position, self.forward_direction);
+        if let Some(shake) = self.shake {
+            let t = self.time;
+            eye += Vec3::new(shake.noise(t, 0.), shake.noise(t, 1.), shake.noise(t, 2.))
+                * shake.amplitude;
+            let yaw = shake.noise(t, 3.) * shake.rotation * std::f32::consts::PI / 180.;
+            let pitch = shake.noise(t, 4.) * shake.rotation * std::f32::consts::PI / 180.;
+            let rotation = Camera::rotate_x_mat(pitch) * Camera::rotate_y_mat(yaw);
+            forward = rotation.transform_vector3(forward);
+        }
+
+        self.view = Mat4::look_at_rh(eye, eye + forward, self.up);
 
         self.inverse_view = self.view.inverse();
 
-        self.calculate_ray_directions();
+        if self.precomputed_rays {
+            self.calculate_ray_directions();
+        } else {
+            self.ray_directions = vec![];
+        }
     }
 
     fn calculate_ray_directions(&mut self) {
-        self.ray_directions = vec![Vec3::ZERO; (self.width * self.height) as usize];
-        let mut y = 0;
-        let mut x = 0;
-
-        while y < self.height {
-            while x < self.width {
-                let p_ndc_x = (x as f32) / self.width as f32;
-                let p_ndc_y = (y as f32) / self.height as f32;
-
-                let p_screen_x = 2.0 * p_ndc_x - 1.;
-                let p_screen_y = 1. - 2.0 * p_ndc_y;
-
-                let target = self.inverse_perspective * Vec4::new(p_screen_x, p_screen_y, 1., 1.);
-                let v3 = Vec3::new(target.x, target.y, target.z) / target.w;
-                let ray_direction = self.inverse_view * Vec4::new(v3.x, v3.y, v3.z, 0.0);
-
-                self.ray_directions[x + y * self.width as usize] =
-                    Vec3::new(ray_direction.x, ray_direction.y, ray_direction.z).normalize();
-
-                x += 1;
-            }
-            y += 1;
-            x = 0;
-        }
+        self.ray_directions = (0..self.width * self.height)
+            .map(|pixel| {
+                self.direction_at((pixel % self.width) as f32, (pixel / self.width) as f32)
+            })
+            .collect();
     }
 }
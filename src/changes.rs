@@ -0,0 +1,60 @@
+use std::ops::BitOrAssign;
+
+use crate::accel::Aabb;
+
+/// What changed since the last frame, from which the renderer decides how much of the
+/// accumulation to keep: all of it when nothing changed, reprojected after a camera move,
+/// all but the tiles of the edited objects, or none.
+#[derive(Debug, Clone, Default)]
+pub struct Changes {
+    /// The camera moved, turned or refocused.
+    pub camera: bool,
+    /// Indices of the objects edited, added or removed, with bounds covering them before
+    /// and after.
+    pub objects: Vec<(usize, Aabb)>,
+    /// Indices of the materials edited.
+    pub materials: Vec<usize>,
+    /// Anything else changing the whole image: lights, environment, fog, textures,
+    /// tracing settings, the image size.
+    pub settings: bool,
+}
+
+impl Changes {
+    /// Everything changed: the accumulation restarts.
+    pub fn all() -> Changes {
+        Changes {
+            camera: true,
+            settings: true,
+            ..Default::default()
+        }
+    }
+
+    /// The camera moved, and nothing else.
+    pub fn camera() -> Changes {
+        Changes {
+            camera: true,
+            ..Default::default()
+        }
+    }
+
+    /// A setting changed, restarting the accumulation.
+    pub fn settings() -> Changes {
+        Changes {
+            settings: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.camera && self.objects.is_empty() && self.materials.is_empty() && !self.settings
+    }
+}
+
+impl BitOrAssign for Changes {
+    fn bitor_assign(&mut self, other: Changes) {
+        self.camera |= other.camera;
+        self.objects.extend(other.objects);
+        self.materials.extend(other.materials);
+        self.settings |= other.settings;
+    }
+}
@@ -0,0 +1,302 @@
+use std::path::Path;
+use std::str::{FromStr, SplitWhitespace};
+
+use glam::{vec3, Vec3};
+
+use crate::camera::Camera;
+use crate::changes::Changes;
+use crate::environment::{Environment, EnvironmentMap, NightSky};
+use crate::objects::{Material, MaterialType, TextureFilter, WrapMode};
+use crate::post::ToneMap;
+use crate::renderer::Renderer;
+use crate::utils::errors::AppError;
+use crate::utils::image::ImageUtils;
+
+/// A one-line command prompt, shown in the window title while open. Commands:
+///
+/// - `set material <index> albedo <r> <g> <b>`, and likewise `roughness`, `emission`,
+///   `transparency`, `refraction`, `reflectivity`, `metallic`, `ior`, `specular`, `sheen`,
+///   `clearcoat`, `transmission` with one value, `texture <index|none>`
+/// - `set texture <index> wrap <repeat|clamp|mirror>`,
+///   `set texture <index> filter <nearest|bilinear|bicubic>`
+/// - `set ambient <r> <g> <b>`, `set bounces <n>`, `set roulette <depth>`,
+///   `set exposure <value>`, `set tonemap <linear|reinhard|aces>`, `set noise <target|off>`
+/// - `set environment <ambient|night>`, `set environment map <path>`
+/// - `light directional <x> <y> <z> <power>`, `light size <degrees> <shadow samples>`,
+///   `light sampling <on|off>`
+/// - `save <path>`, the scene and camera for `.ron` and `.json` paths, the image as EXR
+///   for `.exr` paths and as an 8-bit image otherwise
+#[derive(Debug, Default)]
+pub struct Console {
+    pub open: bool,
+    pub line: String,
+    history: Vec<String>,
+}
+
+impl Console {
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        self.line.clear();
+    }
+
+    pub fn prompt(&self) -> String {
+        format!("> {}", self.line)
+    }
+
+    /// Recalls the last command run.
+    pub fn previous(&mut self) {
+        if let Some(last) = self.history.last() {
+            self.line = last.clone();
+        }
+    }
+
+    /// Runs the typed command. `img` is the displayed image, for `save`. Returns what
+    /// changed in the scene or the renderer.
+    pub fn execute(
+        &mut self,
+        renderer: &mut Renderer,
+        camera: &Camera,
+        img: &[u8],
+    ) -> Result<Changes, AppError> {
+        let line = std::mem::take(&mut self.line);
+        if line.trim().is_empty() {
+            return Ok(Changes::default());
+        }
+        self.history.push(line.clone());
+
+        let mut args = line.split_whitespace();
+        match args.next() {
+            Some("set") => match args.next() {
+                Some("material") => {
+                    let index: usize = arg(&mut args)?;
+                    let property = args.next().unwrap_or_default().to_string();
+                    let mut error = None;
+                    let changed = renderer.update_scene(|scene| {
+                        let Some(material) = scene.materials.get_mut(index) else {
+                            error = Some(format!("no material {}", index));
+                            return false;
+                        };
+                        match set_material(material, &property, &mut args) {
+                            Ok(()) => true,
+                            Err(e) => {
+                                error = Some(e);
+                                false
+                            }
+                        }
+                    });
+                    match error {
+                        Some(e) => Err(AppError::Command(e)),
+                        None => Ok(changed),
+                    }
+                }
+                Some("texture") => {
+                    let index: usize = arg(&mut args)?;
+                    let (property, mode) = (args.next(), args.next());
+                    if index >= renderer.scene.textures.len() {
+                        return Err(AppError::Command(format!("no texture {}", index)));
+                    }
+                    let wrap = match mode {
+                        Some("repeat") => Some(WrapMode::Repeat),
+                        Some("clamp") => Some(WrapMode::Clamp),
+                        Some("mirror") => Some(WrapMode::Mirror),
+                        _ => None,
+                    };
+                    let filter = match mode {
+                        Some("nearest") => Some(TextureFilter::Nearest),
+                        Some("bilinear") => Some(TextureFilter::Bilinear),
+                        Some("bicubic") => Some(TextureFilter::Bicubic),
+                        _ => None,
+                    };
+                    match (property, wrap, filter) {
+                        (Some("wrap"), Some(wrap), _) => Ok(renderer.update_scene(|scene| {
+                            scene.textures[index].wrap = wrap;
+                            true
+                        })),
+                        (Some("filter"), _, Some(filter)) => Ok(renderer.update_scene(|scene| {
+                            scene.textures[index].filter = filter;
+                            true
+                        })),
+                        _ => Err(unknown(mode.or(property))),
+                    }
+                }
+                Some("ambient") => {
+                    let color = vec3_arg(&mut args)?;
+                    Ok(renderer.update_scene(|scene| {
+                        scene.ambient_color = color;
+                        true
+                    }))
+                }
+                Some("environment") => {
+                    let environment = match args.next() {
+                        Some("ambient") => Environment::Ambient,
+                        Some("night") => Environment::NightSky(NightSky::default()),
+                        Some("map") => {
+                            let path = args.next().ok_or_else(|| unknown(None))?;
+                            Environment::Map(EnvironmentMap::load(path)?)
+                        }
+                        other => return Err(unknown(other)),
+                    };
+                    Ok(renderer.update_scene(|scene| {
+                        scene.environment = environment;
+                        true
+                    }))
+                }
+                Some("bounces") => {
+                    let bounces: u8 = arg(&mut args)?;
+                    Ok(renderer.update_scene(|scene| {
+                        scene.max_ray_bounces = bounces;
+                        true
+                    }))
+                }
+                Some("roulette") => {
+                    let depth: u8 = arg(&mut args)?;
+                    Ok(renderer.update_scene(|scene| {
+                        scene.roulette_depth = depth;
+                        true
+                    }))
+                }
+                Some("exposure") => {
+                    renderer.exposure = arg(&mut args)?;
+                    Ok(Changes::settings())
+                }
+                Some("tonemap") => {
+                    renderer.settings.tone_map = match args.next() {
+                        Some("linear") => ToneMap::Linear,
+                        Some("reinhard") => ToneMap::Reinhard,
+                        Some("aces") => ToneMap::AcesFilmic,
+                        other => return Err(unknown(other)),
+                    };
+                    Ok(Changes::settings())
+                }
+                Some("noise") => {
+                    renderer.target_noise = match args.clone().next() {
+                        Some("off") => None,
+                        _ => Some(arg(&mut args)?),
+                    };
+                    Ok(Changes::default())
+                }
+                other => Err(unknown(other)),
+            },
+            Some("light") => match args.next() {
+                Some("directional") => {
+                    let direction = vec3_arg(&mut args)?.normalize_or_zero();
+                    let power: f32 = arg(&mut args)?;
+                    Ok(renderer.update_scene(|scene| {
+                        scene.light.direction = direction;
+                        scene.light.power = power;
+                        true
+                    }))
+                }
+                Some("size") => {
+                    let degrees: f32 = arg(&mut args)?;
+                    let samples: u32 = arg(&mut args)?;
+                    Ok(renderer.update_scene(|scene| {
+                        scene.light.angular_radius = degrees.max(0.).to_radians();
+                        scene.light.shadow_samples = samples;
+                        true
+                    }))
+                }
+                Some("sampling") => {
+                    let on = match args.next() {
+                        Some("on") => true,
+                        Some("off") => false,
+                        other => return Err(unknown(other)),
+                    };
+                    Ok(renderer.update_scene(|scene| {
+                        scene.light_sampling = on;
+                        true
+                    }))
+                }
+                Some(_) => Err(AppError::Command(
+                    "the scene has a single directional light".to_string(),
+                )),
+                None => Err(unknown(None)),
+            },
+            Some("save") => {
+                let path = args.next().ok_or_else(|| unknown(None))?;
+                let extension = Path::new(path)
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .unwrap_or_default()
+                    .to_lowercase();
+                if extension == "ron" || extension == "json" {
+                    renderer.scene.save(path, Some(camera))?;
+                } else if extension == "exr" {
+                    renderer.save_exr(path, camera)?;
+                } else {
+                    ImageUtils::save_image(path, camera.width, camera.height, img)?;
+                }
+                Ok(Changes::default())
+            }
+            other => Err(unknown(other)),
+        }
+    }
+}
+
+fn set_material(
+    material: &mut Material,
+    property: &str,
+    args: &mut SplitWhitespace,
+) -> Result<(), String> {
+    let value = |args: &mut SplitWhitespace| arg::<f32>(args).map_err(|e| format!("{:?}", e));
+    match (property, &mut material.kind) {
+        ("albedo", _) => material.albedo = vec3_arg(args).map_err(|e| format!("{:?}", e))?,
+        ("emission", _) => {
+            material.emission_power = value(args)?;
+            material.emissive = material.emission_power > 0.;
+        }
+        ("texture", _) => {
+            material.texture = match args.next() {
+                Some("none") => None,
+                Some(index) => Some(index.parse().map_err(|_| "bad texture index")?),
+                None => return Err("missing texture index".to_string()),
+            }
+        }
+        ("roughness", MaterialType::Reflective { roughness })
+        | ("roughness", MaterialType::Refractive { roughness, .. })
+        | ("roughness", MaterialType::Pbr { roughness, .. })
+        | ("roughness", MaterialType::Principled { roughness, .. }) => *roughness = value(args)?,
+        ("metallic", MaterialType::Pbr { metallic, .. })
+        | ("metallic", MaterialType::Principled { metallic, .. }) => *metallic = value(args)?,
+        ("ior", MaterialType::Pbr { ior, .. }) | ("ior", MaterialType::Principled { ior, .. }) => {
+            *ior = value(args)?
+        }
+        ("specular", MaterialType::Principled { specular, .. }) => *specular = value(args)?,
+        ("sheen", MaterialType::Principled { sheen, .. }) => *sheen = value(args)?,
+        ("clearcoat", MaterialType::Principled { clearcoat, .. }) => *clearcoat = value(args)?,
+        ("transmission", MaterialType::Principled { transmission, .. }) => {
+            *transmission = value(args)?
+        }
+        ("transparency", MaterialType::Refractive { transparency, .. }) => {
+            *transparency = value(args)?
+        }
+        (
+            "refraction",
+            MaterialType::Refractive {
+                refraction_index, ..
+            },
+        ) => *refraction_index = value(args)?,
+        ("reflectivity", MaterialType::Refractive { reflectivity, .. }) => {
+            *reflectivity = value(args)?
+        }
+        _ => return Err(format!("material has no property {}", property)),
+    }
+    Ok(())
+}
+
+fn arg<T: FromStr>(args: &mut SplitWhitespace) -> Result<T, AppError> {
+    let value = args
+        .next()
+        .ok_or_else(|| AppError::Command("missing argument".to_string()))?;
+    value
+        .parse()
+        .map_err(|_| AppError::Command(format!("bad argument {}", value)))
+}
+
+fn vec3_arg(args: &mut SplitWhitespace) -> Result<Vec3, AppError> {
+    Ok(vec3(arg(args)?, arg(args)?, arg(args)?))
+}
+
+fn unknown(command: Option<&str>) -> AppError {
+    AppError::Command(format!("unknown command {}", command.unwrap_or_default()))
+}
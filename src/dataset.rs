@@ -0,0 +1,300 @@
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use glam::{Affine3A, Mat4, Quat, Vec3, Vec4};
+use image::{ImageBuffer, Luma};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::{Camera, CameraEvent, Projection};
+use crate::job::RenderJob;
+use crate::renderer::RenderSettings;
+use crate::scene::Scene;
+use crate::utils::errors::AppError;
+use crate::utils::image::ImageUtils;
+
+/// Random changes made to the scene before each sample of a `Dataset`, each drawn
+/// uniformly up to the given amount.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Jitter {
+    /// Offset of each object along each axis.
+    pub position: f32,
+    /// Turn of each object about the vertical axis through its centre, in degrees.
+    pub rotation: f32,
+    /// Change of each channel of the material albedos.
+    pub albedo: f32,
+    /// Tilt of the light direction, in degrees.
+    pub light_direction: f32,
+    /// Change of the light power, as a fraction of it.
+    pub light_power: f32,
+}
+
+impl Default for Jitter {
+    fn default() -> Self {
+        Self {
+            position: 0.2,
+            rotation: 30.,
+            albedo: 0.1,
+            light_direction: 15.,
+            light_power: 0.2,
+        }
+    }
+}
+
+impl Jitter {
+    /// Copy of `scene` changed at random, leaving the objects in `fixed` in place.
+    pub fn apply(&self, scene: &Scene, fixed: &[usize], rnd: &mut StdRng) -> Scene {
+        let mut scene = scene.clone();
+        let mut signed = |amount: f32| rnd.gen_range(-1.0..=1.0) * amount;
+
+        for (i, object) in scene.objects.iter_mut().enumerate() {
+            if fixed.contains(&i) {
+                continue;
+            }
+            let center = object.bounds().center();
+            let offset = Vec3::new(
+                signed(self.position),
+                signed(self.position),
+                signed(self.position),
+            );
+            let turn = Quat::from_rotation_y(signed(self.rotation).to_radians());
+            let transform = Affine3A::from_translation(center + offset)
+                * Affine3A::from_quat(turn)
+                * Affine3A::from_translation(-center);
+            *object = object.transformed(&transform);
+        }
+
+        for material in scene.materials.iter_mut() {
+            let change = Vec3::new(
+                signed(self.albedo),
+                signed(self.albedo),
+                signed(self.albedo),
+            );
+            material.albedo = (material.albedo + change).clamp(Vec3::ZERO, Vec3::ONE);
+        }
+
+        let direction = scene.light.direction.normalize_or_zero();
+        if direction != Vec3::ZERO {
+            let (t, b) = direction.any_orthonormal_pair();
+            let tilt = signed(self.light_direction).to_radians();
+            let around = signed(PI) + PI;
+            let axis = t * around.cos() + b * around.sin();
+            scene.light.direction = Quat::from_axis_angle(axis, tilt) * direction;
+        }
+        scene.light.power *= 1. + signed(self.light_power);
+        scene
+    }
+}
+
+/// Camera of a sample, written next to its images. Intrinsics and the world to camera
+/// matrix follow the OpenCV conventions: x right, y down and z forward, in pixels from
+/// the top left corner of the image.
+#[derive(Debug, Clone, Serialize)]
+pub struct CameraRecord {
+    pub width: usize,
+    pub height: usize,
+    pub projection: Projection,
+    /// `[fx, fy, cx, cy]`, for perspective cameras only.
+    pub intrinsics: Option<[f32; 4]>,
+    /// World to camera transform, row by row.
+    pub world_to_camera: [[f32; 4]; 4],
+    pub position: Vec3,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl CameraRecord {
+    pub fn new(camera: &Camera) -> CameraRecord {
+        let (w, h) = (camera.width as f32, camera.height as f32);
+        let p = camera.perspective;
+        let intrinsics = (camera.projection == Projection::Perspective).then(|| {
+            [
+                0.5 * w * p.x_axis.x,
+                0.5 * h * p.y_axis.y,
+                0.5 * w * (1. - p.z_axis.x),
+                0.5 * h * (1. + p.z_axis.y),
+            ]
+        });
+        // The camera looks down -z with y up; OpenCV looks down +z with y down.
+        let flip = Mat4::from_diagonal(Vec4::new(1., -1., -1., 1.));
+        CameraRecord {
+            width: camera.width,
+            height: camera.height,
+            projection: camera.projection,
+            intrinsics,
+            world_to_camera: (flip * camera.view).transpose().to_cols_array_2d(),
+            position: camera.position,
+            near: camera.near,
+            far: camera.far,
+        }
+    }
+}
+
+/// Renders many randomized variations of a scene with their ground truth, for training
+/// models. Each sample `n` is written to the output directory as:
+///
+/// - `n_rgb.png`, the rendered image;
+/// - `n_depth.exr`, the depth of the primary hits as in `Camera::depth`, at the far
+///   plane for misses;
+/// - `n_normal.exr`, world space normals of the primary hits, zero for misses;
+/// - `n_mask.png`, 16-bit object indices plus one, 0 for misses;
+/// - `n_camera.json`, a `CameraRecord`.
+///
+/// `Dataset::new(scene, camera).samples(100).fixed(vec![0]).render("out")?`
+pub struct Dataset {
+    scene: Scene,
+    camera: Camera,
+    settings: RenderSettings,
+    width: usize,
+    height: usize,
+    spp: u32,
+    samples: usize,
+    jitter: Jitter,
+    fixed: Vec<usize>,
+    seed: u64,
+}
+
+impl Dataset {
+    pub fn new(scene: Scene, camera: Camera) -> Dataset {
+        Dataset {
+            width: camera.width,
+            height: camera.height,
+            scene,
+            camera,
+            settings: RenderSettings::default(),
+            spp: 16,
+            samples: 1,
+            jitter: Jitter::default(),
+            fixed: vec![],
+            seed: 0,
+        }
+    }
+
+    pub fn resolution(mut self, width: usize, height: usize) -> Dataset {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn spp(mut self, spp: u32) -> Dataset {
+        self.spp = spp.max(1);
+        self
+    }
+
+    pub fn settings(mut self, settings: RenderSettings) -> Dataset {
+        self.settings = settings;
+        self
+    }
+
+    pub fn samples(mut self, samples: usize) -> Dataset {
+        self.samples = samples;
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Jitter) -> Dataset {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Objects left in place by the jitter, such as the floor.
+    pub fn fixed(mut self, fixed: Vec<usize>) -> Dataset {
+        self.fixed = fixed;
+        self
+    }
+
+    /// Seeds the jitter and the renders, so the same seed gives the same scenes and images.
+    pub fn seed(mut self, seed: u64) -> Dataset {
+        self.seed = seed;
+        self
+    }
+
+    /// Renders every sample into the directory `dir`, created if missing.
+    pub fn render(self, dir: impl AsRef<Path>) -> Result<(), AppError> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        let mut camera = self.camera.clone();
+        camera.update(
+            vec![CameraEvent::Resize {
+                w: self.width,
+                h: self.height,
+            }],
+            0.,
+        );
+        let file = |sample: usize, name: &str| -> String {
+            let path: PathBuf = dir.join(format!("{:05}_{}", sample, name));
+            path.to_string_lossy().into_owned()
+        };
+
+        for sample in 0..self.samples {
+            let seed = self.seed.wrapping_add(sample as u64);
+            let mut rnd = StdRng::seed_from_u64(seed);
+            let scene = self.jitter.apply(&self.scene, &self.fixed, &mut rnd);
+
+            let mut truth = scene.clone();
+            truth.compile(self.settings.accel);
+            self.save_truth(&truth, &camera, |name| file(sample, name))?;
+
+            RenderJob::new(scene, camera.clone())
+                .resolution(self.width, self.height)
+                .spp(self.spp)
+                .settings(self.settings.clone())
+                .seed(seed)
+                .render()?
+                .save(file(sample, "rgb.png"))?;
+            log::info!("Rendered dataset sample {} of {}", sample + 1, self.samples);
+        }
+        Ok(())
+    }
+
+    /// Writes the depth, normals, object mask and camera of the primary hits of `scene`,
+    /// which must be compiled.
+    fn save_truth(
+        &self,
+        scene: &Scene,
+        camera: &Camera,
+        file: impl Fn(&str) -> String,
+    ) -> Result<(), AppError> {
+        let (w, h) = (camera.width, camera.height);
+        let truth: Vec<(f32, Vec3, u16)> = (0..w * h)
+            .into_par_iter()
+            .map(|pixel| {
+                let ray = camera.ray(pixel);
+                let Some(hit) = scene.trace_ray(ray, f32::MAX) else {
+                    return (camera.far, Vec3::ZERO, 0);
+                };
+                let depth = camera
+                    .depth(&ray, hit.distance)
+                    .clamp(camera.near, camera.far);
+                let id = (hit.object_index + 1).min(u16::MAX as usize) as u16;
+                (depth, hit.normal, id)
+            })
+            .collect();
+
+        let depth: Vec<f32> = truth.iter().map(|(d, _, _)| *d).collect();
+        let gray: Vec<Vec4> = depth.iter().map(|d| Vec3::splat(*d).extend(1.)).collect();
+        ImageUtils::save_exr(file("depth.exr"), w, h, &gray, Some(&depth))?;
+
+        let normals: Vec<Vec4> = truth.iter().map(|(_, n, _)| n.extend(1.)).collect();
+        ImageUtils::save_exr(file("normal.exr"), w, h, &normals, None)?;
+
+        let path = file("mask.png");
+        let ids: Vec<u16> = truth.iter().map(|(_, _, id)| *id).collect();
+        ImageBuffer::<Luma<u16>, _>::from_raw(w as u32, h as u32, ids)
+            .expect("one id per pixel")
+            .save(&path)
+            .map_err(|e| AppError::ImageWrite {
+                path,
+                source: Box::new(e),
+            })?;
+
+        let json = File::create(file("camera.json"))?;
+        serde_json::to_writer_pretty(json, &CameraRecord::new(camera)).map_err(io::Error::from)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use glam::{Vec3, Vec4};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::aov::AovPixel;
+
+/// Weights of the 5 taps of the B3 spline along each axis of the À-Trous kernel.
+const KERNEL: [f32; 5] = [1. / 16., 1. / 4., 3. / 8., 1. / 4., 1. / 16.];
+
+/// Edge-avoiding À-Trous wavelet filter (Dammertz et al. 2010). Each pass blurs the image
+/// with a 5x5 kernel whose taps are twice as far apart as in the previous pass, weighting
+/// every tap down by how much its colour, albedo and normal differ from the pixel's, so
+/// the noise of a few samples is smoothed out without blurring edges and textures.
+#[derive(Debug, Copy, Clone)]
+pub struct Denoiser {
+    /// Passes of the filter. The kernel of the last one spans `4 * 2^(iterations - 1)`
+    /// pixels each way.
+    pub iterations: u32,
+    /// Colour difference, in linear units, at which taps start to be ignored. Halved at
+    /// every pass, as the image gets smoother.
+    pub color_sigma: f32,
+    pub albedo_sigma: f32,
+    pub normal_sigma: f32,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            color_sigma: 0.5,
+            albedo_sigma: 0.1,
+            normal_sigma: 0.3,
+        }
+    }
+}
+
+impl Denoiser {
+    /// Denoises the `w` x `h` `image` of averaged linear colours, guided by the albedo
+    /// and normals of the `aovs` of its pixels.
+    pub fn apply(&self, image: &mut [Vec4], aovs: &[AovPixel], w: usize, h: usize) {
+        if image.len() != w * h || aovs.len() != image.len() {
+            return;
+        }
+        let guides: Vec<(Vec3, Vec3)> = aovs.iter().map(|p| (p.albedo(), p.normal())).collect();
+        let mut colors: Vec<Vec3> = image.iter().map(|c| c.truncate()).collect();
+
+        let inv_albedo = 1. / (self.albedo_sigma * self.albedo_sigma).max(f32::EPSILON);
+        let inv_normal = 1. / (self.normal_sigma * self.normal_sigma).max(f32::EPSILON);
+        for pass in 0..self.iterations {
+            let step = 1i64 << pass;
+            let sigma = self.color_sigma * 0.5f32.powi(pass as i32);
+            let inv_color = 1. / (sigma * sigma).max(f32::EPSILON);
+            colors = (0..w * h)
+                .into_par_iter()
+                .map(|pixel| {
+                    let (x, y) = ((pixel % w) as i64, (pixel / w) as i64);
+                    let (c, (albedo, normal)) = (colors[pixel], guides[pixel]);
+                    let mut sum = Vec3::ZERO;
+                    let mut total = 0.;
+                    for (j, ky) in KERNEL.iter().enumerate() {
+                        let qy = y + (j as i64 - 2) * step;
+                        if qy < 0 || qy >= h as i64 {
+                            continue;
+                        }
+                        for (i, kx) in KERNEL.iter().enumerate() {
+                            let qx = x + (i as i64 - 2) * step;
+                            if qx < 0 || qx >= w as i64 {
+                                continue;
+                            }
+                            let q = qy as usize * w + qx as usize;
+                            let (qc, (qa, qn)) = (colors[q], guides[q]);
+                            let distance = (c - qc).length_squared() * inv_color
+                                + (albedo - qa).length_squared() * inv_albedo
+                                + (normal - qn).length_squared() * inv_normal;
+                            let weight = kx * ky * (-distance).exp();
+                            sum += qc * weight;
+                            total += weight;
+                        }
+                    }
+                    // The pixel itself always has a weight, so `total` is never 0.
+                    sum / total
+                })
+                .collect();
+        }
+
+        for (c, denoised) in image.iter_mut().zip(colors) {
+            *c = denoised.extend(c.w);
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use glam::{vec3, Vec2, Vec3};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::objects::Texture;
+use crate::post;
+use crate::ray::{Ray, RayHit, Sampler, EPSILON};
+use crate::utils::errors::AppError;
+use crate::utils::image::ImageUtils;
+use crate::utils::noise::fbm;
+use crate::volume::Medium;
+
+/// Radiance seen by rays leaving the scene.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum Environment {
+    /// The scene `ambient_color` in every direction.
+    #[default]
+    Ambient,
+    NightSky(NightSky),
+    Map(EnvironmentMap),
+}
+
+impl Environment {
+    pub fn radiance(&self, direction: Vec3, ambient_color: Vec3) -> Vec3 {
+        match self {
+            Environment::Ambient => ambient_color,
+            Environment::NightSky(sky) => sky.radiance(direction),
+            Environment::Map(map) => map.radiance(direction),
+        }
+    }
+}
+
+/// An equirectangular image around the scene, usually HDR, lighting it from every
+/// direction. Keeps the distribution of its texels, weighted by their luminance and solid
+/// angle, so bright areas such as the sun can be sampled directly. Scene files store the
+/// texture and the settings, the distribution is rebuilt on load.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "EnvironmentMapFile", into = "EnvironmentMapFile")]
+pub struct EnvironmentMap {
+    pub texture: Arc<Texture>,
+    pub intensity: f32,
+    /// Turn around the vertical axis, in radians.
+    pub rotation: f32,
+    /// Cumulative probability of the rows.
+    row_cdf: Vec<f32>,
+    /// Cumulative probability of the texels within each row.
+    texel_cdf: Vec<f32>,
+    /// Probability of every texel.
+    texel_pdf: Vec<f32>,
+}
+
+/// How an `EnvironmentMap` is written in scene files.
+#[derive(Serialize, Deserialize)]
+struct EnvironmentMapFile {
+    texture: Texture,
+    intensity: f32,
+    rotation: f32,
+}
+
+impl From<EnvironmentMapFile> for EnvironmentMap {
+    fn from(file: EnvironmentMapFile) -> Self {
+        EnvironmentMap {
+            intensity: file.intensity,
+            rotation: file.rotation,
+            ..EnvironmentMap::new(file.texture)
+        }
+    }
+}
+
+impl From<EnvironmentMap> for EnvironmentMapFile {
+    fn from(map: EnvironmentMap) -> Self {
+        EnvironmentMapFile {
+            texture: Texture::clone(&map.texture),
+            intensity: map.intensity,
+            rotation: map.rotation,
+        }
+    }
+}
+
+impl EnvironmentMap {
+    pub fn new(texture: Texture) -> EnvironmentMap {
+        let (w, h) = (
+            texture.width.max(1) as usize,
+            texture.height.max(1) as usize,
+        );
+        let mut texel_pdf = vec![0.; w * h];
+        for y in 0..h.min(texture.height as usize) {
+            let sin_theta = (PI * (y as f32 + 0.5) / h as f32).sin();
+            for x in 0..w.min(texture.width as usize) {
+                let l = post::luminance(texture.pixel(x as u32, y as u32));
+                texel_pdf[y * w + x] = (l.max(0.) + 1e-4) * sin_theta;
+            }
+        }
+        let total: f32 = texel_pdf.iter().sum();
+        texel_pdf.iter_mut().for_each(|p| *p /= total);
+
+        let mut row_cdf = Vec::with_capacity(h);
+        let mut texel_cdf = Vec::with_capacity(w * h);
+        let mut rows = 0.;
+        for row in texel_pdf.chunks_exact(w) {
+            let row_total: f32 = row.iter().sum();
+            rows += row_total;
+            row_cdf.push(rows);
+            let mut texels = 0.;
+            for p in row {
+                texels += p;
+                texel_cdf.push(texels / row_total);
+            }
+        }
+
+        EnvironmentMap {
+            texture: Arc::new(texture),
+            intensity: 1.,
+            rotation: 0.,
+            row_cdf,
+            texel_cdf,
+            texel_pdf,
+        }
+    }
+
+    /// Loads an equirectangular image, keeping the values above 1 of `.hdr` and `.exr`
+    /// files.
+    pub fn load(path: impl Into<String>) -> Result<EnvironmentMap, AppError> {
+        ImageUtils::load_image(path).map(EnvironmentMap::new)
+    }
+
+    fn size(&self) -> (usize, usize) {
+        (
+            self.texture.width.max(1) as usize,
+            self.texture.height.max(1) as usize,
+        )
+    }
+
+    /// Image coordinates in `[0, 1)²` of a direction: longitude across, from straight up
+    /// at the top to straight down at the bottom.
+    fn uv(&self, direction: Vec3) -> Vec2 {
+        let d = direction.normalize();
+        let phi = d.x.atan2(-d.z) + self.rotation;
+        Vec2::new(
+            (phi / (2. * PI)).rem_euclid(1.),
+            d.y.clamp(-1., 1.).acos() / PI,
+        )
+    }
+
+    fn direction(&self, uv: Vec2) -> Vec3 {
+        let phi = 2. * PI * uv.x - self.rotation;
+        let theta = PI * uv.y;
+        vec3(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        )
+    }
+
+    fn texel(&self, uv: Vec2) -> (usize, usize) {
+        let (w, h) = self.size();
+        (
+            ((uv.x * w as f32) as usize).min(w - 1),
+            ((uv.y * h as f32) as usize).min(h - 1),
+        )
+    }
+
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        if self.texture.texels.is_empty() {
+            return Vec3::ZERO;
+        }
+        let (x, y) = self.texel(self.uv(direction));
+        self.texture.pixel(x as u32, y as u32) * self.intensity
+    }
+
+    /// Probability density, per unit of solid angle, of `sample` returning `direction`.
+    pub fn pdf(&self, direction: Vec3) -> f32 {
+        let uv = self.uv(direction);
+        let (x, y) = self.texel(uv);
+        let (w, h) = self.size();
+        let sin_theta = (PI * uv.y).sin().max(EPSILON);
+        self.texel_pdf[y * w + x] * (w * h) as f32 / (2. * PI * PI * sin_theta)
+    }
+
+    /// Direction drawn in proportion to the radiance coming from it.
+    pub fn sample(&self, rnd: &mut Sampler) -> Vec3 {
+        let (w, h) = self.size();
+        let y = self
+            .row_cdf
+            .partition_point(|c| *c < rnd.gen::<f32>())
+            .min(self.row_cdf.len() - 1);
+        let row = &self.texel_cdf[y * w..(y + 1) * w];
+        let x = row.partition_point(|c| *c < rnd.gen::<f32>()).min(w - 1);
+
+        self.direction(Vec2::new(
+            (x as f32 + rnd.gen::<f32>()) / w as f32,
+            (y as f32 + rnd.gen::<f32>()) / h as f32,
+        ))
+    }
+
+    /// Diffuse bounce off `hit` drawn half the time towards the bright parts of the map and
+    /// half the time around the normal, with its weight against a cosine-weighted bounce.
+    /// The bounce keeps the cone of `ray`, which hit.
+    pub fn diffuse_bounce(&self, ray: &Ray, hit: &RayHit, rnd: &mut Sampler) -> (Ray, f32) {
+        let n = hit.normal;
+        let direction = if rnd.gen_bool(0.5) {
+            self.sample(rnd)
+        } else {
+            ray.diffuse_ray(hit, rnd).direction
+        };
+
+        let cos_pdf = n.dot(direction).max(0.) / PI;
+        let weight = if cos_pdf > 0. {
+            cos_pdf / (0.5 * cos_pdf + 0.5 * self.pdf(direction))
+        } else {
+            0.
+        };
+        (
+            ray.bounce(hit.distance, hit.point + n * 0.0001, direction),
+            weight,
+        )
+    }
+}
+
+/// A procedural night sky: hashed stars, a milky way band and a moon disc.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NightSky {
+    pub sky_color: Vec3,
+    /// Share of the star grid cells holding a star.
+    pub star_density: f32,
+    pub star_brightness: f32,
+    /// Normal of the plane the milky way band lies in.
+    pub milky_way_normal: Vec3,
+    /// Angular half-width of the band, in radians.
+    pub milky_way_width: f32,
+    pub milky_way_color: Vec3,
+    pub moon_direction: Vec3,
+    /// Angular radius of the moon disc, in radians.
+    pub moon_radius: f32,
+    pub moon_color: Vec3,
+}
+
+impl Default for NightSky {
+    fn default() -> Self {
+        Self {
+            sky_color: vec3(0.002, 0.003, 0.008),
+            star_density: 0.08,
+            star_brightness: 2.,
+            milky_way_normal: vec3(1., 0.3, 0.2).normalize(),
+            milky_way_width: 0.25,
+            milky_way_color: vec3(0.03, 0.03, 0.045),
+            moon_direction: vec3(-0.4, 0.5, -0.8).normalize(),
+            moon_radius: 0.03,
+            moon_color: vec3(1.5, 1.45, 1.3),
+        }
+    }
+}
+
+/// Star grid cells across one unit of direction.
+const STAR_CELLS: f32 = 120.;
+
+impl NightSky {
+    pub fn radiance(&self, direction: Vec3) -> Vec3 {
+        let d = direction.normalize();
+
+        if d.dot(self.moon_direction.normalize()) > self.moon_radius.cos() {
+            return self.moon_color;
+        }
+
+        // Each grid cell the direction falls in holds at most one star, placed and lit by
+        // hashes of the cell.
+        let cell = (d * STAR_CELLS).floor();
+        let mut color = self.sky_color;
+        if hash(cell, 0) < self.star_density {
+            let jitter = vec3(hash(cell, 1), hash(cell, 2), hash(cell, 3));
+            let star = ((cell + jitter) / STAR_CELLS).normalize();
+            let angle = d.dot(star).clamp(-1., 1.).acos() * STAR_CELLS;
+            let brightness = self.star_brightness * hash(cell, 4).powi(4);
+            let tint = vec3(0.8, 0.85, 1.).lerp(vec3(1., 0.85, 0.7), hash(cell, 5));
+            color += tint * brightness * (-angle * angle * 40.).exp();
+        }
+
+        let band = d.dot(self.milky_way_normal.normalize()).asin() / self.milky_way_width;
+        let dust = 0.6 + 0.4 * hash((d * 16.).floor(), 6);
+        color + self.milky_way_color * dust * (-band * band).exp()
+    }
+}
+
+/// Pseudo-random value in `[0, 1)` for a grid cell and a channel.
+fn hash(cell: Vec3, channel: u32) -> f32 {
+    let mut h = (cell.x as i32 as u32).wrapping_mul(0x8da6_b343)
+        ^ (cell.y as i32 as u32).wrapping_mul(0xd816_3841)
+        ^ (cell.z as i32 as u32).wrapping_mul(0xcb1a_b31f)
+        ^ channel.wrapping_mul(0x1656_67b1);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b_3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a_2d39);
+    h ^= h >> 15;
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+/// A procedural layer of clouds between two altitudes, with its density from fbm noise
+/// across the ground plane. Rays are marched through the layer as a `Medium`, so thick
+/// clouds get bright rims and dark bellies.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CloudLayer {
+    /// Altitude of the cloud bases.
+    pub bottom: f32,
+    /// Altitude of the cloud tops.
+    pub top: f32,
+    /// Share of the sky covered, from 0 to 1.
+    pub coverage: f32,
+    /// Extinction per unit of distance inside the thickest clouds.
+    pub density: f32,
+    /// Horizontal size of the largest clouds.
+    pub feature_size: f32,
+    pub color: Vec3,
+    /// Longest stretch of the layer marched along a ray, for rays close to horizontal.
+    pub max_distance: f32,
+    /// Samples along view rays.
+    pub steps: u32,
+    /// Samples along the rays towards the sun.
+    pub light_steps: u32,
+}
+
+impl Default for CloudLayer {
+    fn default() -> Self {
+        Self {
+            bottom: 40.,
+            top: 60.,
+            coverage: 0.45,
+            density: 0.08,
+            feature_size: 60.,
+            color: Vec3::ONE,
+            max_distance: 400.,
+            steps: 32,
+            light_steps: 6,
+        }
+    }
+}
+
+impl Medium for CloudLayer {
+    /// Extinction at `p`: fbm noise above the coverage threshold, rounded off towards the
+    /// bottom and the top of the layer.
+    fn density_at(&self, p: Vec3) -> f32 {
+        if p.y <= self.bottom || p.y >= self.top {
+            return 0.;
+        }
+        let h = (p.y - self.bottom) / (self.top - self.bottom);
+        let noise = fbm(Vec2::new(p.x, p.z) / self.feature_size, 5);
+        let cover = ((noise - (1. - self.coverage)) / self.coverage.max(EPSILON)).clamp(0., 1.);
+        cover * 4. * h * (1. - h) * self.density
+    }
+
+    fn span(&self, ray: &Ray, distance: f32) -> Option<(f32, f32)> {
+        let (o, d) = (ray.origin.y, ray.direction.y);
+        let (t0, t1) = if d.abs() < EPSILON {
+            if o <= self.bottom || o >= self.top {
+                return None;
+            }
+            (0., f32::INFINITY)
+        } else {
+            let (a, b) = ((self.bottom - o) / d, (self.top - o) / d);
+            (a.min(b).max(0.), a.max(b))
+        };
+        let t1 = t1.min(distance).min(t0 + self.max_distance);
+        (t0 < t1).then_some((t0, t1))
+    }
+
+    fn light_step(&self, to_sun: Vec3) -> f32 {
+        (self.top - self.bottom) / self.light_steps.max(1) as f32 / to_sun.y.abs().max(0.2)
+    }
+
+    fn color(&self) -> Vec3 {
+        self.color
+    }
+
+    fn steps(&self) -> (u32, u32) {
+        (self.steps, self.light_steps)
+    }
+}
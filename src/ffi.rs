@@ -0,0 +1,268 @@
+//! C interface, declared in `include/ray_tracing.h`, for embedding the renderer in other
+//! engines. The same functions are the exports of the WebAssembly build driven by
+//! `web/ray_tracing.js`:
+//!
+//! ```sh
+//! cargo build --release --lib --no-default-features --target wasm32-unknown-unknown
+//! ```
+//!
+//! A renderer owns a scene and a camera and renders progressively: every call to
+//! `rt_render_frame` traces one more sample per pixel into the caller's RGBA buffer.
+//! Functions returning a pointer or a status report failures with null or a negative
+//! value, and `rt_last_error` tells why.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::fmt::Display;
+use std::ptr;
+
+use glam::Vec3;
+
+use crate::camera::{Camera, CameraEvent};
+use crate::changes::Changes;
+use crate::renderer::Renderer;
+use crate::scene::Scene;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_error(error: impl Display) {
+    let message = error.to_string().replace('\0', " ");
+    LAST_ERROR.with(|e| *e.borrow_mut() = CString::new(message).unwrap_or_default());
+}
+
+/// A scene being rendered through a camera, opaque to C.
+pub struct RtRenderer {
+    renderer: Renderer,
+    camera: Camera,
+    /// Changes made since the last frame, applied by the next one.
+    changes: Changes,
+    threads: usize,
+}
+
+impl RtRenderer {
+    fn new(scene: Scene, camera: Option<Camera>, width: usize, height: usize) -> RtRenderer {
+        let mut camera = camera.unwrap_or_default();
+        camera.update(
+            vec![CameraEvent::Resize {
+                w: width,
+                h: height,
+            }],
+            0.,
+        );
+        RtRenderer {
+            renderer: Renderer::new(scene),
+            camera,
+            changes: Changes::all(),
+            threads: num_cpus::get(),
+        }
+    }
+
+    fn boxed(
+        scene: Result<(Scene, Option<Camera>), impl Display>,
+        w: usize,
+        h: usize,
+    ) -> *mut Self {
+        if w == 0 || h == 0 {
+            set_error(format!("invalid resolution {}x{}", w, h));
+            return ptr::null_mut();
+        }
+        match scene {
+            Ok((scene, camera)) => Box::into_raw(Box::new(RtRenderer::new(scene, camera, w, h))),
+            Err(e) => {
+                set_error(e);
+                ptr::null_mut()
+            }
+        }
+    }
+}
+
+/// Why the last call failed on this thread, as a NUL terminated string valid until the
+/// next failing call. Empty when nothing failed.
+#[no_mangle]
+pub extern "C" fn rt_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ptr())
+}
+
+/// Renderer of the RON or JSON scene file at `path`, through the camera saved with it or
+/// a default one, at `width` by `height` pixels.
+///
+/// # Safety
+///
+/// `path` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rt_renderer_load(
+    path: *const c_char,
+    width: usize,
+    height: usize,
+) -> *mut RtRenderer {
+    if path.is_null() {
+        set_error("null scene path");
+        return ptr::null_mut();
+    }
+    let path = CStr::from_ptr(path).to_string_lossy();
+    RtRenderer::boxed(Scene::load_with_camera(path), width, height)
+}
+
+/// Like `rt_renderer_load`, from the `len` bytes of JSON scene file at `json`.
+///
+/// # Safety
+///
+/// `json` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rt_renderer_parse(
+    json: *const u8,
+    len: usize,
+    width: usize,
+    height: usize,
+) -> *mut RtRenderer {
+    if json.is_null() {
+        set_error("null scene text");
+        return ptr::null_mut();
+    }
+    let text = String::from_utf8_lossy(std::slice::from_raw_parts(json, len));
+    RtRenderer::boxed(Scene::parse(&text, false), width, height)
+}
+
+/// # Safety
+///
+/// `renderer` must come from `rt_renderer_load` or `rt_renderer_parse`, or be null, and is
+/// not usable afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rt_renderer_free(renderer: *mut RtRenderer) {
+    if !renderer.is_null() {
+        drop(Box::from_raw(renderer));
+    }
+}
+
+/// Places the camera at `(x, y, z)` looking at `(tx, ty, tz)` with a vertical field of
+/// view of `fov` degrees, restarting the accumulation.
+///
+/// # Safety
+///
+/// `renderer` must be a live renderer.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn rt_set_camera(
+    renderer: *mut RtRenderer,
+    x: f32,
+    y: f32,
+    z: f32,
+    tx: f32,
+    ty: f32,
+    tz: f32,
+    fov: f32,
+) {
+    let Some(r) = renderer.as_mut() else {
+        return;
+    };
+    let (position, target) = (Vec3::new(x, y, z), Vec3::new(tx, ty, tz));
+    r.camera.position = position;
+    r.camera.forward_direction = (target - position).normalize_or(Vec3::NEG_Z);
+    r.camera.fov = fov;
+    let (w, h) = (r.camera.width, r.camera.height);
+    r.camera.update(vec![CameraEvent::Resize { w, h }], 0.);
+    r.changes |= Changes::camera();
+}
+
+/// Changes the image size, restarting the accumulation. Returns -1 for an empty size.
+///
+/// # Safety
+///
+/// `renderer` must be a live renderer.
+#[no_mangle]
+pub unsafe extern "C" fn rt_resize(
+    renderer: *mut RtRenderer,
+    width: usize,
+    height: usize,
+) -> c_int {
+    let Some(r) = renderer.as_mut() else {
+        set_error("null renderer");
+        return -1;
+    };
+    if width == 0 || height == 0 {
+        set_error(format!("invalid resolution {}x{}", width, height));
+        return -1;
+    }
+    r.camera.update(
+        vec![CameraEvent::Resize {
+            w: width,
+            h: height,
+        }],
+        0.,
+    );
+    r.changes |= Changes::all();
+    0
+}
+
+/// Traces one more sample per pixel and writes the image to `rgba`, `len` bytes of
+/// 8-bit RGBA rows from the top. Returns 1 after a frame, 0 once the scene's frame limit
+/// is reached and the image left as it was, and -1 when `len` is not four bytes per pixel.
+///
+/// # Safety
+///
+/// `renderer` must be a live renderer and `rgba` point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rt_render_frame(
+    renderer: *mut RtRenderer,
+    rgba: *mut u8,
+    len: usize,
+) -> c_int {
+    let Some(r) = renderer.as_mut() else {
+        set_error("null renderer");
+        return -1;
+    };
+    let expected = r.camera.width * r.camera.height * 4;
+    if rgba.is_null() || len != expected {
+        set_error(format!(
+            "pixel buffer of {} bytes, expected {}",
+            len, expected
+        ));
+        return -1;
+    }
+    let img = std::slice::from_raw_parts_mut(rgba, len);
+    let changes = std::mem::take(&mut r.changes);
+    r.renderer.render_frame(img, &r.camera, &changes, r.threads) as c_int
+}
+
+/// Memory for the JavaScript side to pass scene text and pixel buffers in, freed with
+/// `rt_free`. C callers use their own allocator.
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub extern "C" fn rt_alloc(len: usize) -> *mut u8 {
+    let mut bytes = vec![0u8; len].into_boxed_slice();
+    let data = bytes.as_mut_ptr();
+    std::mem::forget(bytes);
+    data
+}
+
+/// # Safety
+///
+/// `data` and `len` must come from one call to `rt_alloc`.
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+pub unsafe extern "C" fn rt_free(data: *mut u8, len: usize) {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+}
+
+// A page offers no entropy without JavaScript glue. The samplers only need decorrelated
+// streams, so the seeds come from a fixed sequence.
+#[cfg(target_arch = "wasm32")]
+getrandom::register_custom_getrandom!(sequence_random);
+
+#[cfg(target_arch = "wasm32")]
+fn sequence_random(buf: &mut [u8]) -> Result<(), getrandom::Error> {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static STATE: AtomicU64 = AtomicU64::new(0x853c_49e6_748f_ea9b);
+
+    for chunk in buf.chunks_mut(8) {
+        // SplitMix64.
+        let mut z = STATE.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        chunk.copy_from_slice(&z.to_le_bytes()[..chunk.len()]);
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use std::f32::consts::PI;
+
+/// Reconstruction filter weighting the samples of a pixel by their offset from its
+/// center. Wider filters blur slightly and alias less.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+    BlackmanHarris,
+}
+
+impl Filter {
+    /// Half-width of the filter footprint, in pixels.
+    pub fn radius(&self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.,
+            Filter::Gaussian => 1.5,
+            Filter::BlackmanHarris => 2.,
+        }
+    }
+
+    /// Weight of a sample `dx`, `dy` pixels from the pixel center, zero outside the
+    /// footprint.
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(&self, x: f32) -> f32 {
+        let r = self.radius();
+        if x.abs() > r {
+            return 0.;
+        }
+        match self {
+            Filter::Box => 1.,
+            Filter::Tent => 1. - x.abs() / r,
+            Filter::Gaussian => {
+                // Shifted down so the weight reaches zero at the footprint edge.
+                let gaussian = |x: f32| (-2. * x * x).exp();
+                (gaussian(x) - gaussian(r)).max(0.)
+            }
+            Filter::BlackmanHarris => {
+                let t = 2. * PI * (x + r) / (2. * r);
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2. * t).cos() - 0.01168 * (3. * t).cos()
+            }
+        }
+    }
+}
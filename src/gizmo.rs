@@ -0,0 +1,102 @@
+use std::f32::consts::TAU;
+
+use glam::{Vec2, Vec3};
+
+use crate::accel::Aabb;
+use crate::camera::Camera;
+use crate::objects::Object3D;
+use crate::scene::Scene;
+
+/// Segments of a circle outline.
+const CIRCLE_SEGMENTS: usize = 24;
+
+/// Line segments, in image pixels, marking where the scene light comes from: an arrow
+/// along the directional light aimed at the scene center, a wireframe sphere around
+/// every emissive sphere and a box around every other emissive object.
+pub fn light_gizmos(scene: &Scene, camera: &Camera) -> Vec<(Vec2, Vec2)> {
+    let mut lines = vec![];
+
+    let bounds = scene
+        .objects
+        .iter()
+        .map(|o| o.bounds())
+        .reduce(|a, b| a.union(&b));
+    let (center, size) = bounds
+        .map(|b| (b.center(), (b.max - b.min).length()))
+        .unwrap_or((Vec3::ZERO, 2.));
+    arrow(
+        &mut lines,
+        center - scene.light.direction.normalize() * size * 0.5,
+        center,
+    );
+
+    for object in &scene.objects {
+        let material_index = match object {
+            Object3D::Sphere { material_index, .. }
+            | Object3D::Triangle { material_index, .. }
+            | Object3D::Mesh { material_index, .. }
+            | Object3D::PointCloud { material_index, .. }
+            | Object3D::Terrain { material_index, .. } => *material_index,
+            // Only the hits of custom primitives know their material.
+            Object3D::Custom(_) => continue,
+        };
+        let emissive = scene
+            .materials
+            .get(material_index)
+            .is_some_and(|m| m.emission() > 0.);
+        if !emissive {
+            continue;
+        }
+        match object {
+            Object3D::Sphere {
+                position, radius, ..
+            } => sphere(&mut lines, *position, *radius * 1.05),
+            _ => cube(&mut lines, &object.bounds()),
+        }
+    }
+
+    lines
+        .into_iter()
+        .filter_map(|(a, b)| Some((camera.project(a)?, camera.project(b)?)))
+        .collect()
+}
+
+fn arrow(lines: &mut Vec<(Vec3, Vec3)>, from: Vec3, to: Vec3) {
+    lines.push((from, to));
+    let axis = to - from;
+    let side = axis.any_orthonormal_vector() * axis.length() * 0.08;
+    let up = axis.normalize().cross(side);
+    let base = to - axis * 0.15;
+    for offset in [side, -side, up, -up] {
+        lines.push((to, base + offset));
+    }
+}
+
+fn sphere(lines: &mut Vec<(Vec3, Vec3)>, center: Vec3, radius: f32) {
+    for (u, v) in [(Vec3::X, Vec3::Y), (Vec3::Y, Vec3::Z), (Vec3::Z, Vec3::X)] {
+        let point = |i: usize| {
+            let angle = TAU * i as f32 / CIRCLE_SEGMENTS as f32;
+            center + (u * angle.cos() + v * angle.sin()) * radius
+        };
+        for i in 0..CIRCLE_SEGMENTS {
+            lines.push((point(i), point(i + 1)));
+        }
+    }
+}
+
+fn cube(lines: &mut Vec<(Vec3, Vec3)>, b: &Aabb) {
+    let corner = |i: usize| {
+        Vec3::new(
+            if i & 1 == 0 { b.min.x } else { b.max.x },
+            if i & 2 == 0 { b.min.y } else { b.max.y },
+            if i & 4 == 0 { b.min.z } else { b.max.z },
+        )
+    };
+    for i in 0..8 {
+        for bit in [1, 2, 4] {
+            if i & bit == 0 {
+                lines.push((corner(i), corner(i | bit)));
+            }
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use glam::Vec4;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+use sdl2::VideoSubsystem;
+
+use crate::utils::errors::AppError;
+
+/// Side, in image pixels, of the square shown by the inspector.
+const INSPECTOR_PIXELS: usize = 24;
+
+/// A second window magnifying the image around the cursor of the main window, titled
+/// with the accumulated linear colour of the pixel under the cursor.
+pub struct Inspector {
+    canvas: Canvas<Window>,
+    texture_creator: TextureCreator<WindowContext>,
+    block: Vec<u8>,
+}
+
+impl Inspector {
+    pub fn open(video_subsystem: &VideoSubsystem) -> Result<Inspector, AppError> {
+        let window = video_subsystem
+            .window("Inspector", 384, 384)
+            .resizable()
+            .allow_highdpi()
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        let texture_creator = canvas.texture_creator();
+
+        Ok(Inspector {
+            canvas,
+            texture_creator,
+            block: vec![0; INSPECTOR_PIXELS * INSPECTOR_PIXELS * 4],
+        })
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    /// Draws the pixels of `img` around `(x, y)`. `color` is the linear colour of the
+    /// pixel at `(x, y)`, if it is inside the image.
+    pub fn draw(
+        &mut self,
+        img: &[u8],
+        width: usize,
+        height: usize,
+        (x, y): (i32, i32),
+        color: Option<Vec4>,
+    ) -> Result<(), AppError> {
+        let half = INSPECTOR_PIXELS as i32 / 2;
+        for j in 0..INSPECTOR_PIXELS {
+            for i in 0..INSPECTOR_PIXELS {
+                let (sx, sy) = (x - half + i as i32, y - half + j as i32);
+                let dst = (j * INSPECTOR_PIXELS + i) * 4;
+                let inside = sx >= 0 && sy >= 0 && (sx as usize) < width && (sy as usize) < height;
+                if inside {
+                    let src = (sy as usize * width + sx as usize) * 4;
+                    self.block[dst..dst + 4].copy_from_slice(&img[src..src + 4]);
+                } else {
+                    self.block[dst..dst + 4].fill(0);
+                }
+            }
+        }
+
+        let mut texture = self
+            .texture_creator
+            .create_texture_streaming(
+                PixelFormatEnum::ABGR8888,
+                INSPECTOR_PIXELS as u32,
+                INSPECTOR_PIXELS as u32,
+            )
+            .map_err(|e| e.to_string())?;
+        texture
+            .update(None, &self.block, INSPECTOR_PIXELS * 4)
+            .map_err(|e| e.to_string())?;
+
+        self.canvas.clear();
+        self.canvas.copy(&texture, None, None)?;
+        self.canvas.present();
+
+        let title = match color {
+            Some(c) => format!("({}, {}) r {:.4} g {:.4} b {:.4}", x, y, c.x, c.y, c.z),
+            None => "Inspector".to_string(),
+        };
+        self.canvas
+            .window_mut()
+            .set_title(&title)
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+}
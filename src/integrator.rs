@@ -0,0 +1,138 @@
+use std::fmt::Debug;
+
+use glam::Vec3;
+
+use crate::objects::{Material, MaterialType};
+use crate::ray::{Ray, Sampler, EPSILON};
+use crate::scene::Scene;
+
+/// Light transport: how the light reaching the camera along a ray is estimated. Set one
+/// on `RenderSettings::integrator` to replace the built-in path tracer.
+///
+/// ```ignore
+/// /// Shades every surface by its normal.
+/// #[derive(Debug)]
+/// struct Normals;
+///
+/// impl Integrator for Normals {
+///     fn radiance(&self, scene: &Scene, ray: Ray, _: &mut Sampler) -> Vec3 {
+///         scene
+///             .trace_ray(ray, f32::MAX)
+///             .map_or(Vec3::ZERO, |hit| hit.normal * 0.5 + 0.5)
+///     }
+/// }
+///
+/// let settings = RenderSettings {
+///     integrator: Some(Arc::new(Normals)),
+///     ..Default::default()
+/// };
+/// ```
+pub trait Integrator: Send + Sync + Debug {
+    /// Light arriving at the origin of `ray` from its direction, one sample of it when
+    /// the estimate is random. The renderer averages the samples of every frame.
+    fn radiance(&self, scene: &Scene, ray: Ray, sampler: &mut Sampler) -> Vec3;
+}
+
+/// The path tracer the renderer uses when no integrator is set: diffuse and glossy
+/// bounces up to `Scene::max_ray_bounces`, Russian roulette, light sampling, fog and
+/// clouds. Only the cache of the primary hits is missing.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PathTracer;
+
+impl Integrator for PathTracer {
+    fn radiance(&self, scene: &Scene, ray: Ray, sampler: &mut Sampler) -> Vec3 {
+        scene.path_radiance(ray, sampler)
+    }
+}
+
+/// Whitted-style ray tracing: hits are shaded by the scene light and `Scene::lights`
+/// alone, and only their mirror reflection and refraction are followed, up to
+/// `Scene::max_ray_bounces`. Rough surfaces show their own shading instead of blurred
+/// reflections, and there is no light bounced between surfaces or coming from emissive
+/// spheres, but the image takes very few samples to settle.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Whitted;
+
+impl Whitted {
+    fn trace(&self, scene: &Scene, ray: Ray, sampler: &mut Sampler, depth: u8) -> Vec3 {
+        let Some(hit) = scene.trace_ray(ray, f32::MAX) else {
+            let sky = scene
+                .environment
+                .radiance(ray.direction, scene.ambient_color);
+            return scene.fog.apply(sky, &ray, f32::INFINITY);
+        };
+        let local = scene.direct_light(&ray, &hit, sampler);
+        if depth + 1 >= scene.max_ray_bounces {
+            return scene.fog.apply(local, &ray, hit.distance);
+        }
+
+        let material = &scene.materials[hit.material_index];
+        let albedo = scene.surface_color(&ray, &hit);
+        let cos_view = -ray.direction.dot(hit.normal);
+        let mut trace = |ray: Ray| self.trace(scene, ray, sampler, depth + 1);
+        let mirror = ray.bounce(
+            hit.distance,
+            hit.point + EPSILON * hit.normal * cos_view.signum(),
+            ray.reflect(hit.normal),
+        );
+
+        let color = match material.kind {
+            MaterialType::Reflective { roughness } => {
+                let mirrored = 1. - roughness.clamp(0., 1.);
+                local * (1. - mirrored) + albedo * trace(mirror) * mirrored
+            }
+            MaterialType::Refractive {
+                transparency,
+                refraction_index,
+                reflectivity,
+                ..
+            } => {
+                let kr =
+                    material.fresnel(ray.direction, hit.normal, refraction_index, reflectivity);
+                let refracted = ray
+                    .refraction_ray(hit, refraction_index)
+                    .map_or(Vec3::ZERO, &mut trace);
+                let through = trace(mirror) * kr + refracted * (1. - kr);
+                local * (1. - transparency) + albedo * through * transparency
+            }
+            MaterialType::Pbr {
+                metallic,
+                roughness,
+                ior,
+            } => {
+                let f0 = Vec3::splat(Material::normal_reflectance(ior)).lerp(albedo, metallic);
+                let mirrored = Material::schlick(f0, cos_view) * (1. - roughness.clamp(0., 1.));
+                local * (Vec3::ONE - mirrored) + trace(mirror) * mirrored
+            }
+            MaterialType::Principled {
+                metallic,
+                specular,
+                roughness,
+                transmission,
+                ior,
+                ..
+            } => {
+                let f0 = Vec3::splat(0.08 * specular).lerp(albedo, metallic);
+                let fresnel = Material::schlick(f0, cos_view);
+                let mirrored = fresnel * (1. - roughness.clamp(0., 1.));
+                // The light not reflected passes through in proportion to the transmission.
+                let transmitted = (1. - metallic) * transmission * (1. - fresnel.max_element());
+                let refracted = if transmitted > 0. {
+                    ray.refraction_ray(hit, ior).map_or(Vec3::ZERO, &mut trace)
+                } else {
+                    Vec3::ZERO
+                };
+                local * (Vec3::ONE - mirrored) * (1. - transmitted)
+                    + trace(mirror) * mirrored
+                    + albedo * refracted * transmitted
+            }
+        };
+        scene.fog.apply(color, &ray, hit.distance)
+    }
+}
+
+impl Integrator for Whitted {
+    fn radiance(&self, scene: &Scene, ray: Ray, sampler: &mut Sampler) -> Vec3 {
+        self.trace(scene, ray, sampler, 0)
+    }
+}
@@ -0,0 +1,299 @@
+use std::path::Path;
+
+use glam::Vec4;
+
+use crate::camera::{Camera, CameraEvent};
+use crate::changes::Changes;
+use crate::post;
+use crate::renderer::{RenderSettings, Renderer};
+use crate::scene::Scene;
+use crate::utils::errors::AppError;
+use crate::utils::image::ImageUtils;
+use crate::utils::time::{Duration, Instant};
+
+/// Frames traced before `RenderJob::target_error` is checked, as the first few samples
+/// of a pixel can agree by chance.
+const MIN_TARGET_SPP: u32 = 8;
+
+/// Keeps the relative error of near black pixels finite.
+const ERROR_LUMINANCE_FLOOR: f32 = 1e-3;
+
+/// A rendered image: the averaged linear colours, and the RGBA bytes after exposure and
+/// post-processing as they would be displayed.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec4>,
+    pub rgba: Vec<u8>,
+    /// Samples per pixel traced, fewer than asked when the job stopped early.
+    pub spp: u32,
+    /// Estimated error of the image, as in `ProgressInfo::error`. 0 when nothing needed
+    /// it: no target error and no progress callback.
+    pub error: f32,
+}
+
+impl Frame {
+    /// Saves the linear colours for `.exr` paths, and the displayed bytes in the format
+    /// given by the extension otherwise.
+    pub fn save(&self, path: impl Into<String>) -> Result<(), AppError> {
+        let p: String = path.into();
+        let exr = Path::new(&p)
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("exr"));
+        if exr {
+            ImageUtils::save_exr(p, self.width, self.height, &self.pixels, None)
+        } else {
+            ImageUtils::save_image(p, self.width, self.height, &self.rgba)
+        }
+    }
+}
+
+/// Progress of a `RenderJob`, reported after every frame.
+#[derive(Debug, Copy, Clone)]
+pub struct ProgressInfo {
+    /// Samples per pixel done, out of `total_spp`.
+    pub spp: u32,
+    pub total_spp: u32,
+    pub elapsed: Duration,
+    /// Time left, assuming the remaining frames take as long as the average so far.
+    pub eta: Duration,
+    /// Variance of the pixel luminance estimates, averaged over the image. It falls as the
+    /// image converges.
+    pub variance: f32,
+    /// Standard error of the pixel luminance estimates relative to their value, averaged
+    /// over the image. 0.01 means pixels are typically within 1% of the converged image.
+    pub error: f32,
+}
+
+/// Renders a scene without opening a window:
+///
+/// `RenderJob::new(scene, camera).resolution(640, 480).spp(64).render()?.save("out.png")`
+pub struct RenderJob {
+    scene: Scene,
+    camera: Camera,
+    settings: RenderSettings,
+    width: usize,
+    height: usize,
+    spp: u32,
+    threads: usize,
+    target_error: Option<f32>,
+    time_limit: Option<Duration>,
+    seed: Option<u64>,
+    on_progress: Option<Box<dyn Fn(ProgressInfo)>>,
+}
+
+impl RenderJob {
+    pub fn new(scene: Scene, camera: Camera) -> RenderJob {
+        RenderJob {
+            width: camera.width,
+            height: camera.height,
+            scene,
+            camera,
+            settings: RenderSettings::default(),
+            spp: 1,
+            threads: num_cpus::get(),
+            target_error: None,
+            time_limit: None,
+            seed: None,
+            on_progress: None,
+        }
+    }
+
+    pub fn resolution(mut self, width: usize, height: usize) -> RenderJob {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    /// Frames accumulated, each tracing `RenderSettings::samples_per_pixel` paths per pixel.
+    /// The most traced when the job also has a target error or a time limit.
+    pub fn spp(mut self, spp: u32) -> RenderJob {
+        self.spp = spp.max(1);
+        self
+    }
+
+    pub fn settings(mut self, settings: RenderSettings) -> RenderJob {
+        self.settings = settings;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> RenderJob {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Stops once `ProgressInfo::error` falls below `error`, instead of after `spp` frames.
+    pub fn target_error(mut self, error: f32) -> RenderJob {
+        self.target_error = Some(error);
+        self
+    }
+
+    /// Stops after the first frame that ends past `limit`.
+    pub fn time_limit(mut self, limit: Duration) -> RenderJob {
+        self.time_limit = Some(limit);
+        self
+    }
+
+    /// Renders the same image on every run, as `Renderer::with_seed` does.
+    pub fn seed(mut self, seed: u64) -> RenderJob {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Calls `on_progress` after every frame, for progress bars and status polling.
+    pub fn on_progress(mut self, on_progress: impl Fn(ProgressInfo) + 'static) -> RenderJob {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// Renders straight into the `.png` or `.exr` file at `path`, tiles of `tile_size`
+    /// pixels at a time. Only a band of tiles is ever in memory, so images far larger than
+    /// the accumulation `render` keeps can be rendered. Every tile gets `spp` samples: the
+    /// target error, the time limit, the progress callback and the post-processing of the
+    /// settings need the whole image and are not applied.
+    pub fn render_tiled(self, path: impl Into<String>, tile_size: usize) -> Result<(), AppError> {
+        if self.width == 0 || self.height == 0 || tile_size == 0 {
+            return Err(AppError::Validation(format!(
+                "invalid resolution {}x{} or tile size {}",
+                self.width, self.height, tile_size
+            )));
+        }
+
+        let mut camera = self.camera;
+        // A direction per pixel would take as much memory as the image.
+        camera.precomputed_rays = false;
+        camera.update(
+            vec![CameraEvent::Resize {
+                w: self.width,
+                h: self.height,
+            }],
+            0.,
+        );
+
+        let mut renderer = Renderer::new_with_settings(self.scene, self.settings);
+        if let Some(seed) = self.seed {
+            renderer = renderer.with_seed(seed);
+        }
+        let (width, height) = (self.width, self.height);
+        let start = Instant::now();
+        ImageUtils::save_streamed(path, width, height, tile_size, |rows| {
+            let band = renderer.render_region(
+                &camera,
+                rows.start * width,
+                rows.len() * width,
+                self.spp,
+                self.threads,
+            );
+            log::debug!(
+                "Rendered rows {:?} of {} in {:?}",
+                rows,
+                height,
+                start.elapsed()
+            );
+            Ok(band)
+        })
+    }
+
+    pub fn render(self) -> Result<Frame, AppError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(AppError::Validation(format!(
+                "invalid resolution {}x{}",
+                self.width, self.height
+            )));
+        }
+
+        let mut camera = self.camera;
+        camera.update(
+            vec![CameraEvent::Resize {
+                w: self.width,
+                h: self.height,
+            }],
+            0.,
+        );
+
+        let mut scene = self.scene;
+        scene.max_frames_rendering = self.spp;
+        // Every call has to trace a whole frame.
+        let settings = RenderSettings {
+            frame_budget: None,
+            ..self.settings
+        };
+        let mut renderer = Renderer::new_with_settings(scene, settings);
+        if let Some(seed) = self.seed {
+            renderer = renderer.with_seed(seed);
+        }
+
+        let pixels = self.width * self.height;
+        let mut rgba = vec![0; pixels * 4];
+        // Running sums of each pixel sample luminance and its square, for the variance.
+        let mut moments = vec![(0f32, 0f32); pixels];
+        let start = Instant::now();
+        let measure = self.on_progress.is_some() || self.target_error.is_some();
+        let (mut spp, mut error) = (0, 0.);
+
+        while spp < self.spp {
+            let previous = measure.then(|| renderer.accumulated.clone());
+            // The accumulation starts at the first frame, which finds it empty.
+            renderer.render_frame(&mut rgba, &camera, &Changes::default(), self.threads);
+            spp += 1;
+            let elapsed = start.elapsed();
+            let out_of_time = self.time_limit.is_some_and(|limit| elapsed >= limit);
+
+            let Some(previous) = previous else {
+                if out_of_time {
+                    break;
+                }
+                continue;
+            };
+
+            let (mut variance, mut relative) = (0., 0.);
+            for (pixel, m) in moments.iter_mut().enumerate() {
+                let before = previous.get(pixel).copied().unwrap_or_default();
+                let l = post::luminance((renderer.accumulated[pixel] - before).truncate());
+                m.0 += l;
+                m.1 += l * l;
+                let mean = m.0 / spp as f32;
+                let v = (m.1 / spp as f32 - mean * mean).max(0.) / spp as f32;
+                variance += v;
+                relative += v.sqrt() / mean.abs().max(ERROR_LUMINANCE_FLOOR);
+            }
+            error = relative / pixels as f32;
+
+            if let Some(on_progress) = &self.on_progress {
+                on_progress(ProgressInfo {
+                    spp,
+                    total_spp: self.spp,
+                    elapsed,
+                    eta: elapsed.mul_f32((self.spp - spp) as f32 / spp as f32),
+                    variance: variance / pixels as f32,
+                    error,
+                });
+            }
+
+            let converged = self
+                .target_error
+                .is_some_and(|target| spp >= MIN_TARGET_SPP && error <= target);
+            if converged || out_of_time {
+                break;
+            }
+        }
+        log::info!(
+            "Rendered {} samples per pixel in {:?}, error {:.4}",
+            spp,
+            start.elapsed(),
+            error
+        );
+
+        Ok(Frame {
+            width: self.width,
+            height: self.height,
+            pixels: (0..self.width * self.height)
+                .map(|pixel| renderer.average(pixel).unwrap_or_default())
+                .collect(),
+            rgba,
+            spp,
+            error,
+        })
+    }
+}
@@ -0,0 +1,61 @@
+pub mod accel;
+pub mod animation;
+pub mod aov;
+#[cfg(feature = "window")]
+pub mod app;
+pub mod camera;
+pub mod changes;
+pub mod console;
+pub mod dataset;
+pub mod denoise;
+pub mod environment;
+pub mod ffi;
+pub mod filter;
+pub mod gizmo;
+#[cfg(feature = "window")]
+pub mod inspector;
+pub mod integrator;
+pub mod job;
+pub mod light;
+pub mod light_tree;
+pub mod objects;
+pub mod particles;
+#[cfg(feature = "physics")]
+pub mod physics;
+pub mod post;
+pub mod primitive;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod ray;
+pub mod renderer;
+pub mod scene;
+pub mod turntable;
+pub mod utils;
+pub mod volume;
+
+/// The types needed to build a scene and render it, in a window or to an image.
+pub mod prelude {
+    #[cfg(feature = "window")]
+    pub use crate::app::{App, Clock};
+    pub use crate::camera::{Camera, Projection};
+    pub use crate::dataset::{Dataset, Jitter};
+    pub use crate::environment::{CloudLayer, Environment, EnvironmentMap, NightSky};
+    pub use crate::integrator::{Integrator, PathTracer, Whitted};
+    pub use crate::job::{Frame, RenderJob};
+    pub use crate::light::{LightSample, LightSource, PointLight};
+    pub use crate::objects::{
+        ColorSpace, Cuboid, Material, MaterialType, Motion, Object3D, Texture, TextureFilter,
+        WrapMode,
+    };
+    pub use crate::primitive::{Bounded, Intersection, Primitive, Sdf};
+    pub use crate::renderer::{RenderSettings, Renderer};
+    pub use crate::scene::{Light, LightSelection, Scene};
+    pub use crate::utils::errors::AppError;
+    pub use crate::utils::image::ImageUtils;
+    pub use crate::utils::obj::ObjUtils;
+    pub use crate::utils::lsystem::LSystem;
+    pub use crate::utils::procedural::Procedural;
+    pub use crate::utils::scatter::{GroundScatter, Scatter};
+    pub use crate::volume::{DensityGrid, Medium, Volume};
+    pub use glam::{vec3, Vec3, Vec4};
+}
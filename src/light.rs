@@ -0,0 +1,66 @@
+use std::fmt::Debug;
+
+use glam::Vec3;
+
+use crate::ray::Sampler;
+
+/// Light arriving at a point from one sample of a `LightSource`.
+#[derive(Debug, Copy, Clone)]
+pub struct LightSample {
+    /// Unit direction from the lit point towards the sampled point of the light.
+    pub direction: Vec3,
+    /// Distance to the sampled point, `f32::INFINITY` for lights infinitely far away.
+    /// Whatever lies closer casts a shadow.
+    pub distance: f32,
+    /// Light arriving along `direction`, divided by the density of the sample over the
+    /// directions. For a point light, its intensity over the squared distance.
+    pub radiance: Vec3,
+}
+
+/// A kind of light defined outside this crate, added to `Scene::lights`. Diffuse hits
+/// draw one sample of every light source and add the light of the samples not in
+/// shadow, in the fog. Rays never hit light sources, so they are not seen directly.
+///
+/// ```ignore
+/// #[derive(Debug)]
+/// struct Spot { position: Vec3, axis: Vec3, cos_cutoff: f32, intensity: Vec3 }
+///
+/// impl LightSource for Spot {
+///     fn sample(&self, point: Vec3, _: &mut Sampler) -> Option<LightSample> {
+///         let to_light = self.position - point;
+///         let direction = to_light.normalize();
+///         (-direction.dot(self.axis) >= self.cos_cutoff).then(|| LightSample {
+///             direction,
+///             distance: to_light.length(),
+///             radiance: self.intensity / to_light.length_squared(),
+///         })
+///     }
+/// }
+///
+/// scene.lights.push(Arc::new(Spot { ... }));
+/// ```
+pub trait LightSource: Send + Sync + Debug {
+    /// Samples the light reaching `point`, `None` when none does.
+    fn sample(&self, point: Vec3, sampler: &mut Sampler) -> Option<LightSample>;
+}
+
+/// Light shining equally in every direction from a point, falling off with the square
+/// of the distance. Casts hard shadows.
+#[derive(Debug, Copy, Clone)]
+pub struct PointLight {
+    pub position: Vec3,
+    /// Radiant intensity, the light reaching a point at unit distance.
+    pub intensity: Vec3,
+}
+
+impl LightSource for PointLight {
+    fn sample(&self, point: Vec3, _: &mut Sampler) -> Option<LightSample> {
+        let to_light = self.position - point;
+        let distance2 = to_light.length_squared();
+        (distance2 > 0.).then(|| LightSample {
+            direction: to_light / distance2.sqrt(),
+            distance: distance2.sqrt(),
+            radiance: self.intensity / distance2,
+        })
+    }
+}
@@ -0,0 +1,171 @@
+use glam::Vec3;
+use rand::Rng;
+
+use crate::accel::Aabb;
+use crate::ray::Sampler;
+
+/// A node of a `LightTree`: a leaf holding one light, or the union of two subtrees.
+#[derive(Debug, Copy, Clone)]
+struct LightNode {
+    bounds: Aabb,
+    power: f32,
+    /// Child node indices, `None` for leaves.
+    children: Option<(usize, usize)>,
+    /// Index of the light of a leaf, in the order given to `LightTree::new`.
+    light: usize,
+    /// Index of the parent node, `None` for the root.
+    parent: Option<usize>,
+}
+
+/// Binary tree over many small lights, picking one for a point in logarithmic time. Each
+/// step down favours the subtree that is brighter and closer, so the lights lighting a
+/// point are picked far more often than the ones across the scene.
+#[derive(Debug, Clone, Default)]
+pub struct LightTree {
+    nodes: Vec<LightNode>,
+    /// Leaf node of each light.
+    leaves: Vec<usize>,
+}
+
+impl LightTree {
+    /// Builds the tree over lights given as `(bounds, power)`.
+    pub fn new(lights: &[(Aabb, f32)]) -> LightTree {
+        let mut tree = LightTree {
+            nodes: vec![],
+            leaves: vec![0; lights.len()],
+        };
+        if !lights.is_empty() {
+            let mut order: Vec<usize> = (0..lights.len()).collect();
+            tree.build(lights, &mut order);
+        }
+        tree
+    }
+
+    /// Adds the subtree over the lights in `order`, splitting them in half along the
+    /// longest axis of their centres, and returns its node index.
+    fn build(&mut self, lights: &[(Aabb, f32)], order: &mut [usize]) -> usize {
+        let index = self.nodes.len();
+        if let [light] = order {
+            let (bounds, power) = lights[*light];
+            self.nodes.push(LightNode {
+                bounds,
+                power,
+                children: None,
+                light: *light,
+                parent: None,
+            });
+            self.leaves[*light] = index;
+            return index;
+        }
+
+        let centers = order
+            .iter()
+            .map(|l| {
+                let c = lights[*l].0.center();
+                Aabb::new(c, c)
+            })
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or_default();
+        let extent = centers.extent();
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        order.sort_by(|a, b| lights[*a].0.center()[axis].total_cmp(&lights[*b].0.center()[axis]));
+
+        self.nodes.push(LightNode {
+            bounds: Aabb::default(),
+            power: 0.,
+            children: None,
+            light: 0,
+            parent: None,
+        });
+        let (left, right) = order.split_at_mut(order.len() / 2);
+        let left = self.build(lights, left);
+        let right = self.build(lights, right);
+        self.nodes[left].parent = Some(index);
+        self.nodes[right].parent = Some(index);
+        self.nodes[index] = LightNode {
+            bounds: self.nodes[left].bounds.union(&self.nodes[right].bounds),
+            power: self.nodes[left].power + self.nodes[right].power,
+            children: Some((left, right)),
+            light: 0,
+            parent: self.nodes[index].parent,
+        };
+        index
+    }
+
+    /// How much a subtree may light the segment from `origin` along `direction` over
+    /// `length`: its power over its squared distance, never closer than its own size.
+    fn importance(node: &LightNode, origin: Vec3, direction: Vec3, length: f32) -> f32 {
+        let center = node.bounds.center();
+        let t = (center - origin).dot(direction).clamp(0., length);
+        let radius = node.bounds.extent().length() * 0.5;
+        let distance_squared = (origin + direction * t).distance_squared(center);
+        node.power / distance_squared.max(radius * radius).max(f32::EPSILON)
+    }
+
+    /// Odds of stepping down to the `left` node rather than the `right` one.
+    fn left_odds(
+        &self,
+        left: usize,
+        right: usize,
+        origin: Vec3,
+        direction: Vec3,
+        length: f32,
+    ) -> f32 {
+        let l = Self::importance(&self.nodes[left], origin, direction, length);
+        let r = Self::importance(&self.nodes[right], origin, direction, length);
+        if l + r > 0. {
+            l / (l + r)
+        } else {
+            0.5
+        }
+    }
+
+    /// Picks a light for the segment from `origin` along `direction` over `length`, a
+    /// point when `length` is 0. Returns the light index and the probability it had of
+    /// being picked.
+    pub fn pick(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        length: f32,
+        rnd: &mut Sampler,
+    ) -> Option<(usize, f32)> {
+        let mut node = self.nodes.first()?;
+        let mut probability = 1.;
+        while let Some((left, right)) = node.children {
+            let p_left = self.left_odds(left, right, origin, direction, length);
+            if rnd.gen::<f32>() < p_left {
+                node = &self.nodes[left];
+                probability *= p_left;
+            } else {
+                node = &self.nodes[right];
+                probability *= 1. - p_left;
+            }
+        }
+        Some((node.light, probability))
+    }
+
+    /// Probability `pick` has of picking `light` for the same segment.
+    pub fn probability(&self, origin: Vec3, direction: Vec3, length: f32, light: usize) -> f32 {
+        let Some(&leaf) = self.leaves.get(light) else {
+            return 0.;
+        };
+        let mut probability = 1.;
+        let mut node = leaf;
+        while let Some(parent) = self.nodes[node].parent {
+            let Some((left, right)) = self.nodes[parent].children else {
+                break;
+            };
+            let p_left = self.left_odds(left, right, origin, direction, length);
+            probability *= if node == left { p_left } else { 1. - p_left };
+            node = parent;
+        }
+        probability
+    }
+}
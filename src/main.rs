@@ -1,29 +1,20 @@
-use app::App;
-use camera::Camera;
-use glam::{vec3, Vec3};
-use objects::{Cuboid, Material, MaterialType, Object3D};
-use scene::Scene;
-use utils::{errors::AppError, image::ImageUtils};
-
-mod app;
-mod camera;
-mod objects;
-mod ray;
-mod renderer;
-mod scene;
-mod utils;
+use ray_tracing::prelude::*;
 
 pub fn main() -> Result<(), AppError> {
-    let cube = Cuboid {
-        center: Vec3::new(-0.9, 0., -1.3),
-        length: 1.0,
-        width: 1.,
-        depth: 1.,
-    };
+    ray_tracing::utils::logging::init_logging();
+
+    let cube = Cuboid::build().center(Vec3::new(-0.9, 0., -1.3)).build();
 
     let mut objs = vec![
-        Object3D::new_sphere(Vec3::new(-0.9, 0., 1.), 0.5, 2),
-        Object3D::new_sphere(Vec3::new(-0.6, -0.0, -0.2), 0.5, 0),
+        Object3D::sphere()
+            .position(Vec3::new(-0.9, 0., 1.))
+            .radius(0.5)
+            .material(2)
+            .build(),
+        Object3D::sphere()
+            .position(Vec3::new(-0.6, -0.0, -0.2))
+            .radius(0.5)
+            .build(),
         //Object3D::new_sphere(Vec3::new(0., -100.5, 0.), 100., 2),
     ];
 
@@ -53,7 +44,8 @@ pub fn main() -> Result<(), AppError> {
                 kind: MaterialType::Refractive {
                     transparency: 1.,
                     refraction_index: 1.3,
-                    reflectivity: 0.3
+                    reflectivity: 0.3,
+                    roughness: 0.
                 },
                 //kind: MaterialType::Reflective { roughness: 0.2 },
                 ..Default::default()
@@ -89,9 +81,10 @@ pub fn main() -> Result<(), AppError> {
 
     scene1 = scene1
         .with_texture(ImageUtils::load_image("./resources/chess.png")?)
-        .with_light(scene::Light {
+        .with_light(Light {
             direction: vec3(-1., -1., -1.).normalize(),
             power: 1.5,
+            ..Default::default()
         });
     scene1.difuse = false;
     scene1.max_frames_rendering = 1000;
@@ -99,9 +92,10 @@ pub fn main() -> Result<(), AppError> {
     let scene2 = Scene {
         max_ray_bounces: 5,
         max_frames_rendering: 5000,
-        light: scene::Light {
+        light: Light {
             direction: vec3(1., -1., -1.).normalize(),
             power: 1.5,
+            ..Default::default()
         },
         ambient_color: vec3(0.0, 0.0, 0.0),
         difuse: true,
@@ -129,7 +123,8 @@ pub fn main() -> Result<(), AppError> {
                 kind: MaterialType::Refractive {
                     transparency: 1.0,
                     refraction_index: 0.97,
-                    reflectivity: 0.2
+                    reflectivity: 0.2,
+                    roughness: 0.
                 },
                 emission_power: 0.0,
                 ..Default::default()
@@ -150,10 +145,18 @@ pub fn main() -> Result<(), AppError> {
         ..Default::default()
     };
 
-    let mut renderer = renderer::Renderer::new(scene1);
     let mut camera = Camera::new_with_pos(
         Vec3::new(-2.8777819, 1.3294921, 2.0364523),
         Vec3::new(0.6106094, -0.19236837, -0.76821935),
     );
-    App::run(&mut camera, &mut renderer)
+    App::run(
+        &mut camera,
+        scene1,
+        RenderSettings {
+            blue_noise: true,
+            ..Default::default()
+        },
+        Clock::default(),
+        |_, _| false,
+    )
 }
@@ -1,6 +1,19 @@
-use glam::{vec3, Vec3, Vec4};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
-#[derive(Debug, Copy, Clone)]
+use glam::{vec3, Affine3A, Vec2, Vec3, Vec4};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+
+use crate::accel::{Aabb, Bvh};
+use crate::primitive::{Primitive, Transformed};
+use crate::ray::EPSILON;
+use crate::utils::errors::AppError;
+use crate::utils::image::ImageUtils;
+use crate::utils::mesh::Mesh;
+use crate::utils::terrain::Terrain;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Object3D {
     Sphere {
         position: Vec3,
@@ -14,9 +27,213 @@ pub enum Object3D {
         v3: Vec3,
         material_index: usize,
     },
+
+    Mesh {
+        mesh: Arc<Mesh>,
+        material_index: usize,
+    },
+
+    PointCloud {
+        cloud: Arc<PointCloud>,
+        material_index: usize,
+    },
+
+    Terrain {
+        terrain: Arc<Terrain>,
+        material_index: usize,
+    },
+
+    /// A shape defined outside this crate. Its hits carry their material index. Scene
+    /// files can not hold it: saving a scene with one fails.
+    #[serde(skip)]
+    Custom(Arc<dyn Primitive>),
+}
+
+/// Movement of an object while the shutter is open, for motion blur: transforms applied
+/// on top of its geometry when the shutter opens, at ray time 0, and when it closes, at
+/// time 1. In between, scales and translations are blended linearly and rotations
+/// spherically.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Motion {
+    pub start: Affine3A,
+    pub end: Affine3A,
+}
+
+impl Motion {
+    pub fn new(start: Affine3A, end: Affine3A) -> Motion {
+        Motion { start, end }
+    }
+
+    /// Moves the object by `offset` while the shutter is open.
+    pub fn translation(offset: Vec3) -> Motion {
+        Motion::new(Affine3A::IDENTITY, Affine3A::from_translation(offset))
+    }
+
+    /// Transform at `time`, from 0 to 1.
+    pub fn at(&self, time: f32) -> Affine3A {
+        let (s0, r0, t0) = self.start.to_scale_rotation_translation();
+        let (s1, r1, t1) = self.end.to_scale_rotation_translation();
+        Affine3A::from_scale_rotation_translation(
+            s0.lerp(s1, time),
+            r0.slerp(r1, time),
+            t0.lerp(t1, time),
+        )
+    }
+
+    /// Box around `bounds` wherever the motion takes them, sampled at a few times.
+    pub fn swept_bounds(&self, bounds: &Aabb) -> Aabb {
+        const STEPS: usize = 8;
+        (0..=STEPS)
+            .map(|step| bounds.transformed(&self.at(step as f32 / STEPS as f32)))
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or(*bounds)
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
+pub struct SphereBuilder {
+    position: Vec3,
+    radius: f32,
+    material_index: usize,
+}
+
+impl SphereBuilder {
+    pub fn position(mut self, position: Vec3) -> SphereBuilder {
+        self.position = position;
+        self
+    }
+
+    pub fn radius(mut self, radius: f32) -> SphereBuilder {
+        self.radius = radius;
+        self
+    }
+
+    pub fn material(mut self, material_index: usize) -> SphereBuilder {
+        self.material_index = material_index;
+        self
+    }
+
+    pub fn build(self) -> Object3D {
+        Object3D::new_sphere(self.position, self.radius, self.material_index)
+    }
+}
+
+/// A set of points drawn as small spheres of the same radius, for scan data. Scene files
+/// store the points only, the BVH is rebuilt on load.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "PointCloudFile", into = "PointCloudFile")]
+pub struct PointCloud {
+    pub points: Vec<Vec3>,
+    pub radius: f32,
+    /// Optional color of each point, parallel to `points`.
+    pub colors: Vec<Vec3>,
+    pub bvh: Bvh,
+}
+
+impl PointCloud {
+    pub fn new(points: Vec<Vec3>, radius: f32) -> PointCloud {
+        let bounds: Vec<Aabb> = points
+            .iter()
+            .map(|p| Aabb::new(*p - Vec3::splat(radius), *p + Vec3::splat(radius)))
+            .collect();
+
+        PointCloud {
+            bvh: Bvh::new(&bounds),
+            points,
+            radius,
+            colors: vec![],
+        }
+    }
+
+    pub fn with_colors(mut self, colors: Vec<Vec3>) -> PointCloud {
+        self.colors = colors;
+        self
+    }
+}
+
+/// How a `PointCloud` is written in scene files.
+#[derive(Serialize, Deserialize)]
+struct PointCloudFile {
+    points: Vec<Vec3>,
+    radius: f32,
+    #[serde(default)]
+    colors: Vec<Vec3>,
+}
+
+impl From<PointCloudFile> for PointCloud {
+    fn from(file: PointCloudFile) -> Self {
+        PointCloud::new(file.points, file.radius).with_colors(file.colors)
+    }
+}
+
+impl From<PointCloud> for PointCloudFile {
+    fn from(cloud: PointCloud) -> Self {
+        PointCloudFile {
+            points: cloud.points,
+            radius: cloud.radius,
+            colors: cloud.colors,
+        }
+    }
+}
+
+/// Number of spheres intersected together by `Ray::hit_sphere_pack`.
+pub const SPHERE_LANES: usize = 8;
+
+/// Up to `SPHERE_LANES` spheres stored as structure-of-arrays, so the intersection math
+/// runs lane-wise over fixed-size arrays that the compiler can vectorize.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SpherePack {
+    pub x: [f32; SPHERE_LANES],
+    pub y: [f32; SPHERE_LANES],
+    pub z: [f32; SPHERE_LANES],
+    pub radius: [f32; SPHERE_LANES],
+    pub material_index: [usize; SPHERE_LANES],
+    /// Index of each sphere in the objects the batch was built from.
+    pub object_index: [usize; SPHERE_LANES],
+    pub len: usize,
+}
+
+/// All the spheres of a scene packed in flat `SpherePack`s.
+#[derive(Debug, Clone, Default)]
+pub struct SphereBatch {
+    pub packs: Vec<SpherePack>,
+}
+
+impl SphereBatch {
+    pub fn new(objects: &[Object3D]) -> SphereBatch {
+        let mut packs: Vec<SpherePack> = vec![];
+
+        for (index, obj) in objects.iter().enumerate() {
+            if let Object3D::Sphere {
+                position,
+                radius,
+                material_index,
+            } = obj
+            {
+                if packs.last().is_none_or(|p| p.len == SPHERE_LANES) {
+                    packs.push(SpherePack::default());
+                }
+                let pack = packs.last_mut().unwrap();
+                let i = pack.len;
+                pack.x[i] = position.x;
+                pack.y[i] = position.y;
+                pack.z[i] = position.z;
+                pack.radius[i] = *radius;
+                pack.material_index[i] = *material_index;
+                pack.object_index[i] = index;
+                pack.len += 1;
+            }
+        }
+
+        SphereBatch { packs }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packs.is_empty()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum MaterialType {
     Reflective {
         roughness: f32,
@@ -25,6 +242,38 @@ pub enum MaterialType {
         transparency: f32,
         refraction_index: f32,
         reflectivity: f32,
+        /// 0 for clear glass. Higher values scatter the refracted and reflected rays
+        /// around the normal, for frosted glass.
+        roughness: f32,
+    },
+    /// Opaque metallic/roughness material: a GGX microfacet reflection over a diffuse
+    /// base, blended by Fresnel-Schlick.
+    Pbr {
+        /// 0 for dielectrics, which reflect uncoloured light, 1 for metals, which reflect
+        /// their albedo and have no diffuse base.
+        metallic: f32,
+        /// 0 for a mirror, 1 for a fully rough surface.
+        roughness: f32,
+        /// Index of refraction, setting how much a dielectric reflects head-on.
+        ior: f32,
+    },
+    /// Disney-style principled material, layering a clear coat over a GGX reflection,
+    /// a refraction and a diffuse base with sheen. The base colour is the albedo, or the
+    /// texture. Parameters are in `[0, 1]` as in Blender's Principled BSDF.
+    Principled {
+        metallic: f32,
+        /// Head-on reflectance of the dielectric part, 0.5 for 4%.
+        specular: f32,
+        roughness: f32,
+        /// White grazing-angle reflection over the diffuse base, for cloth.
+        sheen: f32,
+        /// Strength of a smooth, uncoloured varnish layer on top.
+        clearcoat: f32,
+        /// Share of the dielectric part refracting through the surface rather than
+        /// scattering off its diffuse base.
+        transmission: f32,
+        /// Index of refraction of the transmitted light.
+        ior: f32,
     },
 }
 
@@ -36,7 +285,43 @@ pub struct Cuboid {
     pub depth: f32,
 }
 
+/// Builds a `Cuboid` by named parts, defaulting to a unit cube at the origin.
+#[derive(Debug, Copy, Clone)]
+pub struct CuboidBuilder {
+    cuboid: Cuboid,
+}
+
+impl CuboidBuilder {
+    pub fn center(mut self, center: Vec3) -> CuboidBuilder {
+        self.cuboid.center = center;
+        self
+    }
+
+    /// Extent along x, y and z: the length, width and depth.
+    pub fn size(mut self, size: Vec3) -> CuboidBuilder {
+        self.cuboid.length = size.x;
+        self.cuboid.width = size.y;
+        self.cuboid.depth = size.z;
+        self
+    }
+
+    pub fn build(self) -> Cuboid {
+        self.cuboid
+    }
+}
+
 impl Cuboid {
+    pub fn build() -> CuboidBuilder {
+        CuboidBuilder {
+            cuboid: Cuboid {
+                center: Vec3::ZERO,
+                length: 1.,
+                width: 1.,
+                depth: 1.,
+            },
+        }
+    }
+
     pub fn triangles(&self, material_index: usize) -> Vec<Object3D> {
         // Face 1
         let v1_1 = vec3(
@@ -210,12 +495,133 @@ impl Cuboid {
     }
 }
 
+/// Encoding of the 8-bit values of a texture.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum ColorSpace {
+    /// Colour images, such as PNG and JPEG albedo maps.
+    #[default]
+    Srgb,
+    /// Data stored as is, such as height and roughness maps.
+    Linear,
+}
+
+impl ColorSpace {
+    pub fn to_linear(&self, v: u8) -> f32 {
+        let c = v as f32 / 255.;
+        match self {
+            ColorSpace::Linear => c,
+            ColorSpace::Srgb if c <= 0.04045 => c / 12.92,
+            ColorSpace::Srgb => ((c + 0.055) / 1.055).powf(2.4),
+        }
+    }
+}
+
+/// How texture coordinates outside `[0, 1]` map back onto a `Texture`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum WrapMode {
+    /// Tiles the texture.
+    #[default]
+    Repeat,
+    /// Stretches the edge texels.
+    Clamp,
+    /// Tiles the texture, flipping every other tile so the edges meet.
+    Mirror,
+}
+
+impl WrapMode {
+    /// Texel index in `0..size` that `index` maps to.
+    pub fn apply(&self, index: i64, size: u32) -> u32 {
+        let size = size.max(1) as i64;
+        let index = match self {
+            WrapMode::Repeat => index.rem_euclid(size),
+            WrapMode::Clamp => index.clamp(0, size - 1),
+            WrapMode::Mirror => {
+                let i = index.rem_euclid(2 * size);
+                if i < size {
+                    i
+                } else {
+                    2 * size - 1 - i
+                }
+            }
+        };
+        index as u32
+    }
+}
+
+/// How a `Texture` is sampled between its texel centres.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum TextureFilter {
+    /// The closest texel, for pixel art.
+    Nearest,
+    /// Blends the 2x2 closest texels.
+    #[default]
+    Bilinear,
+    /// Catmull-Rom over the 4x4 closest texels, sharper than bilinear when magnified.
+    Bicubic,
+}
+
+/// A copy of a texture at a power of two below its full size.
 #[derive(Default, Debug, Clone)]
+pub struct MipLevel {
+    pub width: u32,
+    pub height: u32,
+    pub texels: Vec<Vec3>,
+}
+
+/// An image read by the materials. Scene files refer to it by `path`, `color_space`,
+/// `wrap` and `filter`, and it is loaded again from there.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "TextureFile", into = "TextureFile")]
 pub struct Texture {
     pub path: String,
     pub width: u32,
     pub height: u32,
+    /// RGB bytes as loaded, encoded in `color_space`. Empty for float textures.
     pub bytes: Vec<u8>,
+    pub color_space: ColorSpace,
+    /// Linear colours decoded from `bytes`, read by `pixel`.
+    pub texels: Vec<Vec3>,
+    pub wrap: WrapMode,
+    pub filter: TextureFilter,
+    /// Box-filtered copies of `texels` at half, a quarter and so on down to one texel,
+    /// generated when the texture is loaded or baked. Read by `sample` for surfaces that
+    /// cover many texels per pixel, which would alias otherwise.
+    pub mipmaps: Vec<MipLevel>,
+}
+
+/// How a `Texture` is written in scene files.
+#[derive(Serialize, Deserialize)]
+struct TextureFile {
+    path: String,
+    #[serde(default)]
+    color_space: ColorSpace,
+    #[serde(default)]
+    wrap: WrapMode,
+    #[serde(default)]
+    filter: TextureFilter,
+}
+
+impl TryFrom<TextureFile> for Texture {
+    type Error = AppError;
+
+    fn try_from(file: TextureFile) -> Result<Self, Self::Error> {
+        Ok(Texture {
+            wrap: file.wrap,
+            filter: file.filter,
+            ..ImageUtils::load_image(file.path)?.with_color_space(file.color_space)
+        })
+    }
+}
+
+impl From<Texture> for TextureFile {
+    fn from(texture: Texture) -> Self {
+        TextureFile {
+            path: texture.path,
+            color_space: texture.color_space,
+            wrap: texture.wrap,
+            filter: texture.filter,
+        }
+    }
 }
 
 impl Texture {
@@ -226,25 +632,167 @@ impl Texture {
         }
     }
 
+    /// Decodes the bytes again as `color_space`, to override the space a texture was
+    /// loaded with. Float textures have no bytes and are kept as they are.
+    pub fn with_color_space(mut self, color_space: ColorSpace) -> Texture {
+        if self.bytes.is_empty() {
+            return self;
+        }
+        let table: Vec<f32> = (0..=255).map(|v| color_space.to_linear(v)).collect();
+        self.texels = self
+            .bytes
+            .chunks_exact(3)
+            .map(|rgb| {
+                Vec3::new(
+                    table[rgb[0] as usize],
+                    table[rgb[1] as usize],
+                    table[rgb[2] as usize],
+                )
+            })
+            .collect();
+        self.color_space = color_space;
+        self.with_mipmaps()
+    }
+
+    /// Generates `mipmaps` again from `texels`, each level averaging 2x2 texels of the
+    /// one above. Odd sizes round down, their last row or column folded into the previous.
+    pub fn with_mipmaps(mut self) -> Texture {
+        self.mipmaps.clear();
+        let (mut width, mut height) = (self.width, self.height);
+        while width > 1 || height > 1 {
+            let (w, h) = ((width / 2).max(1), (height / 2).max(1));
+            let above = self.mipmaps.last().map_or(&self.texels, |level| &level.texels);
+            let texel =
+                |x: u32, y: u32| above[(y.min(height - 1) * width + x.min(width - 1)) as usize];
+            let texels = (0..w * h)
+                .map(|i| {
+                    let (x, y) = (2 * (i % w), 2 * (i / w));
+                    (texel(x, y) + texel(x + 1, y) + texel(x, y + 1) + texel(x + 1, y + 1)) * 0.25
+                })
+                .collect();
+            self.mipmaps.push(MipLevel {
+                width: w,
+                height: h,
+                texels,
+            });
+            (width, height) = (w, h);
+        }
+        self
+    }
+
+    /// Float texture of `width` x `height` texels holding `color` evaluated once at each
+    /// texel centre, with UVs in `[0, 1)²`. Bakes procedural textures that are too slow to
+    /// evaluate per sample. It has no path, so scene files can not refer to it.
+    pub fn bake(width: u32, height: u32, color: impl Fn(Vec2) -> Vec3 + Sync) -> Texture {
+        let (w, h) = (width.max(1), height.max(1));
+        let texels = (0..w * h)
+            .into_par_iter()
+            .map(|i| {
+                let (x, y) = (i % w, i / w);
+                color(Vec2::new(
+                    (x as f32 + 0.5) / w as f32,
+                    (y as f32 + 0.5) / h as f32,
+                ))
+            })
+            .collect();
+        Texture {
+            width: w,
+            height: h,
+            color_space: ColorSpace::Linear,
+            texels,
+            ..Default::default()
+        }
+        .with_mipmaps()
+    }
+
+    /// Colour at texture coordinates `(u, v)`, wrapped by `wrap` and filtered by `filter`.
     pub fn baricentric_pixel(&self, u: f32, v: f32) -> Vec3 {
-        let x = (self.width as f32 * u) as u32;
-        let y = (self.height as f32 * v) as u32;
-        self.pixel(x, y)
+        self.level_pixel(0, u, v)
     }
 
-    pub fn pixel(&self, x: u32, y: u32) -> Vec3 {
-        //println!("x {} y {} w {}, h {}", x, y, self.width, self.height);
-        let pos = (y * 3 * self.width + x * 3) as usize;
+    /// Colour at `(u, v)` for a footprint `2^lod` texels wide, blended between the two
+    /// mip levels closest to it. A `lod` of 0 or below reads the full size texture.
+    pub fn sample(&self, u: f32, v: f32, lod: f32) -> Vec3 {
+        if lod.is_nan() || lod <= 0. || self.mipmaps.is_empty() {
+            return self.baricentric_pixel(u, v);
+        }
+        let lod = lod.min(self.mipmaps.len() as f32);
+        let level = lod.floor() as usize;
+        let t = lod - level as f32;
+        let color = self.level_pixel(level, u, v);
+        if t > 0. {
+            color.lerp(self.level_pixel(level + 1, u, v), t)
+        } else {
+            color
+        }
+    }
 
-        Vec3::new(
-            (self.bytes[pos] as f32) / 255.,
-            (self.bytes[pos + 1] as f32) / 255.,
-            (self.bytes[pos + 2] as f32) / 255.,
-        )
+    /// Size and texels of mip `level`, 0 being the texture itself.
+    fn level(&self, level: usize) -> (u32, u32, &[Vec3]) {
+        match level.checked_sub(1).and_then(|l| self.mipmaps.get(l)) {
+            Some(mip) => (mip.width, mip.height, &mip.texels),
+            None => (self.width, self.height, &self.texels),
+        }
+    }
+
+    /// Colour at `(u, v)` in mip `level`, wrapped by `wrap` and filtered by `filter`.
+    fn level_pixel(&self, level: usize, u: f32, v: f32) -> Vec3 {
+        let (width, height, texels) = self.level(level);
+        let wrapped_pixel = |x: i64, y: i64| {
+            let (x, y) = (self.wrap.apply(x, width), self.wrap.apply(y, height));
+            texels[(y * width + x) as usize]
+        };
+
+        // Texel centres sit at half-integer coordinates.
+        let x = u * width as f32 - 0.5;
+        let y = v * height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        match self.filter {
+            TextureFilter::Nearest => {
+                wrapped_pixel(x0 + (tx >= 0.5) as i64, y0 + (ty >= 0.5) as i64)
+            }
+            TextureFilter::Bilinear => {
+                let top = wrapped_pixel(x0, y0).lerp(wrapped_pixel(x0 + 1, y0), tx);
+                let bottom = wrapped_pixel(x0, y0 + 1).lerp(wrapped_pixel(x0 + 1, y0 + 1), tx);
+                top.lerp(bottom, ty)
+            }
+            TextureFilter::Bicubic => {
+                let (wx, wy) = (catmull_rom(tx), catmull_rom(ty));
+                let mut color = Vec3::ZERO;
+                for (j, wy) in wy.into_iter().enumerate() {
+                    for (i, wx) in wx.into_iter().enumerate() {
+                        let texel = wrapped_pixel(x0 + i as i64 - 1, y0 + j as i64 - 1);
+                        color += texel * wx * wy;
+                    }
+                }
+                // The negative lobes can overshoot below black next to sharp edges.
+                color.max(Vec3::ZERO)
+            }
+        }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Vec3 {
+        self.texels[(y * self.width + x) as usize]
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Catmull-Rom weights of the texels at offsets -1, 0, 1 and 2 from a point `t` past
+/// texel 0.
+fn catmull_rom(t: f32) -> [f32; 4] {
+    let (t2, t3) = (t * t, t * t * t);
+    [
+        (-t3 + 2. * t2 - t) * 0.5,
+        (3. * t3 - 5. * t2 + 2.) * 0.5,
+        (-3. * t3 + 4. * t2 + t) * 0.5,
+        (t3 - t2) * 0.5,
+    ]
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Material {
     pub ambience: f32,
     pub diffuse: f32,
@@ -254,6 +802,9 @@ pub struct Material {
     pub texture: Option<usize>,
     pub kind: MaterialType,
     pub emission_power: f32,
+    /// Makes the material glow with `albedo * emission_power`, as the particles do. Left
+    /// off, `emission_power` is ignored.
+    pub emissive: bool,
 }
 
 impl Default for Material {
@@ -267,11 +818,37 @@ impl Default for Material {
             texture: None,
             kind: MaterialType::Reflective { roughness: 1.0 },
             emission_power: 0.0,
+            emissive: false,
         }
     }
 }
 
 impl Material {
+    /// Power emitted per unit of albedo: `emission_power` for emissive materials, 0 for
+    /// the others.
+    pub fn emission(&self) -> f32 {
+        if self.emissive {
+            self.emission_power
+        } else {
+            0.
+        }
+    }
+
+    /// Fully transparent glass with the index of refraction of window glass, clear at
+    /// `roughness` 0 and frosted above. Lit through the diffuse path its caustics converge.
+    pub fn glass(roughness: f32) -> Material {
+        Material {
+            albedo: Vec3::ONE,
+            kind: MaterialType::Refractive {
+                transparency: 1.,
+                refraction_index: 1.5,
+                reflectivity: 0.,
+                roughness,
+            },
+            ..Default::default()
+        }
+    }
+
     pub fn fresnel(
         &self,
         incident: Vec3,
@@ -301,6 +878,33 @@ impl Material {
         reflectivity + (1.0 - reflectivity) * ret
     }
 
+    /// Fresnel-Schlick reflectance seen at `cos_theta` from the microfacet normal, of a
+    /// surface reflecting `f0` head-on.
+    pub fn schlick(f0: Vec3, cos_theta: f32) -> Vec3 {
+        f0 + (Vec3::ONE - f0) * (1. - cos_theta.clamp(0., 1.)).powi(5)
+    }
+
+    /// Head-on reflectance of a dielectric of index of refraction `ior`.
+    pub fn normal_reflectance(ior: f32) -> f32 {
+        ((ior - 1.) / (ior + 1.)).powi(2)
+    }
+
+    /// Throughput of a reflection about a microfacet normal drawn by
+    /// `Ray::microfacet_normal`, Fresnel aside: the GGX distribution cancels out with the
+    /// sampling density, leaving the Smith masking-shadowing over the cosines.
+    pub fn ggx_weight(normal: Vec3, view: Vec3, light: Vec3, facet: Vec3, roughness: f32) -> f32 {
+        let n_dot_l = normal.dot(light);
+        if n_dot_l <= 0. {
+            return 0.;
+        }
+        let alpha = roughness * roughness;
+        let alpha2 = alpha * alpha;
+        let smith_g1 = |cos: f32| 2. * cos / (cos + (alpha2 + (1. - alpha2) * cos * cos).sqrt());
+        let n_dot_v = normal.dot(view).max(EPSILON);
+        let n_dot_m = normal.dot(facet).max(EPSILON);
+        smith_g1(n_dot_v) * smith_g1(n_dot_l) * view.dot(facet).max(0.) / (n_dot_v * n_dot_m)
+    }
+
     pub fn _fresnel(&self, incident: Vec3, normal: Vec3, index: f32) -> f32 {
         let mut i_dot_n = incident.dot(normal).clamp(-1., 1.);
         let mut eta_i = 1.0;
@@ -333,7 +937,58 @@ impl Material {
     }
 }
 
+/// Hashes spheres and triangles by their coordinates, and the shapes held in an `Arc` by
+/// identity, which is cheap enough to run over every object at every update.
+impl Hash for Object3D {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Object3D::Sphere {
+                position,
+                radius,
+                material_index,
+            } => {
+                position.to_array().map(f32::to_bits).hash(state);
+                radius.to_bits().hash(state);
+                material_index.hash(state);
+            }
+            Object3D::Triangle {
+                v1,
+                v2,
+                v3,
+                material_index,
+            } => {
+                [v1, v2, v3].map(|v| v.to_array().map(f32::to_bits)).hash(state);
+                material_index.hash(state);
+            }
+            Object3D::Mesh {
+                mesh,
+                material_index,
+            } => (Arc::as_ptr(mesh), material_index).hash(state),
+            Object3D::PointCloud {
+                cloud,
+                material_index,
+            } => (Arc::as_ptr(cloud), material_index).hash(state),
+            Object3D::Terrain {
+                terrain,
+                material_index,
+            } => (Arc::as_ptr(terrain), material_index).hash(state),
+            Object3D::Custom(primitive) => (Arc::as_ptr(primitive) as *const ()).hash(state),
+        }
+    }
+}
+
 impl Object3D {
+    /// Builds a sphere by named parts, defaulting to a unit sphere at the origin drawn with
+    /// the first material.
+    pub fn sphere() -> SphereBuilder {
+        SphereBuilder {
+            position: Vec3::ZERO,
+            radius: 1.,
+            material_index: 0,
+        }
+    }
+
     pub fn new_sphere(origin: Vec3, radius: f32, material_index: usize) -> Object3D {
         Object3D::Sphere {
             position: origin,
@@ -349,4 +1004,130 @@ impl Object3D {
             material_index,
         }
     }
+
+    pub fn new_mesh(mesh: Mesh, material_index: usize) -> Object3D {
+        Object3D::Mesh {
+            mesh: Arc::new(mesh),
+            material_index,
+        }
+    }
+
+    pub fn new_point_cloud(cloud: PointCloud, material_index: usize) -> Object3D {
+        Object3D::PointCloud {
+            cloud: Arc::new(cloud),
+            material_index,
+        }
+    }
+
+    pub fn new_terrain(terrain: Terrain, material_index: usize) -> Object3D {
+        Object3D::Terrain {
+            terrain: Arc::new(terrain),
+            material_index,
+        }
+    }
+
+    /// Axis-aligned box enclosing the object, padded so flat objects keep some thickness.
+    pub fn bounds(&self) -> Aabb {
+        let b = match self {
+            Object3D::Sphere {
+                position, radius, ..
+            } => Aabb::new(*position - Vec3::splat(*radius), *position + Vec3::splat(*radius)),
+            Object3D::Triangle { v1, v2, v3, .. } => {
+                Aabb::new(v1.min(*v2).min(*v3), v1.max(*v2).max(*v3))
+            }
+            Object3D::Mesh { mesh, .. } => mesh
+                .positions
+                .iter()
+                .fold(Aabb::default(), |b, p| b.union(&Aabb::new(*p, *p))),
+            Object3D::PointCloud { cloud, .. } => cloud.bvh.bounds(),
+            Object3D::Terrain { terrain, .. } => terrain.bounds(),
+            Object3D::Custom(primitive) => primitive.bounds(),
+        };
+        Aabb::new(b.min - Vec3::splat(EPSILON), b.max + Vec3::splat(EPSILON))
+    }
+
+    /// Copy of the object drawn with material `index`. Custom primitives pick their own
+    /// material and are copied as they are.
+    pub fn with_material(&self, index: usize) -> Object3D {
+        let mut object = self.clone();
+        match &mut object {
+            Object3D::Sphere { material_index, .. }
+            | Object3D::Triangle { material_index, .. }
+            | Object3D::Mesh { material_index, .. }
+            | Object3D::PointCloud { material_index, .. }
+            | Object3D::Terrain { material_index, .. } => *material_index = index,
+            Object3D::Custom(_) => {}
+        }
+        object
+    }
+
+    /// Copy of the object moved by `transform`. Sphere radii are scaled by the largest
+    /// axis scale, as a sphere can not be stretched into an ellipsoid. Terrains are only
+    /// translated, and custom primitives are wrapped in a `Transformed`.
+    pub fn transformed(&self, transform: &Affine3A) -> Object3D {
+        let m = transform.matrix3;
+        let scale = m.x_axis.length().max(m.y_axis.length()).max(m.z_axis.length());
+        match self {
+            Object3D::Sphere {
+                position,
+                radius,
+                material_index,
+            } => Object3D::new_sphere(
+                transform.transform_point3(*position),
+                *radius * scale,
+                *material_index,
+            ),
+            Object3D::Triangle {
+                v1,
+                v2,
+                v3,
+                material_index,
+            } => Object3D::new_triangle(
+                transform.transform_point3(*v1),
+                transform.transform_point3(*v2),
+                transform.transform_point3(*v3),
+                *material_index,
+            ),
+            Object3D::Mesh {
+                mesh,
+                material_index,
+            } => {
+                let mut mesh = Mesh::clone(mesh);
+                for p in mesh.positions.iter_mut() {
+                    *p = transform.transform_point3(*p);
+                }
+                for corners in mesh.normals.iter_mut() {
+                    *corners = corners.map(|n| transform.transform_vector3(n).normalize());
+                }
+                Object3D::new_mesh(mesh, *material_index)
+            }
+            Object3D::PointCloud {
+                cloud,
+                material_index,
+            } => {
+                let points = cloud
+                    .points
+                    .iter()
+                    .map(|p| transform.transform_point3(*p))
+                    .collect();
+                let moved = PointCloud::new(points, cloud.radius * scale)
+                    .with_colors(cloud.colors.clone());
+                Object3D::new_point_cloud(moved, *material_index)
+            }
+            Object3D::Terrain {
+                terrain,
+                material_index,
+            } => {
+                // Heightfields stay upright, so only the translation applies.
+                let moved = Terrain {
+                    origin: terrain.origin + Vec3::from(transform.translation),
+                    ..Terrain::clone(terrain)
+                };
+                Object3D::new_terrain(moved, *material_index)
+            }
+            Object3D::Custom(primitive) => {
+                Object3D::Custom(Arc::new(Transformed::new(primitive.clone(), *transform)))
+            }
+        }
+    }
 }
@@ -0,0 +1,128 @@
+use glam::{vec3, Vec3};
+use rand::rngs::ThreadRng;
+use rand::Rng;
+
+use crate::objects::{Material, MaterialType, Object3D};
+use crate::scene::Scene;
+
+/// Settings of a particle emitter. Sizes and emissions are interpolated from their
+/// first to their second value over the particle life.
+#[derive(Debug, Copy, Clone)]
+pub struct Emitter {
+    pub position: Vec3,
+    /// Particles spawned per second.
+    pub rate: f32,
+    pub velocity: Vec3,
+    /// Largest random speed added to `velocity`, in any direction.
+    pub spread: f32,
+    pub acceleration: Vec3,
+    /// Seconds a particle lives.
+    pub lifetime: f32,
+    pub size: (f32, f32),
+    pub emission: (f32, f32),
+    pub color: Vec3,
+    pub max_particles: usize,
+}
+
+impl Default for Emitter {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            rate: 50.,
+            velocity: vec3(0., 2., 0.),
+            spread: 1.,
+            acceleration: vec3(0., -9.81, 0.),
+            lifetime: 1.5,
+            size: (0.03, 0.01),
+            emission: (5., 0.),
+            color: vec3(1., 0.6, 0.2),
+            max_particles: 500,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Particle {
+    position: Vec3,
+    velocity: Vec3,
+    age: f32,
+}
+
+/// Live particles of an emitter, drawn as small emissive spheres. The spheres and their
+/// materials are kept at the end of the scene lists, so nothing may be added to the
+/// scene after `Particles::new`.
+pub struct Particles {
+    pub emitter: Emitter,
+    particles: Vec<Particle>,
+    pending: f32,
+    first_object: usize,
+    first_material: usize,
+}
+
+impl Particles {
+    pub fn new(emitter: Emitter, scene: &Scene) -> Particles {
+        Particles {
+            emitter,
+            particles: vec![],
+            pending: 0.,
+            first_object: scene.objects.len(),
+            first_material: scene.materials.len(),
+        }
+    }
+
+    /// Ages, moves and spawns particles over `dt` seconds, then writes them to the scene.
+    /// Returns whether any particle is alive or was just removed.
+    pub fn update(&mut self, scene: &mut Scene, dt: f32, rnd: &mut ThreadRng) -> bool {
+        let e = self.emitter;
+        let had_particles = !self.particles.is_empty();
+
+        for p in self.particles.iter_mut() {
+            p.age += dt;
+            p.velocity += e.acceleration * dt;
+            p.position += p.velocity * dt;
+        }
+        self.particles.retain(|p| p.age < e.lifetime);
+
+        self.pending += e.rate * dt;
+        while self.pending >= 1. {
+            self.pending -= 1.;
+            if self.particles.len() >= e.max_particles {
+                continue;
+            }
+            let jitter = vec3(
+                rnd.gen_range(-1.0..1.0),
+                rnd.gen_range(-1.0..1.0),
+                rnd.gen_range(-1.0..1.0),
+            );
+            self.particles.push(Particle {
+                position: e.position,
+                velocity: e.velocity + jitter.normalize_or_zero() * rnd.gen_range(0.0..=e.spread),
+                age: 0.,
+            });
+        }
+
+        scene.objects.truncate(self.first_object);
+        scene.materials.truncate(self.first_material);
+
+        for p in self.particles.iter() {
+            let life = p.age / e.lifetime;
+            let radius = e.size.0 + (e.size.1 - e.size.0) * life;
+            let emission = e.emission.0 + (e.emission.1 - e.emission.0) * life;
+
+            scene.materials.push(Material {
+                albedo: e.color,
+                kind: MaterialType::Reflective { roughness: 1.0 },
+                emission_power: emission,
+                emissive: true,
+                ..Default::default()
+            });
+            scene.objects.push(Object3D::new_sphere(
+                p.position,
+                radius,
+                scene.materials.len() - 1,
+            ));
+        }
+
+        had_particles || !self.particles.is_empty()
+    }
+}
@@ -0,0 +1,168 @@
+use glam::{Affine3A, Quat, Vec3};
+use rapier3d::prelude::{
+    CCDSolver, Collider, ColliderBuilder, ColliderSet, DefaultBroadPhase, ImpulseJointSet,
+    IntegrationParameters, IslandManager, Isometry, MultibodyJointSet, NarrowPhase,
+    PhysicsPipeline, Real, RigidBodyBuilder, RigidBodyHandle, RigidBodySet, Vector,
+};
+
+use crate::objects::{Cuboid, Object3D};
+use crate::scene::Scene;
+
+/// Scene objects moved by one rigid body, kept relative to the body origin.
+struct Link {
+    body: RigidBodyHandle,
+    first_object: usize,
+    local: Vec<Object3D>,
+}
+
+/// A rapier3d world whose rigid bodies drive scene objects. Call `step` from
+/// `App::run` to let spheres and cuboids fall, tumble and stack.
+pub struct Physics {
+    pub gravity: Vec3,
+    /// The simulated bodies, for setting velocities or applying impulses.
+    pub bodies: RigidBodySet,
+    colliders: ColliderSet,
+    pipeline: PhysicsPipeline,
+    parameters: IntegrationParameters,
+    islands: IslandManager,
+    broad_phase: DefaultBroadPhase,
+    narrow_phase: NarrowPhase,
+    impulse_joints: ImpulseJointSet,
+    multibody_joints: MultibodyJointSet,
+    ccd_solver: CCDSolver,
+    links: Vec<Link>,
+}
+
+impl Default for Physics {
+    fn default() -> Self {
+        Self {
+            gravity: Vec3::new(0., -9.81, 0.),
+            bodies: RigidBodySet::new(),
+            colliders: ColliderSet::new(),
+            pipeline: PhysicsPipeline::new(),
+            parameters: IntegrationParameters::default(),
+            islands: IslandManager::new(),
+            broad_phase: DefaultBroadPhase::new(),
+            narrow_phase: NarrowPhase::new(),
+            impulse_joints: ImpulseJointSet::new(),
+            multibody_joints: MultibodyJointSet::new(),
+            ccd_solver: CCDSolver::new(),
+            links: vec![],
+        }
+    }
+}
+
+impl Physics {
+    pub fn new() -> Physics {
+        Physics::default()
+    }
+
+    /// Static infinite floor at `height`, facing up.
+    pub fn add_ground(&mut self, height: f32) {
+        let ground = ColliderBuilder::halfspace(Vector::y_axis())
+            .translation(Vector::new(0., height, 0.))
+            .build();
+        self.colliders.insert(ground);
+    }
+
+    /// Makes the sphere at `scene.objects[object]` a dynamic body.
+    pub fn add_sphere(&mut self, scene: &Scene, object: usize) -> Option<RigidBodyHandle> {
+        let Object3D::Sphere {
+            position, radius, ..
+        } = scene.objects[object]
+        else {
+            return None;
+        };
+
+        let collider = ColliderBuilder::ball(radius).build();
+        Some(self.add_body(scene, position, collider, object, 1))
+    }
+
+    /// Makes the 12 triangles `Cuboid::triangles` put at `scene.objects[first_object..]`
+    /// a dynamic body.
+    pub fn add_cuboid(
+        &mut self,
+        scene: &Scene,
+        cuboid: &Cuboid,
+        first_object: usize,
+    ) -> RigidBodyHandle {
+        let collider =
+            ColliderBuilder::cuboid(cuboid.length / 2., cuboid.width / 2., cuboid.depth / 2.)
+                .build();
+        self.add_body(scene, cuboid.center, collider, first_object, 12)
+    }
+
+    fn add_body(
+        &mut self,
+        scene: &Scene,
+        center: Vec3,
+        collider: Collider,
+        first_object: usize,
+        count: usize,
+    ) -> RigidBodyHandle {
+        let body = RigidBodyBuilder::dynamic()
+            .translation(Vector::new(center.x, center.y, center.z))
+            .build();
+        let handle = self.bodies.insert(body);
+        self.colliders
+            .insert_with_parent(collider, handle, &mut self.bodies);
+
+        let to_local = Affine3A::from_translation(-center);
+        self.links.push(Link {
+            body: handle,
+            first_object,
+            local: scene.objects[first_object..first_object + count]
+                .iter()
+                .map(|obj| obj.transformed(&to_local))
+                .collect(),
+        });
+
+        handle
+    }
+
+    /// Advances the simulation by `dt` seconds and moves the linked scene objects.
+    /// Returns whether any of them moved.
+    pub fn step(&mut self, scene: &mut Scene, dt: f32) -> bool {
+        self.parameters.dt = dt;
+        self.pipeline.step(
+            &Vector::new(self.gravity.x, self.gravity.y, self.gravity.z),
+            &self.parameters,
+            &mut self.islands,
+            &mut self.broad_phase,
+            &mut self.narrow_phase,
+            &mut self.bodies,
+            &mut self.colliders,
+            &mut self.impulse_joints,
+            &mut self.multibody_joints,
+            &mut self.ccd_solver,
+            None,
+            &(),
+            &(),
+        );
+
+        let mut moved = false;
+        for link in self.links.iter() {
+            let body = &self.bodies[link.body];
+            if body.is_sleeping() {
+                continue;
+            }
+
+            let transform = affine(body.position());
+            for (i, obj) in link.local.iter().enumerate() {
+                scene.objects[link.first_object + i] = obj.transformed(&transform);
+            }
+            moved = true;
+        }
+
+        moved
+    }
+}
+
+fn affine(position: &Isometry<Real>) -> Affine3A {
+    let t = position.translation.vector;
+    let r = position.rotation;
+    Affine3A::from_rotation_translation(
+        Quat::from_xyzw(r.i, r.j, r.k, r.w),
+        Vec3::new(t.x, t.y, t.z),
+    )
+}
@@ -0,0 +1,448 @@
+use std::sync::Arc;
+
+use glam::{vec3, Vec3, Vec4};
+
+use crate::utils::errors::AppError;
+
+/// Rec. 709 luminance of a linear colour.
+pub fn luminance(c: Vec3) -> f32 {
+    c.dot(vec3(0.2126, 0.7152, 0.0722))
+}
+
+/// Bins of `luminance_histogram`, spread evenly over log2 luminance.
+pub const HISTOGRAM_BINS: usize = 64;
+static MIN_LOG_LUMINANCE: f32 = -10.;
+static MAX_LOG_LUMINANCE: f32 = 6.;
+
+/// Pixel count per log2 luminance bin, from `MIN_LOG_LUMINANCE` to `MAX_LOG_LUMINANCE`.
+/// Black pixels go to the first bin.
+pub fn luminance_histogram(image: &[Vec4]) -> [u32; HISTOGRAM_BINS] {
+    let mut histogram = [0; HISTOGRAM_BINS];
+    for c in image {
+        histogram[histogram_bin(luminance(c.truncate()))] += 1;
+    }
+    histogram
+}
+
+fn histogram_bin(l: f32) -> usize {
+    let range = MAX_LOG_LUMINANCE - MIN_LOG_LUMINANCE;
+    let x = (l.max(f32::MIN_POSITIVE).log2() - MIN_LOG_LUMINANCE) / range;
+    ((x * HISTOGRAM_BINS as f32).max(0.) as usize).min(HISTOGRAM_BINS - 1)
+}
+
+fn bin_log_luminance(bin: usize) -> f32 {
+    let range = MAX_LOG_LUMINANCE - MIN_LOG_LUMINANCE;
+    MIN_LOG_LUMINANCE + (bin as f32 + 0.5) / HISTOGRAM_BINS as f32 * range
+}
+
+/// What the renderer shows.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum DisplayMode {
+    #[default]
+    Beauty,
+    /// Exposure zones in flat colours, see `false_color`.
+    FalseColor,
+    /// The image with its luminance histogram drawn in the bottom left corner.
+    Histogram,
+}
+
+impl DisplayMode {
+    pub fn next(self) -> DisplayMode {
+        match self {
+            DisplayMode::Beauty => DisplayMode::FalseColor,
+            DisplayMode::FalseColor => DisplayMode::Histogram,
+            DisplayMode::Histogram => DisplayMode::Beauty,
+        }
+    }
+}
+
+/// Curve bringing the exposed linear colours into the displayable `[0, 1]`, applied to
+/// the whole accumulated frame before grading.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub enum ToneMap {
+    /// Clips whatever is brighter than 1.
+    #[default]
+    Linear,
+    /// `c / (1 + c)` on the luminance, keeping the hue: highlights roll off smoothly but
+    /// never reach white.
+    Reinhard,
+    /// Fit of the ACES filmic curve by Krzysztof Narkowicz: a toe in the shadows, more
+    /// contrast in the midtones and highlights desaturating into white.
+    AcesFilmic,
+}
+
+impl ToneMap {
+    pub fn apply(self, c: Vec3) -> Vec3 {
+        let c = c.max(Vec3::ZERO);
+        match self {
+            ToneMap::Linear => c.min(Vec3::ONE),
+            ToneMap::Reinhard => {
+                let l = luminance(c);
+                if l <= 0. {
+                    return Vec3::ZERO;
+                }
+                (c * (1. / (1. + l))).min(Vec3::ONE)
+            }
+            ToneMap::AcesFilmic => {
+                let mapped = (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14);
+                mapped.clamp(Vec3::ZERO, Vec3::ONE)
+            }
+        }
+    }
+}
+
+/// Exposure zone of a displayed luminance, in stops from middle grey: purple for crushed
+/// blacks, blue and cyan for shadows, grey around middle grey, green and yellow for
+/// highlights and red where the display clips.
+pub fn false_color(l: f32) -> Vec3 {
+    if l >= 1. {
+        return vec3(1., 0., 0.);
+    }
+    let stops = (l.max(f32::MIN_POSITIVE) / 0.18).log2();
+    if stops < -5. {
+        vec3(0.5, 0., 0.5)
+    } else if stops < -3. {
+        vec3(0., 0., 1.)
+    } else if stops < -1. {
+        vec3(0., 0.6, 0.8)
+    } else if stops < 1. {
+        vec3(0.5, 0.5, 0.5)
+    } else if stops < 2. {
+        vec3(0., 0.8, 0.)
+    } else {
+        vec3(1., 0.9, 0.)
+    }
+}
+
+/// Draws `histogram` as bars over the bottom left corner of a `w` x `h` RGBA image. Bins
+/// that clip on the display are drawn in red.
+pub fn draw_histogram(img: &mut [u8], w: usize, h: usize, histogram: &[u32; HISTOGRAM_BINS]) {
+    let bar = (w / 3 / HISTOGRAM_BINS).max(1);
+    let (box_w, box_h) = ((bar * HISTOGRAM_BINS).min(w), (h / 4).max(1));
+    let peak = histogram.iter().copied().max().unwrap_or(0).max(1) as f32;
+
+    for x in 0..box_w {
+        let bin = x / bar;
+        let height = (histogram[bin] as f32 / peak * box_h as f32) as usize;
+        let clipped = bin_log_luminance(bin) >= 0.;
+
+        for y in 0..box_h {
+            let i = ((h - 1 - y) * w + x) * 4;
+            let pixel = &mut img[i..i + 4];
+            if y < height {
+                let color: [u8; 3] = if clipped {
+                    [255, 40, 40]
+                } else {
+                    [230, 230, 230]
+                };
+                pixel[..3].copy_from_slice(&color);
+            } else {
+                // Darken the background so the bars stay readable.
+                for c in pixel[..3].iter_mut() {
+                    *c /= 3;
+                }
+            }
+        }
+    }
+}
+
+/// Exposure that maps the average luminance of the frame to `key`, ignoring the darkest
+/// and brightest pixels so a few emitters or shadows do not dominate.
+#[derive(Debug, Copy, Clone)]
+pub struct AutoExposure {
+    /// Luminance the average of the frame is exposed to.
+    pub key: f32,
+    pub min: f32,
+    pub max: f32,
+    /// Fraction of the way to the target exposure covered each frame.
+    pub adaptation: f32,
+    /// Fractions of the darkest and of the brightest pixels left out of the average.
+    pub low_percentile: f32,
+    pub high_percentile: f32,
+}
+
+impl Default for AutoExposure {
+    fn default() -> Self {
+        Self {
+            key: 0.18,
+            min: 0.1,
+            max: 10.,
+            adaptation: 0.05,
+            low_percentile: 0.5,
+            high_percentile: 0.05,
+        }
+    }
+}
+
+impl AutoExposure {
+    pub fn target(&self, image: &[Vec4]) -> f32 {
+        let histogram = luminance_histogram(image);
+        let total = image.len() as f32;
+        let low = total * self.low_percentile;
+        let high = total * (1. - self.high_percentile);
+
+        let mut seen = 0.;
+        let mut sum = 0.;
+        let mut count = 0.;
+        for (bin, n) in histogram.iter().enumerate() {
+            let n = *n as f32;
+            // Part of this bin that lies between the two percentiles.
+            let kept = (seen + n).min(high) - seen.max(low);
+            if kept > 0. {
+                sum += bin_log_luminance(bin) * kept;
+                count += kept;
+            }
+            seen += n;
+        }
+
+        if count <= 0. {
+            return 1.;
+        }
+        let average = (sum / count).exp2();
+        (self.key / average).clamp(self.min, self.max)
+    }
+
+    /// Moves `current` towards the exposure of `image`.
+    pub fn adapt(&self, current: f32, image: &[Vec4]) -> f32 {
+        let target = self.target(image);
+        // Adapting in log space keeps the speed the same when brightening and darkening.
+        (current.log2() + (target.log2() - current.log2()) * self.adaptation).exp2()
+    }
+}
+
+/// Screen-space lens flare: pixels brighter than `threshold` bleed into horizontal and
+/// vertical streaks and into ghosts mirrored through the image center.
+#[derive(Debug, Copy, Clone)]
+pub struct Flare {
+    /// Luminance above which a pixel flares.
+    pub threshold: f32,
+    pub intensity: f32,
+    /// Fraction of a streak carried over to the next pixel, in `[0, 1)`.
+    pub streak_decay: f32,
+    pub ghosts: usize,
+    /// Distance between ghosts along the line through the image center.
+    pub ghost_spacing: f32,
+}
+
+impl Default for Flare {
+    fn default() -> Self {
+        Self {
+            threshold: 1.,
+            intensity: 0.2,
+            streak_decay: 0.97,
+            ghosts: 4,
+            ghost_spacing: 0.4,
+        }
+    }
+}
+
+static GHOST_TINTS: [Vec3; 3] = [
+    Vec3::new(1., 0.6, 0.3),
+    Vec3::new(0.4, 0.7, 1.),
+    Vec3::new(0.6, 1., 0.5),
+];
+
+impl Flare {
+    /// Adds the flare of a `w` x `h` linear image to it.
+    pub fn apply(&self, image: &mut [Vec4], w: usize, h: usize) {
+        let bright: Vec<Vec3> = image
+            .iter()
+            .map(|c| {
+                let c = c.truncate();
+                let l = luminance(c);
+                if l > self.threshold {
+                    c * (l - self.threshold) / l
+                } else {
+                    Vec3::ZERO
+                }
+            })
+            .collect();
+
+        if bright.iter().all(|b| *b == Vec3::ZERO) {
+            return;
+        }
+
+        let mut flare = vec![Vec3::ZERO; w * h];
+        self.streak(&bright, &mut flare, w, h, 1, w);
+        self.streak(&bright, &mut flare, h, w, w, 1);
+
+        let center = vec3(w as f32 / 2., h as f32 / 2., 0.);
+        for y in 0..h {
+            for x in 0..w {
+                let p = vec3(x as f32, y as f32, 0.);
+                for k in 0..self.ghosts {
+                    let scale = (k + 1) as f32 * self.ghost_spacing;
+                    let g = center + (center - p) * scale;
+                    if g.x < 0. || g.y < 0. || g.x >= w as f32 || g.y >= h as f32 {
+                        continue;
+                    }
+                    let src = bright[g.y as usize * w + g.x as usize];
+                    flare[y * w + x] += src * GHOST_TINTS[k % GHOST_TINTS.len()] / (k + 1) as f32;
+                }
+            }
+        }
+
+        for (c, f) in image.iter_mut().zip(flare) {
+            *c += (f * self.intensity).extend(0.);
+        }
+    }
+
+    /// Smears `bright` along lines of `len` pixels `step` apart, starting `stride` apart,
+    /// in both directions with an exponential decay.
+    fn streak(
+        &self,
+        bright: &[Vec3],
+        flare: &mut [Vec3],
+        len: usize,
+        lines: usize,
+        step: usize,
+        stride: usize,
+    ) {
+        let weight = (1. - self.streak_decay) * 0.5;
+        for line in 0..lines {
+            let start = line * stride;
+            let mut forward = Vec3::ZERO;
+            let mut backward = Vec3::ZERO;
+            for i in 0..len {
+                let a = start + i * step;
+                let b = start + (len - 1 - i) * step;
+                forward = forward * self.streak_decay + bright[a];
+                backward = backward * self.streak_decay + bright[b];
+                flare[a] += forward * weight;
+                flare[b] += backward * weight;
+            }
+        }
+    }
+}
+
+/// A 3D colour lookup table, as stored in Adobe/Resolve `.cube` files.
+#[derive(Debug, Clone)]
+pub struct Lut3d {
+    pub size: usize,
+    pub domain_min: Vec3,
+    pub domain_max: Vec3,
+    /// `size`^3 entries, red changing fastest, then green, then blue.
+    pub table: Vec<Vec3>,
+}
+
+impl Lut3d {
+    pub fn load(path: impl Into<String>) -> Result<Lut3d, AppError> {
+        let p: String = path.into();
+        let text = std::fs::read_to_string(&p)?;
+
+        let mut lut = Lut3d {
+            size: 0,
+            domain_min: Vec3::ZERO,
+            domain_max: Vec3::ONE,
+            table: vec![],
+        };
+
+        for (n, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() || words[0].starts_with('#') {
+                continue;
+            }
+            let (key, args) = if words[0].starts_with(|c: char| c.is_ascii_alphabetic()) {
+                (words[0], &words[1..])
+            } else {
+                ("", &words[..])
+            };
+
+            let bad_line = || AppError::Parse {
+                path: p.clone(),
+                line: n + 1,
+                message: format!("bad line '{}'", line),
+            };
+            let rgb = |args: &[&str]| -> Result<Vec3, AppError> {
+                let v: Vec<f32> = args.iter().filter_map(|w| w.parse().ok()).collect();
+                if v.len() == 3 && args.len() == 3 {
+                    Ok(Vec3::from_slice(&v))
+                } else {
+                    Err(bad_line())
+                }
+            };
+
+            match key {
+                "LUT_3D_SIZE" => {
+                    lut.size = args
+                        .first()
+                        .and_then(|w| w.parse().ok())
+                        .ok_or_else(bad_line)?
+                }
+                "DOMAIN_MIN" => lut.domain_min = rgb(args)?,
+                "DOMAIN_MAX" => lut.domain_max = rgb(args)?,
+                "" => lut.table.push(rgb(args)?),
+                // TITLE and other keywords.
+                _ => {}
+            }
+        }
+
+        if lut.size < 2 || lut.table.len() != lut.size * lut.size * lut.size {
+            return Err(AppError::Validation(format!(
+                "{}: expected a LUT_3D_SIZE of at least 2 and that many entries cubed",
+                p
+            )));
+        }
+
+        log::debug!("Loaded {}x{}x{} LUT {}", lut.size, lut.size, lut.size, p);
+        Ok(lut)
+    }
+
+    /// Trilinear lookup of `c`.
+    pub fn apply(&self, c: Vec3) -> Vec3 {
+        let n = self.size - 1;
+        let x = ((c - self.domain_min) / (self.domain_max - self.domain_min))
+            .clamp(Vec3::ZERO, Vec3::ONE)
+            * n as f32;
+        let i = x.floor().to_array().map(|v| (v as usize).min(n - 1));
+        let f = x - Vec3::new(i[0] as f32, i[1] as f32, i[2] as f32);
+        let at = |r: usize, g: usize, b: usize| self.table[r + self.size * (g + self.size * b)];
+
+        let mut out = Vec3::ZERO;
+        for corner in 0..8 {
+            let o = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let w = [0, 1, 2].map(|a| if o[a] == 1 { f[a] } else { 1. - f[a] });
+            out += at(i[0] + o[0], i[1] + o[1], i[2] + o[2]) * w[0] * w[1] * w[2];
+        }
+        out
+    }
+}
+
+/// Final colour grade. White balance scales the linear colours before they are tone
+/// mapped for display; lift, gamma, gain and then the LUT are applied to the tone mapped
+/// colours.
+#[derive(Debug, Clone)]
+pub struct Grade {
+    pub white_balance: Vec3,
+    /// Raises the blacks.
+    pub lift: Vec3,
+    /// Bends the midtones, values above 1 brightening them.
+    pub gamma: Vec3,
+    /// Scales the whites.
+    pub gain: Vec3,
+    pub lut: Option<Arc<Lut3d>>,
+}
+
+impl Default for Grade {
+    fn default() -> Self {
+        Self {
+            white_balance: Vec3::ONE,
+            lift: Vec3::ZERO,
+            gamma: Vec3::ONE,
+            gain: Vec3::ONE,
+            lut: None,
+        }
+    }
+}
+
+impl Grade {
+    /// Grades a colour already brought into `[0, 1]`.
+    pub fn apply(&self, c: Vec3) -> Vec3 {
+        let c = (self.gain * (c + self.lift * (Vec3::ONE - c))).clamp(Vec3::ZERO, Vec3::ONE);
+        let c = Vec3::from_array([0, 1, 2].map(|a| c[a].powf(1. / self.gamma[a])));
+        match &self.lut {
+            Some(lut) => lut.apply(c),
+            None => c,
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use glam::{Affine3A, Vec3, Vec3A};
+
+use crate::accel::Aabb;
+use crate::ray::{Ray, RayHit, EPSILON};
+
+/// Most steps `sphere_trace` takes along a ray before giving up.
+const MAX_TRACE_STEPS: usize = 256;
+
+/// Closest hit of a ray with a shape.
+pub trait Intersection {
+    /// Closest hit of `ray` further than `EPSILON`, with its distance, point, outward
+    /// normal, texture coordinates and the index of the material to shade it with.
+    fn intersect(&self, ray: &Ray) -> Option<RayHit>;
+}
+
+/// Signed distance field of a shape: the distance to its surface, negative inside.
+pub trait Sdf {
+    fn distance(&self, point: Vec3) -> f32;
+
+    /// Outward normal of the surface near `point`, the gradient of the field.
+    fn normal(&self, point: Vec3) -> Vec3 {
+        let e = EPSILON;
+        let gradient =
+            |axis: Vec3| self.distance(point + axis * e) - self.distance(point - axis * e);
+        Vec3::new(gradient(Vec3::X), gradient(Vec3::Y), gradient(Vec3::Z)).normalize_or_zero()
+    }
+}
+
+/// Shape of a known extent.
+pub trait Bounded {
+    /// Axis-aligned box enclosing the shape.
+    fn bounds(&self) -> Aabb;
+}
+
+/// A shape defined outside this crate, added to scenes as `Object3D::Custom`. Anything
+/// intersectable, bounded and with a distance field is a primitive.
+///
+/// ```ignore
+/// #[derive(Debug)]
+/// struct Torus { major: f32, minor: f32, material_index: usize }
+///
+/// impl Sdf for Torus {
+///     fn distance(&self, p: Vec3) -> f32 {
+///         Vec2::new(p.xz().length() - self.major, p.y).length() - self.minor
+///     }
+/// }
+/// impl Intersection for Torus {
+///     fn intersect(&self, ray: &Ray) -> Option<RayHit> {
+///         sphere_trace(self, ray, f32::MAX, self.material_index)
+///     }
+/// }
+/// impl Bounded for Torus { ... }
+///
+/// scene.objects.push(Object3D::Custom(Arc::new(Torus { ... })));
+/// ```
+pub trait Primitive: Intersection + Sdf + Bounded + Send + Sync + Debug {}
+
+impl<T: Intersection + Sdf + Bounded + Send + Sync + Debug> Primitive for T {}
+
+/// Hit of `ray` with the surface of `sdf` closer than `t_max`, found by stepping along
+/// the ray by the distance to the surface, for primitives described by their distance
+/// field alone.
+pub fn sphere_trace<S: Sdf + ?Sized>(
+    sdf: &S,
+    ray: &Ray,
+    t_max: f32,
+    material_index: usize,
+) -> Option<RayHit> {
+    let mut t = EPSILON * 10.;
+    for _ in 0..MAX_TRACE_STEPS {
+        let point = ray.at(t);
+        let d = sdf.distance(point);
+        if d.abs() < EPSILON * t.max(1.) {
+            return Some(RayHit {
+                distance: t,
+                point,
+                normal: sdf.normal(point),
+                material_index,
+                ..Default::default()
+            });
+        }
+        t += d.abs();
+        if t >= t_max {
+            break;
+        }
+    }
+    None
+}
+
+/// A primitive moved by a transform, which `Object3D::transformed` wraps custom
+/// primitives in.
+#[derive(Debug)]
+pub struct Transformed {
+    pub primitive: Arc<dyn Primitive>,
+    pub transform: Affine3A,
+    inverse: Affine3A,
+}
+
+impl Transformed {
+    pub fn new(primitive: Arc<dyn Primitive>, transform: Affine3A) -> Transformed {
+        Transformed {
+            primitive,
+            transform,
+            inverse: transform.inverse(),
+        }
+    }
+
+    /// Normal of the primitive, in its space, moved into the world.
+    fn world_normal(&self, normal: Vec3) -> Vec3 {
+        (self.inverse.matrix3.transpose() * Vec3A::from(normal))
+            .normalize()
+            .into()
+    }
+}
+
+impl Intersection for Transformed {
+    fn intersect(&self, ray: &Ray) -> Option<RayHit> {
+        let local = Ray {
+            origin: self.inverse.transform_point3(ray.origin),
+            direction: self.inverse.transform_vector3(ray.direction).normalize(),
+            ..*ray
+        };
+        let hit = self.primitive.intersect(&local)?;
+        let point = self.transform.transform_point3(hit.point);
+        Some(RayHit {
+            distance: point.distance(ray.origin),
+            point,
+            normal: self.world_normal(hit.normal),
+            ..hit
+        })
+    }
+}
+
+impl Sdf for Transformed {
+    /// Distance of the primitive scaled by the smallest axis scale, which never
+    /// overshoots the surface.
+    fn distance(&self, point: Vec3) -> f32 {
+        let m = self.transform.matrix3;
+        let scale = m
+            .x_axis
+            .length()
+            .min(m.y_axis.length())
+            .min(m.z_axis.length());
+        self.primitive
+            .distance(self.inverse.transform_point3(point))
+            * scale
+    }
+
+    fn normal(&self, point: Vec3) -> Vec3 {
+        self.world_normal(self.primitive.normal(self.inverse.transform_point3(point)))
+    }
+}
+
+impl Bounded for Transformed {
+    fn bounds(&self) -> Aabb {
+        self.primitive.bounds().transformed(&self.transform)
+    }
+}
@@ -0,0 +1,211 @@
+//! Python bindings, built with the `python` feature into a `ray_tracing` extension module:
+//!
+//! ```python
+//! import ray_tracing as rt
+//!
+//! scene = rt.Scene()
+//! red = scene.add_material(rt.Material(albedo=(0.9, 0.2, 0.2), roughness=0.3))
+//! scene.add_sphere((0, 0, 0), 0.5, red)
+//! camera = rt.Camera(position=(0, 0.5, 3), target=(0, 0, 0))
+//! image = rt.render(scene, camera, width=320, height=240, spp=32)  # (240, 320, 4) float32
+//! ```
+
+use numpy::ndarray::Array3;
+use numpy::{IntoPyArray, PyArray3};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+
+use crate::camera::Camera;
+use crate::job::RenderJob;
+use crate::objects::{Material, MaterialType, Object3D};
+use crate::scene::Scene;
+use crate::utils::errors::AppError;
+use crate::utils::obj::ObjUtils;
+
+type Vec3Tuple = (f32, f32, f32);
+
+fn vec3(v: Vec3Tuple) -> glam::Vec3 {
+    glam::Vec3::new(v.0, v.1, v.2)
+}
+
+fn to_py_err(e: AppError) -> PyErr {
+    match e {
+        AppError::Io(e) => PyIOError::new_err(e.to_string()),
+        e => PyValueError::new_err(e.to_string()),
+    }
+}
+
+/// A material: rough or polished diffuse by default, metallic with `metallic`, glass with
+/// `glass`.
+#[pyclass(name = "Material")]
+#[derive(Clone)]
+pub struct PyMaterial(Material);
+
+#[pymethods]
+impl PyMaterial {
+    #[new]
+    #[pyo3(signature = (albedo = (0.5, 0.5, 0.5), roughness = 1.0, metallic = 0.0, ior = 1.5, emission = 0.0, glass = false))]
+    fn new(
+        albedo: Vec3Tuple,
+        roughness: f32,
+        metallic: f32,
+        ior: f32,
+        emission: f32,
+        glass: bool,
+    ) -> PyMaterial {
+        let kind = if glass {
+            Material::glass(roughness).kind
+        } else if metallic > 0. {
+            MaterialType::Pbr {
+                metallic,
+                roughness,
+                ior,
+            }
+        } else {
+            MaterialType::Reflective { roughness }
+        };
+        PyMaterial(Material {
+            albedo: vec3(albedo),
+            kind,
+            emission_power: emission,
+            emissive: emission > 0.,
+            ..Default::default()
+        })
+    }
+}
+
+/// Objects, materials and the light. Objects refer to materials by the index returned by
+/// `add_material`.
+#[pyclass(name = "Scene")]
+pub struct PyScene(Scene);
+
+#[pymethods]
+impl PyScene {
+    #[new]
+    fn new() -> PyScene {
+        PyScene(Scene::new(vec![], vec![]))
+    }
+
+    /// Reads a scene file written by `save` or by the viewer.
+    #[staticmethod]
+    fn load(path: &str) -> PyResult<PyScene> {
+        Scene::load(path).map(PyScene).map_err(to_py_err)
+    }
+
+    fn save(&self, path: &str) -> PyResult<()> {
+        self.0.save(path, None).map_err(to_py_err)
+    }
+
+    fn add_material(&mut self, material: PyMaterial) -> usize {
+        self.0.materials.push(material.0);
+        self.0.materials.len() - 1
+    }
+
+    /// Adds a sphere and returns its object index.
+    fn add_sphere(&mut self, center: Vec3Tuple, radius: f32, material: usize) -> PyResult<usize> {
+        self.add(
+            Object3D::new_sphere(vec3(center), radius, material),
+            material,
+        )
+    }
+
+    /// Adds the mesh of an OBJ file and returns its object index.
+    fn add_obj(&mut self, path: &str, material: usize) -> PyResult<usize> {
+        let mesh = ObjUtils::load_obj(path).map_err(to_py_err)?;
+        self.add(Object3D::new_mesh(mesh, material), material)
+    }
+
+    /// Sets the directional light, `direction` pointing from the light into the scene.
+    fn set_light(&mut self, direction: Vec3Tuple, power: f32) {
+        self.0.light.direction = vec3(direction).normalize_or_zero();
+        self.0.light.power = power;
+    }
+
+    #[getter]
+    fn object_count(&self) -> usize {
+        self.0.objects.len()
+    }
+}
+
+impl PyScene {
+    fn add(&mut self, object: Object3D, material: usize) -> PyResult<usize> {
+        if material >= self.0.materials.len() {
+            return Err(PyValueError::new_err(format!(
+                "no material {}, the scene has {}",
+                material,
+                self.0.materials.len()
+            )));
+        }
+        self.0.objects.push(object);
+        Ok(self.0.objects.len() - 1)
+    }
+}
+
+/// A pinhole or thin lens camera at `position` looking at `target`.
+#[pyclass(name = "Camera")]
+pub struct PyCamera(Camera);
+
+#[pymethods]
+impl PyCamera {
+    #[new]
+    #[pyo3(signature = (position = (0., 0., 3.), target = (0., 0., 0.), fov = None, aperture = 0., focus_distance = None))]
+    fn new(
+        position: Vec3Tuple,
+        target: Vec3Tuple,
+        fov: Option<f32>,
+        aperture: f32,
+        focus_distance: Option<f32>,
+    ) -> PyCamera {
+        let (position, target) = (vec3(position), vec3(target));
+        let mut camera = Camera::new_with_pos(position, (target - position).normalize());
+        if let Some(fov) = fov {
+            camera.fov = fov;
+        }
+        camera.aperture = aperture;
+        camera.focus_distance = focus_distance.unwrap_or(position.distance(target));
+        PyCamera(camera)
+    }
+}
+
+/// Renders `scene` through `camera` and returns the linear colours as a float32 array of
+/// shape `(height, width, 4)`. A `seed` renders the same image on every call. The GIL is
+/// released while rendering.
+#[pyfunction]
+#[pyo3(signature = (scene, camera, width = 640, height = 480, spp = 16, seed = None))]
+fn render<'py>(
+    py: Python<'py>,
+    scene: &PyScene,
+    camera: &PyCamera,
+    width: usize,
+    height: usize,
+    spp: u32,
+    seed: Option<u64>,
+) -> PyResult<Bound<'py, PyArray3<f32>>> {
+    let (scene, camera) = (scene.0.clone(), camera.0.clone());
+    let frame = py
+        .detach(|| {
+            let job = RenderJob::new(scene, camera)
+                .resolution(width, height)
+                .spp(spp);
+            match seed {
+                Some(seed) => job.seed(seed),
+                None => job,
+            }
+            .render()
+        })
+        .map_err(to_py_err)?;
+
+    let values = frame.pixels.iter().flat_map(|p| p.to_array()).collect();
+    let image = Array3::from_shape_vec((frame.height, frame.width, 4), values)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(image.into_pyarray(py))
+}
+
+#[pymodule]
+fn ray_tracing(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyMaterial>()?;
+    m.add_class::<PyScene>()?;
+    m.add_class::<PyCamera>()?;
+    m.add_function(wrap_pyfunction!(render, m)?)?;
+    Ok(())
+}
@@ -1,14 +1,32 @@
 use glam::{vec3, Vec3};
-use rand::{rngs::ThreadRng, Rng};
+use rand::{rngs::SmallRng, Rng};
 
-use crate::objects::Object3D;
+use crate::objects::{Object3D, PointCloud, SpherePack, SPHERE_LANES};
+use crate::utils::mesh::Mesh;
 
 pub static EPSILON: f32 = 0.0001_f32;
 
-#[derive(Debug, Copy, Clone)]
+/// Random number generator drawn from while tracing. The renderer seeds one per chunk,
+/// from `Renderer::with_seed` when set.
+pub type Sampler = SmallRng;
+
+#[derive(Debug, Copy, Clone, Default)]
 pub struct Ray {
     pub origin: Vec3,
     pub direction: Vec3,
+    /// Width of the cone of directions the ray stands for, at its origin. Together with
+    /// `spread` this gives the footprint the ray covers at a hit, from which textures pick
+    /// their mip level. Zero for rays that never sample textures, like shadow rays.
+    pub width: f32,
+    /// Angle, in radians, by which the cone widens per unit of distance.
+    pub spread: f32,
+    /// Moment within the open shutter the ray is traced at, from 0 to 1, which places
+    /// the objects with a `Motion`.
+    pub time: f32,
+    /// Density, over solid angle, of the diffuse bounce that drew `direction`. Against it
+    /// the emissive spheres hit are weighted with their light samples under
+    /// `Scene::light_sampling`. 0 for every other ray, whose hits count in full.
+    pub pdf: f32,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -19,27 +37,65 @@ pub struct RayHit {
     pub material_index: usize,
     pub u: f32,
     pub v: f32,
+    /// Interpolated vertex color, for meshes that carry one.
+    pub color: Option<Vec3>,
+    /// Texture space units per world unit around the hit, 0 when `u, v` are not texture
+    /// coordinates.
+    pub uv_density: f32,
+    /// `Ray::time` of the ray that hit, set by `Scene::trace_ray` for the rays leaving
+    /// the hit.
+    pub time: f32,
+    /// Index in `Scene::objects` of the object hit, set by `Scene::trace_ray`.
+    pub object_index: usize,
 }
 
 impl Default for RayHit {
     fn default() -> Self {
         Self {
-            distance: f32::MIN,
+            distance: f32::MAX,
             point: Default::default(),
             normal: Default::default(),
             material_index: Default::default(),
             u: 0.,
             v: 0.,
+            color: None,
+            uv_density: 0.,
+            time: 0.,
+            object_index: 0,
         }
     }
 }
 
 impl Ray {
+    /// Point at `distance` along the ray. Every primitive places its hit point with this,
+    /// so `hit.point` and `hit.distance` always agree in world space.
+    pub fn at(&self, distance: f32) -> Vec3 {
+        self.origin + self.direction * distance
+    }
+
+    /// Width of the ray cone at `distance`.
+    pub fn width_at(&self, distance: f32) -> f32 {
+        self.width + self.spread * distance
+    }
+
+    /// Ray leaving the hit at `distance` from `origin` along `direction`. It keeps the cone
+    /// of this one, widening from the width reached at the hit.
+    pub fn bounce(&self, distance: f32, origin: Vec3, direction: Vec3) -> Ray {
+        Ray {
+            origin,
+            direction,
+            width: self.width_at(distance),
+            spread: self.spread,
+            time: self.time,
+            pdf: 0.,
+        }
+    }
+
     pub fn reflect(&self, normal: Vec3) -> Vec3 {
         self.direction - (2. * (self.direction.dot(normal))) * normal
     }
 
-    pub fn reflection_ray(&self, hit: RayHit, roughness: f32, rnd: &mut ThreadRng) -> Ray {
+    pub fn reflection_ray(&self, hit: RayHit, roughness: f32, rnd: &mut Sampler) -> Ray {
         let dir: Vec3;
         if roughness < 1. {
             dir = self
@@ -60,11 +116,45 @@ impl Ray {
                 rnd.gen_range(-1.0..1.0),
             );
 
-            dir = -(hit.normal + rnd).normalize();
+            dir = (hit.normal + rnd).normalize();
         }
-        Ray {
-            origin: hit.point + hit.normal * 0.0001,
-            direction: dir,
+        self.bounce(hit.distance, hit.point + hit.normal * 0.0001, dir)
+    }
+
+    /// Diffuse reflection off `hit`, drawn with the density `cos / π` of the angle to the
+    /// normal.
+    pub fn diffuse_ray(&self, hit: &RayHit, rnd: &mut Sampler) -> Ray {
+        let n = hit.normal;
+        let (r, angle) = (rnd.gen::<f32>().sqrt(), 2. * std::f32::consts::PI * rnd.gen::<f32>());
+        let (t, b) = n.any_orthonormal_pair();
+        let up = (1. - r * r).max(0.).sqrt();
+        let direction = (t * r * angle.cos() + b * r * angle.sin() + n * up).normalize();
+        self.bounce(hit.distance, hit.point + n * EPSILON, direction)
+    }
+
+    /// Microfacet normal around `normal` drawn from the GGX distribution of `roughness`,
+    /// kept on the same side as `normal` for the ray.
+    pub fn microfacet_normal(&self, normal: Vec3, roughness: f32, rnd: &mut Sampler) -> Vec3 {
+        if roughness <= 0. {
+            return normal;
+        }
+        let alpha = roughness * roughness;
+        let (u1, u2): (f32, f32) = (rnd.gen(), rnd.gen());
+        let theta = (alpha * (u1 / (1. - u1)).sqrt()).atan();
+        let phi = 2. * std::f32::consts::PI * u2;
+
+        let tangent = normal.any_orthonormal_vector();
+        let bitangent = normal.cross(tangent);
+        let m = (tangent * phi.cos() * theta.sin()
+            + bitangent * phi.sin() * theta.sin()
+            + normal * theta.cos())
+        .normalize();
+
+        // A facet the ray sees from the other side would flip refraction inside out.
+        if m.dot(self.direction).signum() == normal.dot(self.direction).signum() {
+            m
+        } else {
+            normal
         }
     }
 
@@ -91,66 +181,94 @@ impl Ray {
         let c2 = k.sqrt();
         let direction = eta * self.direction + normal * (eta * c1 - c2);
 
-        Some(Ray {
-            origin: hit.point + EPSILON * normal,
-            direction: direction,
-        })
+        Some(self.bounce(hit.distance, hit.point - EPSILON * normal, direction))
     }
 
-    fn moller_trumbore_intersection(
+    /// Watertight ray/triangle test (Woop, Benthin and Wald 2013): the vertices are moved
+    /// into a space where the ray runs along +z from the origin, so the edge functions of
+    /// neighbouring triangles agree exactly along a shared edge and no ray slips between
+    /// them. Edge functions that come out exactly zero are recomputed in f64, which keeps
+    /// hits on edges exact far from the origin as well. With `cull`, faces whose corners run
+    /// clockwise as seen from the ray are skipped.
+    fn triangle_intersection(
         &self,
         v1: Vec3,
         v2: Vec3,
         v3: Vec3,
         material_index: usize,
+        cull: bool,
     ) -> Option<RayHit> {
-        let e1 = v2 - v1;
-        let e2 = v3 - v1;
-        let ray_cross_e2 = self.direction.cross(e2);
-        let det = e1.dot(ray_cross_e2);
-
-        if det > -f32::EPSILON && det < f32::EPSILON {
-            return None; // This ray is parallel to this triangle.
-        }
-        let back_facing = det > f32::EPSILON;
-
-        let inv_det = 1.0 / det;
-        let s = self.origin - v1;
-        let u = inv_det * s.dot(ray_cross_e2);
-        if u < 0.0 || u > 1.0 {
+        let d = self.direction;
+        let face_normal = (v2 - v1).cross(v3 - v1);
+        let back_facing = d.dot(face_normal) > 0.;
+        if cull && back_facing {
             return None;
         }
 
-        let s_cross_e1 = s.cross(e1);
+        let m = d.abs();
+        let kz = if m.x >= m.y && m.x >= m.z {
+            0
+        } else if m.y >= m.z {
+            1
+        } else {
+            2
+        };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        if d[kz] < 0. {
+            // Keep the winding of the projected triangle.
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        let (sx, sy, sz) = (d[kx] / d[kz], d[ky] / d[kz], 1. / d[kz]);
+
+        let (a, b, c) = (v1 - self.origin, v2 - self.origin, v3 - self.origin);
+        let shear = |p: Vec3| (p[kx] - sx * p[kz], p[ky] - sy * p[kz]);
+        let ((ax, ay), (bx, by), (cx, cy)) = (shear(a), shear(b), shear(c));
+
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+
+        if u == 0. || v == 0. || w == 0. {
+            let edge = |px: f32, py: f32, qx: f32, qy: f32| {
+                (px as f64 * qy as f64 - py as f64 * qx as f64) as f32
+            };
+            u = edge(cx, cy, bx, by);
+            v = edge(ax, ay, cx, cy);
+            w = edge(bx, by, ax, ay);
+        }
 
-        let v = inv_det * self.direction.dot(s_cross_e1);
-        if v < 0.0 || u + v > 1.0 {
+        if (u < 0. || v < 0. || w < 0.) && (u > 0. || v > 0. || w > 0.) {
             return None;
         }
 
-        // At this stage we can compute t to find out where the intersection point is on the line.
-        let t = inv_det * e2.dot(s_cross_e1);
-
-        if t < f32::EPSILON {
-            let hit_point = self.origin + self.direction * t;
-
-            let mut normal = (v2 - v1).cross(v3 - v1).normalize();
-            if back_facing {
-                normal = -normal;
-            }
+        let det = u + v + w;
+        if det == 0. {
+            return None; // This ray is parallel to this triangle.
+        }
 
-            return Some(RayHit {
-                distance: t,
-                point: hit_point,
-                normal,
-                material_index,
-                u,
-                v,
-            });
-        } else {
+        let t = (u * sz * a[kz] + v * sz * b[kz] + w * sz * c[kz]) / det;
+        if t <= EPSILON {
             // This means that there is a line intersection but not a ray intersection.
             return None;
         }
+
+        let mut normal = face_normal.normalize();
+        if back_facing {
+            normal = -normal;
+        }
+
+        Some(RayHit {
+            distance: t,
+            point: self.at(t),
+            normal,
+            material_index,
+            // Barycentric weights of v2 and v3.
+            u: v / det,
+            v: w / det,
+            color: None,
+            ..Default::default()
+        })
     }
 
     pub fn hit(&self, obj: &Object3D) -> Option<RayHit> {
@@ -166,10 +284,129 @@ impl Ray {
                 v2,
                 v3,
                 material_index,
-            } => self.moller_trumbore_intersection(*v1, *v2, *v3, *material_index),
+            } => self.triangle_intersection(*v1, *v2, *v3, *material_index, false),
+
+            Object3D::Mesh {
+                mesh,
+                material_index,
+            } => self.mesh_intersection(mesh, *material_index),
+
+            Object3D::PointCloud {
+                cloud,
+                material_index,
+            } => self.point_cloud_intersection(cloud, *material_index),
+
+            Object3D::Terrain {
+                terrain,
+                material_index,
+            } => terrain.intersect(self, *material_index),
+
+            Object3D::Custom(primitive) => primitive.intersect(self),
         }
     }
 
+    fn point_cloud_intersection(
+        &self,
+        cloud: &PointCloud,
+        material_index: usize,
+    ) -> Option<RayHit> {
+        let mut closest: Option<(usize, RayHit)> = None;
+
+        cloud.bvh.traverse(self, f32::MAX, |i, closest_t| {
+            let point = &cloud.points[i];
+            if let Some(hit) = self.sphere_intersection(point, &cloud.radius, material_index) {
+                if hit.distance > EPSILON && hit.distance < *closest_t {
+                    *closest_t = hit.distance;
+                    closest = Some((i, hit));
+                }
+            }
+            true
+        });
+
+        closest.map(|(i, mut hit)| {
+            hit.color = cloud.colors.get(i).copied();
+            hit
+        })
+    }
+
+    fn mesh_intersection(&self, mesh: &Mesh, material_index: usize) -> Option<RayHit> {
+        let mut closest: Option<(usize, RayHit)> = None;
+
+        for face in 0..mesh.indices.len() {
+            let (v1, v2, v3) = mesh.triangle(face);
+            if let Some(hit) = self.triangle_intersection(v1, v2, v3, material_index, mesh.cull_backfaces) {
+                if closest.is_none_or(|(_, c)| hit.distance < c.distance) {
+                    closest = Some((face, hit));
+                }
+            }
+        }
+
+        closest.map(|(face, mut hit)| {
+            if let Some(normal) = mesh.normal(face, hit.u, hit.v) {
+                // Keep the shading normal on the side the ray comes from.
+                hit.normal = if normal.dot(hit.normal) < 0. {
+                    -normal
+                } else {
+                    normal
+                };
+            }
+            hit.color = mesh.color(face, hit.u, hit.v);
+            // Textures are looked up at (u, v), so meshes with UVs report those instead of
+            // the barycentric coordinates.
+            if let Some(uv) = mesh.uv(face, hit.u, hit.v) {
+                (hit.u, hit.v) = (uv.x, uv.y);
+                hit.uv_density = mesh.uv_density(face);
+            }
+            hit
+        })
+    }
+
+    /// Intersects all the spheres of a pack at once and returns the closest one whose
+    /// distance lies in `(EPSILON, t_max)`.
+    pub fn hit_sphere_pack(&self, pack: &SpherePack, t_max: f32) -> Option<RayHit> {
+        let a = self.direction.dot(self.direction);
+        let mut distances = [f32::MAX; SPHERE_LANES];
+
+        for (i, distance) in distances.iter_mut().enumerate() {
+            let ox = self.origin.x - pack.x[i];
+            let oy = self.origin.y - pack.y[i];
+            let oz = self.origin.z - pack.z[i];
+
+            let b = 2. * (ox * self.direction.x + oy * self.direction.y + oz * self.direction.z);
+            let c = ox * ox + oy * oy + oz * oz - pack.radius[i] * pack.radius[i];
+            let disc = b * b - 4. * a * c;
+
+            let sqrt_disc = disc.max(0.).sqrt();
+            let t1 = (-b - sqrt_disc) / (2.0 * a);
+            let t0 = (-b + sqrt_disc) / (2.0 * a);
+            let t = if t1 > EPSILON { t1 } else { t0 };
+            *distance = if disc >= 0. && t > EPSILON { t } else { f32::MAX };
+        }
+
+        let mut closest: Option<usize> = None;
+        let mut closest_t = t_max;
+        for (i, t) in distances.iter().enumerate().take(pack.len) {
+            if *t < closest_t {
+                closest = Some(i);
+                closest_t = *t;
+            }
+        }
+
+        closest.map(|i| {
+            let position = Vec3::new(pack.x[i], pack.y[i], pack.z[i]);
+            let hit_point = self.at(closest_t);
+
+            RayHit {
+                distance: closest_t,
+                point: hit_point,
+                normal: (hit_point - position).normalize(),
+                material_index: pack.material_index[i],
+                object_index: pack.object_index[i],
+                ..Default::default()
+            }
+        })
+    }
+
     fn sphere_intersection(
         &self,
         position: &Vec3,
@@ -195,14 +432,17 @@ impl Ray {
             return None;
         }
 
-        // closest to ray origin
+        // closest to ray origin, or the exit point when starting inside the sphere
         let t0 = (-b + disc.sqrt()) / (2.0 * a);
         let t1 = (-b - disc.sqrt()) / (2.0 * a);
-        
-        let t = t1;
-        
 
-        let hit_point = self.origin + self.direction * t;
+        let t = if t1 > EPSILON { t1 } else { t0 };
+        if t <= EPSILON {
+            // Both hits are behind the ray origin.
+            return None;
+        }
+
+        let hit_point = self.at(t);
 
         let normal = (hit_point - *position).normalize();
 
@@ -215,3 +455,133 @@ impl Ray {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Affine3A, Vec3};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::objects::{Cuboid, SphereBatch};
+    use crate::utils::terrain::Terrain;
+
+    fn ray(origin: Vec3, direction: Vec3) -> Ray {
+        Ray {
+            origin,
+            direction,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn scaled_spheres_give_world_distances() {
+        let transform =
+            Affine3A::from_translation(vec3(0., 0., -10.)) * Affine3A::from_scale(Vec3::splat(3.));
+        let sphere = Object3D::new_sphere(Vec3::ZERO, 1., 0).transformed(&transform);
+        let hit = ray(Vec3::ZERO, Vec3::NEG_Z).hit(&sphere).unwrap();
+        assert!((hit.distance - 7.).abs() < 1e-4, "{}", hit.distance);
+    }
+
+    #[test]
+    fn non_uniform_scales_use_the_largest_axis() {
+        let transform = Affine3A::from_scale(vec3(1., 4., 2.));
+        let sphere = Object3D::new_sphere(Vec3::ZERO, 1., 0).transformed(&transform);
+        let hit = ray(vec3(0., 0., 10.), Vec3::NEG_Z).hit(&sphere).unwrap();
+        assert!((hit.distance - 6.).abs() < 1e-4, "{}", hit.distance);
+    }
+
+    #[test]
+    fn spheres_behind_the_ray_are_missed() {
+        let sphere = Object3D::new_sphere(vec3(0., 0., 5.), 1., 0);
+        assert!(ray(Vec3::ZERO, Vec3::NEG_Z).hit(&sphere).is_none());
+        assert!(ray(vec3(0., 0., 6.), Vec3::Z).hit(&sphere).is_none());
+    }
+
+    fn random_point(rnd: &mut StdRng, size: f32) -> Vec3 {
+        (vec3(rnd.gen(), rnd.gen(), rnd.gen()) - 0.5) * size
+    }
+
+    /// Casts rays from random points up to 10 away from `(0, lift, 0)` towards the
+    /// middle of the scene, and checks that every hit lies at its distance along the ray.
+    fn check_hits(lift: f32, hit: impl Fn(&Ray) -> Option<RayHit>) {
+        let mut rnd = StdRng::seed_from_u64(7);
+        let mut hits = 0;
+        for _ in 0..500 {
+            let origin = random_point(&mut rnd, 20.) + Vec3::Y * lift;
+            let target = random_point(&mut rnd, 4.);
+            let ray = ray(origin, (target - origin).normalize());
+            if let Some(hit) = hit(&ray) {
+                assert_eq!(hit.point, ray.origin + ray.direction * hit.distance);
+                assert!(hit.distance > EPSILON);
+                hits += 1;
+            }
+        }
+        assert!(hits > 100, "{} hits", hits);
+    }
+
+    #[test]
+    fn sphere_hits_lie_at_their_distance() {
+        let sphere = Object3D::new_sphere(vec3(0.5, 0., -0.5), 1.5, 0);
+        check_hits(0., |r| r.hit(&sphere));
+    }
+
+    #[test]
+    fn hits_from_inside_spheres_lie_at_their_distance() {
+        let (center, radius) = (vec3(0.5, 0., -0.5), 3.);
+        let sphere = Object3D::new_sphere(center, radius, 0);
+        let mut rnd = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let origin = center + random_point(&mut rnd, 2.);
+            let direction = random_point(&mut rnd, 2.).normalize();
+            let ray = ray(origin, direction);
+            let hit = ray.hit(&sphere).unwrap();
+            assert_eq!(hit.point, ray.origin + ray.direction * hit.distance);
+            assert!((hit.point.distance(center) - radius).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn sphere_pack_hits_lie_at_their_distance() {
+        let spheres: Vec<Object3D> = (0..SPHERE_LANES)
+            .map(|i| Object3D::new_sphere(vec3(i as f32 - 2., 0., 0.), 0.7, i))
+            .collect();
+        let pack = SphereBatch::new(&spheres).packs[0];
+        check_hits(0., |r| r.hit_sphere_pack(&pack, f32::MAX));
+    }
+
+    #[test]
+    fn triangle_hits_lie_at_their_distance() {
+        let (v1, v2, v3) = (vec3(-2., -2., 0.), vec3(2., -1., 1.), vec3(0., 2., -1.));
+        let triangle = Object3D::new_triangle(v1, v2, v3, 0);
+        check_hits(0., |r| r.hit(&triangle));
+    }
+
+    #[test]
+    fn mesh_hits_lie_at_their_distance() {
+        let cuboid = Cuboid::build().size(vec3(3., 2., 1.)).build();
+        let mut positions = vec![];
+        for triangle in cuboid.triangles(0) {
+            if let Object3D::Triangle { v1, v2, v3, .. } = triangle {
+                positions.extend([v1, v2, v3]);
+            }
+        }
+        let indices = (0..positions.len() / 3).map(|i| [3 * i, 3 * i + 1, 3 * i + 2]).collect();
+        let mesh = Object3D::new_mesh(Mesh::new(positions, indices), 0);
+        check_hits(0., |r| r.hit(&mesh));
+    }
+
+    #[test]
+    fn point_cloud_hits_lie_at_their_distance() {
+        let mut rnd = StdRng::seed_from_u64(3);
+        let points = (0..200).map(|_| random_point(&mut rnd, 4.)).collect();
+        let cloud = Object3D::new_point_cloud(PointCloud::new(points, 0.3), 0);
+        check_hits(0., |r| r.hit(&cloud));
+    }
+
+    #[test]
+    fn terrain_hits_lie_at_their_distance() {
+        let terrain = Object3D::new_terrain(Terrain::default(), 0);
+        check_hits(30., |r| r.hit(&terrain));
+    }
+}
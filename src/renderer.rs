@@ -1,61 +1,348 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
 use std::sync::Arc;
 
-use glam::Vec4;
-use rand::rngs::ThreadRng;
+use glam::{vec2, BVec3, Vec2, Vec3, Vec4};
+use rand::{Rng, SeedableRng};
+#[cfg(feature = "window")]
 use sdl2::render::Texture;
 
-use crate::{camera::Camera, ray::Ray, scene::Scene};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use crate::{
+    accel::{Aabb, Accel},
+    aov::{Aov, AovPixel, AovSample},
+    camera::Camera,
+    changes::Changes,
+    denoise::Denoiser,
+    filter::Filter,
+    integrator::Integrator,
+    job::{Frame, RenderJob},
+    post::{self, AutoExposure, DisplayMode, Flare, Grade, ToneMap},
+    ray::{Ray, Sampler},
+    scene::{PixelCache, Scene},
+    utils::{
+        blue_noise::blue_noise,
+        errors::AppError,
+        image::ImageUtils,
+        time::{Duration, Instant},
+    },
+};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use rayon::slice::ParallelSliceMut;
 
 
+/// Averages an accumulated colour, which keeps the sum of its sample weights in `w`.
+fn resolve(c: Vec4) -> Vec4 {
+    if c.w > 0. {
+        c / c.w
+    } else {
+        Vec4::ZERO
+    }
+}
+
+/// Shown for pixels with a NaN or infinite colour under `RenderSettings::nan_check`.
+const NAN_COLOR: (u8, u8, u8, u8) = (255, 0, 255, 255);
+
+/// Rows of the bands a frame is cut into under `RenderSettings::frame_budget` or
+/// `Renderer::target_noise`.
+const TILE_ROWS: usize = 8;
+
+/// Frames traced everywhere before `Renderer::target_noise` skips any tile, so the pixel
+/// variances are meaningful.
+const ADAPTIVE_MIN_FRAMES: u32 = 4;
+
+/// Most samples per pixel a noisy tile gets in one frame.
+const MAX_TILE_SAMPLES: u32 = 4;
+
+/// Most sample weight a pixel keeps when `RenderSettings::reproject` moves it, so the
+/// samples of the new view soon outweigh it.
+const REPROJECTED_WEIGHT: f32 = 4.;
+
+/// Keeps the relative noise of near black pixels finite.
+const NOISE_LUMINANCE_FLOOR: f32 = 1e-3;
+
 #[derive(Debug, Copy, Clone)]
 struct Chunk {
     size: usize,
     pixel_offset: usize,
 }
 
+/// Options controlling how the renderer traces a frame.
+#[derive(Debug, Clone, Default)]
+pub struct RenderSettings {
+    /// Trace each chunk breadth-first through `Scene::pixels_wavefront` instead of
+    /// recursing pixel by pixel.
+    pub wavefront: bool,
+    /// Acceleration structure built over the scene objects.
+    pub accel: Accel,
+    /// Lens flare added to the whole frame after it is accumulated.
+    pub flare: Option<Flare>,
+    /// Adapts `Renderer::exposure` to the frame luminance.
+    pub auto_exposure: Option<AutoExposure>,
+    /// Curve compressing the exposed colours of the whole frame for display, before
+    /// grading.
+    pub tone_map: ToneMap,
+    /// White balance, lift/gamma/gain and LUT applied to the displayed colours.
+    pub grade: Option<Grade>,
+    pub display: DisplayMode,
+    /// Jitters the primary rays over the filter footprint and weights the samples by the
+    /// filter, for anti-aliasing. Samples go through the pixel positions when unset.
+    pub filter: Option<Filter>,
+    /// Stops tracing a frame once this much time has passed and shows what is done,
+    /// finishing the frame on the next calls. Keeps the window responsive in slow scenes.
+    pub frame_budget: Option<Duration>,
+    /// Fixed image size, letterboxed into the window. The image follows the window size
+    /// when unset.
+    pub resolution: Option<(usize, usize)>,
+    /// Paints pixels whose colour turns NaN or infinite magenta, and logs the ray and the
+    /// surface it first hit.
+    pub nan_check: bool,
+    /// Draws the filter jitter, the lens samples and the shutter times from a blue-noise
+    /// mask instead of white noise, so frames of few samples look smoother.
+    pub blue_noise: bool,
+    /// Keeps the accumulation through camera moves by reprojecting it, instead of
+    /// restarting it. Reflections and newly visible parts take a few frames to settle.
+    pub reproject: bool,
+    /// Restarts only the tiles covered by edited objects. Their shadows and reflections
+    /// elsewhere take a while to update.
+    pub dirty_tiles: bool,
+    /// Samples traced for every pixel each frame, jittered over the pixel, so edges are
+    /// smooth from the first frame. The samples go through `filter` when set, else
+    /// through a box filter. One sample through the pixel position when 0 or 1.
+    pub samples_per_pixel: u32,
+    /// Accumulates the auxiliary outputs of every pixel into `Renderer::aovs`, for
+    /// `Renderer::aov`. Costs a ray per sample when the primary hits are not cached.
+    pub aovs: bool,
+    /// Smooths the displayed frame with the albedo and normals of the first hits as
+    /// guides, accumulated as under `aovs`, so diffuse scenes look clean after a handful
+    /// of frames. The accumulated colours stay as traced.
+    pub denoise: Option<Denoiser>,
+    /// Light transport tracing the samples instead of the scene's own path tracer.
+    /// `wavefront` and the cache of the primary hits only apply to the latter.
+    pub integrator: Option<Arc<dyn Integrator>>,
+}
+
+impl RenderSettings {
+    /// Filter jittering and weighting the primary rays: `filter`, else a box filter when
+    /// more than one sample per pixel is traced.
+    pub fn jitter_filter(&self) -> Option<Filter> {
+        self.filter
+            .or((self.samples_per_pixel > 1).then_some(Filter::Box))
+    }
+}
+
 pub struct Renderer {
     pub scene: Arc<Scene>,
+    pub settings: RenderSettings,
     pub accumulated: Vec<Vec4>,
+    /// Weighted sum of the squared luminance of each pixel samples, for the noise.
+    pub luminance_squares: Vec<f32>,
+    /// First hits of each pixel samples, accumulated under `RenderSettings::aovs` or
+    /// `RenderSettings::denoise` only.
+    pub aovs: Vec<AovPixel>,
+    pub pixel_cache: Vec<PixelCache>,
     pub frame_index: u32,
+    /// Scale applied to the accumulated colours before display.
+    pub exposure: f32,
+    /// Relative noise, as given by `noise`, under which a pixel is converged. The frame is
+    /// then cut in tiles: converged tiles are no longer traced, and the noisiest get up to
+    /// `MAX_TILE_SAMPLES` samples per frame. Every tile is traced once per frame when unset.
+    pub target_noise: Option<f32>,
+    /// First tile of the current frame still to trace under `RenderSettings::frame_budget`.
+    next_tile: usize,
+    /// Seed of the random numbers of every chunk, set by `with_seed`.
+    seed: Option<u64>,
 }
 
 impl Renderer {
     pub fn new(scene: Scene) -> Renderer {
+        Renderer::new_with_settings(scene, RenderSettings::default())
+    }
+
+    pub fn new_with_settings(mut scene: Scene, settings: RenderSettings) -> Renderer {
+        scene.compile(settings.accel);
         Renderer{
             scene: Arc::new(scene),
+            settings,
             accumulated: vec![],
+            luminance_squares: vec![],
+            aovs: vec![],
+            pixel_cache: vec![],
             frame_index: 1,
+            exposure: 1.,
+            target_noise: None,
+            next_tile: 0,
+            seed: None,
+        }
+    }
+
+    /// Draws the random numbers of each chunk from `seed`, the frame and the chunk
+    /// position instead of fresh entropy, so the same scene, settings, image size and
+    /// number of chunks render the same image every time.
+    pub fn with_seed(mut self, seed: u64) -> Renderer {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Random number generator for the chunk starting at pixel `offset` of `frame`.
+    fn sampler(&self, frame: u32, offset: usize) -> Sampler {
+        match self.seed {
+            Some(seed) => {
+                let mut hasher = DefaultHasher::new();
+                (seed, frame, offset).hash(&mut hasher);
+                Sampler::seed_from_u64(hasher.finish())
+            }
+            None => Sampler::seed_from_u64(rand::random()),
         }
     }
+
+    /// Renders `samples` frames of the scene at `width` x `height` without a window, as
+    /// `RenderJob` does. `Frame::save` writes the result as PNG or EXR.
+    pub fn render_to_image(
+        scene: Scene,
+        camera: Camera,
+        width: usize,
+        height: usize,
+        samples: u32,
+    ) -> Result<Frame, AppError> {
+        RenderJob::new(scene, camera)
+            .resolution(width, height)
+            .spp(samples)
+            .render()
+    }
+
+    /// Lets `update` edit the scene and recompiles it when `update` reports a change.
+    /// Returns what the edit changed, found by `Scene::changes`, so a reported change that
+    /// leaves the scene as it was does not disturb the accumulation.
+    pub fn update_scene(&mut self, update: impl FnOnce(&mut Scene) -> bool) -> Changes {
+        let scene = Arc::make_mut(&mut self.scene);
+        if !update(scene) {
+            return Changes::default();
+        }
+        let changes = scene.changes();
+        if !changes.is_empty() {
+            scene.compile(self.settings.accel);
+        }
+        changes
+    }
+
+    /// Traces sample `sample` of the current frame for every pixel of `chunk`.
     fn render_chunk(
         &mut self, 
         camera: &Camera,
-        rnd: &mut ThreadRng,
+        rnd: &mut Sampler,
         chunk: Chunk,
+        sample: u32,
         bytes: &mut [u8],
     ) {
         let mut i = 0;
 
-        for pos in 0..chunk.size {
-            let ray_dir = camera.ray_directions[pos + chunk.pixel_offset];
+        // Sample in `[0, 1)` of a dimension for the pixel at `pos` in the chunk.
+        let blue = self.settings.blue_noise;
+        let per_frame = MAX_TILE_SAMPLES * self.settings.samples_per_pixel.max(1);
+        let frame = self.frame_index * per_frame + sample;
+        let mut sample = |pos: usize, dimension: u32| -> f32 {
+            if blue {
+                let pixel = chunk.pixel_offset + pos;
+                blue_noise(pixel % camera.width, pixel / camera.width, frame, dimension)
+            } else {
+                rnd.gen()
+            }
+        };
 
-            let vcolor = self.scene.pixel(
-                Ray {
-                    origin: camera.position,
-                    direction: ray_dir,
-                },
-                rnd,
-            );
+        let (rays, weights): (Vec<Ray>, Vec<f32>) = match self.settings.jitter_filter() {
+            Some(filter) => {
+                let r = filter.radius();
+                let offsets: Vec<Vec2> = (0..chunk.size)
+                    .map(|pos| (vec2(sample(pos, 0), sample(pos, 1)) * 2. - 1.) * r)
+                    .collect();
+                (
+                    camera.rays_offset(chunk.pixel_offset, &offsets),
+                    offsets.iter().map(|o| filter.weight(o.x, o.y)).collect(),
+                )
+            }
+            None => (camera.rays(chunk.pixel_offset, chunk.size), vec![1.; chunk.size]),
+        };
+        // Rays the lens barrel blocks are not traced, and count as black.
+        let rays: Vec<Option<Ray>> = if camera.aperture > 0. {
+            rays.into_iter()
+                .enumerate()
+                .map(|(pos, ray)| camera.lens_ray(ray, vec2(sample(pos, 2), sample(pos, 3))))
+                .collect()
+        } else {
+            rays.into_iter().map(Some).collect()
+        };
+        let rays: Vec<Option<Ray>> = if camera.motion_blur() {
+            let (open, close) = (camera.shutter.x, camera.shutter.y);
+            rays.into_iter()
+                .enumerate()
+                .map(|(pos, ray)| {
+                    let time = open + (close - open) * sample(pos, 4);
+                    ray.map(|ray| Ray { time, ..ray })
+                })
+                .collect()
+        } else {
+            rays
+        };
+
+        let colors: Vec<Vec4> = if let Some(integrator) = &self.settings.integrator {
+            rays.iter()
+                .map(|ray| match ray {
+                    Some(ray) => integrator.radiance(&self.scene, *ray, rnd).extend(1.),
+                    None => Vec4::W,
+                })
+                .collect()
+        } else if self.settings.wavefront {
+            self.scene
+                .pixels_wavefront(&rays, rnd, &mut self.pixel_cache)
+        } else {
+            rays.iter()
+                .enumerate()
+                .map(|(pos, ray)| match ray {
+                    Some(ray) => self.scene.pixel(*ray, rnd, &mut self.pixel_cache[pos]),
+                    None => Vec4::W,
+                })
+                .collect()
+        };
 
-            self.accumulated[pos] += vcolor;
+        for (pos, vcolor) in colors.into_iter().enumerate() {
+            let pixel = chunk.pixel_offset + pos;
+            let finite = self.accumulated[pos].is_finite();
+            self.accumulated[pos] += vcolor * weights[pos];
+            let luminance = post::luminance(vcolor.truncate());
+            self.luminance_squares[pos] += luminance * luminance * weights[pos];
+            if self.settings.aovs || self.settings.denoise.is_some() {
+                let sample = rays[pos].and_then(|ray| {
+                    let hit = match self.pixel_cache[pos].primary_hit {
+                        Some(hit) => hit,
+                        None => self.scene.trace_ray(ray, f32::MAX),
+                    };
+                    hit.map(|hit| AovSample {
+                        albedo: self.scene.surface_color(&ray, &hit),
+                        normal: hit.normal,
+                        depth: camera.depth(&ray, hit.distance),
+                        material_index: hit.material_index,
+                        object_index: hit.object_index,
+                    })
+                });
+                self.aovs[pos].add(sample, weights[pos]);
+            }
 
-            let mut accumulated = self.accumulated[pos];
-            accumulated /= self.frame_index as f32;
+            if self.settings.nan_check && finite && !vcolor.is_finite() {
+                if let Some(ray) = rays[pos] {
+                    self.report_non_finite(camera, pixel, ray, vcolor);
+                }
+            }
+
+            let mut accumulated = resolve(self.accumulated[pos]);
 
             accumulated = accumulated.clamp(Vec4::ZERO, Vec4::ONE);
 
-            let color = Scene::to_rgba(accumulated);
+            let color = if self.settings.nan_check && !self.accumulated[pos].is_finite() {
+                NAN_COLOR
+            } else {
+                Scene::to_rgba_dithered(accumulated, pixel % camera.width, pixel / camera.width)
+            };
             bytes[i] = color.0;
             bytes[i + 1] = color.1;
             bytes[i + 2] = color.2;
@@ -65,74 +352,591 @@ impl Renderer {
         }
     }
 
+    /// Logs the first non-finite sample of a pixel with what its primary ray hit.
+    fn report_non_finite(&self, camera: &Camera, pixel: usize, ray: Ray, color: Vec4) {
+        let (x, y) = (pixel % camera.width, pixel / camera.width);
+        match self.scene.trace_ray(ray, f32::MAX) {
+            Some(hit) => log::warn!(
+                "Non-finite colour {} at pixel ({}, {}): ray {:?} hit material {} ({:?}) at {} \
+                 with normal {} of length {}",
+                color,
+                x,
+                y,
+                ray,
+                hit.material_index,
+                self.scene.materials.get(hit.material_index).map(|m| m.kind),
+                hit.point,
+                hit.normal,
+                hit.normal.length()
+            ),
+            None => log::warn!(
+                "Non-finite colour {} at pixel ({}, {}): ray {:?} hit nothing",
+                color,
+                x,
+                y,
+                ray
+            ),
+        }
+    }
+
+    #[cfg(feature = "window")]
     pub fn render_par(
         &mut self,
         texture: &mut Texture,
         img: &mut Vec<u8>,
         camera: &Camera,
-        updated: bool,
+        changes: &Changes,
         num_chunks: usize
     ) -> Result<(), String> {
+        if self.render_frame(img, camera, changes, num_chunks) {
+            texture
+                .update(None, img.as_slice(), camera.width * 4)
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    /// Traces one more frame into the accumulation over `num_chunks` threads and writes the
+    /// RGBA bytes to `img`, or only part of it under `RenderSettings::frame_budget`.
+    /// `changes` throws away the part of the accumulation they invalidate, as
+    /// `apply_changes` does. Returns false without rendering once
+    /// `Scene::max_frames_rendering` frames are accumulated, or once every tile is under
+    /// `target_noise`.
+    pub fn render_frame(
+        &mut self,
+        img: &mut [u8],
+        camera: &Camera,
+        changes: &Changes,
+        num_chunks: usize,
+    ) -> bool {
         let w = camera.width;
         let h = camera.height;
 
-        if updated {
-            self.accumulated = vec![Vec4::ZERO; w * h];
+        self.apply_changes(camera, changes);
+
+        if self.frame_index > self.scene.max_frames_rendering {
+            return false;
+        }
+        let start = Instant::now();
+
+        // Whole frames are split evenly over the threads. Under a time budget the frame is
+        // cut in thin bands traced a batch at a time, so tracing can stop between batches,
+        // and for adaptive sampling so each band gets the samples its noise calls for.
+        let pixels = w * h;
+        let tile_size = match (self.settings.frame_budget, self.target_noise) {
+            (None, None) => pixels.div_ceil(num_chunks),
+            _ => w * TILE_ROWS,
+        }
+        .clamp(1, pixels.max(1));
+        let tile_count = pixels.div_ceil(tile_size);
+        let samples = self.tile_samples(tile_size, tile_count);
+        if self.next_tile == 0 && samples.iter().all(|s| *s == 0) {
+            return false;
+        }
+
+        let mut tiles = img
+            .chunks_mut(tile_size * 4)
+            .enumerate()
+            .map(|(i, bytes)| (i * tile_size, samples[i], bytes))
+            .skip(self.next_tile);
+
+        loop {
+            let batch: Vec<(usize, u32, &mut [u8])> = tiles.by_ref().take(num_chunks).collect();
+            if batch.is_empty() {
+                break;
+            }
+            self.next_tile += batch.len();
+            self.trace_tiles(camera, batch.into_iter().filter(|t| t.1 > 0).collect());
+
+            if self
+                .settings
+                .frame_budget
+                .is_some_and(|budget| start.elapsed() >= budget)
+            {
+                break;
+            }
+        }
+
+        if self.next_tile < tile_count {
+            return true;
+        }
+        self.next_tile = 0;
+
+        let settings = &self.settings;
+        if settings.flare.is_some()
+            || settings.auto_exposure.is_some()
+            || settings.grade.is_some()
+            || settings.denoise.is_some()
+            || settings.tone_map != ToneMap::Linear
+            || settings.display != DisplayMode::Beauty
+            || self.exposure != 1.
+        {
+            self.post_process(img, w, h);
+        }
+
+        log::trace!("Frame {} rendered in {:?}", self.frame_index, start.elapsed());
+        self.frame_index += 1;
+
+        true
+    }
+
+    /// Throws away the part of the accumulation `changes` invalidate. Camera moves are
+    /// reprojected under `RenderSettings::reproject`, and edited objects only restart the
+    /// tiles they cover under `RenderSettings::dirty_tiles`. Anything else, or a new image
+    /// size, restarts the whole accumulation.
+    pub fn apply_changes(&mut self, camera: &Camera, changes: &Changes) {
+        let pixels = camera.width * camera.height;
+        let resized = self.accumulated.len() != pixels;
+        if changes.is_empty() && !resized {
+            return;
+        }
+        if resized
+            || changes.settings
+            || !changes.materials.is_empty()
+            || (changes.camera && !self.settings.reproject)
+            || (!changes.objects.is_empty() && !self.settings.dirty_tiles)
+        {
+            self.accumulated = vec![Vec4::ZERO; pixels];
+            self.luminance_squares = vec![0.; pixels];
+            self.aovs = vec![AovPixel::default(); pixels];
+            self.pixel_cache = vec![PixelCache::default(); pixels];
             self.frame_index = 1;
+            self.next_tile = 0;
+            return;
         }
 
-        if self.frame_index > self.scene.max_frames_rendering {
-            return Ok(())
+        if changes.camera {
+            self.reproject(camera);
         }
+        for (_, bounds) in changes.objects.iter() {
+            self.reset_tiles(camera, bounds);
+        }
+    }
 
-        let img_len = img.len();
-        let img_chunk_size = (img_len / (num_chunks * 4)) * 4;
+    /// Moves the accumulated colours to where their primary hits land in the moved
+    /// `camera`, keeping at most `REPROJECTED_WEIGHT` of sample weight so they fade under
+    /// the new samples. Pixels that saw the sky or something newly in view start empty.
+    fn reproject(&mut self, camera: &Camera) {
+        let (w, h) = (camera.width, camera.height);
+        let mut accumulated = vec![Vec4::ZERO; w * h];
+        let mut squares = vec![0.; w * h];
+        for (pixel, cache) in self.pixel_cache.iter().enumerate() {
+            let Some(Some(hit)) = cache.primary_hit else {
+                continue;
+            };
+            let Some(p) = camera.project(hit.point) else {
+                continue;
+            };
+            if p.x < 0. || p.y < 0. || p.x >= w as f32 || p.y >= h as f32 {
+                continue;
+            }
+            let c = self.accumulated[pixel];
+            if c.w <= 0. {
+                continue;
+            }
+            let scale = (REPROJECTED_WEIGHT / c.w).min(1.);
+            let target = p.y as usize * w + p.x as usize;
+            accumulated[target] = c * scale;
+            squares[target] = self.luminance_squares[pixel] * scale;
+        }
 
-        let chunks: Vec<(usize, &mut [u8])> = img.chunks_mut(img_chunk_size).enumerate().collect();
+        self.accumulated = accumulated;
+        self.luminance_squares = squares;
+        self.aovs = vec![AovPixel::default(); w * h];
+        self.pixel_cache = vec![PixelCache::default(); w * h];
+        self.frame_index = 1;
+        self.next_tile = 0;
+    }
 
-        let col: Vec<Renderer> = chunks
-            .into_par_iter()
-            .map(|e| {
-                let mut rnd = rand::thread_rng();
-                let buf_len = e.1.len();
+    /// Restarts the accumulation of the tiles of `TILE_ROWS` rows that the world `bounds`
+    /// cover on screen, or of the whole image when they reach behind the camera.
+    fn reset_tiles(&mut self, camera: &Camera, bounds: &Aabb) {
+        let (w, h) = (camera.width, camera.height);
+        let mut rows = (h, 0);
+        for corner in 0..8 {
+            let point = Vec3::select(
+                BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0),
+                bounds.max,
+                bounds.min,
+            );
+            let Some(p) = camera.project(point) else {
+                rows = (0, h);
+                break;
+            };
+            let y = p.y.clamp(0., h as f32) as usize;
+            rows = (rows.0.min(y), rows.1.max(y + 1));
+        }
+        let first = rows.0 / TILE_ROWS * TILE_ROWS;
+        let last = rows.1.div_ceil(TILE_ROWS).saturating_mul(TILE_ROWS).min(h);
+        if first >= last {
+            return;
+        }
 
-                let acc_size = buf_len / 4;
+        let pixels = first * w..last * w;
+        self.accumulated[pixels.clone()].fill(Vec4::ZERO);
+        self.luminance_squares[pixels.clone()].fill(0.);
+        self.aovs[pixels.clone()].fill(AovPixel::default());
+        self.pixel_cache[pixels].fill(PixelCache::default());
+    }
 
-                let offset = e.0 * acc_size;
+    /// Samples per pixel each tile of `tile_size` pixels gets this frame: 1 everywhere
+    /// without `target_noise`, otherwise none for converged tiles and more the noisier
+    /// they are.
+    fn tile_samples(&self, tile_size: usize, tile_count: usize) -> Vec<u32> {
+        let target = match self.target_noise {
+            Some(target) if self.frame_index > ADAPTIVE_MIN_FRAMES => target,
+            _ => return vec![1; tile_count],
+        };
+        (0..tile_count)
+            .map(|tile| {
+                let end = ((tile + 1) * tile_size).min(self.accumulated.len());
+                let pixels = tile * tile_size..end;
+                let count = pixels.len().max(1) as f32;
+                let noise = pixels.map(|p| self.noise(p)).sum::<f32>() / count;
+                if noise <= target {
+                    0
+                } else {
+                    ((noise / target.max(f32::EPSILON)).ceil() as u32).min(MAX_TILE_SAMPLES)
+                }
+            })
+            .collect()
+    }
+
+    /// Standard error of the mean luminance of a pixel, relative to that luminance. Falls
+    /// as the pixel converges.
+    pub fn noise(&self, pixel: usize) -> f32 {
+        let c = self.accumulated[pixel];
+        if c.w <= 0. {
+            return f32::INFINITY;
+        }
+        let mean = post::luminance(c.truncate() / c.w);
+        let variance = (self.luminance_squares[pixel] / c.w - mean * mean).max(0.);
+        (variance / c.w).sqrt() / mean.abs().max(NOISE_LUMINANCE_FLOOR)
+    }
+
+    /// Whether primary rays stay the same every frame, so their hits can be cached.
+    fn reuses_primary_hits(settings: &RenderSettings, camera: &Camera) -> bool {
+        settings.jitter_filter().is_none() && camera.aperture <= 0. && !camera.motion_blur()
+    }
+
+    /// Traces the pixels of every `(first pixel, samples, RGBA bytes)` tile in parallel.
+    fn trace_tiles(&mut self, camera: &Camera, tiles: Vec<(usize, u32, &mut [u8])>) {
+        let col: Vec<(usize, Renderer)> = tiles
+            .into_par_iter()
+            .map(|(offset, samples, bytes)| {
+                let mut rnd = self.sampler(self.frame_index, offset);
+                let acc_size = bytes.len() / 4;
 
                 let mut acc = vec![Vec4::ZERO; acc_size];
                 acc.copy_from_slice(&self.accumulated[offset..(offset + acc_size)]);
+                let squares = self.luminance_squares[offset..(offset + acc_size)].to_vec();
+                let aovs = self.aovs[offset..(offset + acc_size)].to_vec();
+
+                // Jittered primary rays, rays through a lens and rays at random times hit
+                // something else every sample, so nothing is reused.
+                let reuses = Self::reuses_primary_hits(&self.settings, camera);
+                let mut cache = vec![PixelCache::default(); acc_size];
+                if reuses {
+                    cache.copy_from_slice(&self.pixel_cache[offset..(offset + acc_size)]);
+                }
 
                 let mut s = Renderer {
                     scene: self.scene.clone(),
+                    settings: self.settings.clone(),
                     accumulated: acc,
+                    luminance_squares: squares,
+                    aovs,
+                    pixel_cache: cache,
                     frame_index: self.frame_index,
+                    exposure: self.exposure,
+                    target_noise: self.target_noise,
+                    next_tile: 0,
+                    seed: self.seed,
                 };
 
                 let chunk = Chunk {
                     size: acc_size,
                     pixel_offset: offset,
                 };
-                
-                s.render_chunk(camera, &mut rnd, chunk, e.1);
-                s
+
+                for sample in 0..samples * self.settings.samples_per_pixel.max(1) {
+                    if !reuses && sample > 0 {
+                        s.pixel_cache.fill(PixelCache::default());
+                    }
+                    s.render_chunk(camera, &mut rnd, chunk, sample, bytes);
+                }
+                (offset, s)
             })
             .collect();
 
-        let mut offset = 0;
-        for c in col {
+        for (offset, c) in col {
             let len = c.accumulated.len();
             self.accumulated[offset..offset + len].copy_from_slice(c.accumulated.as_slice());
-            offset += len;
+            self.luminance_squares[offset..offset + len]
+                .copy_from_slice(c.luminance_squares.as_slice());
+            self.aovs[offset..offset + len].copy_from_slice(c.aovs.as_slice());
+            self.pixel_cache[offset..offset + len].copy_from_slice(c.pixel_cache.as_slice());
         }
+    }
 
-        texture
-            .update(None, img.as_slice(), w * 4)
-            .map_err(|e| e.to_string())?;
+    /// Traces `samples` frames of the `count` pixels starting at `first` over `num_chunks`
+    /// threads, apart from the accumulation, and returns their averaged linear colours with
+    /// the RGBA bytes. Renders images too large to accumulate a piece at a time, without
+    /// the post-processing, which needs the whole image.
+    pub fn render_region(
+        &self,
+        camera: &Camera,
+        first: usize,
+        count: usize,
+        samples: u32,
+        num_chunks: usize,
+    ) -> (Vec<Vec4>, Vec<u8>) {
+        let chunk_size = count.div_ceil(num_chunks.max(1)).max(1);
+        let mut bytes = vec![0; count * 4];
+        let pixels = bytes
+            .par_chunks_mut(chunk_size * 4)
+            .enumerate()
+            .flat_map_iter(|(i, bytes)| {
+                let mut rnd = self.sampler(0, first + i * chunk_size);
+                let size = bytes.len() / 4;
+                let mut s = Renderer {
+                    scene: self.scene.clone(),
+                    settings: self.settings.clone(),
+                    accumulated: vec![Vec4::ZERO; size],
+                    luminance_squares: vec![0.; size],
+                    aovs: vec![AovPixel::default(); size],
+                    pixel_cache: vec![PixelCache::default(); size],
+                    frame_index: 1,
+                    exposure: self.exposure,
+                    target_noise: None,
+                    next_tile: 0,
+                    seed: self.seed,
+                };
+                let chunk = Chunk {
+                    size,
+                    pixel_offset: first + i * chunk_size,
+                };
+                for frame in 1..=samples {
+                    s.frame_index = frame;
+                    for sample in 0..self.settings.samples_per_pixel.max(1) {
+                        // As in `trace_tiles`, jittered rays do not reuse the primary hits.
+                        if !Self::reuses_primary_hits(&self.settings, camera) {
+                            s.pixel_cache.fill(PixelCache::default());
+                        }
+                        s.render_chunk(camera, &mut rnd, chunk, sample, bytes);
+                    }
+                }
+                s.accumulated.into_iter().map(resolve)
+            })
+            .collect();
+        (pixels, bytes)
+    }
 
-        self.frame_index += 1;
+    /// Accumulated linear colour of a pixel, averaged over the frames rendered so far.
+    pub fn average(&self, pixel: usize) -> Option<Vec4> {
+        self.accumulated
+            .get(pixel)
+            .map(|c| resolve(*c))
+    }
 
-        Ok(())
+    /// Linear depth along the camera axis of every primary hit, clamped to the camera near
+    /// and far planes. Misses and pixels not traced yet are at the far plane.
+    pub fn depth_buffer(&self, camera: &Camera) -> Vec<f32> {
+        self.pixel_cache
+            .iter()
+            .enumerate()
+            .map(|(pixel, cache)| match cache.primary_hit {
+                Some(Some(hit)) => camera
+                    .depth(&camera.ray(pixel), hit.distance)
+                    .clamp(camera.near, camera.far),
+                _ => camera.far,
+            })
+            .collect()
+    }
+
+    /// Saves the accumulated linear colours, before exposure and grading, with the depth
+    /// buffer as the `Z` channel of an EXR file.
+    pub fn save_exr(&self, path: impl Into<String>, camera: &Camera) -> Result<(), AppError> {
+        let pixels: Vec<Vec4> = self
+            .accumulated
+            .iter()
+            .map(|c| resolve(*c))
+            .collect();
+        let depth = self.depth_buffer(camera);
+        ImageUtils::save_exr(path, camera.width, camera.height, &pixels, Some(&depth))
+    }
+
+    /// Saves the accumulated linear colours, before exposure, grading and display, for
+    /// post-processing elsewhere: as `save_exr` does for `.exr` paths, and as a 16-bit PNG
+    /// clamped to [0, 1] for `.png` paths.
+    pub fn save_hdr(&self, path: impl Into<String>, camera: &Camera) -> Result<(), AppError> {
+        let p: String = path.into();
+        let extension = Path::new(&p)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        match extension.as_str() {
+            "exr" => self.save_exr(p, camera),
+            "png" => {
+                let pixels: Vec<Vec4> = self
+                    .accumulated
+                    .iter()
+                    .map(|c| resolve(*c))
+                    .collect();
+                ImageUtils::save_png16(p, camera.width, camera.height, &pixels)
+            }
+            _ => Err(AppError::Validation(format!(
+                "linear images are saved as .exr or .png, not {}",
+                p
+            ))),
+        }
+    }
+
+    /// Value of `aov` for every pixel, as given by `AovPixel::value`. The auxiliary
+    /// outputs are only accumulated under `RenderSettings::aovs` or
+    /// `RenderSettings::denoise`, and are empty otherwise.
+    pub fn aov(&self, aov: Aov) -> Vec<Vec4> {
+        self.aovs.iter().map(|p| p.value(aov)).collect()
+    }
+
+    /// Saves `aov` with its raw values for `.exr` paths, and as shown by `Aov::display` in
+    /// the 8-bit format given by the extension otherwise.
+    pub fn save_aov(
+        &self,
+        path: impl Into<String>,
+        aov: Aov,
+        camera: &Camera,
+    ) -> Result<(), AppError> {
+        if !self.settings.aovs && self.settings.denoise.is_none() {
+            return Err(AppError::Validation(
+                "auxiliary outputs are not accumulated without RenderSettings::aovs".to_string(),
+            ));
+        }
+        let p: String = path.into();
+        let pixels = self.aov(aov);
+        let exr = Path::new(&p)
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("exr"));
+        if exr {
+            return ImageUtils::save_exr(p, camera.width, camera.height, &pixels, None);
+        }
+        let rgba: Vec<u8> = pixels
+            .iter()
+            .flat_map(|c| aov.display(*c).to_array())
+            .map(|v| (v * 255.).round() as u8)
+            .collect();
+        ImageUtils::save_image(p, camera.width, camera.height, &rgba)
     }
 
+    /// Rewrites the whole image from the accumulated colours with screen-space effects,
+    /// which need neighbouring pixels from other chunks.
+    fn post_process(&mut self, img: &mut [u8], w: usize, h: usize) {
+        let mut image: Vec<Vec4> = self
+            .accumulated
+            .iter()
+            .map(|c| resolve(*c))
+            .collect();
+
+        if let Some(denoiser) = self.settings.denoise {
+            denoiser.apply(&mut image, &self.aovs, w, h);
+        }
+
+        if let Some(auto_exposure) = self.settings.auto_exposure {
+            self.exposure = auto_exposure.adapt(self.exposure, &image);
+        }
+
+        if let Some(flare) = self.settings.flare {
+            flare.apply(&mut image, w, h);
+        }
+
+        let grade = self.settings.grade.clone().unwrap_or_default();
+        let scale = (grade.white_balance * self.exposure).extend(1.);
+        for c in image.iter_mut() {
+            *c *= scale;
+        }
+
+        let display = self.settings.display;
+        for (i, (pixel, c)) in img.chunks_exact_mut(4).zip(image.iter()).enumerate() {
+            if self.settings.nan_check && !self.accumulated[i].is_finite() {
+                let color = NAN_COLOR;
+                pixel.copy_from_slice(&[color.0, color.1, color.2, color.3]);
+                continue;
+            }
+            let c = if display == DisplayMode::FalseColor {
+                post::false_color(post::luminance(c.truncate()))
+            } else {
+                grade.apply(self.settings.tone_map.apply(c.truncate()))
+            };
+            let color =
+                Scene::to_rgba_dithered(c.clamp(Vec3::ZERO, Vec3::ONE).extend(1.), i % w, i / w);
+            pixel.copy_from_slice(&[color.0, color.1, color.2, color.3]);
+        }
+
+        if display == DisplayMode::Histogram {
+            post::draw_histogram(img, w, h, &post::luminance_histogram(&image));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::vec3;
+
+    use super::*;
+    use crate::camera::CameraEvent;
+    use crate::objects::{Material, Object3D};
+    use crate::scene::Light;
+    use crate::utils::mesh::Mesh;
+
+    fn renderer() -> (Renderer, Camera) {
+        let scene = Scene {
+            light: Light {
+                direction: vec3(0., -1., 0.),
+                ..Default::default()
+            },
+            materials: vec![Material::default()],
+            objects: vec![
+                Object3D::new_sphere(vec3(0., 0., 5.), 1., 0),
+                Object3D::new_mesh(
+                    Mesh::new(
+                        vec![vec3(-1., -1., 8.), vec3(1., -1., 8.), vec3(0., 1., 8.)],
+                        vec![[0, 1, 2]],
+                    ),
+                    0,
+                ),
+            ],
+            ..Default::default()
+        };
+        let mut camera = Camera::new_with_pos(Vec3::ZERO, Vec3::Z);
+        camera.update(vec![CameraEvent::Resize { w: 8, h: 8 }], 0.);
+        (Renderer::new(scene), camera)
+    }
+
+    #[test]
+    fn unchanged_updates_keep_the_accumulation() {
+        let (mut renderer, camera) = renderer();
+        let mut img = vec![0; 8 * 8 * 4];
+        for _ in 0..3 {
+            renderer.render_frame(&mut img, &camera, &Changes::default(), 1);
+        }
+        let changes = renderer.update_scene(|_| true);
+        assert!(changes.is_empty(), "{:?}", changes);
+        renderer.render_frame(&mut img, &camera, &changes, 1);
+        assert_eq!(renderer.frame_index, 5);
+    }
+
+    #[test]
+    fn meshes_edited_in_place_are_changes() {
+        let (mut renderer, _) = renderer();
+        let changes = renderer.update_scene(|scene| {
+            if let Object3D::Mesh { mesh, .. } = &mut scene.objects[1] {
+                Arc::make_mut(mesh).positions[2].y = 2.;
+            }
+            true
+        });
+        assert_eq!(changes.objects.len(), 1);
+        assert_eq!(changes.objects[0].0, 1);
+        assert!((changes.objects[0].1.max.y - 2.).abs() < 1e-3);
+    }
 }
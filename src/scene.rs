@@ -1,27 +1,229 @@
-use glam::{vec3, Vec3, Vec4};
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::f32::consts::PI;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::Arc;
+
+use glam::{vec3, Vec3, Vec3A, Vec4};
 
 use glam::vec4;
-use rand::rngs::ThreadRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::accel::{Aabb, Accel, AccelStructure};
+use crate::camera::{Camera, CameraEvent};
+use crate::changes::Changes;
+use crate::environment::{CloudLayer, Environment};
+use crate::light::LightSource;
+use crate::light_tree::LightTree;
+use crate::objects::{Material, MaterialType, Motion, Object3D, Texture};
+use crate::post;
+use crate::ray::{Ray, RayHit, Sampler, EPSILON};
+use crate::utils::errors::AppError;
+use crate::utils::time::Instant;
+use crate::volume::{Medium, Volume};
+#[cfg(feature = "gltf")]
+use crate::utils::gltf::GltfUtils;
+
+/// Lowest chance Russian roulette gives a path to continue, bounding the boost of the
+/// survivors.
+const MIN_SURVIVAL: f32 = 0.05;
+/// Roughness of the clear coat of `MaterialType::Principled`, a smooth varnish.
+const CLEARCOAT_ROUGHNESS: f32 = 0.1;
+
+/// Probability of re-tracing a cached light visibility on each accumulation frame.
+pub static LIGHT_CACHE_REFRESH: f64 = 0.1;
+
+/// Per-pixel shading results reused between accumulation frames while neither the
+/// camera nor the scene change.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PixelCache {
+    /// Closest hit of the primary ray, `None` until first traced and `Some(None)` on a miss.
+    pub primary_hit: Option<Option<RayHit>>,
+    /// Light visibility at the primary hit, `None` until first traced.
+    pub light_visibility: Option<f32>,
+}
+
+/// A secondary ray spawned by `Scene::scatter`, weighted by its share of the parent
+/// ray colour.
+#[derive(Debug, Copy, Clone)]
+struct Bounce {
+    ray: Ray,
+    light: Vec3,
+    contribution: Vec3,
+    weight: f32,
+}
+
+/// A path waiting in a wavefront queue, with the pixel it contributes to.
+#[derive(Debug, Copy, Clone)]
+struct PathState {
+    ray: Ray,
+    depth: u8,
+    light: Vec3,
+    contribution: Vec3,
+    weight: f32,
+    pixel: usize,
+}
+
+/// Exponential height fog applied along every traced ray segment, hit or miss.
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Fog {
+    /// Extinction per unit of distance at `height`. 0 disables the fog.
+    pub density: f32,
+    pub color: Vec3,
+    /// Height below which the fog has at least `density`.
+    pub height: f32,
+    /// How fast the density decays above `height`. 0 gives a uniform fog.
+    pub falloff: f32,
+    /// Share of the extinction that scatters the light of emissive spheres towards the
+    /// eye, drawing glows and light shafts around them. 0 disables it.
+    pub scattering: f32,
+}
+
+impl Fog {
+    /// Extinction per unit of distance at `point`.
+    pub fn density_at(&self, point: Vec3) -> f32 {
+        self.density * (-self.falloff * (point.y - self.height)).exp()
+    }
+
+    /// Fraction of the light that crosses `distance` along `ray`.
+    pub fn transmittance(&self, ray: &Ray, distance: f32) -> f32 {
+        let start = self.density_at(ray.origin);
+        if start <= 0. {
+            return 1.;
+        }
+
+        // Density integrated along the ray, in closed form.
+        let k = self.falloff * ray.direction.y;
+        let depth = if k.abs() < EPSILON {
+            start * distance
+        } else {
+            start * (1. - (-k * distance).exp()) / k
+        };
+        (-depth).exp()
+    }
 
-use crate::objects::{Material, MaterialType, Object3D, Texture};
-use crate::ray::{Ray, RayHit, EPSILON};
+    pub fn apply(&self, color: Vec3, ray: &Ray, distance: f32) -> Vec3 {
+        let t = self.transmittance(ray, distance);
+        color * t + self.color * (1. - t)
+    }
+}
 
-#[derive(Clone, Default)]
+/// Thresholds of the 8x8 Bayer ordered dither, out of 64.
+const BAYER_8X8: [u8; 64] = [
+    0, 32, 8, 40, 2, 34, 10, 42,
+    48, 16, 56, 24, 50, 18, 58, 26,
+    12, 44, 4, 36, 14, 46, 6, 38,
+    60, 28, 52, 20, 62, 30, 54, 22,
+    3, 35, 11, 43, 1, 33, 9, 41,
+    51, 19, 59, 27, 49, 17, 57, 25,
+    15, 47, 7, 39, 13, 45, 5, 37,
+    63, 31, 55, 23, 61, 29, 53, 21,
+];
+
+/// How one of many emissive spheres is picked to light a point, instead of sampling all.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum LightSelection {
+    /// Every sphere is as likely.
+    Uniform,
+    /// Spheres are picked in proportion to their power, so bright lights get the samples
+    /// and dim ones are rarely traced.
+    #[default]
+    Power,
+    /// Spheres are picked by walking `Scene::light_tree`, by power and closeness, so the
+    /// lights near a ray get the samples even among hundreds.
+    Tree,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Light {
     pub direction: Vec3,
     pub power: f32,
+    /// Angular radius of the light disc, in radians, like the sun's 0.0047. 0 gives a
+    /// point-like light with hard shadows.
+    pub angular_radius: f32,
+    /// Shadow rays cast per hit towards random points of the disc. Fewer samples give a
+    /// noisier penumbra that still converges under accumulation.
+    pub shadow_samples: u32,
+}
+
+impl Light {
+    /// Direction towards a uniformly picked point of the light disc.
+    pub fn sample_direction(&self, rnd: &mut Sampler) -> Vec3 {
+        let towards = -self.direction;
+        if self.angular_radius <= 0. {
+            return towards;
+        }
+        let cos_theta = 1. - rnd.gen::<f32>() * (1. - self.angular_radius.cos());
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let phi = 2. * std::f32::consts::PI * rnd.gen::<f32>();
+        let (t, b) = towards.any_orthonormal_pair();
+        (t * phi.cos() * sin_theta + b * phi.sin() * sin_theta + towards * cos_theta).normalize()
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Scene {
     pub light: Light,
     pub ambient_color: Vec3,
+    /// Radiance of the rays that miss every object.
+    pub environment: Environment,
+    pub fog: Fog,
+    /// Clouds composited over everything seen through them.
+    pub clouds: Option<CloudLayer>,
+    /// Smoke and clouds from density grids, composited like the clouds.
+    pub volumes: Vec<Volume>,
     pub objects: Vec<Object3D>,
+    /// Movement of the objects while the shutter is open, by index, for motion blur.
+    /// Objects without one, or past the end of the list, stand still.
+    pub motion: Vec<Option<Motion>>,
     pub materials: Vec<Material>,
     pub textures: Vec<Texture>,
     pub difuse: bool,
     pub max_ray_bounces: u8,
+    /// Bounces always traced before Russian roulette may stop a path, in proportion to
+    /// how little light it still carries. Values from `max_ray_bounces` on disable it.
+    pub roulette_depth: u8,
     pub max_frames_rendering: u32,
+    #[serde(skip)]
+    pub accel: AccelStructure,
+    /// Indices of the objects with a `motion`, set by `compile`. They are left out of
+    /// `accel` and intersected one by one, placed at the time of each ray.
+    #[serde(skip)]
+    pub moving: Vec<usize>,
+    /// `objects` without the moving ones, which `accel` is built over when some move.
+    #[serde(skip)]
+    pub still_objects: Vec<Object3D>,
+    /// Box around all the objects, set by `compile`. Shadow rays are dimmed by the fog
+    /// within it.
+    #[serde(skip)]
+    pub bounds: Aabb,
+    /// Indices of the emissive spheres, set by `compile`. The fog scatters their light.
+    #[serde(skip)]
+    pub emitters: Vec<usize>,
+    /// Running sum of the power of `emitters`, set by `compile`.
+    #[serde(skip)]
+    pub emitter_cdf: Vec<f32>,
+    /// Tree over `emitters` for `LightSelection::Tree`, set by `compile`.
+    #[serde(skip)]
+    pub light_tree: LightTree,
+    pub light_selection: LightSelection,
+    /// Sample one emissive sphere, picked by `light_selection`, at every diffuse hit (next
+    /// event estimation), weighting the samples and the diffuse bounces that hit the
+    /// spheres by multiple importance sampling. The light of the spheres then adds up
+    /// along the paths instead of only reaching them by chance.
+    pub light_sampling: bool,
+    /// Lights of kinds defined outside this crate, sampled at every diffuse hit. Scene
+    /// files can not hold them: they are left out when saving.
+    #[serde(skip)]
+    pub lights: Vec<Arc<dyn LightSource>>,
+    /// `hashes` when last compiled, which `changes` compares against.
+    #[serde(skip)]
+    pub compiled_hashes: SceneHashes,
 }
 
 impl Default for Scene {
@@ -29,22 +231,193 @@ impl Default for Scene {
         Self {
             light: Default::default(),
             ambient_color: Default::default(),
+            environment: Default::default(),
+            fog: Default::default(),
+            clouds: None,
+            volumes: Default::default(),
             objects: Default::default(),
+            motion: Default::default(),
             materials: Default::default(),
             textures: Default::default(),
             difuse: Default::default(),
             max_ray_bounces: Default::default(),
+            roulette_depth: 3,
             max_frames_rendering: 1000,
+            accel: Default::default(),
+            moving: Default::default(),
+            still_objects: Default::default(),
+            bounds: Default::default(),
+            emitters: Default::default(),
+            emitter_cdf: Default::default(),
+            light_tree: Default::default(),
+            light_selection: Default::default(),
+            light_sampling: true,
+            lights: Default::default(),
+            compiled_hashes: Default::default(),
         }
     }
 }
 
+/// Hashes of the parts of a scene, from `Scene::hashes`.
+#[derive(Debug, Clone, Default)]
+pub struct SceneHashes {
+    /// Everything but the objects and materials: lights, environment, fog, textures and
+    /// tracing settings.
+    pub settings: u64,
+    /// Hash and world bounds of each object.
+    pub objects: Vec<(u64, Aabb)>,
+    pub materials: Vec<u64>,
+    /// The meshes and other shared data hashed by identity, kept alive so that no other
+    /// data takes their address while the hashes are compared against.
+    _held: Vec<Arc<dyn Any + Send + Sync>>,
+}
+
+/// Contents of a scene file: the scene and, optionally, the camera looking at it.
+#[derive(Deserialize)]
+struct SceneFile {
+    scene: Scene,
+    #[serde(default)]
+    camera: Option<Camera>,
+}
+
+/// `SceneFile` borrowing what it writes.
+#[derive(Serialize)]
+struct SceneFileRef<'a> {
+    scene: &'a Scene,
+    camera: Option<&'a Camera>,
+}
+
+/// Feeds `hasher` with the serialized form of `value`, without building it in memory.
+fn hash_serialized(hasher: &mut DefaultHasher, value: &impl Serialize) {
+    // Writing to a hasher can not fail.
+    let _ = serde_json::to_writer(HashWriter(hasher), value);
+}
+
+fn hash_of(value: &impl Serialize) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_serialized(&mut hasher, value);
+    hasher.finish()
+}
+
+struct HashWriter<'a>(&'a mut DefaultHasher);
+
+impl std::io::Write for HashWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether `path` names a RON file rather than a JSON one.
+fn is_ron(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("ron"))
+}
+
+/// `1 - cos` of the half angle of the cone a sphere covers, from the square of its sine,
+/// the radius over the distance to the centre. Stays precise for small far spheres.
+fn cone_gap(sin2: f32) -> f32 {
+    sin2 / (1. + (1. - sin2).sqrt())
+}
+
+/// Weight of a sample drawn with density `pdf` against another strategy drawing it with
+/// density `other`.
+fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    pdf * pdf / (pdf * pdf + other * other)
+}
+
 impl Scene {
+    /// Reads a scene written by `save`, as RON for `.ron` paths and as JSON otherwise.
+    /// Fields left out of the file keep their defaults, and textures are loaded from
+    /// their paths.
+    pub fn load(path: impl Into<String>) -> Result<Scene, AppError> {
+        Scene::load_with_camera(path).map(|(scene, _)| scene)
+    }
+
+    /// Like `load`, also returning the camera saved with the scene, if any, ready to
+    /// render.
+    pub fn load_with_camera(
+        path: impl Into<String>,
+    ) -> Result<(Scene, Option<Camera>), AppError> {
+        let path: String = path.into();
+        let text = std::fs::read_to_string(&path)?;
+        let (scene, camera) = Scene::parse_named(&path, &text, is_ron(&path))?;
+        log::info!("Loaded scene {} with {} objects", path, scene.objects.len());
+        Ok((scene, camera))
+    }
+
+    /// Like `load_with_camera`, from the RON or JSON `text` of a scene file, for callers
+    /// without a file system.
+    pub fn parse(text: &str, ron: bool) -> Result<(Scene, Option<Camera>), AppError> {
+        Scene::parse_named("text", text, ron)
+    }
+
+    /// `parse`, naming the scene `name` in errors.
+    fn parse_named(
+        name: &str,
+        text: &str,
+        ron: bool,
+    ) -> Result<(Scene, Option<Camera>), AppError> {
+        let file: SceneFile = if ron {
+            ron::from_str(text).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_str(text).map_err(|e| e.to_string())
+        }
+        .map_err(|message| AppError::SceneLoad {
+            path: name.to_string(),
+            message,
+        })?;
+
+        let camera = file.camera.map(|mut camera| {
+            let (w, h) = (camera.width, camera.height);
+            camera.update(vec![CameraEvent::Resize { w, h }], 0.);
+            camera
+        });
+        Ok((file.scene, camera))
+    }
+
+    /// Writes the scene and `camera` to `path`, as RON for `.ron` paths and as JSON
+    /// otherwise. Textures are written as their paths, so generated textures are lost.
+    pub fn save(&self, path: impl Into<String>, camera: Option<&Camera>) -> Result<(), AppError> {
+        let path: String = path.into();
+        let file = SceneFileRef {
+            scene: self,
+            camera,
+        };
+        let text = if is_ron(&path) {
+            ron::ser::to_string_pretty(&file, ron::ser::PrettyConfig::default())
+                .map_err(|e| e.to_string())
+        } else {
+            serde_json::to_string_pretty(&file).map_err(|e| e.to_string())
+        }
+        .map_err(|message| AppError::SceneSave {
+            path: path.clone(),
+            message,
+        })?;
+        std::fs::write(&path, text)?;
+        Ok(())
+    }
+
+    /// Scene of the meshes of a glTF file, placed by their node transforms and drawn with
+    /// the file materials and base colour textures.
+    #[cfg(feature = "gltf")]
+    pub fn from_gltf(path: impl Into<String>) -> Result<Scene, AppError> {
+        let mut scene = Scene::new(vec![], vec![]);
+        GltfUtils::load_gltf_with_materials(path, &mut scene)?;
+        Ok(scene)
+    }
+
     pub fn new(objects: Vec<Object3D>, materials: Vec<Material>) -> Scene {
         Scene {
             light: Light {
                 direction: vec3(1., -1., -1.).normalize(),
                 power: 1.,
+                ..Default::default()
             },
             ambient_color: vec3(0.1, 0.1, 0.1),
             objects,
@@ -65,6 +438,14 @@ impl Scene {
         )
     }
 
+    /// Like `to_rgba`, adding an ordered dither by pixel position before quantizing, so
+    /// smooth gradients such as the sky and soft shadows show no banding.
+    pub fn to_rgba_dithered(c: Vec4, x: usize, y: usize) -> (u8, u8, u8, u8) {
+        let threshold = (BAYER_8X8[(y % 8) * 8 + x % 8] as f32 + 0.5) / 64.;
+        let quantize = |v: f32| (v * 255. + threshold).clamp(0., 255.) as u8;
+        (quantize(c.x), quantize(c.y), quantize(c.z), (c.w + 255.) as u8)
+    }
+
     pub fn with_light(&self, light: Light) -> Scene {
         let mut s = self.clone();
         s.light = light;
@@ -83,25 +464,292 @@ impl Scene {
         s
     }
 
-    fn trace_ray(&self, ray: Ray) -> Option<RayHit> {
-        if self.objects.is_empty() {
-            return None;
+    /// Hashes of everything that shapes the image, part by part. Small values are hashed
+    /// by content, but meshes, point clouds, terrains, environment maps and volume grids
+    /// only by identity. The hashes hold on to them, so editing one through
+    /// `Arc::make_mut` copies it and changes its identity. Texture texels are hashed by
+    /// address and size, so they must be replaced rather than edited in place.
+    pub fn hashes(&self) -> SceneHashes {
+        self.hashes_since(&SceneHashes::default())
+    }
+
+    /// `hashes`, reusing the bounds of the objects whose hash is the same in `previous`.
+    fn hashes_since(&self, previous: &SceneHashes) -> SceneHashes {
+        let mut held: Vec<Arc<dyn Any + Send + Sync>> = vec![];
+        let mut hasher = DefaultHasher::new();
+        let h = &mut hasher;
+        hash_serialized(h, &(
+            &self.light,
+            self.ambient_color,
+            &self.fog,
+            &self.clouds,
+            self.difuse,
+            self.max_ray_bounces,
+            self.roulette_depth,
+            self.light_selection,
+            self.light_sampling,
+        ));
+        match &self.environment {
+            Environment::Map(map) => {
+                hash_serialized(h, &(
+                    Arc::as_ptr(&map.texture) as usize,
+                    map.intensity,
+                    map.rotation,
+                ));
+                held.push(map.texture.clone());
+            }
+            environment => hash_serialized(h, environment),
+        }
+        for volume in self.volumes.iter() {
+            hash_serialized(h, &(Arc::as_ptr(&volume.grid) as usize, volume));
+            held.push(volume.grid.clone());
+        }
+        for light in self.lights.iter() {
+            hash_serialized(h, &(Arc::as_ptr(light) as *const () as usize));
+        }
+        for texture in self.textures.iter() {
+            hash_serialized(h, &(
+                &texture.path,
+                texture.width,
+                texture.height,
+                texture.texels.as_ptr() as usize,
+                texture.wrap,
+                texture.filter,
+            ));
         }
 
-        let mut closest_t = f32::MIN;
+        SceneHashes {
+            settings: hasher.finish(),
+            objects: self
+                .objects
+                .iter()
+                .enumerate()
+                .map(|(i, object)| {
+                    let mut hasher = DefaultHasher::new();
+                    object.hash(&mut hasher);
+                    match object {
+                        Object3D::Mesh { mesh, .. } => held.push(mesh.clone()),
+                        Object3D::PointCloud { cloud, .. } => held.push(cloud.clone()),
+                        Object3D::Terrain { terrain, .. } => held.push(terrain.clone()),
+                        _ => {}
+                    }
+                    let motion = self.object_motion(i);
+                    if let Some(motion) = motion {
+                        hash_serialized(&mut hasher, motion);
+                    }
+                    let hash = hasher.finish();
+                    // Mesh bounds go through every vertex: keep those already known.
+                    let bounds = match previous.objects.get(i) {
+                        Some((before, bounds)) if *before == hash => *bounds,
+                        _ => match motion {
+                            Some(motion) => motion.swept_bounds(&object.bounds()),
+                            None => object.bounds(),
+                        },
+                    };
+                    (hash, bounds)
+                })
+                .collect(),
+            materials: self
+                .materials
+                .iter()
+                .map(hash_of)
+                .collect(),
+            _held: held,
+        }
+    }
 
-        let mut closest_hit: Option<RayHit> = None;
+    /// Hash of everything that shapes the image, combining `hashes`.
+    pub fn content_hash(&self) -> u64 {
+        let hashes = self.hashes();
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(hashes.settings);
+        for (object, _) in hashes.objects.iter() {
+            hasher.write_u64(*object);
+        }
+        for material in hashes.materials.iter() {
+            hasher.write_u64(*material);
+        }
+        hasher.finish()
+    }
 
-        for obj in self.objects.iter() {
-            if let k @ Some(t) = ray.hit(&obj) {
-                if t.distance < 0. && t.distance > closest_t {
-                    closest_hit = k;
-                    closest_t = t.distance;
+    /// What changed since the scene was last compiled.
+    pub fn changes(&self) -> Changes {
+        let hashes = self.hashes_since(&self.compiled_hashes);
+        let compiled = &self.compiled_hashes;
+        let objects = (0..hashes.objects.len().max(compiled.objects.len()))
+            .filter_map(|i| match (compiled.objects.get(i), hashes.objects.get(i)) {
+                (Some(before), Some(after)) if before.0 == after.0 => None,
+                (Some(before), Some(after)) => Some((i, before.1.union(&after.1))),
+                (Some((_, bounds)), None) | (None, Some((_, bounds))) => Some((i, *bounds)),
+                (None, None) => None,
+            })
+            .collect();
+        let materials = (0..hashes.materials.len().max(compiled.materials.len()))
+            .filter(|i| compiled.materials.get(*i) != hashes.materials.get(*i))
+            .collect();
+        Changes {
+            camera: false,
+            objects,
+            materials,
+            settings: hashes.settings != compiled.settings,
+        }
+    }
+
+    /// Rebuilds the data derived from `objects`. Must be called after the objects change.
+    pub fn compile(&mut self, accel: Accel) {
+        let start = Instant::now();
+        self.compiled_hashes = self.hashes_since(&self.compiled_hashes);
+        self.moving = (0..self.objects.len())
+            .filter(|i| self.object_motion(*i).is_some())
+            .collect();
+        self.still_objects = if self.moving.is_empty() {
+            vec![]
+        } else {
+            (0..self.objects.len())
+                .filter(|i| self.object_motion(*i).is_none())
+                .map(|i| self.objects[i].clone())
+                .collect()
+        };
+        self.accel = AccelStructure::new(accel, self.accel_objects());
+        self.bounds = self
+            .objects
+            .iter()
+            .map(|o| o.bounds())
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or_default();
+        self.emitters = (0..self.objects.len())
+            .filter(|i| match self.objects[*i] {
+                Object3D::Sphere { material_index, .. } => {
+                    self.materials[material_index].emission() > 0.
                 }
+                _ => false,
+            })
+            .collect();
+        self.emitter_cdf = self
+            .emitters
+            .iter()
+            .scan(0., |total, i| {
+                *total += self.emitter_power(*i);
+                Some(*total)
+            })
+            .collect();
+        let lights: Vec<(Aabb, f32)> = self
+            .emitters
+            .iter()
+            .map(|i| (self.objects[*i].bounds(), self.emitter_power(*i)))
+            .collect();
+        self.light_tree = LightTree::new(&lights);
+        log::debug!(
+            "Built {:?} over {} objects in {:?}",
+            accel,
+            self.objects.len(),
+            start.elapsed()
+        );
+    }
+
+    /// Movement of object `index`, if it has one.
+    pub fn object_motion(&self, index: usize) -> Option<&Motion> {
+        self.motion.get(index).and_then(Option::as_ref)
+    }
+
+    /// The objects `accel` was built over.
+    fn accel_objects(&self) -> &[Object3D] {
+        if self.moving.is_empty() {
+            &self.objects
+        } else {
+            &self.still_objects
+        }
+    }
+
+    /// Closest-hit query: returns the nearest hit whose distance lies in `(EPSILON, t_max)`.
+    /// The scene must be compiled.
+    pub fn trace_ray(&self, ray: Ray, t_max: f32) -> Option<RayHit> {
+        if self.objects.is_empty() {
+            return None;
+        }
+
+        let mut closest = if self.accel_objects().is_empty() {
+            None
+        } else {
+            self.accel
+                .trace(self.accel_objects(), ray, t_max)
+                .map(|hit| RayHit {
+                    object_index: self.still_object_index(hit.object_index),
+                    ..hit
+                })
+        };
+        for i in self.moving.iter() {
+            let t_max = closest.map_or(t_max, |hit| hit.distance);
+            if let Some(hit) = self.moving_hit(*i, ray, t_max) {
+                closest = Some(hit);
             }
         }
+        closest.map(|hit| RayHit {
+            time: ray.time,
+            ..hit
+        })
+    }
+
+    /// Index in `objects` of the object at `index` in `accel_objects`.
+    fn still_object_index(&self, index: usize) -> usize {
+        // Moving objects are left out of `still_objects`, in order.
+        self.moving
+            .iter()
+            .fold(index, |index, moving| index + (*moving <= index) as usize)
+    }
+
+    /// Any-hit query for shadow rays: returns on the first occluder found in
+    /// `(EPSILON, t_max)` instead of searching for the closest one.
+    fn occluded(&self, ray: Ray, t_max: f32) -> bool {
+        self.accel.occluded(self.accel_objects(), ray, t_max)
+            || self
+                .moving
+                .iter()
+                .any(|i| self.moving_hit(*i, ray, t_max).is_some())
+    }
+
+    /// Hit of `ray` with the moving object `index` where it is at the time of the ray, if
+    /// closer than `t_max`. The ray is moved into the space of the object geometry instead.
+    fn moving_hit(&self, index: usize, ray: Ray, t_max: f32) -> Option<RayHit> {
+        let transform = self.object_motion(index)?.at(ray.time);
+        let inverse = transform.inverse();
+        let local = Ray {
+            origin: inverse.transform_point3(ray.origin),
+            direction: inverse.transform_vector3(ray.direction).normalize(),
+            ..ray
+        };
+        let hit = local.hit(&self.objects[index])?;
+        let point = transform.transform_point3(hit.point);
+        let distance = point.distance(ray.origin);
+        if hit.distance <= EPSILON || distance >= t_max {
+            return None;
+        }
+        Some(RayHit {
+            distance,
+            point,
+            normal: (inverse.matrix3.transpose() * Vec3A::from(hit.normal))
+                .normalize()
+                .into(),
+            object_index: index,
+            ..hit
+        })
+    }
+
+    /// Primary ray through pixel `(x, y)` and its closest hit, if the pixel is inside the
+    /// image and the ray hits anything.
+    pub fn pick(&self, camera: &Camera, x: i32, y: i32) -> Option<(Ray, RayHit)> {
+        if x < 0 || y < 0 || x as usize >= camera.width || y as usize >= camera.height {
+            return None;
+        }
+        let ray = camera.ray(y as usize * camera.width + x as usize);
+        self.trace_ray(ray, f32::MAX).map(|hit| (ray, hit))
+    }
 
-        closest_hit
+    /// Depth along the camera axis of the surface seen through pixel `(x, y)`, for
+    /// click-to-focus.
+    pub fn focus_distance(&self, camera: &Camera, x: i32, y: i32) -> Option<f32> {
+        self.pick(camera, x, y)
+            .map(|(ray, hit)| camera.depth(&ray, hit.distance))
     }
 
     fn make_light(&self, albedo: Vec3, emission_power: f32, light: Vec3, light_angle: f32) -> Vec3 {
@@ -116,6 +764,105 @@ impl Scene {
         direction - (2. * (direction.dot(normal))) * normal
     }
 
+    /// Share of the light reaching `hit`: the fraction of shadow rays towards the light
+    /// disc that are not blocked, dimmed by what the fog, the clouds and the volumes let
+    /// through.
+    fn light_visibility(&self, hit: &RayHit, light: &Light, rnd: &mut Sampler) -> f32 {
+        let samples = light.shadow_samples.max(1);
+        let visible: f32 = (0..samples)
+            .map(|_| {
+                // Directional lights are infinitely far away, so any occluder counts.
+                let shadow_ray = Ray {
+                    origin: hit.point + hit.normal * EPSILON,
+                    direction: light.sample_direction(rnd),
+                    time: hit.time,
+                    ..Default::default()
+                };
+                if self.occluded(shadow_ray, f32::MAX) {
+                    return 0.;
+                }
+
+                // A uniform fog would swallow the light over an infinite path, so only the
+                // fog inside the scene bounds dims it.
+                let fog_distance =
+                    self.bounds.hit(&shadow_ray, f32::MAX).map_or(0., |(_, exit)| exit);
+                let clouds = self
+                    .clouds
+                    .map_or(1., |c| c.transmittance(&shadow_ray, f32::INFINITY));
+                let volumes: f32 = self
+                    .volumes
+                    .iter()
+                    .map(|v| v.transmittance(&shadow_ray, f32::INFINITY))
+                    .product();
+                self.fog.transmittance(&shadow_ray, fog_distance) * clouds * volumes
+            })
+            .sum();
+        visible / samples as f32
+    }
+
+    /// Reuses the visibility cached for this pixel, re-tracing it only now and then. Soft
+    /// shadows are never cached, as each frame must add a fresh estimate of the penumbra.
+    fn cached_light_visibility(
+        &self,
+        hit: &RayHit,
+        rnd: &mut Sampler,
+        cache: Option<&mut PixelCache>,
+    ) -> f32 {
+        match cache {
+            Some(cache) if self.light.angular_radius <= 0. => match cache.light_visibility {
+                Some(visibility) if !rnd.gen_bool(LIGHT_CACHE_REFRESH) => visibility,
+                _ => {
+                    let visibility = self.light_visibility(hit, &self.light, rnd);
+                    cache.light_visibility = Some(visibility);
+                    visibility
+                }
+            },
+            _ => self.light_visibility(hit, &self.light, rnd),
+        }
+    }
+
+    /// Colour of texture `index` at `hit`, read from the mip level whose texels match the
+    /// footprint of the ray cone there.
+    fn texture_color(&self, index: usize, ray: &Ray, hit: &RayHit) -> Vec3 {
+        let texture = &self.textures[index];
+        // A cone meeting the surface at an angle covers an ellipse stretched by 1 / cos;
+        // the circle of the same area stands for it.
+        let cos = ray.direction.dot(hit.normal).abs().max(EPSILON);
+        let footprint = ray.width_at(hit.distance) / cos.sqrt();
+        let size = (texture.width as f32 * texture.height as f32).sqrt();
+        let texels = footprint * hit.uv_density * size;
+        texture.sample(hit.u, hit.v, texels.log2())
+    }
+
+    /// Colour of the surface at `hit`: its texture, else its vertex colour, else the
+    /// albedo of its material. Refractive materials ignore their texture.
+    pub fn surface_color(&self, ray: &Ray, hit: &RayHit) -> Vec3 {
+        let material = &self.materials[hit.material_index];
+        match material.texture {
+            Some(idx) if !matches!(material.kind, MaterialType::Refractive { .. }) => {
+                self.texture_color(idx, ray, hit)
+            }
+            _ => hit.color.unwrap_or(material.albedo),
+        }
+    }
+
+    /// Light `hit` sends back along `ray` by itself: its Phong shading under the scene
+    /// light, dimmed by a fresh shadow test, its emission and the light it diffuses from
+    /// `lights`.
+    pub fn direct_light(&self, ray: &Ray, hit: &RayHit, rnd: &mut Sampler) -> Vec3 {
+        let material = &self.materials[hit.material_index];
+        let albedo = self.surface_color(ray, hit);
+        let visibility = self.light_visibility(hit, &self.light, rnd);
+        let emission = if matches!(material.kind, MaterialType::Refractive { .. }) {
+            Vec3::ZERO
+        } else {
+            albedo * material.emission()
+        };
+        self.phong(ray, hit, &self.light, albedo, material, visibility)
+            + emission
+            + self.sample_sources(hit, |_| albedo, rnd)
+    }
+
     fn phong(
         &self,
         ray: &Ray,
@@ -123,96 +870,787 @@ impl Scene {
         light: &Light,
         color: Vec3,
         material: &Material,
+        visibility: f32,
     ) -> Vec3 {
         let coeff = -ray.direction.dot(hit.normal);
         let ambience = material.ambience * color;
+
+        if visibility <= 0. {
+            return ambience;
+        }
+
         let diffuse = material.diffuse * coeff.max(0.) * color;
-        let shininess = (ray
+        let shininess = (-ray
             .direction
             .dot(Self::reflect(light.direction, hit.normal)))
         .max(0.)
         .powf(material.shininess);
         let specular = material.specular * shininess * color;
 
-        ambience + diffuse + specular
+        ambience + (diffuse + specular) * visibility
+    }
+
+    /// Shades a hit and returns the secondary rays it spawns, each weighted by its share
+    /// of the parent ray colour, after the light the hit adds to the path whatever
+    /// follows: under `light_sampling`, its emission and the light sampled from the
+    /// emissive spheres, already scaled by `contribution`.
+    fn scatter(
+        &self,
+        ray: &Ray,
+        hit: &RayHit,
+        rnd: &mut Sampler,
+        light_color: Vec3,
+        contribution: Vec3,
+        visibility: f32,
+    ) -> (Vec3, [Option<Bounce>; 2]) {
+        let material = self.materials[hit.material_index];
+        let albedo = self.surface_color(ray, hit);
+        let refractive = matches!(material.kind, MaterialType::Refractive { .. });
+
+        // Without light sampling the emission is only the light of the paths ending here.
+        let mut emission = if refractive {
+            Vec3::ZERO
+        } else {
+            albedo * material.emission()
+        };
+        let mut emitted = Vec3::ZERO;
+        if self.light_sampling {
+            emitted = contribution * emission * self.emission_weight(ray, hit);
+            emission = Vec3::ZERO;
+        }
+
+        let bounces = match material.kind {
+            MaterialType::Reflective { roughness } => {
+                let p_light = self.phong(ray, hit, &self.light, albedo, &material, visibility)
+                    + emission;
+
+                let (r, weight) = if roughness >= 1. {
+                    emitted += contribution * self.sample_lights(hit, 1., |_| albedo, rnd);
+                    self.diffuse_bounce(ray, hit, 1., rnd)
+                } else {
+                    (ray.reflection_ray(*hit, roughness, rnd), 1.)
+                };
+
+                [
+                    Some(Bounce {
+                        ray: r,
+                        light: p_light,
+                        contribution: contribution * albedo,
+                        weight,
+                    }),
+                    None,
+                ]
+            }
+            MaterialType::Refractive {
+                transparency,
+                refraction_index,
+                reflectivity,
+                roughness,
+            } => {
+                // Rough glass refracts and reflects about a sampled microfacet normal.
+                let hit = &RayHit {
+                    normal: ray.microfacet_normal(hit.normal, roughness, rnd),
+                    ..*hit
+                };
+                let kr = material.fresnel(ray.direction, hit.normal, refraction_index, reflectivity);
+
+                let refraction =
+                    ray.refraction_ray(*hit, refraction_index)
+                        .map(|refraction_ray| Bounce {
+                            ray: refraction_ray,
+                            light: light_color,
+                            contribution: contribution * albedo,
+                            weight: (1.0 - kr) * transparency,
+                        });
+
+                let reflection_ray = ray.bounce(
+                    hit.distance,
+                    hit.point + EPSILON * hit.normal,
+                    ray.reflect(hit.normal),
+                );
+
+                let p_light = self.phong(
+                    &reflection_ray,
+                    hit,
+                    &self.light,
+                    albedo,
+                    &material,
+                    visibility,
+                );
+
+                [
+                    Some(Bounce {
+                        ray: reflection_ray,
+                        light: p_light,
+                        contribution: contribution * albedo,
+                        weight: kr * transparency,
+                    }),
+                    refraction,
+                ]
+            }
+            MaterialType::Pbr {
+                metallic,
+                roughness,
+                ior,
+            } => {
+                let p_light = self.phong(ray, hit, &self.light, albedo, &material, visibility)
+                    + emission;
+
+                let view = -ray.direction;
+                let facet = ray.microfacet_normal(hit.normal, roughness, rnd);
+                let f0 = Vec3::splat(Material::normal_reflectance(ior)).lerp(albedo, metallic);
+                let fresnel = Material::schlick(f0, view.dot(facet));
+
+                // One lobe is followed per bounce: the microfacet reflection as often as it
+                // reflects, the diffuse base otherwise, each weighted by its odds.
+                let p_specular = metallic + (1. - metallic) * fresnel.max_element();
+                let bounce = if rnd.gen::<f32>() < p_specular {
+                    let direction = ray.reflect(facet);
+                    let ggx = Material::ggx_weight(hit.normal, view, direction, facet, roughness);
+                    Bounce {
+                        ray: ray.bounce(hit.distance, hit.point + EPSILON * hit.normal, direction),
+                        light: p_light,
+                        contribution: contribution * fresnel * ggx / p_specular,
+                        weight: 1.,
+                    }
+                } else {
+                    let base = (1. - metallic) * (Vec3::ONE - fresnel) / (1. - p_specular);
+                    let lobe = albedo * base;
+                    emitted +=
+                        contribution * self.sample_lights(hit, 1. - p_specular, |_| lobe, rnd);
+                    let (r, weight) = self.diffuse_bounce(ray, hit, 1. - p_specular, rnd);
+                    Bounce {
+                        ray: r,
+                        light: p_light,
+                        contribution: contribution * albedo * base,
+                        weight,
+                    }
+                };
+
+                [Some(bounce), None]
+            }
+            MaterialType::Principled { .. } => {
+                let p_light = self.phong(ray, hit, &self.light, albedo, &material, visibility)
+                    + emission;
+                let (bounce, sampled) =
+                    self.principled_bounce(ray, hit, rnd, &material, albedo, light_color, p_light);
+                emitted += contribution * sampled;
+                [
+                    Some(Bounce {
+                        contribution: contribution * bounce.contribution,
+                        ..bounce
+                    }),
+                    None,
+                ]
+            }
+        };
+        (emitted, bounces)
+    }
+
+    /// Follows one lobe of a `MaterialType::Principled` material, picked by how much it
+    /// is expected to reflect, and weights the bounce by its odds. The contribution of the
+    /// bounce is that of this hit alone, and so is the light sampled from the emissive
+    /// spheres when the diffuse lobe is picked, returned with it.
+    #[allow(clippy::too_many_arguments)]
+    fn principled_bounce(
+        &self,
+        ray: &Ray,
+        hit: &RayHit,
+        rnd: &mut Sampler,
+        material: &Material,
+        albedo: Vec3,
+        light_color: Vec3,
+        p_light: Vec3,
+    ) -> (Bounce, Vec3) {
+        let MaterialType::Principled {
+            metallic,
+            specular,
+            roughness,
+            sheen,
+            clearcoat,
+            transmission,
+            ior,
+        } = material.kind
+        else {
+            unreachable!("principled_bounce is only called for principled materials")
+        };
+        let view = -ray.direction;
+        let cos_view = view.dot(hit.normal);
+
+        let transmitted = |rnd: &mut Sampler, weight: Vec3| {
+            // Refracted out of the material, or reflected inside it past the critical angle.
+            let hit = &RayHit {
+                normal: ray.microfacet_normal(hit.normal, roughness, rnd),
+                ..*hit
+            };
+            let ray = ray.refraction_ray(*hit, ior).unwrap_or_else(|| {
+                ray.bounce(
+                    hit.distance,
+                    hit.point + EPSILON * hit.normal * cos_view.signum(),
+                    ray.reflect(hit.normal),
+                )
+            });
+            Bounce {
+                ray,
+                light: light_color,
+                contribution: weight * albedo,
+                weight: 1.,
+            }
+        };
+        // Seen from inside, only the transmission lets the light out.
+        if cos_view <= 0. {
+            return (transmitted(rnd, Vec3::ONE), Vec3::ZERO);
+        }
+
+        // Odds of each lobe from its reflectance along the view direction.
+        let f0 = Vec3::splat(0.08 * specular).lerp(albedo, metallic);
+        let coat = clearcoat * Material::schlick(Vec3::splat(0.04), cos_view).x;
+        let base = (1. - coat) * (Vec3::ONE - Material::schlick(f0, cos_view)).max_element();
+        let lobes = [
+            coat,
+            (1. - coat) * Material::schlick(f0, cos_view).max_element(),
+            base * (1. - metallic) * transmission,
+            base * (1. - metallic) * (1. - transmission),
+        ];
+        let total: f32 = lobes.iter().sum();
+        let mut pick = rnd.gen::<f32>() * total;
+        let lobe = lobes.iter().position(|p| {
+            pick -= p;
+            pick < 0.
+        });
+
+        match lobe {
+            Some(lobe @ (0 | 1)) => {
+                // Clear coat or specular: GGX reflections, the coat always fairly smooth.
+                let (f0, roughness, weight) = if lobe == 0 {
+                    (Vec3::splat(0.04), CLEARCOAT_ROUGHNESS, clearcoat)
+                } else {
+                    (f0, roughness, 1. - coat)
+                };
+                let facet = ray.microfacet_normal(hit.normal, roughness, rnd);
+                let direction = ray.reflect(facet);
+                let ggx = Material::ggx_weight(hit.normal, view, direction, facet, roughness);
+                let bounce = Bounce {
+                    ray: ray.bounce(hit.distance, hit.point + EPSILON * hit.normal, direction),
+                    light: p_light,
+                    contribution: Material::schlick(f0, view.dot(facet)) * ggx * weight * total
+                        / lobes[lobe],
+                    weight: 1.,
+                };
+                (bounce, Vec3::ZERO)
+            }
+            // The other lobes reflect what they were picked for, so they weigh `total`.
+            Some(2) => (transmitted(rnd, Vec3::splat(total)), Vec3::ZERO),
+            _ => {
+                let diffuse = |direction: Vec3| {
+                    let half = (view + direction).normalize_or_zero();
+                    let sheen = sheen * (1. - half.dot(direction).clamp(0., 1.)).powi(5);
+                    (albedo + Vec3::splat(sheen)) * total
+                };
+                let odds = if total > 0. { lobes[3] / total } else { 0. };
+                let sampled = self.sample_lights(hit, odds, diffuse, rnd);
+                let (r, weight) = self.diffuse_bounce(ray, hit, odds, rnd);
+                let bounce = Bounce {
+                    ray: r,
+                    light: p_light,
+                    contribution: diffuse(r.direction),
+                    weight,
+                };
+                (bounce, sampled)
+            }
+        }
     }
 
     fn color(
         &self,
         ray: Ray,
-        rnd: &mut ThreadRng,
+        rnd: &mut Sampler,
         depth: u8,
         light_color: Vec3,
         contribution: Vec3,
+        cache: Option<&mut PixelCache>,
     ) -> Vec3 {
         if depth >= self.max_ray_bounces {
             return light_color;
         }
-        if let Some(hit) = self.trace_ray(ray) {
-            let material = self.materials[hit.material_index];
-            let mut albedo = material.albedo;
+        let survival = self.survival(depth, contribution);
+        if survival >= 1. {
+            return self.path_color(ray, rnd, depth, light_color, contribution, cache);
+        }
+        if rnd.gen::<f32>() >= survival {
+            return light_color;
+        }
+        // Stopped paths return `light_color` as if they had reached `max_ray_bounces`, so
+        // the survivors scale up what they add to it.
+        let color = self.path_color(ray, rnd, depth, light_color, contribution, cache);
+        light_color + (color - light_color) / survival
+    }
 
-            match material.kind {
-                MaterialType::Reflective { roughness } => {
-                    if let Some(idx) = material.texture {
-                        albedo = self.textures[idx].baricentric_pixel(hit.u, hit.v);
-                    }
-                    let p_light = self.phong(&ray, &hit, &self.light, albedo, &material);
+    /// Chance of a path carrying `contribution` to keep going at `depth`: 1 before
+    /// `roulette_depth`, then its brightest channel, so dim paths stop early.
+    fn survival(&self, depth: u8, contribution: Vec3) -> f32 {
+        if depth < self.roulette_depth {
+            return 1.;
+        }
+        contribution.max_element().clamp(MIN_SURVIVAL, 1.)
+    }
 
-                    let r = ray.reflection_ray(hit, roughness, rnd);
+    fn path_color(
+        &self,
+        ray: Ray,
+        rnd: &mut Sampler,
+        depth: u8,
+        light_color: Vec3,
+        contribution: Vec3,
+        mut cache: Option<&mut PixelCache>,
+    ) -> Vec3 {
+        let closest_hit = match cache.as_deref_mut() {
+            Some(cache) => *cache
+                .primary_hit
+                .get_or_insert_with(|| self.trace_ray(ray, f32::MAX)),
+            None => self.trace_ray(ray, f32::MAX),
+        };
+        if let Some(hit) = closest_hit {
+            let visibility = self.cached_light_visibility(&hit, rnd, cache);
 
-                    self.color(r, rnd, depth + 1, p_light, contribution * albedo)
-                }
-                MaterialType::Refractive {
-                    transparency,
-                    refraction_index,
-                    reflectivity,
-                } => {
-                    let mut refraction_color = Vec3::ZERO;
-                    let kr =
-                        material.fresnel(ray.direction, hit.normal, refraction_index, reflectivity)
-                            as f32;
-
-                    if let Some(refraction_ray) = ray.refraction_ray(hit, refraction_index) {
-                        refraction_color = self.color(
-                            refraction_ray,
-                            rnd,
-                            depth + 1,
-                            light_color,
-                            contribution * albedo,
-                        );
-                    }
+            let (emitted, bounces) =
+                self.scatter(&ray, &hit, rnd, light_color, contribution, visibility);
+            let color = emitted
+                + bounces
+                    .into_iter()
+                    .flatten()
+                    .map(|b| {
+                        self.color(b.ray, rnd, depth + 1, b.light, b.contribution, None) * b.weight
+                    })
+                    .sum::<Vec3>();
+            self.fog.apply(self.apply_media(color, &ray, hit.distance), &ray, hit.distance)
+                + self.fog_inscattering(&ray, hit.distance, rnd)
+        } else {
+            let sky = self.environment.radiance(ray.direction, self.ambient_color);
+            let color = self.apply_media(light_color + sky * contribution, &ray, f32::INFINITY);
+            self.fog.apply(color, &ray, f32::INFINITY)
+                + self.fog_inscattering(&ray, f32::INFINITY, rnd)
+        }
+    }
 
-                    let reflection_ray = Ray {
-                        origin: hit.point + EPSILON * hit.normal,
-                        direction: ray.reflect(hit.normal),
-                    };
+    /// Light of the emissive spheres scattered by the fog towards the origin of `ray`
+    /// over its first `distance`, from one sphere picked by `light_selection`. The sample
+    /// distances follow the equi-angular distribution, denser where the ray passes close
+    /// to the light, which keeps the noise low where the glow is bright.
+    fn fog_inscattering(&self, ray: &Ray, distance: f32, rnd: &mut Sampler) -> Vec3 {
+        if self.fog.scattering <= 0. || self.fog.density <= 0. {
+            return Vec3::ZERO;
+        }
+        // Past the scene bounds the lights are too far to matter.
+        let distance = match self.bounds.hit(ray, distance) {
+            Some((_, exit)) => distance.min(exit),
+            None => return Vec3::ZERO,
+        };
+        let Some((i, probability)) = self.pick_emitter(ray, distance, rnd) else {
+            return Vec3::ZERO;
+        };
+        let Object3D::Sphere {
+            position,
+            radius,
+            material_index,
+        } = self.objects[i]
+        else {
+            return Vec3::ZERO;
+        };
+        let material = &self.materials[material_index];
 
-                    let p_light = self.phong(&reflection_ray, &hit, &self.light, albedo, &material);
-                    let reflection_color = self.color(
-                        reflection_ray,
-                        rnd,
-                        depth + 1,
-                        p_light,
-                        contribution * albedo,
-                    );
-
-                    let color = reflection_color * kr + refraction_color * (1.0 - kr);
-                    color * transparency
-                }
+        // Angles of the segment ends as seen from the light, around the closest point.
+        let closest = (position - ray.origin).dot(ray.direction);
+        let d = (ray.at(closest) - position).length().max(EPSILON);
+        let theta_a = (-closest).atan2(d);
+        let theta_b = (distance - closest).atan2(d);
+        if theta_b - theta_a <= EPSILON {
+            return Vec3::ZERO;
+        }
+        let theta = theta_a + rnd.gen::<f32>() * (theta_b - theta_a);
+        let t = d * theta.tan();
+        let pdf = d / ((theta_b - theta_a) * (d * d + t * t));
+
+        let point = ray.at(closest + t);
+        let to_light = position - point;
+        let light_distance = to_light.length();
+        if light_distance <= radius {
+            return Vec3::ZERO;
+        }
+        let shadow_ray = Ray {
+            origin: point,
+            direction: to_light / light_distance,
+            time: ray.time,
+            ..Default::default()
+        };
+        if self.occluded(shadow_ray, light_distance - radius - EPSILON) {
+            return Vec3::ZERO;
+        }
+
+        // The sphere seen from afar, emitting its radiance over its cross-section,
+        // scattered the same way in every direction.
+        let intensity = material.albedo * material.emission() * radius * radius;
+        let scattering = self.fog.scattering * self.fog.density_at(point);
+        let transmittance = self.fog.transmittance(ray, closest + t)
+            * self.fog.transmittance(&shadow_ray, light_distance - radius);
+        intensity * scattering * transmittance
+            / (4. * light_distance * light_distance * pdf * probability)
+    }
+
+    /// Power of an emissive sphere: its luminance over its cross-section.
+    fn emitter_power(&self, object: usize) -> f32 {
+        match self.objects[object] {
+            Object3D::Sphere {
+                radius,
+                material_index,
+                ..
+            } => {
+                let material = &self.materials[material_index];
+                post::luminance(material.albedo) * material.emission() * radius * radius
             }
-        } else {
-            light_color + self.ambient_color * contribution
+            _ => 0.,
         }
     }
 
-    pub fn pixel(&self, ray: Ray, rnd: &mut ThreadRng) -> Vec4 {
+    /// Picks one of `emitters` to light the first `distance` of `ray` following
+    /// `light_selection`, returning the object index and the probability it had of being
+    /// picked.
+    fn pick_emitter(&self, ray: &Ray, distance: f32, rnd: &mut Sampler) -> Option<(usize, f32)> {
+        let count = self.emitters.len();
+        let total = self.emitter_cdf.last().copied().unwrap_or(0.);
+        if count == 0 {
+            return None;
+        }
+        if self.light_selection == LightSelection::Tree {
+            return self
+                .light_tree
+                .pick(ray.origin, ray.direction, distance, rnd)
+                .map(|(k, probability)| (self.emitters[k], probability));
+        }
+        if self.light_selection == LightSelection::Uniform || total <= 0. {
+            return Some((self.emitters[rnd.gen_range(0..count)], 1. / count as f32));
+        }
+
+        let u = rnd.gen::<f32>() * total;
+        let k = self.emitter_cdf.partition_point(|c| *c <= u).min(count - 1);
+        let below = if k == 0 { 0. } else { self.emitter_cdf[k - 1] };
+        Some((self.emitters[k], (self.emitter_cdf[k] - below) / total))
+    }
+
+    /// Probability `pick_emitter` has of picking `emitters[k]` for the point `origin`.
+    fn emitter_probability(&self, k: usize, origin: Vec3) -> f32 {
+        let total = self.emitter_cdf.last().copied().unwrap_or(0.);
+        if self.light_selection == LightSelection::Tree {
+            return self.light_tree.probability(origin, Vec3::ZERO, 0., k);
+        }
+        if self.light_selection == LightSelection::Uniform || total <= 0. {
+            return 1. / self.emitters.len() as f32;
+        }
+        let below = if k == 0 { 0. } else { self.emitter_cdf[k - 1] };
+        (self.emitter_cdf[k] - below) / total
+    }
+
+    /// Diffuse bounce off `hit` of a lobe picked with `odds`. Bounces are steered towards
+    /// the bright parts of an environment map, and weighted to make up for it.
+    fn diffuse_bounce(
+        &self,
+        ray: &Ray,
+        hit: &RayHit,
+        odds: f32,
+        rnd: &mut Sampler,
+    ) -> (Ray, f32) {
+        let (r, weight) = match &self.environment {
+            Environment::Map(map) => map.diffuse_bounce(ray, hit, rnd),
+            _ => (ray.diffuse_ray(hit, rnd), 1.),
+        };
+        let pdf = odds * self.diffuse_pdf(hit.normal, r.direction);
+        (Ray { pdf, ..r }, weight)
+    }
+
+    /// Density of `diffuse_bounce` along `direction` off a surface facing `normal`.
+    fn diffuse_pdf(&self, normal: Vec3, direction: Vec3) -> f32 {
+        let cos_pdf = normal.dot(direction).max(0.) / PI;
+        match &self.environment {
+            Environment::Map(map) => 0.5 * cos_pdf + 0.5 * map.pdf(direction),
+            _ => cos_pdf,
+        }
+    }
+
+    /// Light of one emissive sphere, picked by `light_selection`, reflected at `hit` by a
+    /// diffuse lobe picked with `odds` whose bounces towards a direction are weighted by
+    /// `lobe`. The sample is weighted against the bounces of the lobe hitting the sphere,
+    /// which `emission_weight` discounts in turn. Zero without `light_sampling`.
+    fn sample_emitters(
+        &self,
+        hit: &RayHit,
+        odds: f32,
+        lobe: impl Fn(Vec3) -> Vec3,
+        rnd: &mut Sampler,
+    ) -> Vec3 {
+        if !self.light_sampling || odds <= 0. {
+            return Vec3::ZERO;
+        }
+        let at = Ray {
+            origin: hit.point + hit.normal * EPSILON,
+            direction: hit.normal,
+            time: hit.time,
+            ..Default::default()
+        };
+        let Some((i, probability)) = self.pick_emitter(&at, 0., rnd) else {
+            return Vec3::ZERO;
+        };
+        let Object3D::Sphere {
+            position,
+            radius,
+            material_index,
+        } = self.objects[i]
+        else {
+            return Vec3::ZERO;
+        };
+        let to_center = position - at.origin;
+        let sin2 = radius * radius / to_center.length_squared();
+        if sin2 >= 1. {
+            return Vec3::ZERO;
+        }
+
+        // Uniformly within the cone of directions the sphere covers.
+        let gap = cone_gap(sin2);
+        let cos_theta = 1. - rnd.gen::<f32>() * gap;
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let phi = 2. * PI * rnd.gen::<f32>();
+        let axis = to_center.normalize();
+        let (t, b) = axis.any_orthonormal_pair();
+        let direction =
+            (t * phi.cos() * sin_theta + b * phi.sin() * sin_theta + axis * cos_theta).normalize();
+        let cos = direction.dot(hit.normal);
+        if cos <= 0. {
+            return Vec3::ZERO;
+        }
+
+        let along = to_center.dot(direction);
+        let distance = along - (radius * radius - (to_center.length_squared() - along * along))
+            .max(0.)
+            .sqrt();
+        let shadow_ray = Ray {
+            direction,
+            ..at
+        };
+        if self.occluded(shadow_ray, distance - EPSILON) {
+            return Vec3::ZERO;
+        }
+
+        let material = &self.materials[material_index];
+        let radiance = material.albedo
+            * material.emission()
+            * self.fog.transmittance(&shadow_ray, distance);
+        let p_light = probability / (2. * PI * gap);
+        let p_bounce = odds * self.diffuse_pdf(hit.normal, direction);
+        lobe(direction) * radiance * cos / (PI * p_light) * power_heuristic(p_light, p_bounce)
+    }
+
+    /// Light of `sample_emitters` and `sample_sources` together.
+    fn sample_lights(
+        &self,
+        hit: &RayHit,
+        odds: f32,
+        lobe: impl Fn(Vec3) -> Vec3,
+        rnd: &mut Sampler,
+    ) -> Vec3 {
+        if odds <= 0. {
+            return Vec3::ZERO;
+        }
+        self.sample_emitters(hit, odds, &lobe, rnd) + self.sample_sources(hit, lobe, rnd)
+    }
+
+    /// Light of one sample of each of `lights` reflected at `hit` by a diffuse lobe, whose
+    /// reflectance towards a direction is `lobe`.
+    fn sample_sources(
+        &self,
+        hit: &RayHit,
+        lobe: impl Fn(Vec3) -> Vec3,
+        rnd: &mut Sampler,
+    ) -> Vec3 {
+        let mut light = Vec3::ZERO;
+        let origin = hit.point + hit.normal * EPSILON;
+        for source in self.lights.iter() {
+            let Some(sample) = source.sample(origin, rnd) else {
+                continue;
+            };
+            let cos = sample.direction.dot(hit.normal);
+            if cos <= 0. {
+                continue;
+            }
+            let shadow_ray = Ray {
+                origin,
+                direction: sample.direction,
+                time: hit.time,
+                ..Default::default()
+            };
+            if self.occluded(shadow_ray, sample.distance - EPSILON) {
+                continue;
+            }
+            // As for the scene light, only the fog inside the scene bounds dims lights
+            // infinitely far away.
+            let fog_distance = if sample.distance.is_finite() {
+                sample.distance
+            } else {
+                self.bounds.hit(&shadow_ray, f32::MAX).map_or(0., |(_, exit)| exit)
+            };
+            let radiance = sample.radiance * self.fog.transmittance(&shadow_ray, fog_distance);
+            light += lobe(sample.direction) * radiance * cos / PI;
+        }
+        light
+    }
+
+    /// Weight of the emission at `hit` against the light samples, for the rays of diffuse
+    /// bounces hitting an emissive sphere. The other hits count in full.
+    fn emission_weight(&self, ray: &Ray, hit: &RayHit) -> f32 {
+        if ray.pdf <= 0. {
+            return 1.;
+        }
+        let Ok(k) = self.emitters.binary_search(&hit.object_index) else {
+            return 1.;
+        };
+        let Object3D::Sphere {
+            position, radius, ..
+        } = self.objects[hit.object_index]
+        else {
+            return 1.;
+        };
+        let sin2 = radius * radius / ray.origin.distance_squared(position);
+        if sin2 >= 1. {
+            return 1.;
+        }
+        let p_light = self.emitter_probability(k, ray.origin) / (2. * PI * cone_gap(sin2));
+        power_heuristic(ray.pdf, p_light)
+    }
+
+    /// `color` seen through the clouds, then through the volumes, which are closer.
+    fn apply_media(&self, color: Vec3, ray: &Ray, distance: f32) -> Vec3 {
+        let color = match &self.clouds {
+            Some(clouds) => clouds.apply(color, ray, distance, &self.light, self.ambient_color),
+            None => color,
+        };
+        self.volumes.iter().fold(color, |color, volume| {
+            volume.apply(color, ray, distance, &self.light, self.ambient_color)
+        })
+    }
+
+    /// Light arriving along `ray`, path traced without the pixel cache of `pixel`.
+    pub fn path_radiance(&self, ray: Ray, rnd: &mut Sampler) -> Vec3 {
+        self.color(ray, rnd, 0, Vec3::ZERO, Vec3::ONE, None)
+    }
+
+    pub fn pixel(&self, ray: Ray, rnd: &mut Sampler, cache: &mut PixelCache) -> Vec4 {
         let mut light = Vec3::ZERO; // BLACK
 
         let contribution = Vec3::ONE;
 
-        light = self.color(ray, rnd, 0, light, contribution);
+        light = self.color(ray, rnd, 0, light, contribution, Some(cache));
 
         vec4(light.x, light.y, light.z, 1.)
     }
+
+    /// Same result as calling `pixel` for every ray, but the paths are traced breadth-first:
+    /// each bounce intersects the whole queue of pending rays, then shades all the hits,
+    /// producing the queue of the next bounce. Pixels without a ray are left black.
+    pub fn pixels_wavefront(
+        &self,
+        rays: &[Option<Ray>],
+        rnd: &mut Sampler,
+        caches: &mut [PixelCache],
+    ) -> Vec<Vec4> {
+        let mut light = vec![Vec3::ZERO; rays.len()];
+
+        // Generate
+        let mut queue: Vec<PathState> = rays
+            .iter()
+            .enumerate()
+            .filter(|_| self.max_ray_bounces > 0)
+            .filter_map(|(pixel, ray)| {
+                ray.map(|ray| PathState {
+                    ray,
+                    depth: 0,
+                    light: Vec3::ZERO,
+                    contribution: Vec3::ONE,
+                    weight: 1.,
+                    pixel,
+                })
+            })
+            .collect();
+
+        while !queue.is_empty() {
+            // Intersect
+            let hits: Vec<Option<RayHit>> = queue
+                .iter()
+                .map(|path| {
+                    if path.depth == 0 {
+                        *caches[path.pixel]
+                            .primary_hit
+                            .get_or_insert_with(|| self.trace_ray(path.ray, f32::MAX))
+                    } else {
+                        self.trace_ray(path.ray, f32::MAX)
+                    }
+                })
+                .collect();
+
+            // Shade
+            let mut next: Vec<PathState> = Vec::with_capacity(queue.len());
+            for (path, hit) in queue.iter().zip(hits) {
+                // Fog over this segment: its colour is added once, and everything seen
+                // through it is dimmed by the transmittance.
+                let distance = hit.map_or(f32::INFINITY, |h| h.distance);
+                let transmittance = self.fog.transmittance(&path.ray, distance);
+                light[path.pixel] += (self.fog.color * (1. - transmittance)
+                    + self.fog_inscattering(&path.ray, distance, rnd))
+                    * path.weight;
+                let mut path_weight = path.weight * transmittance;
+                let media = self.volumes.iter().rev().map(|v| v as &dyn Medium);
+                for medium in media.chain(self.clouds.iter().map(|c| c as &dyn Medium)) {
+                    let (scattered, transmittance) =
+                        medium.segment(&path.ray, distance, &self.light, self.ambient_color);
+                    light[path.pixel] += scattered * path_weight;
+                    path_weight *= transmittance;
+                }
+
+                let Some(hit) = hit else {
+                    let sky = self.environment.radiance(path.ray.direction, self.ambient_color);
+                    light[path.pixel] += (path.light + sky * path.contribution) * path_weight;
+                    continue;
+                };
+
+                let cache = (path.depth == 0).then(|| &mut caches[path.pixel]);
+                let visibility = self.cached_light_visibility(&hit, rnd, cache);
+
+                let (emitted, bounces) =
+                    self.scatter(&path.ray, &hit, rnd, path.light, path.contribution, visibility);
+                light[path.pixel] += emitted * path_weight;
+                for b in bounces.into_iter().flatten() {
+                    let weight = path_weight * b.weight;
+                    let survival = self.survival(path.depth + 1, b.contribution);
+                    if path.depth + 1 >= self.max_ray_bounces || rnd.gen::<f32>() >= survival {
+                        light[path.pixel] += b.light * weight;
+                    } else {
+                        // As in `color`: the survivors are scaled up above `b.light`.
+                        light[path.pixel] += b.light * weight * (1. - 1. / survival);
+                        next.push(PathState {
+                            ray: b.ray,
+                            depth: path.depth + 1,
+                            light: b.light,
+                            contribution: b.contribution,
+                            weight: weight / survival,
+                            pixel: path.pixel,
+                        });
+                    }
+                }
+            }
+
+            queue = next;
+        }
+
+        light
+            .into_iter()
+            .map(|l| vec4(l.x, l.y, l.z, 1.))
+            .collect()
+    }
 }
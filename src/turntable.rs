@@ -0,0 +1,94 @@
+use std::f32::consts::TAU;
+
+use glam::{Quat, Vec2, Vec3};
+
+use crate::accel::Aabb;
+use crate::camera::{Camera, CameraEvent};
+use crate::scene::Scene;
+use crate::utils::errors::AppError;
+use crate::utils::image::ImageUtils;
+
+/// Orbits the camera once around the scene, writing `<prefix>_<frame>.png` for every
+/// step after `samples` frames are accumulated.
+#[derive(Debug, Clone)]
+pub struct Turntable {
+    pub frames: u32,
+    pub samples: u32,
+    pub prefix: String,
+    center: Vec3,
+    start: Vec3,
+    frame: u32,
+}
+
+impl Turntable {
+    /// Orbits around the surface at the image center, or around the center of the scene
+    /// bounds when nothing is there, starting from the current camera position.
+    pub fn new(
+        camera: &Camera,
+        scene: &Scene,
+        frames: u32,
+        samples: u32,
+        prefix: impl Into<String>,
+    ) -> Turntable {
+        let center = scene
+            .pick(camera, camera.width as i32 / 2, camera.height as i32 / 2)
+            .map(|(_, hit)| hit.point)
+            .or_else(|| {
+                scene
+                    .objects
+                    .iter()
+                    .map(|o| o.bounds())
+                    .reduce(|a, b| a.union(&b))
+                    .map(|b: Aabb| b.center())
+            })
+            .unwrap_or_default();
+
+        Turntable {
+            frames: frames.max(1),
+            samples: samples.min(scene.max_frames_rendering).max(1),
+            prefix: prefix.into(),
+            center,
+            start: camera.position - center,
+            frame: 0,
+        }
+    }
+
+    /// Moves the camera to the current step, looking at the orbit center.
+    pub fn place(&self, camera: &mut Camera) {
+        let angle = TAU * self.frame as f32 / self.frames as f32;
+        camera.position = self.center + Quat::from_rotation_y(angle) * self.start;
+        camera.forward_direction = (self.center - camera.position).normalize();
+        camera.velocity = Vec3::ZERO;
+        camera.angular_velocity = Vec2::ZERO;
+        camera.update(
+            vec![CameraEvent::Resize {
+                w: camera.width,
+                h: camera.height,
+            }],
+            0.,
+        );
+    }
+
+    /// Saves `img` and moves the camera on once `accumulated` frames reach `samples`.
+    /// Returns whether the camera moved, and `None` after the last step.
+    pub fn advance(
+        &mut self,
+        camera: &mut Camera,
+        img: &[u8],
+        accumulated: u32,
+    ) -> Result<Option<bool>, AppError> {
+        if accumulated < self.samples {
+            return Ok(Some(false));
+        }
+
+        let path = format!("{}_{:04}.png", self.prefix, self.frame);
+        ImageUtils::save_image(path, camera.width, camera.height, img)?;
+
+        self.frame += 1;
+        if self.frame == self.frames {
+            return Ok(None);
+        }
+        self.place(camera);
+        Ok(Some(true))
+    }
+}
@@ -0,0 +1,32 @@
+use std::sync::OnceLock;
+
+/// Side of the blue-noise mask, which tiles the image.
+const SIZE: usize = 64;
+
+/// Ranks of a 64x64 void-and-cluster pattern, scaled to one byte per pixel.
+static MASK_PNG: &[u8] = include_bytes!("../../resources/blue_noise.png");
+
+fn mask() -> &'static [u8] {
+    static MASK: OnceLock<Vec<u8>> = OnceLock::new();
+    MASK.get_or_init(|| {
+        image::load_from_memory(MASK_PNG)
+            .expect("the embedded blue-noise mask is a valid PNG")
+            .to_luma8()
+            .into_raw()
+    })
+}
+
+/// Sample in `[0, 1)` of `dimension` for pixel `(x, y)` on accumulation frame `frame`.
+/// Neighbouring pixels get values far apart, so the error of a few samples shows as fine
+/// grain instead of clumps. Each dimension reads the mask at its own offset, and each
+/// frame shifts all values by the golden ratio so the frames keep covering `[0, 1)` evenly.
+pub fn blue_noise(x: usize, y: usize, frame: u32, dimension: u32) -> f32 {
+    // Offsets from the R2 sequence, far apart for consecutive dimensions.
+    let d = dimension as f64 + 1.;
+    let ox = ((d * 0.754_877_666).fract() * SIZE as f64) as usize;
+    let oy = ((d * 0.569_840_291).fract() * SIZE as f64) as usize;
+    let rank = mask()[((y + oy) % SIZE) * SIZE + (x + ox) % SIZE];
+
+    let shift = frame as f64 * 0.618_033_988_75;
+    ((rank as f64 + 0.5) / 256. + shift).fract() as f32
+}
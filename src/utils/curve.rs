@@ -0,0 +1,215 @@
+use std::f32::consts::PI;
+
+use glam::{Vec2, Vec3};
+
+use super::mesh::Mesh;
+
+#[derive(Debug, Clone)]
+pub enum Spline {
+    /// Passes through every control point.
+    CatmullRom(Vec<Vec3>),
+    /// Cubic Bézier segments sharing their end points: 0..=3, 3..=6, ...
+    Bezier(Vec<Vec3>),
+}
+
+impl Spline {
+    pub fn segments(&self) -> usize {
+        match self {
+            Spline::CatmullRom(points) => points.len().saturating_sub(1),
+            Spline::Bezier(points) => points.len().saturating_sub(1) / 3,
+        }
+    }
+
+    /// Control points of `segment` and the matching Bernstein/Catmull-Rom weights for the
+    /// position and the derivative at `t` in `[0, 1]`.
+    fn segment(&self, segment: usize, t: f32) -> ([Vec3; 4], [f32; 4], [f32; 4]) {
+        match self {
+            Spline::CatmullRom(points) => {
+                let last = points.len() - 1;
+                let p = [
+                    points[segment.saturating_sub(1)],
+                    points[segment],
+                    points[(segment + 1).min(last)],
+                    points[(segment + 2).min(last)],
+                ];
+                let (t2, t3) = (t * t, t * t * t);
+                (
+                    p,
+                    [
+                        0.5 * (-t3 + 2. * t2 - t),
+                        0.5 * (3. * t3 - 5. * t2 + 2.),
+                        0.5 * (-3. * t3 + 4. * t2 + t),
+                        0.5 * (t3 - t2),
+                    ],
+                    [
+                        0.5 * (-3. * t2 + 4. * t - 1.),
+                        0.5 * (9. * t2 - 10. * t),
+                        0.5 * (-9. * t2 + 8. * t + 1.),
+                        0.5 * (3. * t2 - 2. * t),
+                    ],
+                )
+            }
+            Spline::Bezier(points) => {
+                let i = segment * 3;
+                let s = 1. - t;
+                (
+                    [points[i], points[i + 1], points[i + 2], points[i + 3]],
+                    [s * s * s, 3. * s * s * t, 3. * s * t * t, t * t * t],
+                    [
+                        -3. * s * s,
+                        3. * s * s - 6. * s * t,
+                        6. * s * t - 3. * t * t,
+                        3. * t * t,
+                    ],
+                )
+            }
+        }
+    }
+
+    /// Position and tangent at `t` in `[0, 1]` along the whole curve. Curves without a
+    /// segment stay at their first point, with no tangent.
+    pub fn sample(&self, t: f32) -> (Vec3, Vec3) {
+        let segments = self.segments();
+        if segments == 0 {
+            let (Spline::CatmullRom(points) | Spline::Bezier(points)) = self;
+            return (points.first().copied().unwrap_or_default(), Vec3::ZERO);
+        }
+        let x = t.clamp(0., 1.) * segments as f32;
+        let segment = (x as usize).min(segments - 1);
+
+        let (p, w, dw) = self.segment(segment, x - segment as f32);
+        let point = p[0] * w[0] + p[1] * w[1] + p[2] * w[2] + p[3] * w[3];
+        let tangent = p[0] * dw[0] + p[1] * dw[1] + p[2] * dw[2] + p[3] * dw[3];
+
+        (point, tangent.normalize_or_zero())
+    }
+}
+
+/// A tube of constant radius swept along a spline, for cables, pipes or strands. Change
+/// the control points and rebuild the mesh to animate it.
+#[derive(Debug, Clone)]
+pub struct Tube {
+    pub spline: Spline,
+    pub radius: f32,
+    /// Rings per spline segment.
+    pub steps: usize,
+    /// Vertices around each ring.
+    pub sides: usize,
+}
+
+impl Tube {
+    /// Triangles of the tube with its end caps, none when the spline has no segment.
+    pub fn mesh(&self) -> Mesh {
+        if self.spline.segments() == 0 {
+            return Mesh::default();
+        }
+        let rings = self.spline.segments() * self.steps.max(1) + 1;
+        let sides = self.sides.max(3);
+
+        let mut positions = vec![];
+        let mut normals = vec![];
+        let mut uvs = vec![];
+
+        // Parallel transport keeps the ring orientation from twisting along the curve.
+        let (_, first_tangent) = self.spline.sample(0.);
+        let mut side = first_tangent.any_orthonormal_vector();
+
+        let mut ends = [(Vec3::ZERO, Vec3::ZERO); 2];
+        for i in 0..rings {
+            let t = i as f32 / (rings - 1) as f32;
+            let (center, tangent) = self.spline.sample(t);
+            side = (side - tangent * side.dot(tangent)).normalize_or(side);
+            let up = tangent.cross(side);
+
+            if i == 0 {
+                ends[0] = (center, -tangent);
+            } else if i == rings - 1 {
+                ends[1] = (center, tangent);
+            }
+
+            // The seam vertex is repeated so the UVs wrap around cleanly.
+            for j in 0..=sides {
+                let angle = 2. * PI * j as f32 / sides as f32;
+                let normal = side * angle.cos() + up * angle.sin();
+                positions.push(center + normal * self.radius);
+                normals.push(normal);
+                uvs.push(Vec2::new(t, j as f32 / sides as f32));
+            }
+        }
+
+        let ring = sides + 1;
+        let mut indices = vec![];
+        let mut corner_normals = vec![];
+        for i in 0..rings - 1 {
+            for j in 0..sides {
+                let a = i * ring + j;
+                let (b, c, d) = (a + 1, a + ring + 1, a + ring);
+                for tri in [[a, b, c], [a, c, d]] {
+                    indices.push(tri);
+                    corner_normals.push(tri.map(|v| normals[v]));
+                }
+            }
+        }
+
+        // End caps as triangle fans around the ring centers.
+        for (k, (center, normal)) in ends.into_iter().enumerate() {
+            let first = if k == 0 { 0 } else { (rings - 1) * ring };
+            let c = positions.len();
+            positions.push(center);
+            uvs.push(Vec2::new(k as f32, 0.5));
+            for j in 0..sides {
+                let (a, b) = (first + j, first + j + 1);
+                let tri = if k == 0 { [c, b, a] } else { [c, a, b] };
+                indices.push(tri);
+                corner_normals.push([normal; 3]);
+            }
+        }
+
+        let mut mesh = Mesh::new(positions, indices).with_uvs(uvs);
+        mesh.normals = corner_normals;
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tube(spline: Spline) -> Tube {
+        Tube {
+            spline,
+            radius: 0.1,
+            steps: 4,
+            sides: 6,
+        }
+    }
+
+    #[test]
+    fn splines_without_segments_give_empty_tubes() {
+        let p = Vec3::new(1., 2., 3.);
+        for spline in [
+            Spline::CatmullRom(vec![]),
+            Spline::CatmullRom(vec![p]),
+            Spline::Bezier(vec![]),
+            Spline::Bezier(vec![p, p, p]),
+        ] {
+            assert_eq!(spline.segments(), 0);
+            assert!(tube(spline).mesh().indices.is_empty());
+        }
+        assert_eq!(Spline::Bezier(vec![p, p, p]).sample(0.5), (p, Vec3::ZERO));
+    }
+
+    #[test]
+    fn shortest_splines_give_closed_tubes() {
+        let (a, b) = (Vec3::ZERO, Vec3::X);
+        for spline in [
+            Spline::CatmullRom(vec![a, b]),
+            Spline::Bezier(vec![a, a.lerp(b, 0.3), a.lerp(b, 0.6), b]),
+        ] {
+            assert_eq!(spline.segments(), 1);
+            let mesh = tube(spline).mesh();
+            // 4 steps of 6 sides, two triangles each, and two caps of 6 triangles.
+            assert_eq!(mesh.indices.len(), 4 * 6 * 2 + 2 * 6);
+        }
+    }
+}
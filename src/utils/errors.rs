@@ -1,26 +1,54 @@
 use image::ImageError;
+use thiserror::Error;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Error)]
 pub enum AppError {
-    ErrorIo(String),
-    ErrorLoadTexture(String),
-    ErrorString(String)
-}
-
-impl From<std::io::Error> for AppError {
-    fn from(value: std::io::Error) -> Self {
-        AppError::ErrorIo(format!("{}", value))
-    }
-}
-
-impl From<ImageError> for AppError {
-    fn from(value: ImageError) -> Self {
-        AppError::ErrorLoadTexture(value.to_string())
-    }
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("cannot decode texture {path}")]
+    TextureDecode {
+        path: String,
+        #[source]
+        source: ImageError,
+    },
+    #[error("cannot write image {path}")]
+    ImageWrite {
+        path: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("cannot load scene {path}: {message}")]
+    SceneLoad { path: String, message: String },
+    #[error("cannot save scene {path}: {message}")]
+    SceneSave { path: String, message: String },
+    #[error("cannot load volume {path}: {message}")]
+    VolumeLoad { path: String, message: String },
+    #[cfg(feature = "gltf")]
+    #[error("cannot load model {path}")]
+    ModelLoad {
+        path: String,
+        #[source]
+        source: gltf::Error,
+    },
+    /// A malformed line in a text file: a mesh, a LUT or a scene.
+    #[error("{path}:{line}: {message}")]
+    Parse {
+        path: String,
+        line: usize,
+        message: String,
+    },
+    /// Errors reported by SDL, which only gives a message.
+    #[error("SDL: {0}")]
+    Sdl(String),
+    /// Settings or arguments out of their valid range.
+    #[error("{0}")]
+    Validation(String),
+    #[error("{0}")]
+    Command(String),
 }
 
 impl From<String> for AppError {
     fn from(value: String) -> Self {
-        AppError::ErrorString(value)
+        AppError::Sdl(value)
     }
-}
\ No newline at end of file
+}
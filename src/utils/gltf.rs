@@ -0,0 +1,294 @@
+
+use glam::{Quat, Vec2, Vec3};
+use gltf::animation::util::ReadOutputs;
+use gltf::mesh::Mode;
+
+use crate::animation::{Animator, Channel, Clip, Interpolation, Keyframes, Node};
+use crate::objects::{ColorSpace, Material, MaterialType, Object3D, Texture};
+use crate::scene::Scene;
+
+use super::errors::AppError;
+use super::mesh::{Mesh, Shading};
+use super::time::Instant;
+
+pub struct GltfUtils {}
+
+impl GltfUtils {
+    /// Appends the triangle meshes of a glTF file to `scene`, placed by their node
+    /// transforms and drawn with `material_index`. The returned animator holds the node
+    /// hierarchy and the file animations, with the first one playing.
+    pub fn load_gltf(
+        path: impl Into<String>,
+        scene: &mut Scene,
+        material_index: usize,
+    ) -> Result<Animator, AppError> {
+        Self::import(path.into(), scene, Some(material_index))
+    }
+
+    /// Like `load_gltf`, but draws the meshes with the file materials, appended to the
+    /// scene materials along with their base colour textures.
+    pub fn load_gltf_with_materials(
+        path: impl Into<String>,
+        scene: &mut Scene,
+    ) -> Result<Animator, AppError> {
+        Self::import(path.into(), scene, None)
+    }
+
+    fn import(
+        p: String,
+        scene: &mut Scene,
+        material_index: Option<usize>,
+    ) -> Result<Animator, AppError> {
+        let start = Instant::now();
+        let (document, buffers, images) = gltf::import(&p).map_err(|e| AppError::ModelLoad {
+            path: p.clone(),
+            source: e,
+        })?;
+        // Scene material of each file material, the last one standing for primitives
+        // without a material.
+        let materials = match material_index {
+            Some(index) => vec![index; document.materials().len() + 1],
+            None => load_materials(&p, &document, &images, scene),
+        };
+        let buffer = |b: gltf::Buffer| buffers.get(b.index()).map(|data| &data.0[..]);
+
+        let mut animator = Animator {
+            nodes: vec![Node::default(); document.nodes().len()],
+            ..Default::default()
+        };
+
+        for node in document.nodes() {
+            let (t, r, s) = node.transform().decomposed();
+            let n = &mut animator.nodes[node.index()];
+            n.translation = Vec3::from(t);
+            n.rotation = Quat::from_array(r);
+            n.scale = Vec3::from(s);
+            for child in node.children() {
+                animator.nodes[child.index()].parent = Some(node.index());
+            }
+        }
+
+        let world = animator.world_transforms(&animator.rest_pose());
+
+        for node in document.nodes() {
+            let Some(mesh) = node.mesh() else {
+                continue;
+            };
+            for primitive in mesh.primitives() {
+                if primitive.mode() != Mode::Triangles {
+                    continue;
+                }
+                let reader = primitive.reader(buffer);
+                let Some(positions) = reader.read_positions() else {
+                    continue;
+                };
+                let positions: Vec<Vec3> = positions.map(Vec3::from).collect();
+
+                let flat: Vec<usize> = match reader.read_indices() {
+                    Some(indices) => indices.into_u32().map(|i| i as usize).collect(),
+                    None => (0..positions.len()).collect(),
+                };
+                let indices: Vec<[usize; 3]> =
+                    flat.chunks_exact(3).map(|t| [t[0], t[1], t[2]]).collect();
+
+                let mut m = Mesh::new(positions, indices);
+                if let Some(uvs) = reader.read_tex_coords(0) {
+                    m = m.with_uvs(uvs.into_f32().map(Vec2::from).collect());
+                }
+                if let Some(colors) = reader.read_colors(0) {
+                    m = m.with_colors(colors.into_rgb_f32().map(Vec3::from).collect());
+                }
+                match reader.read_normals() {
+                    Some(normals) => {
+                        let normals: Vec<Vec3> = normals.map(Vec3::from).collect();
+                        m.normals = m.indices.iter().map(|t| t.map(|v| normals[v])).collect();
+                    }
+                    None => m.compute_normals(Shading::Flat),
+                }
+
+                let material = primitive.material().index();
+                let local =
+                    Object3D::new_mesh(m, materials[material.unwrap_or(materials.len() - 1)]);
+                scene.objects.push(local.transformed(&world[node.index()]));
+                animator.nodes[node.index()]
+                    .objects
+                    .push((scene.objects.len() - 1, local));
+            }
+        }
+
+        for animation in document.animations() {
+            let mut clip = Clip {
+                name: animation.name().unwrap_or_default().to_string(),
+                ..Default::default()
+            };
+
+            for channel in animation.channels() {
+                let reader = channel.reader(buffer);
+                let (Some(inputs), Some(outputs)) = (reader.read_inputs(), reader.read_outputs())
+                else {
+                    continue;
+                };
+                let times: Vec<f32> = inputs.collect();
+                clip.duration = times.iter().fold(clip.duration, |d, t| d.max(*t));
+
+                let sampler = channel.sampler().interpolation();
+                let cubic = sampler == gltf::animation::Interpolation::CubicSpline;
+                let interpolation = if sampler == gltf::animation::Interpolation::Step {
+                    Interpolation::Step
+                } else {
+                    Interpolation::Linear
+                };
+                let animated = match outputs {
+                    ReadOutputs::Translations(t) => Channel::Translation(keyframes(
+                        &times,
+                        t.map(Vec3::from),
+                        interpolation,
+                        cubic,
+                    )),
+                    ReadOutputs::Rotations(r) => Channel::Rotation(keyframes(
+                        &times,
+                        r.into_f32().map(Quat::from_array),
+                        interpolation,
+                        cubic,
+                    )),
+                    ReadOutputs::Scales(s) => {
+                        Channel::Scale(keyframes(&times, s.map(Vec3::from), interpolation, cubic))
+                    }
+                    ReadOutputs::MorphTargetWeights(_) => continue,
+                };
+                clip.channels
+                    .push((channel.target().node().index(), animated));
+            }
+
+            animator.clips.push(clip);
+        }
+
+        animator.clip = (!animator.clips.is_empty()).then_some(0);
+        log::debug!(
+            "Loaded {} with {} nodes and {} animations in {:?}",
+            p,
+            animator.nodes.len(),
+            animator.clips.len(),
+            start.elapsed()
+        );
+
+        Ok(animator)
+    }
+}
+
+/// Appends the materials of `document` to the scene, with the base colour images they use
+/// as textures, and returns the scene index of each. A default material comes last.
+fn load_materials(
+    path: &str,
+    document: &gltf::Document,
+    images: &[gltf::image::Data],
+    scene: &mut Scene,
+) -> Vec<usize> {
+    let mut textures: Vec<Option<usize>> = vec![None; images.len()];
+    let mut texture = |image: usize, scene: &mut Scene| {
+        if textures[image].is_none() {
+            textures[image] = image_texture(path, image, &images[image]).map(|t| {
+                scene.textures.push(t);
+                scene.textures.len() - 1
+            });
+        }
+        textures[image]
+    };
+
+    let mut indices = vec![];
+    for material in document.materials() {
+        let pbr = material.pbr_metallic_roughness();
+        let [r, g, b, alpha] = pbr.base_color_factor();
+        let emission = Vec3::from(material.emissive_factor()).max_element();
+
+        let kind = if material.alpha_mode() == gltf::material::AlphaMode::Blend && alpha < 1. {
+            MaterialType::Refractive {
+                transparency: 1. - alpha,
+                refraction_index: 1.5,
+                reflectivity: 0.04,
+                roughness: pbr.roughness_factor(),
+            }
+        } else {
+            MaterialType::Pbr {
+                metallic: pbr.metallic_factor(),
+                roughness: pbr.roughness_factor(),
+                ior: 1.5,
+            }
+        };
+
+        let base_texture = pbr
+            .base_color_texture()
+            .and_then(|info| texture(info.texture().source().index(), scene));
+        scene.materials.push(Material {
+            albedo: Vec3::new(r, g, b),
+            specular: 0.5 + 0.5 * pbr.metallic_factor(),
+            texture: base_texture,
+            kind,
+            emission_power: emission,
+            emissive: emission > 0.,
+            ..Default::default()
+        });
+        indices.push(scene.materials.len() - 1);
+    }
+
+    scene.materials.push(Material {
+        albedo: Vec3::ONE,
+        ..Default::default()
+    });
+    indices.push(scene.materials.len() - 1);
+    indices
+}
+
+/// Texture of an 8-bit sRGB image of the file. Other formats are skipped.
+fn image_texture(path: &str, index: usize, image: &gltf::image::Data) -> Option<Texture> {
+    use gltf::image::Format;
+
+    let channels = match image.format {
+        Format::R8 => 1,
+        Format::R8G8 => 2,
+        Format::R8G8B8 => 3,
+        Format::R8G8B8A8 => 4,
+        format => {
+            log::warn!("{}: skipping image {} in {:?}", path, index, format);
+            return None;
+        }
+    };
+    let bytes = image
+        .pixels
+        .chunks_exact(channels)
+        .flat_map(|c| match channels {
+            1 | 2 => [c[0]; 3],
+            _ => [c[0], c[1], c[2]],
+        })
+        .collect();
+
+    Some(
+        Texture {
+            path: format!("{}#{}", path, index),
+            width: image.width,
+            height: image.height,
+            bytes,
+            ..Default::default()
+        }
+        .with_color_space(ColorSpace::Srgb),
+    )
+}
+
+/// Cubic splines store an in-tangent, the value and an out-tangent per key. Only the
+/// values are kept, and they are interpolated linearly.
+fn keyframes<T>(
+    times: &[f32],
+    values: impl Iterator<Item = T>,
+    interpolation: Interpolation,
+    cubic: bool,
+) -> Keyframes<T> {
+    Keyframes {
+        times: times.to_vec(),
+        values: if cubic {
+            values.skip(1).step_by(3).collect()
+        } else {
+            values.collect()
+        },
+        interpolation,
+    }
+}
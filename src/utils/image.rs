@@ -1,30 +1,257 @@
-use std::io::Cursor;
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Write};
+use std::ops::Range;
+use std::path::Path;
 
-use crate::objects::Texture;
+use crate::objects::{ColorSpace, Texture};
 
 use super::errors::AppError;
-use image::ImageReader;
+use super::time::Instant;
+use exr::block::writer::ChunksWriter;
+use exr::block::UncompressedBlock;
+use exr::meta::attribute::{ChannelDescription, LevelMode, SampleType, TileDescription};
+use exr::meta::header::Header;
+use exr::meta::BlockDescription;
+use exr::prelude::{
+    AnyChannel, AnyChannels, Compression, Encoding, FlatSamples, Image, Layer, LayerAttributes,
+    LineOrder, SmallVec, WritableImage,
+};
+use glam::{Vec3, Vec4};
+use image::{DynamicImage, ExtendedColorType, ImageReader};
 
 pub struct ImageUtils {
 }
 
 impl ImageUtils {
     
+    /// Loads an image as a texture. 8-bit images are decoded from sRGB, while float
+    /// images such as Radiance HDR and OpenEXR keep their linear values, beyond 1 too.
     pub fn load_image(path: impl Into<String>) -> Result<Texture, AppError> {
         let p: String = path.into();
-        let img = ImageReader::open(p.clone())?.decode()?;
+        let start = Instant::now();
+        let img = ImageReader::open(p.clone())?
+            .decode()
+            .map_err(|e| AppError::TextureDecode {
+                path: p.clone(),
+                source: e,
+            })?;
+        let (w, h) = (img.width(), img.height());
+
+        if matches!(img, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_)) {
+            let texels = img
+                .into_rgb32f()
+                .pixels()
+                .map(|p| Vec3::from_array(p.0))
+                .collect();
+            log::debug!("Loaded HDR texture {} ({}x{}) in {:?}", p, w, h, start.elapsed());
+            return Ok(Texture {
+                path: p,
+                width: w,
+                height: h,
+                color_space: ColorSpace::Linear,
+                texels,
+                ..Default::default()
+            }
+            .with_mipmaps());
+        }
+
         let rgb8 = img.clone().into_rgb8();
         let k = rgb8.as_ref();
-        let (w, h) = (img.width(), img.height());
 
         let  bytes: Vec<u8> = Vec::from(k);
 
-        println!("img len {}", bytes.len());
-        Ok(Texture{
+        log::debug!("Loaded texture {} ({}x{}) in {:?}", p, w, h, start.elapsed());
+        Ok(Texture {
             path: p,
             width: w,
             height: h,
-            bytes
+            bytes,
+            ..Default::default()
+        }
+        .with_color_space(ColorSpace::Srgb))
+    }
+
+    /// Writes `w` x `h` RGBA bytes in the format given by the path extension.
+    pub fn save_image(
+        path: impl Into<String>,
+        w: usize,
+        h: usize,
+        rgba: &[u8],
+    ) -> Result<(), AppError> {
+        let p: String = path.into();
+        image::save_buffer(&p, rgba, w as u32, h as u32, ExtendedColorType::Rgba8).map_err(|e| {
+            AppError::ImageWrite {
+                path: p.clone(),
+                source: Box::new(e),
+            }
         })
     }
-}
\ No newline at end of file
+
+    /// Writes a `w` x `h` linear RGBA image as 32-bit float EXR, with `depth` as an extra
+    /// `Z` channel when given.
+    pub fn save_exr(
+        path: impl Into<String>,
+        w: usize,
+        h: usize,
+        pixels: &[Vec4],
+        depth: Option<&[f32]>,
+    ) -> Result<(), AppError> {
+        let p: String = path.into();
+        let channel = |name: &str, i: usize| {
+            AnyChannel::new(name, FlatSamples::F32(pixels.iter().map(|c| c[i]).collect()))
+        };
+
+        let mut channels: SmallVec<[AnyChannel<FlatSamples>; 4]> = SmallVec::new();
+        channels.push(channel("R", 0));
+        channels.push(channel("G", 1));
+        channels.push(channel("B", 2));
+        channels.push(channel("A", 3));
+        if let Some(depth) = depth {
+            channels.push(AnyChannel::new("Z", FlatSamples::F32(depth.to_vec())));
+        }
+
+        let layer = Layer::new(
+            (w, h),
+            LayerAttributes::default(),
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(channels),
+        );
+        Image::from_layer(layer)
+            .write()
+            .to_file(&p)
+            .map_err(|e| AppError::ImageWrite {
+                path: p.clone(),
+                source: Box::new(e),
+            })
+    }
+
+    /// Writes a `w` x `h` linear RGBA image as a 16-bit PNG, clamped to [0, 1] and tagged
+    /// with a gamma of 1 so viewers know the values are linear.
+    pub fn save_png16(
+        path: impl Into<String>,
+        w: usize,
+        h: usize,
+        pixels: &[Vec4],
+    ) -> Result<(), AppError> {
+        let p: String = path.into();
+        let write_error = |e: png::EncodingError| AppError::ImageWrite {
+            path: p.clone(),
+            source: Box::new(e),
+        };
+
+        let mut encoder = png::Encoder::new(BufWriter::new(File::create(&p)?), w as u32, h as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Sixteen);
+        encoder.set_source_gamma(png::ScaledFloat::new(1.));
+        let data: Vec<u8> = pixels
+            .iter()
+            .flat_map(|c| c.to_array())
+            .flat_map(|v| ((v.clamp(0., 1.) * 65535.).round() as u16).to_be_bytes())
+            .collect();
+        let mut writer = encoder.write_header().map_err(write_error)?;
+        writer.write_image_data(&data).map_err(write_error)?;
+        writer.finish().map_err(write_error)
+    }
+
+    /// Writes a `w` x `h` image band by band from the top, asking `band` for the linear
+    /// colours and RGBA bytes of each range of `band_rows` rows, so the whole image is
+    /// never held in memory. EXR paths get the linear colours in square tiles of
+    /// `band_rows` pixels, PNG paths the bytes.
+    pub fn save_streamed(
+        path: impl Into<String>,
+        w: usize,
+        h: usize,
+        band_rows: usize,
+        mut band: impl FnMut(Range<usize>) -> Result<(Vec<Vec4>, Vec<u8>), AppError>,
+    ) -> Result<(), AppError> {
+        let p: String = path.into();
+        let band_rows = band_rows.max(1);
+        let extension = Path::new(&p)
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let write_error = |e: Box<dyn std::error::Error + Send + Sync>| AppError::ImageWrite {
+            path: p.clone(),
+            source: e,
+        };
+
+        match extension.as_str() {
+            "png" => {
+                let mut encoder = png::Encoder::new(
+                    BufWriter::new(File::create(&p)?),
+                    w as u32,
+                    h as u32,
+                );
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                let mut writer = encoder.write_header().map_err(|e| write_error(e.into()))?;
+                let mut stream = writer.stream_writer().map_err(|e| write_error(e.into()))?;
+                for first in (0..h).step_by(band_rows) {
+                    let (_, rgba) = band(first..(first + band_rows).min(h))?;
+                    stream.write_all(&rgba)?;
+                }
+                stream.finish().map_err(|e| write_error(e.into()))
+            }
+            "exr" => {
+                // Channels are stored sorted by name.
+                let channels: SmallVec<[ChannelDescription; 5]> = ["A", "B", "G", "R"]
+                    .into_iter()
+                    .map(|name| ChannelDescription::new(name, SampleType::F32, true))
+                    .collect();
+                let header = Header::new("beauty".into(), (w, h), channels).with_encoding(
+                    Compression::ZIP16,
+                    BlockDescription::Tiles(TileDescription {
+                        tile_size: exr::math::Vec2(band_rows, band_rows),
+                        level_mode: LevelMode::Singular,
+                        rounding_mode: exr::math::RoundingMode::Down,
+                    }),
+                    LineOrder::Increasing,
+                );
+
+                let mut failure = None;
+                let file = BufWriter::new(File::create(&p)?);
+                let headers = std::iter::once(header).collect();
+                let written = exr::block::write(file, headers, true, |meta, writer| {
+                    let mut compressor = writer.sequential_blocks_compressor(&meta);
+                    // Tiles come row of tiles by row of tiles, each row being one band.
+                    let mut current: Option<(usize, Vec<Vec4>)> = None;
+                    for (index, block) in meta.enumerate_ordered_header_block_indices() {
+                        let first = block.pixel_position.y();
+                        if current.as_ref().map(|(row, _)| *row) != Some(first) {
+                            match band(first..(first + band_rows).min(h)) {
+                                Ok((pixels, _)) => current = Some((first, pixels)),
+                                Err(e) => {
+                                    failure = Some(e);
+                                    return Err(exr::error::Error::Aborted);
+                                }
+                            }
+                        }
+                        let Some((_, pixels)) = current.as_ref() else {
+                            unreachable!("a band was just rendered");
+                        };
+                        let channels = &meta.headers[0].channels;
+                        let block = UncompressedBlock::from_lines(channels, block, |line| {
+                            let position = line.location.position;
+                            let start = (position.y() - first) * w + position.x();
+                            // A, B, G, R
+                            let component = 3 - line.location.channel;
+                            line.write_samples(|i| pixels[start + i][component])
+                                .expect("lines are sized for their samples");
+                        });
+                        compressor.compress_block(index, block)?;
+                    }
+                    Ok(())
+                });
+                match (failure, written) {
+                    (Some(e), _) => Err(e),
+                    (None, written) => written.map_err(|e| write_error(Box::new(e))),
+                }
+            }
+            _ => Err(AppError::Validation(format!(
+                "streamed images are written as .png or .exr, not {}",
+                p
+            ))),
+        }
+    }
+}
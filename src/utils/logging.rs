@@ -0,0 +1,44 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::utils::time::Instant;
+
+/// Writes log records to stderr with the time since start, level and module.
+struct StderrLogger {
+    start: Instant,
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "[{:>9.3}s {:<5} {}] {}",
+                self.start.elapsed().as_secs_f32(),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs a stderr logger for the binaries. The level comes from the `RAY_TRACING_LOG`
+/// variable (`error` to `trace`), `info` by default. Does nothing if a logger is already
+/// installed, so library users keep theirs.
+pub fn init_logging() {
+    let level = std::env::var("RAY_TRACING_LOG")
+        .ok()
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    let logger = StderrLogger {
+        start: Instant::now(),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use glam::{Quat, Vec3};
+
+use crate::objects::Object3D;
+
+use super::curve::{Spline, Tube};
+use super::mesh::Mesh;
+
+/// A Lindenmayer system: an axiom rewritten by rules a few times over, then drawn by a
+/// turtle as tubes and spheres, for plants, corals and other branching structures.
+///
+/// The turtle starts at the origin heading along +Y and reads:
+/// - `F` moves forward by `step`, drawing a branch, and `f` moves without drawing;
+/// - `+` and `-` turn by `angle`, `&` and `^` pitch, `\` and `/` roll, `|` turns around;
+/// - `[` and `]` save and restore the turtle, branches in between being `thinning` times
+///   thinner;
+/// - `*` draws a leaf, a sphere of `leaf_radius`.
+///
+/// Other symbols only take part in the rewriting.
+#[derive(Debug, Clone)]
+pub struct LSystem {
+    pub axiom: String,
+    /// Replacement of each symbol at every rewrite. Symbols without one are kept.
+    pub rules: HashMap<char, String>,
+    pub iterations: usize,
+    /// Angle of every turn, in radians.
+    pub angle: f32,
+    pub step: f32,
+    /// Radius of the trunk, the branches drawn outside any `[`.
+    pub radius: f32,
+    pub thinning: f32,
+    pub leaf_radius: f32,
+}
+
+impl Default for LSystem {
+    fn default() -> Self {
+        LSystem {
+            axiom: String::new(),
+            rules: HashMap::new(),
+            iterations: 3,
+            angle: 25f32.to_radians(),
+            step: 0.2,
+            radius: 0.02,
+            thinning: 0.7,
+            leaf_radius: 0.03,
+        }
+    }
+}
+
+/// Turtle state saved by `[`.
+struct Turtle {
+    position: Vec3,
+    rotation: Quat,
+    radius: f32,
+    /// Points of the branch being drawn.
+    branch: Vec<Vec3>,
+}
+
+impl LSystem {
+    pub fn new(axiom: &str, rules: &[(char, &str)]) -> LSystem {
+        LSystem {
+            axiom: axiom.to_string(),
+            rules: rules
+                .iter()
+                .map(|(symbol, replacement)| (*symbol, replacement.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The axiom rewritten `iterations` times.
+    pub fn expand(&self) -> String {
+        (0..self.iterations).fold(self.axiom.clone(), |text, _| {
+            text.chars()
+                .map(|c| match self.rules.get(&c) {
+                    Some(replacement) => replacement.clone(),
+                    None => c.to_string(),
+                })
+                .collect()
+        })
+    }
+
+    /// The structure drawn by the turtle: the branches as one mesh of `branch_material`,
+    /// followed by the leaves as spheres of `leaf_material`.
+    pub fn objects(&self, branch_material: usize, leaf_material: usize) -> Vec<Object3D> {
+        let mut branches = Mesh::default();
+        let mut leaves = vec![];
+        let mut draw = |points: &mut Vec<Vec3>, radius: f32| {
+            if points.len() >= 2 {
+                let tube = Tube {
+                    spline: Spline::CatmullRom(points.clone()),
+                    radius,
+                    steps: 4,
+                    sides: 8,
+                };
+                branches.append(&tube.mesh());
+            }
+            points.clear();
+        };
+
+        let mut turtle = Turtle {
+            position: Vec3::ZERO,
+            rotation: Quat::IDENTITY,
+            radius: self.radius,
+            branch: vec![],
+        };
+        let mut saved: Vec<Turtle> = vec![];
+        for symbol in self.expand().chars() {
+            let turn = |axis: Vec3, angle: f32| Quat::from_axis_angle(axis, angle);
+            match symbol {
+                'F' => {
+                    if turtle.branch.is_empty() {
+                        turtle.branch.push(turtle.position);
+                    }
+                    turtle.position += turtle.rotation * Vec3::Y * self.step;
+                    turtle.branch.push(turtle.position);
+                }
+                'f' => {
+                    draw(&mut turtle.branch, turtle.radius);
+                    turtle.position += turtle.rotation * Vec3::Y * self.step;
+                }
+                '+' => turtle.rotation *= turn(Vec3::Z, self.angle),
+                '-' => turtle.rotation *= turn(Vec3::Z, -self.angle),
+                '&' => turtle.rotation *= turn(Vec3::X, self.angle),
+                '^' => turtle.rotation *= turn(Vec3::X, -self.angle),
+                '\\' => turtle.rotation *= turn(Vec3::Y, self.angle),
+                '/' => turtle.rotation *= turn(Vec3::Y, -self.angle),
+                '|' => turtle.rotation *= turn(Vec3::Z, PI),
+                '[' => {
+                    let branch = Turtle {
+                        position: turtle.position,
+                        rotation: turtle.rotation,
+                        radius: turtle.radius * self.thinning,
+                        branch: vec![],
+                    };
+                    saved.push(std::mem::replace(&mut turtle, branch));
+                }
+                ']' => {
+                    if let Some(parent) = saved.pop() {
+                        draw(&mut turtle.branch, turtle.radius);
+                        turtle = parent;
+                    }
+                }
+                '*' => leaves.push(Object3D::new_sphere(
+                    turtle.position,
+                    self.leaf_radius,
+                    leaf_material,
+                )),
+                _ => {}
+            }
+        }
+        draw(&mut turtle.branch, turtle.radius);
+        for mut turtle in saved.into_iter().rev() {
+            draw(&mut turtle.branch, turtle.radius);
+        }
+
+        let mut objects = vec![];
+        if !branches.indices.is_empty() {
+            objects.push(Object3D::new_mesh(branches, branch_material));
+        }
+        objects.extend(leaves);
+        objects
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::ops::{Add, Mul};
+
+use glam::{Vec2, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::objects::Texture;
+
+/// How the vertex normals of a mesh are generated.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Shading {
+    /// Every face uses its own geometric normal, giving a faceted look.
+    Flat,
+    /// Normals are averaged over the faces sharing a vertex, except across edges where
+    /// the faces meet at more than `crease_angle` (radians).
+    Smooth { crease_angle: f32 },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    pub indices: Vec<[usize; 3]>,
+    /// One normal per triangle corner, parallel to `indices`. Empty means flat shading.
+    pub normals: Vec<[Vec3; 3]>,
+    /// Smoothing group of each face. Faces only share normals within a group, and group 0
+    /// is always flat. Empty means every face is in group 1.
+    pub smoothing_groups: Vec<u32>,
+    /// Optional color of each vertex, parallel to `positions`.
+    pub colors: Vec<Vec3>,
+    /// Optional texture coordinates of each vertex, parallel to `positions`.
+    pub uvs: Vec<Vec2>,
+    /// Ignores faces seen from behind, whose corners run clockwise from the ray. Halves the
+    /// triangle hits of closed meshes, but glass needs the inside faces to refract out.
+    pub cull_backfaces: bool,
+}
+
+impl Mesh {
+    pub fn new(positions: Vec<Vec3>, indices: Vec<[usize; 3]>) -> Mesh {
+        Mesh {
+            positions,
+            indices,
+            ..Default::default()
+        }
+    }
+
+    /// Two triangles spanning `v1`..`v4` in order, with UVs running from (0, 0) at `v1`
+    /// to (1, 1) at `v3`.
+    pub fn quad(v1: Vec3, v2: Vec3, v3: Vec3, v4: Vec3) -> Mesh {
+        Mesh::new(vec![v1, v2, v3, v4], vec![[0, 1, 2], [0, 2, 3]]).with_uvs(vec![
+            Vec2::new(0., 0.),
+            Vec2::new(1., 0.),
+            Vec2::new(1., 1.),
+            Vec2::new(0., 1.),
+        ])
+    }
+
+    pub fn with_smoothing_groups(mut self, smoothing_groups: Vec<u32>) -> Mesh {
+        self.smoothing_groups = smoothing_groups;
+        self
+    }
+
+    pub fn with_colors(mut self, colors: Vec<Vec3>) -> Mesh {
+        self.colors = colors;
+        self
+    }
+
+    pub fn with_uvs(mut self, uvs: Vec<Vec2>) -> Mesh {
+        self.uvs = uvs;
+        self
+    }
+
+    pub fn with_backface_culling(mut self, cull_backfaces: bool) -> Mesh {
+        self.cull_backfaces = cull_backfaces;
+        self
+    }
+
+    pub fn with_shading(mut self, shading: Shading) -> Mesh {
+        self.compute_normals(shading);
+        self
+    }
+
+    /// Adds the triangles of `other` to the mesh. Normals, smoothing groups, colours and
+    /// texture coordinates are kept only when both meshes have them.
+    pub fn append(&mut self, other: &Mesh) {
+        if self.indices.is_empty() {
+            *self = other.clone();
+            return;
+        }
+        fn extend<T: Clone>(data: &mut Vec<T>, other: &[T]) {
+            if other.is_empty() {
+                data.clear();
+            } else if !data.is_empty() {
+                data.extend_from_slice(other);
+            }
+        }
+        extend(&mut self.normals, &other.normals);
+        extend(&mut self.smoothing_groups, &other.smoothing_groups);
+        extend(&mut self.colors, &other.colors);
+        extend(&mut self.uvs, &other.uvs);
+
+        let offset = self.positions.len();
+        self.positions.extend_from_slice(&other.positions);
+        self.indices
+            .extend(other.indices.iter().map(|face| face.map(|i| i + offset)));
+    }
+
+    pub fn triangle(&self, face: usize) -> (Vec3, Vec3, Vec3) {
+        let [a, b, c] = self.indices[face];
+        (self.positions[a], self.positions[b], self.positions[c])
+    }
+
+    fn smoothing_group(&self, face: usize) -> u32 {
+        self.smoothing_groups.get(face).copied().unwrap_or(1)
+    }
+
+    pub fn compute_normals(&mut self, shading: Shading) {
+        // Not normalized: the length weights each face by its area when averaging.
+        let face_normals: Vec<Vec3> = (0..self.indices.len())
+            .map(|f| {
+                let (v1, v2, v3) = self.triangle(f);
+                (v2 - v1).cross(v3 - v1)
+            })
+            .collect();
+
+        self.normals = match shading {
+            Shading::Flat => face_normals
+                .iter()
+                .map(|n| [n.normalize_or_zero(); 3])
+                .collect(),
+            Shading::Smooth { crease_angle } => {
+                let cos_crease = crease_angle.cos();
+
+                let mut vertex_faces: Vec<Vec<usize>> = vec![vec![]; self.positions.len()];
+                for (f, tri) in self.indices.iter().enumerate() {
+                    for v in tri {
+                        vertex_faces[*v].push(f);
+                    }
+                }
+
+                self.indices
+                    .iter()
+                    .enumerate()
+                    .map(|(f, tri)| {
+                        let face_normal = face_normals[f].normalize_or_zero();
+                        let group = self.smoothing_group(f);
+
+                        tri.map(|v| {
+                            if group == 0 {
+                                return face_normal;
+                            }
+                            vertex_faces[v]
+                                .iter()
+                                .filter(|g| {
+                                    self.smoothing_group(**g) == group
+                                        && face_normals[**g].normalize_or_zero().dot(face_normal)
+                                            >= cos_crease
+                                })
+                                .map(|g| face_normals[*g])
+                                .sum::<Vec3>()
+                                .normalize_or(face_normal)
+                        })
+                    })
+                    .collect()
+            }
+        };
+    }
+
+    /// Shading normal at barycentric coordinates `(u, v)` of a face.
+    pub fn normal(&self, face: usize, u: f32, v: f32) -> Option<Vec3> {
+        self.normals
+            .get(face)
+            .map(|n| ((1. - u - v) * n[0] + u * n[1] + v * n[2]).normalize())
+    }
+
+    /// Vertex color interpolated at barycentric coordinates `(u, v)` of a face.
+    pub fn color(&self, face: usize, u: f32, v: f32) -> Option<Vec3> {
+        if self.colors.is_empty() {
+            return None;
+        }
+        let [a, b, c] = self.indices[face];
+        Some((1. - u - v) * self.colors[a] + u * self.colors[b] + v * self.colors[c])
+    }
+
+    /// Texture coordinates interpolated at barycentric coordinates `(u, v)` of a face.
+    pub fn uv(&self, face: usize, u: f32, v: f32) -> Option<Vec2> {
+        if self.uvs.is_empty() {
+            return None;
+        }
+        let [a, b, c] = self.indices[face];
+        Some((1. - u - v) * self.uvs[a] + u * self.uvs[b] + v * self.uvs[c])
+    }
+
+    /// Texture space units per world unit across a face: the square root of its area in
+    /// texture space over its area in space. 0 for faces without UVs or without area.
+    pub fn uv_density(&self, face: usize) -> f32 {
+        if self.uvs.is_empty() {
+            return 0.;
+        }
+        let [a, b, c] = self.indices[face];
+        let (v1, v2, v3) = self.triangle(face);
+        let area = (v2 - v1).cross(v3 - v1).length();
+        let uv_area = (self.uvs[b] - self.uvs[a])
+            .perp_dot(self.uvs[c] - self.uvs[a])
+            .abs();
+        if area > 0. {
+            (uv_area / area).sqrt()
+        } else {
+            0.
+        }
+    }
+
+    /// Loop subdivision repeated `levels` times: every level splits each triangle in four
+    /// and smooths positions and colors towards the limit surface. Normals are dropped and
+    /// must be regenerated with `with_shading`.
+    pub fn subdivide(&self, levels: u32) -> Mesh {
+        (0..levels).fold(self.clone(), |mesh, _| mesh.subdivide_once(true))
+    }
+
+    /// Splits each triangle in four `levels` times without moving any vertex, so the shape
+    /// is unchanged but finer.
+    pub fn tessellate(&self, levels: u32) -> Mesh {
+        (0..levels).fold(self.clone(), |mesh, _| mesh.subdivide_once(false))
+    }
+
+    /// Tessellates the mesh `levels` times, then moves every vertex along its normal by
+    /// `scale` times the height read from `height` at the vertex UV. Meshes without UVs are
+    /// only tessellated. Normals are dropped and must be regenerated with `with_shading`.
+    pub fn displace(&self, height: &Texture, scale: f32, levels: u32) -> Mesh {
+        let mut mesh = self.tessellate(levels);
+        if mesh.uvs.is_empty() {
+            return mesh;
+        }
+
+        let mut vertex_normals = vec![Vec3::ZERO; mesh.positions.len()];
+        for f in 0..mesh.indices.len() {
+            let (v1, v2, v3) = mesh.triangle(f);
+            let n = (v2 - v1).cross(v3 - v1);
+            for v in mesh.indices[f] {
+                vertex_normals[v] += n;
+            }
+        }
+
+        for (i, p) in mesh.positions.iter_mut().enumerate() {
+            let uv = mesh.uvs[i];
+            let h = height.baricentric_pixel(uv.x, uv.y).element_sum() / 3.;
+            *p += vertex_normals[i].normalize_or_zero() * h * scale;
+        }
+
+        mesh
+    }
+
+    fn subdivide_once(&self, smooth_surface: bool) -> Mesh {
+        let n = self.positions.len();
+
+        // Each edge gets a new vertex index and remembers the vertices opposite to it.
+        let mut edges: HashMap<(usize, usize), (usize, Vec<usize>)> = HashMap::new();
+        for tri in self.indices.iter() {
+            for k in 0..3 {
+                let (a, b, opposite) = (tri[k], tri[(k + 1) % 3], tri[(k + 2) % 3]);
+                let next = n + edges.len();
+                edges
+                    .entry((a.min(b), a.max(b)))
+                    .or_insert((next, vec![]))
+                    .1
+                    .push(opposite);
+            }
+        }
+
+        let mut neighbors: Vec<Vec<usize>> = vec![vec![]; n];
+        let mut boundary: Vec<Vec<usize>> = vec![vec![]; n];
+        for (&(a, b), (_, opposite)) in edges.iter() {
+            neighbors[a].push(b);
+            neighbors[b].push(a);
+            if opposite.len() == 1 {
+                boundary[a].push(b);
+                boundary[b].push(a);
+            }
+        }
+
+        let smooth = |values: &[Vec3]| -> Vec<Vec3> {
+            if !smooth_surface {
+                return split_edges(values, &edges);
+            }
+
+            let mut out = vec![Vec3::ZERO; n + edges.len()];
+
+            for v in 0..n {
+                let k = neighbors[v].len();
+                out[v] = if boundary[v].len() == 2 {
+                    0.75 * values[v] + 0.125 * (values[boundary[v][0]] + values[boundary[v][1]])
+                } else if !boundary[v].is_empty() || k == 0 {
+                    // Corners and isolated vertices stay in place.
+                    values[v]
+                } else {
+                    let beta = if k == 3 {
+                        3. / 16.
+                    } else {
+                        3. / (8. * k as f32)
+                    };
+                    (1. - k as f32 * beta) * values[v]
+                        + beta * neighbors[v].iter().map(|u| values[*u]).sum::<Vec3>()
+                };
+            }
+
+            for (&(a, b), (i, opposite)) in edges.iter() {
+                out[*i] = if opposite.len() == 2 {
+                    0.375 * (values[a] + values[b])
+                        + 0.125 * (values[opposite[0]] + values[opposite[1]])
+                } else {
+                    0.5 * (values[a] + values[b])
+                };
+            }
+
+            out
+        };
+
+        let edge_vertex = |a: usize, b: usize| edges[&(a.min(b), a.max(b))].0;
+        let mut indices = Vec::with_capacity(self.indices.len() * 4);
+        for [a, b, c] in self.indices.iter().copied() {
+            let (ab, bc, ca) = (edge_vertex(a, b), edge_vertex(b, c), edge_vertex(c, a));
+            indices.push([a, ab, ca]);
+            indices.push([ab, b, bc]);
+            indices.push([ca, bc, c]);
+            indices.push([ab, bc, ca]);
+        }
+
+        Mesh {
+            positions: smooth(&self.positions),
+            indices,
+            normals: vec![],
+            smoothing_groups: self.smoothing_groups.iter().flat_map(|g| [*g; 4]).collect(),
+            colors: if self.colors.is_empty() {
+                vec![]
+            } else {
+                smooth(&self.colors)
+            },
+            uvs: if self.uvs.is_empty() {
+                vec![]
+            } else {
+                split_edges(&self.uvs, &edges)
+            },
+            cull_backfaces: self.cull_backfaces,
+        }
+    }
+}
+
+/// Keeps the existing vertex values and gives each new edge vertex the midpoint of its edge.
+fn split_edges<T>(values: &[T], edges: &HashMap<(usize, usize), (usize, Vec<usize>)>) -> Vec<T>
+where
+    T: Copy + Default + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let mut out = vec![T::default(); values.len() + edges.len()];
+    out[..values.len()].copy_from_slice(values);
+    for (&(a, b), (i, _)) in edges.iter() {
+        out[*i] = (values[a] + values[b]) * 0.5;
+    }
+    out
+}
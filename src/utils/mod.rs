@@ -1,5 +1,20 @@
+pub mod blue_noise;
+pub mod curve;
 pub mod errors;
+#[cfg(feature = "gltf")]
+pub mod gltf;
 pub mod image;
+pub mod logging;
+pub mod lsystem;
+pub mod mesh;
+pub mod noise;
+pub mod obj;
+pub mod procedural;
+pub mod scatter;
+pub mod terrain;
+pub mod time;
+#[cfg(feature = "vdb")]
+pub mod vdb;
 
 
 
@@ -0,0 +1,41 @@
+use glam::{Mat2, Vec2};
+
+/// Pseudo-random value in `[0, 1)` for an integer lattice point.
+fn hash(x: i32, y: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343) ^ (y as u32).wrapping_mul(0xd816_3841);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b_3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a_2d39);
+    h ^= h >> 15;
+    (h >> 8) as f32 / (1 << 24) as f32
+}
+
+/// Value noise in `[0, 1)`: random values on the integer lattice, blended smoothly.
+pub fn value_noise(p: Vec2) -> f32 {
+    let cell = p.floor();
+    let f = p - cell;
+    let w = f * f * (3. - 2. * f);
+    let (x, y) = (cell.x as i32, cell.y as i32);
+
+    let top = hash(x, y) + (hash(x + 1, y) - hash(x, y)) * w.x;
+    let bottom = hash(x, y + 1) + (hash(x + 1, y + 1) - hash(x, y + 1)) * w.x;
+    top + (bottom - top) * w.y
+}
+
+/// Fractal Brownian motion in `[0, 1)`: `octaves` layers of value noise, each at twice the
+/// frequency and half the amplitude of the previous one.
+pub fn fbm(p: Vec2, octaves: u32) -> f32 {
+    // Rotating every octave keeps the lattice axes from lining up between layers.
+    let rotation = Mat2::from_cols_array(&[1.6, 1.2, -1.2, 1.6]);
+    let mut p = p;
+    let mut amplitude = 0.5;
+    let (mut sum, mut total) = (0., 0.);
+    for _ in 0..octaves.max(1) {
+        sum += value_noise(p) * amplitude;
+        total += amplitude;
+        p = rotation * p;
+        amplitude *= 0.5;
+    }
+    sum / total
+}
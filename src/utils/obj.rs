@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use glam::{Vec2, Vec3};
+
+use super::errors::AppError;
+use super::mesh::{Mesh, Shading};
+use super::time::Instant;
+
+pub struct ObjUtils {}
+
+impl ObjUtils {
+    /// Loads the faces of a Wavefront OBJ file as one mesh, polygons split in fans. Vertex
+    /// colors after the positions, texture coordinates and normals are kept when present.
+    /// Files without normals get flat faces, or smooth ones within their `s` groups.
+    pub fn load_obj(path: impl Into<String>) -> Result<Mesh, AppError> {
+        let p: String = path.into();
+        let start = Instant::now();
+        let text = std::fs::read_to_string(&p)?;
+
+        let mut positions: Vec<Vec3> = vec![];
+        let mut colors: Vec<Vec3> = vec![];
+        let mut uvs: Vec<Vec2> = vec![];
+        let mut normals: Vec<Vec3> = vec![];
+
+        let mut mesh = Mesh::default();
+        // Mesh vertex of each distinct position/uv pair used by the faces.
+        let mut vertices: HashMap<(usize, Option<usize>), usize> = HashMap::new();
+        let mut face_normals: Vec<Option<[Vec3; 3]>> = vec![];
+        let mut smoothing_group = 1;
+        let mut grouped = false;
+
+        for (n, line) in text.lines().enumerate() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let Some((key, args)) = words.split_first() else {
+                continue;
+            };
+
+            let bad_line = || AppError::Parse {
+                path: p.clone(),
+                line: n + 1,
+                message: format!("bad line '{}'", line),
+            };
+            let floats = |count: usize| -> Result<Vec<f32>, AppError> {
+                let v: Vec<f32> = args.iter().filter_map(|w| w.parse().ok()).collect();
+                if v.len() >= count && v.len() == args.len() {
+                    Ok(v)
+                } else {
+                    Err(bad_line())
+                }
+            };
+            // OBJ indices start at 1 and count back from the end when negative.
+            let index = |word: &str, len: usize| -> Result<usize, AppError> {
+                let i: i64 = word.parse().map_err(|_| bad_line())?;
+                let i = if i < 0 { len as i64 + i } else { i - 1 };
+                if i >= 0 && (i as usize) < len {
+                    Ok(i as usize)
+                } else {
+                    Err(bad_line())
+                }
+            };
+
+            match *key {
+                "v" => {
+                    let v = floats(3)?;
+                    positions.push(Vec3::new(v[0], v[1], v[2]));
+                    if v.len() >= 6 {
+                        colors.push(Vec3::new(v[3], v[4], v[5]));
+                    }
+                }
+                "vt" => {
+                    let v = floats(1)?;
+                    // OBJ puts v = 0 at the bottom of the image, textures at the top.
+                    uvs.push(Vec2::new(v[0], 1. - v.get(1).copied().unwrap_or(0.)));
+                }
+                "vn" => {
+                    let v = floats(3)?;
+                    normals.push(Vec3::new(v[0], v[1], v[2]).normalize_or_zero());
+                }
+                "s" => {
+                    grouped = true;
+                    smoothing_group = match args.first() {
+                        Some(&"off") => 0,
+                        Some(g) => g.parse().map_err(|_| bad_line())?,
+                        None => return Err(bad_line()),
+                    };
+                }
+                "f" => {
+                    if args.len() < 3 {
+                        return Err(bad_line());
+                    }
+                    let mut corners = vec![];
+                    for corner in args.iter() {
+                        let mut parts = corner.split('/');
+                        let v = index(parts.next().unwrap_or_default(), positions.len())?;
+                        let vt = match parts.next() {
+                            Some(w) if !w.is_empty() => Some(index(w, uvs.len())?),
+                            _ => None,
+                        };
+                        let vn = match parts.next() {
+                            Some(w) if !w.is_empty() => Some(index(w, normals.len())?),
+                            _ => None,
+                        };
+
+                        let vertex = *vertices.entry((v, vt)).or_insert_with(|| {
+                            mesh.positions.push(positions[v]);
+                            mesh.uvs.push(vt.map_or(Vec2::ZERO, |i| uvs[i]));
+                            mesh.colors
+                                .push(colors.get(v).copied().unwrap_or(Vec3::ONE));
+                            mesh.positions.len() - 1
+                        });
+                        corners.push((vertex, vn.map(|i| normals[i])));
+                    }
+
+                    for i in 1..corners.len() - 1 {
+                        let tri = [corners[0], corners[i], corners[i + 1]];
+                        mesh.indices.push(tri.map(|c| c.0));
+                        face_normals.push(match tri.map(|c| c.1) {
+                            [Some(a), Some(b), Some(c)] => Some([a, b, c]),
+                            _ => None,
+                        });
+                        mesh.smoothing_groups.push(smoothing_group);
+                    }
+                }
+                // Objects, groups, materials and comments.
+                _ => {}
+            }
+        }
+
+        if mesh.indices.is_empty() {
+            return Err(AppError::Validation(format!("{}: no faces", p)));
+        }
+        if uvs.is_empty() {
+            mesh.uvs.clear();
+        }
+        if colors.len() != positions.len() {
+            mesh.colors.clear();
+        }
+
+        if face_normals.iter().all(|n| n.is_some()) {
+            mesh.normals = face_normals.into_iter().flatten().collect();
+        } else if grouped {
+            mesh.compute_normals(Shading::Smooth { crease_angle: PI });
+        } else {
+            mesh.smoothing_groups.clear();
+        }
+
+        log::debug!(
+            "Loaded {} ({} vertices, {} triangles) in {:?}",
+            p,
+            mesh.positions.len(),
+            mesh.indices.len(),
+            start.elapsed()
+        );
+        Ok(mesh)
+    }
+}
@@ -0,0 +1,50 @@
+use glam::{Vec2, Vec3};
+
+use crate::objects::Texture;
+
+use super::noise::fbm;
+
+/// Textures computed from their UV coordinates rather than read from an image.
+#[derive(Debug, Copy, Clone)]
+pub enum Procedural {
+    /// Squares alternating between two colours, `scale` squares along each side.
+    Checker { scale: f32, even: Vec3, odd: Vec3 },
+    /// Blend from `from` at `u` = 0 to `to` at `u` = 1.
+    Ramp { from: Vec3, to: Vec3 },
+    /// Fractal noise of `octaves` layers, blending between `low` and `high`, with about
+    /// `scale` features along each side.
+    Noise {
+        scale: f32,
+        octaves: u32,
+        low: Vec3,
+        high: Vec3,
+    },
+}
+
+impl Procedural {
+    /// Colour at `uv`, in `[0, 1)²` over the texture.
+    pub fn color(&self, uv: Vec2) -> Vec3 {
+        match *self {
+            Procedural::Checker { scale, even, odd } => {
+                let cell = (uv * scale).floor();
+                if (cell.x + cell.y).rem_euclid(2.) < 1. {
+                    even
+                } else {
+                    odd
+                }
+            }
+            Procedural::Ramp { from, to } => from.lerp(to, uv.x.clamp(0., 1.)),
+            Procedural::Noise {
+                scale,
+                octaves,
+                low,
+                high,
+            } => low.lerp(high, fbm(uv * scale, octaves)),
+        }
+    }
+
+    /// Evaluates the texture once per texel of a `width` x `height` image.
+    pub fn bake(&self, width: u32, height: u32) -> Texture {
+        Texture::bake(width, height, |uv| self.color(uv))
+    }
+}
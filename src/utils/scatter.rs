@@ -0,0 +1,382 @@
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use glam::{Affine3A, IVec2, IVec3, Quat, UVec3, Vec2, Vec3};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::objects::{Object3D, Texture};
+use crate::post;
+
+use super::mesh::Mesh;
+
+/// Random places tried per instance before `Scatter` gives up on finding room for more.
+const ATTEMPTS_PER_INSTANCE: usize = 30;
+
+/// Candidates tried around each point of `poisson_disk` before it is closed.
+const POISSON_CANDIDATES: usize = 30;
+
+/// Points per unit of area, in units of the squared spacing, that `poisson_disk` is sure
+/// to reach once nothing more fits, about 0.65 on average. Gives the widest spacing that
+/// still fits a number of points.
+const POISSON_DENSITY: f32 = 0.6;
+
+/// Where `Scatter` puts an instance.
+#[derive(Debug, Copy, Clone)]
+pub struct Placement {
+    pub position: Vec3,
+    /// Normal of the surface at `position`.
+    pub normal: Vec3,
+    /// Moves an object modelled at the origin, standing along +Y, into place, turned to a
+    /// random heading and scaled.
+    pub transform: Affine3A,
+}
+
+/// Spreads copies of an object over the faces of a mesh at random places, headings and
+/// sizes, like rocks over a terrain or trees over a hill. Faces get instances in
+/// proportion to their area.
+#[derive(Debug, Clone)]
+pub struct Scatter<'a> {
+    surface: &'a Mesh,
+    count: usize,
+    min_distance: f32,
+    density: Option<&'a Texture>,
+    scale: (f32, f32),
+    align: bool,
+    seed: u64,
+}
+
+impl<'a> Scatter<'a> {
+    pub fn new(surface: &'a Mesh, count: usize) -> Scatter<'a> {
+        Scatter {
+            surface,
+            count,
+            min_distance: 0.,
+            density: None,
+            scale: (1., 1.),
+            align: false,
+            seed: 0,
+        }
+    }
+
+    /// Keeps the instances at least `distance` apart. Fewer than `count` are placed when
+    /// the surface runs out of room.
+    pub fn min_distance(mut self, distance: f32) -> Scatter<'a> {
+        self.min_distance = distance.max(0.);
+        self
+    }
+
+    /// Thins the instances out where the luminance of `density`, read at the texture
+    /// coordinates of the surface, is low: none are placed where it is black. Ignored for
+    /// meshes without texture coordinates.
+    pub fn density(mut self, density: &'a Texture) -> Scatter<'a> {
+        self.density = Some(density);
+        self
+    }
+
+    /// Scales each instance by a random factor between `min` and `max`.
+    pub fn scale(mut self, min: f32, max: f32) -> Scatter<'a> {
+        self.scale = (min, max);
+        self
+    }
+
+    /// Stands the instances along the surface normal instead of straight up.
+    pub fn align_to_normal(mut self, align: bool) -> Scatter<'a> {
+        self.align = align;
+        self
+    }
+
+    /// Seeds the placement, so the same seed gives the same layout.
+    pub fn seed(mut self, seed: u64) -> Scatter<'a> {
+        self.seed = seed;
+        self
+    }
+
+    pub fn placements(&self) -> Vec<Placement> {
+        let mesh = self.surface;
+        // Running sum of the face areas, to pick faces in proportion to them.
+        let mut total = 0.;
+        let areas: Vec<f32> = (0..mesh.indices.len())
+            .map(|face| {
+                let (a, b, c) = mesh.triangle(face);
+                total += (b - a).cross(c - a).length() * 0.5;
+                total
+            })
+            .collect();
+        if total <= 0. {
+            return vec![];
+        }
+
+        let mut rnd = StdRng::seed_from_u64(self.seed);
+        let mut placed: Vec<Placement> = vec![];
+        // Instances by cell of a grid `min_distance` wide, so only the neighbouring cells
+        // are searched for instances too close.
+        let mut cells: HashMap<IVec3, Vec<usize>> = HashMap::new();
+        let cell = |p: Vec3| (p / self.min_distance).floor().as_ivec3();
+
+        for _ in 0..self.count * ATTEMPTS_PER_INSTANCE {
+            if placed.len() >= self.count {
+                break;
+            }
+            let pick = rnd.gen::<f32>() * total;
+            let face = areas
+                .partition_point(|sum| *sum < pick)
+                .min(areas.len() - 1);
+            let (mut s, mut t) = (rnd.gen::<f32>(), rnd.gen::<f32>());
+            if s + t > 1. {
+                (s, t) = (1. - s, 1. - t);
+            }
+            let [i, j, k] = mesh.indices[face];
+            let (a, b, c) = mesh.triangle(face);
+            let position = a + (b - a) * s + (c - a) * t;
+
+            if let (Some(density), false) = (self.density, mesh.uvs.is_empty()) {
+                let uv =
+                    mesh.uvs[i] + (mesh.uvs[j] - mesh.uvs[i]) * s + (mesh.uvs[k] - mesh.uvs[i]) * t;
+                if rnd.gen::<f32>() >= post::luminance(density.sample(uv.x, uv.y, 0.)) {
+                    continue;
+                }
+            }
+
+            if self.min_distance > 0. {
+                let home = cell(position);
+                let crowded = (0..27)
+                    .map(|n| home + IVec3::new(n % 3 - 1, n / 3 % 3 - 1, n / 9 - 1))
+                    .filter_map(|near| cells.get(&near))
+                    .flatten()
+                    .any(|n| placed[*n].position.distance(position) < self.min_distance);
+                if crowded {
+                    continue;
+                }
+                cells.entry(home).or_default().push(placed.len());
+            }
+
+            let normal = match mesh.normals.get(face) {
+                Some([na, nb, nc]) => *na + (*nb - *na) * s + (*nc - *na) * t,
+                None => (b - a).cross(c - a),
+            }
+            .normalize_or(Vec3::Y);
+            let up = if self.align { normal } else { Vec3::Y };
+            let rotation = Quat::from_rotation_arc(Vec3::Y, up)
+                * Quat::from_rotation_y(rnd.gen::<f32>() * 2. * PI);
+            let (min, max) = self.scale;
+            let scale = min + (max - min) * rnd.gen::<f32>();
+            placed.push(Placement {
+                position,
+                normal,
+                transform: Affine3A::from_scale_rotation_translation(
+                    Vec3::splat(scale),
+                    rotation,
+                    position,
+                ),
+            });
+        }
+        placed
+    }
+
+    /// Copies of `object`, modelled at the origin standing along +Y, at every placement.
+    pub fn instances(&self, object: &Object3D) -> Vec<Object3D> {
+        self.placements()
+            .iter()
+            .map(|p| object.transformed(&p.transform))
+            .collect()
+    }
+}
+
+/// Copies of `object` on a grid of `counts` along X, Y and Z, `spacing` apart and centered
+/// on the object.
+pub fn grid(object: &Object3D, counts: UVec3, spacing: Vec3) -> Vec<Object3D> {
+    let center = (counts.max(UVec3::ONE) - UVec3::ONE).as_vec3() * spacing * 0.5;
+    let mut objects = Vec::with_capacity((counts.x * counts.y * counts.z) as usize);
+    for x in 0..counts.x {
+        for y in 0..counts.y {
+            for z in 0..counts.z {
+                let offset = UVec3::new(x, y, z).as_vec3() * spacing - center;
+                objects.push(object.transformed(&Affine3A::from_translation(offset)));
+            }
+        }
+    }
+    objects
+}
+
+/// Points of the rectangle from `min` to `max` no closer than `spacing` to each other,
+/// added until no gap is left for another (Bridson's algorithm). Unlike independent
+/// random points they never clump, and unlike a grid they show no pattern.
+pub fn poisson_disk(min: Vec2, max: Vec2, spacing: f32, rnd: &mut impl Rng) -> Vec<Vec2> {
+    let size = max - min;
+    if spacing <= 0. || size.x <= 0. || size.y <= 0. {
+        return vec![];
+    }
+    // Cells small enough to hold a single point each.
+    let cell_size = spacing / 2f32.sqrt();
+    let cells = (size / cell_size).ceil().as_ivec2();
+    let mut grid: Vec<Option<usize>> = vec![None; (cells.x * cells.y) as usize];
+    let cell = |p: Vec2| {
+        ((p - min) / cell_size)
+            .as_ivec2()
+            .clamp(IVec2::ZERO, cells - 1)
+    };
+    let index = |c: IVec2| (c.y * cells.x + c.x) as usize;
+
+    let first = min + size * Vec2::new(rnd.gen(), rnd.gen());
+    let mut points = vec![first];
+    grid[index(cell(first))] = Some(0);
+    let mut active = vec![0];
+
+    while !active.is_empty() {
+        let slot = rnd.gen_range(0..active.len());
+        let center = points[active[slot]];
+        // Candidates in the ring between one and two spacings away.
+        let found = (0..POISSON_CANDIDATES).find_map(|_| {
+            let angle = rnd.gen::<f32>() * 2. * PI;
+            let distance = spacing * (1. + rnd.gen::<f32>());
+            let candidate = center + Vec2::from_angle(angle) * distance;
+            if candidate.cmplt(min).any() || candidate.cmpge(max).any() {
+                return None;
+            }
+            let home = cell(candidate);
+            let crowded = (-2..=2)
+                .flat_map(|y| (-2..=2).map(move |x| home + IVec2::new(x, y)))
+                .filter(|c| c.cmpge(IVec2::ZERO).all() && c.cmplt(cells).all())
+                .filter_map(|c| grid[index(c)])
+                .any(|p| points[p].distance(candidate) < spacing);
+            (!crowded).then_some(candidate)
+        });
+        match found {
+            Some(candidate) => {
+                grid[index(cell(candidate))] = Some(points.len());
+                active.push(points.len());
+                points.push(candidate);
+            }
+            None => {
+                active.swap_remove(slot);
+            }
+        }
+    }
+    points
+}
+
+/// Spreads copies of an object over a rectangle of the ground plane with Poisson-disk
+/// spacing, at random headings and sizes and in materials picked from a palette, for
+/// fields of rocks, spheres or trees.
+#[derive(Debug, Clone)]
+pub struct GroundScatter {
+    min: Vec2,
+    max: Vec2,
+    height: f32,
+    count: usize,
+    spacing: Option<f32>,
+    scale: (f32, f32),
+    palette: Vec<usize>,
+    seed: u64,
+}
+
+impl GroundScatter {
+    /// `count` copies between the `min` and `max` X and Z coordinates of the plane y = 0.
+    pub fn new(min: Vec2, max: Vec2, count: usize) -> GroundScatter {
+        GroundScatter {
+            min,
+            max,
+            height: 0.,
+            count,
+            spacing: None,
+            scale: (1., 1.),
+            palette: vec![],
+            seed: 0,
+        }
+    }
+
+    /// Height of the ground plane.
+    pub fn height(mut self, height: f32) -> GroundScatter {
+        self.height = height;
+        self
+    }
+
+    /// Least distance between two copies. Defaults to the widest spacing that still fits
+    /// `count` of them; fewer are placed when a wider one is given.
+    pub fn spacing(mut self, spacing: f32) -> GroundScatter {
+        self.spacing = Some(spacing);
+        self
+    }
+
+    /// Scales each copy by a random factor between `min` and `max`.
+    pub fn scale(mut self, min: f32, max: f32) -> GroundScatter {
+        self.scale = (min, max);
+        self
+    }
+
+    /// Materials the copies are drawn with, one picked at random for each. The copies
+    /// keep the material of the object when empty.
+    pub fn palette(mut self, materials: Vec<usize>) -> GroundScatter {
+        self.palette = materials;
+        self
+    }
+
+    /// Seeds the placement, so the same seed gives the same field.
+    pub fn seed(mut self, seed: u64) -> GroundScatter {
+        self.seed = seed;
+        self
+    }
+
+    /// Places the copies, each with the material it is drawn with, if picked from the
+    /// palette.
+    fn layout(&self) -> Vec<(Placement, Option<usize>)> {
+        let size = self.max - self.min;
+        let area = size.x * size.y;
+        if self.count == 0 || area <= 0. {
+            return vec![];
+        }
+        let spacing = self
+            .spacing
+            .unwrap_or_else(|| (POISSON_DENSITY * area / self.count as f32).sqrt());
+        let mut rnd = StdRng::seed_from_u64(self.seed);
+        let mut points = poisson_disk(self.min, self.max, spacing, &mut rnd);
+        // The points grow out from the first one: a random subset keeps the field even.
+        let kept = self.count.min(points.len());
+        for i in 0..kept {
+            let j = rnd.gen_range(i..points.len());
+            points.swap(i, j);
+        }
+        points.truncate(kept);
+
+        let (min, max) = self.scale;
+        points
+            .into_iter()
+            .map(|p| {
+                let position = Vec3::new(p.x, self.height, p.y);
+                let rotation = Quat::from_rotation_y(rnd.gen::<f32>() * 2. * PI);
+                let scale = min + (max - min) * rnd.gen::<f32>();
+                let material = (!self.palette.is_empty())
+                    .then(|| self.palette[rnd.gen_range(0..self.palette.len())]);
+                let placement = Placement {
+                    position,
+                    normal: Vec3::Y,
+                    transform: Affine3A::from_scale_rotation_translation(
+                        Vec3::splat(scale),
+                        rotation,
+                        position,
+                    ),
+                };
+                (placement, material)
+            })
+            .collect()
+    }
+
+    pub fn placements(&self) -> Vec<Placement> {
+        self.layout().into_iter().map(|(p, _)| p).collect()
+    }
+
+    /// Copies of `object`, modelled at the origin resting on y = 0, at every placement.
+    pub fn instances(&self, object: &Object3D) -> Vec<Object3D> {
+        self.layout()
+            .into_iter()
+            .map(|(placement, material)| {
+                let moved = object.transformed(&placement.transform);
+                match material {
+                    Some(material) => moved.with_material(material),
+                    None => moved,
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,146 @@
+use glam::{Vec2, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::accel::Aabb;
+use crate::ray::{Ray, RayHit, EPSILON};
+
+use super::noise::fbm;
+
+/// Most steps a ray takes over a terrain before giving up.
+const MAX_STEPS: usize = 512;
+/// Bisection steps refining a hit once a ray went below the surface.
+const REFINE_STEPS: usize = 8;
+
+/// A heightfield of fractal noise, ray marched instead of tessellated. Hits are coloured
+/// by slope and altitude through `RayHit::color`, so the material only adds its lighting
+/// properties.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Terrain {
+    /// Corner of the terrain with the lowest x and z, at the bottom of the valleys.
+    pub origin: Vec3,
+    /// Extent along x and z.
+    pub size: Vec2,
+    /// Height of the highest peaks above `origin`.
+    pub height: f32,
+    /// Horizontal distance between the largest hills.
+    pub feature_size: f32,
+    pub octaves: u32,
+    pub grass_color: Vec3,
+    pub rock_color: Vec3,
+    pub snow_color: Vec3,
+    /// Cosine of the slope from which grass gives way to rock.
+    pub rock_slope: f32,
+    /// Share of `height` above which flat enough ground is covered in snow.
+    pub snow_line: f32,
+}
+
+impl Default for Terrain {
+    fn default() -> Self {
+        Self {
+            origin: Vec3::new(-50., -2., -50.),
+            size: Vec2::splat(100.),
+            height: 12.,
+            feature_size: 25.,
+            octaves: 6,
+            grass_color: Vec3::new(0.18, 0.3, 0.1),
+            rock_color: Vec3::new(0.35, 0.3, 0.27),
+            snow_color: Vec3::new(0.9, 0.92, 0.95),
+            rock_slope: 0.8,
+            snow_line: 0.65,
+        }
+    }
+}
+
+impl Terrain {
+    /// Ground height at `(x, z)`, in world units.
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let p = Vec2::new(x - self.origin.x, z - self.origin.z) / self.feature_size;
+        self.origin.y + fbm(p, self.octaves) * self.height
+    }
+
+    pub fn normal_at(&self, x: f32, z: f32) -> Vec3 {
+        let e = self.feature_size * 1e-3;
+        let dx = self.height_at(x + e, z) - self.height_at(x - e, z);
+        let dz = self.height_at(x, z + e) - self.height_at(x, z - e);
+        Vec3::new(-dx, 2. * e, -dz).normalize()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb::new(
+            self.origin,
+            self.origin + Vec3::new(self.size.x, self.height, self.size.y),
+        )
+    }
+
+    /// Grass on gentle slopes, rock on steep ones and snow on high, flat enough ground.
+    pub fn color_at(&self, point: Vec3, normal: Vec3) -> Vec3 {
+        let smoothstep = |e0: f32, e1: f32, x: f32| {
+            let t = ((x - e0) / (e1 - e0)).clamp(0., 1.);
+            t * t * (3. - 2. * t)
+        };
+        let altitude = (point.y - self.origin.y) / self.height;
+        let rock = 1. - smoothstep(self.rock_slope - 0.1, self.rock_slope + 0.05, normal.y);
+        let snow = smoothstep(self.snow_line, self.snow_line + 0.1, altitude)
+            * smoothstep(self.rock_slope - 0.2, self.rock_slope, normal.y);
+        self.grass_color
+            .lerp(self.rock_color, rock)
+            .lerp(self.snow_color, snow)
+    }
+
+    /// Marches `ray` over the heightfield with steps as long as a fraction of its height
+    /// above the ground, then refines the crossing by bisection. Rays starting below the
+    /// surface only hit it after coming back above.
+    pub fn intersect(&self, ray: &Ray, material_index: usize) -> Option<RayHit> {
+        let (t_enter, t_exit) = self.bounds().hit(ray, f32::MAX)?;
+        let min_step = self.feature_size * 1e-3;
+
+        let above = |t: f32| {
+            let p = ray.at(t);
+            p.y - self.height_at(p.x, p.z)
+        };
+
+        let mut t = t_enter.max(EPSILON);
+        let mut previous: Option<f32> = None;
+        for _ in 0..MAX_STEPS {
+            if t > t_exit {
+                return None;
+            }
+            let h = above(t);
+            if h < 0. {
+                if let Some(mut low) = previous {
+                    let mut high = t;
+                    for _ in 0..REFINE_STEPS {
+                        let mid = 0.5 * (low + high);
+                        if above(mid) < 0. {
+                            high = mid;
+                        } else {
+                            low = mid;
+                        }
+                    }
+                    return Some(self.hit_at(ray, high, material_index));
+                }
+            } else {
+                previous = Some(t);
+            }
+            t += (h.abs() * 0.4).max(min_step * (1. + t * 0.05));
+        }
+        None
+    }
+
+    fn hit_at(&self, ray: &Ray, t: f32, material_index: usize) -> RayHit {
+        let point = ray.at(t);
+        let normal = self.normal_at(point.x, point.z);
+        RayHit {
+            distance: t,
+            point,
+            normal,
+            material_index,
+            u: (point.x - self.origin.x) / self.size.x,
+            v: (point.z - self.origin.z) / self.size.y,
+            color: Some(self.color_at(point, normal)),
+            uv_density: (self.size.x * self.size.y).sqrt().recip(),
+            ..Default::default()
+        }
+    }
+}
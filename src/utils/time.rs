@@ -0,0 +1,37 @@
+//! `std::time` everywhere but WebAssembly, where `Instant::now` would panic: the clock
+//! there is `rt_now` of the page, as provided by `web/ray_tracing.js`.
+
+pub use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use std::time::Instant;
+
+#[cfg(target_arch = "wasm32")]
+pub use self::wasm::Instant;
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use super::Duration;
+
+    extern "C" {
+        /// Milliseconds since the page loaded, `performance.now()`.
+        fn rt_now() -> f64;
+    }
+
+    #[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+    pub struct Instant(f64);
+
+    impl Instant {
+        pub fn now() -> Instant {
+            Instant(unsafe { rt_now() })
+        }
+
+        pub fn duration_since(&self, earlier: Instant) -> Duration {
+            Duration::from_secs_f64((self.0 - earlier.0).max(0.) / 1000.)
+        }
+
+        pub fn elapsed(&self) -> Duration {
+            Instant::now().duration_since(*self)
+        }
+    }
+}
@@ -0,0 +1,560 @@
+use std::io::Read;
+
+use glam::{Affine3A, DVec3, IVec3, Mat4};
+
+use crate::volume::DensityGrid;
+
+use super::errors::AppError;
+use super::time::Instant;
+
+const MAGIC: i64 = 0x5644_4220;
+/// First file format with the compression settings stored per grid and the node masks
+/// compressed, written by OpenVDB 2.0 and later.
+const MIN_VERSION: u32 = 222;
+const GRID_TYPE: &str = "Tree_float_5_4_3";
+const HALF_FLOAT_SUFFIX: &str = "_HalfFloat";
+
+const COMPRESS_ZIP: u32 = 0x1;
+const COMPRESS_ACTIVE_MASK: u32 = 0x2;
+const COMPRESS_BLOSC: u32 = 0x4;
+
+/// `log2` of the side of the internal nodes below the root, and of their children.
+const LEVELS: [(u32, u32); 2] = [(5, 7), (4, 3)];
+const LEAF_VOXELS: usize = 512;
+
+pub struct VdbUtils {}
+
+impl VdbUtils {
+    /// Loads the float grid named `grid_name` of an OpenVDB file, or its first float grid
+    /// when the name is empty, such as the density of a smoke or cloud asset. The file
+    /// can be uncompressed or zip compressed, with the grid saved as 32 or 16 bit floats.
+    pub fn load_grid(path: &str, grid_name: &str) -> Result<DensityGrid, AppError> {
+        let start = Instant::now();
+        let bytes = std::fs::read(path)?;
+        let grid = Self::read_grid(&bytes, grid_name).map_err(|message| AppError::VolumeLoad {
+            path: path.to_string(),
+            message,
+        })?;
+        log::debug!(
+            "Loaded VDB grid '{}' of {} in {:?}",
+            grid_name,
+            path,
+            start.elapsed()
+        );
+        Ok(grid)
+    }
+
+    /// Like `load_grid`, from the bytes of a file.
+    pub fn read_grid(bytes: &[u8], grid_name: &str) -> Result<DensityGrid, String> {
+        let mut r = Reader { bytes, pos: 0 };
+        if r.i64()? != MAGIC {
+            return Err("not a VDB file".to_string());
+        }
+        let version = r.u32()?;
+        if version < MIN_VERSION {
+            return Err(format!("file format {} is too old", version));
+        }
+        // Library version, then whether the grids can be found from their offsets.
+        r.skip(8)?;
+        if r.u8()? == 0 {
+            return Err("streamed files without grid offsets are not supported".to_string());
+        }
+        // UUID, as 36 ASCII characters.
+        r.skip(36)?;
+        r.skip_metadata()?;
+
+        let grids = r.u32()?;
+        for _ in 0..grids {
+            let unique_name = r.string()?;
+            // Grids sharing a name are told apart by a suffix after a record separator.
+            let name = unique_name.split('\x1e').next().unwrap_or_default();
+            let grid_type = r.string()?;
+            let instance_parent = r.string()?;
+            let grid_pos = r.i64()?;
+            let _block_pos = r.i64()?;
+            let end_pos = r.i64()?;
+
+            let half = grid_type.ends_with(HALF_FLOAT_SUFFIX);
+            let grid_type = grid_type.trim_end_matches(HALF_FLOAT_SUFFIX);
+            let wanted = grid_name.is_empty() || name == grid_name;
+            if wanted && grid_type == GRID_TYPE && instance_parent.is_empty() {
+                r.seek(grid_pos)?;
+                return r.grid(half);
+            }
+            if !grid_name.is_empty() && name == grid_name {
+                return Err(format!(
+                    "grid '{}' is a {}, not a float grid",
+                    name, grid_type
+                ));
+            }
+            r.seek(end_pos)?;
+        }
+        Err(match grid_name {
+            "" => "no float grid".to_string(),
+            name => format!("no grid named '{}'", name),
+        })
+    }
+}
+
+/// A leaf or a tile of active voxels of the same value, read from the tree.
+struct Block {
+    origin: IVec3,
+    /// Voxels along each side.
+    size: i32,
+    values: Vec<f32>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], String> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.bytes.len());
+        let end = end.ok_or_else(|| "unexpected end of file".to_string())?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap_or([0; N]))
+    }
+
+    fn skip(&mut self, n: usize) -> Result<(), String> {
+        self.take(n).map(|_| ())
+    }
+
+    fn seek(&mut self, pos: i64) -> Result<(), String> {
+        if pos < 0 || pos as usize > self.bytes.len() {
+            return Err(format!("bad offset {}", pos));
+        }
+        self.pos = pos as usize;
+        Ok(())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.array().map(u32::from_le_bytes)
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        self.array().map(i32::from_le_bytes)
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        self.array().map(i64::from_le_bytes)
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        self.array().map(f32::from_le_bytes)
+    }
+
+    fn dvec3(&mut self) -> Result<DVec3, String> {
+        let mut v = [0.; 3];
+        for c in v.iter_mut() {
+            *c = f64::from_le_bytes(self.array()?);
+        }
+        Ok(DVec3::from_array(v))
+    }
+
+    fn coord(&mut self) -> Result<IVec3, String> {
+        Ok(IVec3::new(self.i32()?, self.i32()?, self.i32()?))
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    /// Skips a map of metadata: every value is preceded by its size.
+    fn skip_metadata(&mut self) -> Result<(), String> {
+        for _ in 0..self.u32()? {
+            let _name = self.string()?;
+            let _type = self.string()?;
+            let size = self.u32()? as usize;
+            self.skip(size)?;
+        }
+        Ok(())
+    }
+
+    /// A node mask of `bits` bits.
+    fn mask(&mut self, bits: usize) -> Result<Vec<u64>, String> {
+        (0..bits / 64)
+            .map(|_| self.array().map(u64::from_le_bytes))
+            .collect()
+    }
+
+    fn grid(&mut self, half: bool) -> Result<DensityGrid, String> {
+        let compression = self.u32()?;
+        if compression & COMPRESS_BLOSC != 0 {
+            return Err("blosc compression is not supported, save the file with zip".to_string());
+        }
+        self.skip_metadata()?;
+        let to_world = self.transform()?;
+
+        // Buffer count, always 1.
+        self.skip(4)?;
+        let background = self.f32()?;
+        let (tiles, children) = (self.u32()?, self.u32()?);
+        for _ in 0..tiles {
+            let origin = self.coord()?;
+            let (value, active) = (self.f32()?, self.u8()? != 0);
+            if active && value != 0. {
+                log::warn!("Ignoring a VDB root tile of {} at {}", value, origin);
+            }
+        }
+
+        let mut tree = Tree {
+            compression,
+            half,
+            background,
+            leaves: vec![],
+            tiles: vec![],
+        };
+        for _ in 0..children {
+            let origin = self.coord()?;
+            self.internal_topology(&mut tree, origin, 0)?;
+        }
+
+        // The leaf values follow the topology of the whole tree, in the same order.
+        let mut blocks = std::mem::take(&mut tree.tiles);
+        for origin in std::mem::take(&mut tree.leaves) {
+            let mask = self.mask(LEAF_VOXELS)?;
+            let values = self.values(&tree, LEAF_VOXELS, &mask)?;
+            blocks.push(Block {
+                origin,
+                size: 8,
+                values,
+            });
+        }
+
+        let Some(min) = blocks.iter().map(|b| b.origin).reduce(IVec3::min) else {
+            return Ok(DensityGrid::new(IVec3::ZERO, IVec3::ZERO, to_world));
+        };
+        let max = blocks
+            .iter()
+            .map(|b| b.origin + b.size - 1)
+            .fold(min, IVec3::max);
+        let mut grid = DensityGrid::new(min, max, to_world);
+        for block in blocks {
+            if block.values.len() == LEAF_VOXELS {
+                if let Some(voxels) = grid.block_mut(block.origin) {
+                    voxels.copy_from_slice(&block.values);
+                }
+                continue;
+            }
+            // Tiles span whole 8x8x8 blocks.
+            let blocks_per_side = block.size / 8;
+            for i in 0..blocks_per_side.pow(3) {
+                let offset = IVec3::new(
+                    i / (blocks_per_side * blocks_per_side),
+                    i / blocks_per_side % blocks_per_side,
+                    i % blocks_per_side,
+                );
+                if let Some(voxels) = grid.block_mut(block.origin + offset * 8) {
+                    voxels.fill(block.values[0]);
+                }
+            }
+        }
+        Ok(grid)
+    }
+
+    /// Index space to world space, for the linear maps.
+    fn transform(&mut self) -> Result<Affine3A, String> {
+        let map = self.string()?;
+        let (translation, scale) = match map.as_str() {
+            "UniformScaleMap" | "ScaleMap" => (DVec3::ZERO, self.dvec3()?),
+            "UniformScaleTranslateMap" | "ScaleTranslateMap" => (self.dvec3()?, self.dvec3()?),
+            "TranslationMap" => (self.dvec3()?, DVec3::ONE),
+            "AffineMap" | "UnitaryMap" => {
+                // Row major, translation in the last row, which reads as column major in
+                // glam conventions.
+                let mut m = [0.; 16];
+                for c in m.iter_mut() {
+                    *c = f64::from_le_bytes(self.array()?) as f32;
+                }
+                return Ok(Affine3A::from_mat4(Mat4::from_cols_array(&m)));
+            }
+            map => return Err(format!("unsupported transform {}", map)),
+        };
+        if map != "TranslationMap" {
+            // Voxel size, inverse scale, inverse scale squared and half inverse scale.
+            self.skip(4 * 3 * 8)?;
+        }
+        Ok(Affine3A::from_scale_rotation_translation(
+            scale.as_vec3(),
+            Default::default(),
+            translation.as_vec3(),
+        ))
+    }
+
+    /// Reads the masks and tiles of an internal node at `level` below the root, then its
+    /// children, collecting the tiles and the leaf origins.
+    fn internal_topology(
+        &mut self,
+        tree: &mut Tree,
+        origin: IVec3,
+        level: usize,
+    ) -> Result<(), String> {
+        let (log2, child_log2) = LEVELS[level];
+        let slots = 1usize << (3 * log2);
+        let child_mask = self.mask(slots)?;
+        let value_mask = self.mask(slots)?;
+        let values = self.values(tree, slots, &value_mask)?;
+
+        let slot_origin = |i: usize| {
+            let n = 1usize << log2;
+            let local = IVec3::new((i / (n * n)) as i32, (i / n % n) as i32, (i % n) as i32);
+            origin + local * (1 << child_log2)
+        };
+        for (i, value) in values.into_iter().enumerate() {
+            if !bit(&child_mask, i) && bit(&value_mask, i) && value != 0. {
+                tree.tiles.push(Block {
+                    origin: slot_origin(i),
+                    size: 1 << child_log2,
+                    values: vec![value],
+                });
+            }
+        }
+        for i in (0..slots).filter(|i| bit(&child_mask, *i)) {
+            if level + 1 < LEVELS.len() {
+                self.internal_topology(tree, slot_origin(i), level + 1)?;
+            } else {
+                // Leaf topology: its value mask, read again with its values.
+                self.mask(LEAF_VOXELS)?;
+                tree.leaves.push(slot_origin(i));
+            }
+        }
+        Ok(())
+    }
+
+    /// `count` values of a node whose active voxels are in `mask`. Compressed nodes leave
+    /// out the inactive values, which are the background or given by a selection mask.
+    fn values(&mut self, tree: &Tree, count: usize, mask: &[u64]) -> Result<Vec<f32>, String> {
+        let metadata = self.u8()?;
+        let mut inactive = [
+            if metadata == 0 {
+                tree.background
+            } else {
+                -tree.background
+            },
+            tree.background,
+        ];
+        if matches!(metadata, 2 | 4 | 5) {
+            inactive[0] = self.f32()?;
+        }
+        if metadata == 5 {
+            inactive[1] = self.f32()?;
+        }
+        let selection = if matches!(metadata, 3..=5) {
+            self.mask(count)?
+        } else {
+            vec![0; count / 64]
+        };
+
+        let stored = if tree.compression & COMPRESS_ACTIVE_MASK != 0 && metadata != 6 {
+            (0..count).filter(|i| bit(mask, *i)).count()
+        } else {
+            count
+        };
+        let size = if tree.half { 2 } else { 4 };
+        let len = stored * size;
+        // Zipped data starts with its size, negative when it was left uncompressed.
+        let zipped = match tree.compression & COMPRESS_ZIP {
+            0 => 0,
+            _ => self.i64()?,
+        };
+        let data = if zipped > 0 {
+            let mut data = vec![0u8; len];
+            flate2::read::ZlibDecoder::new(self.take(zipped as usize)?)
+                .read_exact(&mut data)
+                .map_err(|e| e.to_string())?;
+            data
+        } else {
+            self.take(len)?.to_vec()
+        };
+        let stored: Vec<f32> = if tree.half {
+            data.chunks_exact(2)
+                .map(|b| half_to_f32(u16::from_le_bytes([b[0], b[1]])))
+                .collect()
+        } else {
+            data.chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect()
+        };
+        if stored.len() == count {
+            return Ok(stored);
+        }
+
+        let mut stored = stored.into_iter();
+        Ok((0..count)
+            .map(|i| match bit(mask, i) {
+                true => stored.next().unwrap_or_default(),
+                false => inactive[bit(&selection, i) as usize],
+            })
+            .collect())
+    }
+}
+
+/// Settings of the grid being read, and what was read of its tree so far.
+struct Tree {
+    compression: u32,
+    half: bool,
+    background: f32,
+    /// Origins of the leaves, in the order of their values.
+    leaves: Vec<IVec3>,
+    tiles: Vec<Block>,
+}
+
+fn bit(mask: &[u64], i: usize) -> bool {
+    mask[i / 64] >> (i % 64) & 1 != 0
+}
+
+/// Converts an IEEE 754 half precision float.
+fn half_to_f32(h: u16) -> f32 {
+    let sign = ((h >> 15) as u32) << 31;
+    let exponent = ((h >> 10) & 0x1f) as u32;
+    let mantissa = (h & 0x3ff) as u32;
+    let bits = match exponent {
+        0 if mantissa == 0 => sign,
+        // Subnormal: normalize the mantissa.
+        0 => {
+            let shift = mantissa.leading_zeros() - 21;
+            sign | ((113 - shift) << 23) | (((mantissa << shift) & 0x3ff) << 13)
+        }
+        0x1f => sign | 0x7f80_0000 | (mantissa << 13),
+        e => sign | ((e + 112) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use glam::{vec3, IVec3};
+
+    use super::*;
+
+    /// Values of a node, with only the active ones stored.
+    fn values(out: &mut Vec<u8>, values: &[f32], compression: u32, half: bool) {
+        out.push(0);
+        let mut data = vec![];
+        for v in values {
+            match half {
+                // Halves of the powers of 2 used here.
+                true => data.extend(((v.log2() as i32 + 15) as u16 * 0x400).to_le_bytes()),
+                false => data.extend(v.to_le_bytes()),
+            }
+        }
+        if compression & COMPRESS_ZIP != 0 {
+            let mut zip = flate2::write::ZlibEncoder::new(vec![], Default::default());
+            zip.write_all(&data).unwrap();
+            let zipped = zip.finish().unwrap();
+            out.extend((zipped.len() as i64).to_le_bytes());
+            out.extend(zipped);
+        } else {
+            out.extend(data);
+        }
+    }
+
+    fn mask(out: &mut Vec<u8>, bits: usize, on: &[usize]) {
+        let mut words = vec![0u64; bits / 64];
+        for i in on {
+            words[i / 64] |= 1 << (i % 64);
+        }
+        words.iter().for_each(|w| out.extend(w.to_le_bytes()));
+    }
+
+    fn string(out: &mut Vec<u8>, s: &str) {
+        out.extend((s.len() as u32).to_le_bytes());
+        out.extend(s.as_bytes());
+    }
+
+    /// A file with a grid of a leaf at the origin holding 1 at voxel (1, 2, 3), next to a
+    /// tile of 0.25, its index space scaled by 0.5 and moved by (1, 2, 3).
+    fn file(compression: u32, half: bool) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend(MAGIC.to_le_bytes());
+        out.extend(224u32.to_le_bytes());
+        out.extend([11u32.to_le_bytes(), 0u32.to_le_bytes()].concat());
+        out.push(1);
+        out.extend([b'0'; 36]);
+        out.extend([0u32.to_le_bytes(), 1u32.to_le_bytes()].concat());
+
+        string(&mut out, "density");
+        let suffix = if half { HALF_FLOAT_SUFFIX } else { "" };
+        string(&mut out, &format!("{}{}", GRID_TYPE, suffix));
+        string(&mut out, "");
+        out.extend((out.len() as i64 + 24).to_le_bytes());
+        out.extend([0; 16]);
+
+        out.extend(compression.to_le_bytes());
+        out.extend(0u32.to_le_bytes());
+        string(&mut out, "ScaleTranslateMap");
+        for v in [1., 2., 3., 0.5, 0.5, 0.5].into_iter().chain([0.; 12]) {
+            out.extend(f64::to_le_bytes(v));
+        }
+        out.extend(1i32.to_le_bytes());
+        out.extend([0f32.to_le_bytes(), 0u32.to_le_bytes(), 1u32.to_le_bytes()].concat());
+        out.extend([0; 12]);
+        mask(&mut out, 1 << 15, &[0]);
+        mask(&mut out, 1 << 15, &[]);
+        values(&mut out, &[], compression, half);
+        mask(&mut out, 1 << 12, &[0]);
+        mask(&mut out, 1 << 12, &[1]);
+        values(&mut out, &[0.25], compression, half);
+        let voxel = (1 << 6) | (2 << 3) | 3;
+        mask(&mut out, LEAF_VOXELS, &[voxel]);
+
+        mask(&mut out, LEAF_VOXELS, &[voxel]);
+        values(&mut out, &[1.], compression, half);
+        out
+    }
+
+    #[test]
+    fn reads_leaves_and_tiles() {
+        for (compression, half) in [(2, false), (3, false), (3, true)] {
+            let grid = VdbUtils::read_grid(&file(compression, half), "").unwrap();
+            assert_eq!(grid.value(IVec3::new(1, 2, 3)), 1.);
+            assert_eq!(grid.value(IVec3::new(1, 2, 4)), 0.);
+            assert_eq!(grid.value(IVec3::new(3, 4, 12)), 0.25);
+            assert_eq!(grid.value(IVec3::new(3, 4, 16)), 0.);
+            assert_eq!(grid.sample(vec3(1.5, 3., 4.5)), 1.);
+            assert_eq!(grid.sample(vec3(1.5, 3., 4.75)), 0.5);
+        }
+    }
+
+    #[test]
+    fn finds_grids_by_name() {
+        let file = file(2, false);
+        assert!(VdbUtils::read_grid(&file, "density").is_ok());
+        assert!(VdbUtils::read_grid(&file, "temperature").is_err());
+        assert!(VdbUtils::read_grid(&file[..file.len() - 1], "").is_err());
+    }
+
+    #[test]
+    fn converts_halves() {
+        for (bits, v) in [
+            (0x0000, 0.),
+            (0x3c00, 1.),
+            (0xc000, -2.),
+            (0x3555, 0.33325195),
+            (0x7bff, 65504.),
+            (0x0400, 6.1035156e-5),
+            (0x0200, 3.0517578e-5),
+            (0x0001, 5.9604645e-8),
+            (0x7c00, f32::INFINITY),
+        ] {
+            assert_eq!(half_to_f32(bits), v, "{:x}", bits);
+        }
+    }
+}
@@ -0,0 +1,412 @@
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+use glam::{Affine3A, IVec3, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::accel::Aabb;
+use crate::ray::Ray;
+use crate::scene::Light;
+use crate::utils::errors::AppError;
+
+/// Voxels along each side of the blocks of a `DensityGrid`, as in the leaves of OpenVDB
+/// trees.
+const BLOCK: i32 = 8;
+const BLOCK_VOXELS: usize = (BLOCK * BLOCK * BLOCK) as usize;
+
+/// A sparse grid of densities, kept in blocks of 8x8x8 voxels with the empty blocks left
+/// out. Voxel centres are at integer coordinates, and densities are blended between them.
+#[derive(Debug, Clone)]
+pub struct DensityGrid {
+    /// Voxel coordinates of the first corner of the first block.
+    origin: IVec3,
+    /// Blocks along each axis.
+    size: IVec3,
+    /// Index in `blocks` of every block, `u32::MAX` for the empty ones.
+    table: Vec<u32>,
+    blocks: Vec<[f32; BLOCK_VOXELS]>,
+    /// Voxel space to world space.
+    to_world: Affine3A,
+    to_voxel: Affine3A,
+}
+
+impl DensityGrid {
+    /// An empty grid able to hold the voxels from `min` to `max`, both included.
+    pub fn new(min: IVec3, max: IVec3, to_world: Affine3A) -> DensityGrid {
+        let origin = min.div_euclid(IVec3::splat(BLOCK)) * BLOCK;
+        let size = (max.max(min) - origin).div_euclid(IVec3::splat(BLOCK)) + 1;
+        DensityGrid {
+            origin,
+            size,
+            table: vec![u32::MAX; (size.x * size.y * size.z) as usize],
+            blocks: vec![],
+            to_world,
+            to_voxel: to_world.inverse(),
+        }
+    }
+
+    pub fn to_world(&self) -> Affine3A {
+        self.to_world
+    }
+
+    /// Box around the voxels that may hold a density, in world space.
+    pub fn bounds(&self) -> Aabb {
+        let min = self.origin.as_vec3() - 1.;
+        let max = (self.origin + self.size * BLOCK).as_vec3();
+        Aabb::new(min, max).transformed(&self.to_world)
+    }
+
+    fn block_index(&self, voxel: IVec3) -> Option<(usize, usize)> {
+        let v = voxel - self.origin;
+        let block = v.div_euclid(IVec3::splat(BLOCK));
+        if block.cmplt(IVec3::ZERO).any() || block.cmpge(self.size).any() {
+            return None;
+        }
+        let local = v - block * BLOCK;
+        Some((
+            ((block.x * self.size.y + block.y) * self.size.z + block.z) as usize,
+            ((local.x * BLOCK + local.y) * BLOCK + local.z) as usize,
+        ))
+    }
+
+    /// The voxels of the block holding `voxel`, indexed by `(x * 8 + y) * 8 + z` from its
+    /// first corner, added empty if needed. `None` outside the grid.
+    pub fn block_mut(&mut self, voxel: IVec3) -> Option<&mut [f32; BLOCK_VOXELS]> {
+        let (block, _) = self.block_index(voxel)?;
+        if self.table[block] == u32::MAX {
+            self.table[block] = self.blocks.len() as u32;
+            self.blocks.push([0.; BLOCK_VOXELS]);
+        }
+        Some(&mut self.blocks[self.table[block] as usize])
+    }
+
+    /// Sets the density of `voxel`, ignoring voxels outside the grid.
+    pub fn set(&mut self, voxel: IVec3, value: f32) {
+        if let Some((_, i)) = self.block_index(voxel) {
+            if let Some(block) = self.block_mut(voxel) {
+                block[i] = value;
+            }
+        }
+    }
+
+    /// Density of `voxel`, 0 in the empty blocks and outside the grid.
+    pub fn value(&self, voxel: IVec3) -> f32 {
+        match self.block_index(voxel) {
+            Some((block, i)) if self.table[block] != u32::MAX => {
+                self.blocks[self.table[block] as usize][i]
+            }
+            _ => 0.,
+        }
+    }
+
+    /// Density at `p` in world space, blended between the 8 nearest voxels.
+    pub fn sample(&self, p: Vec3) -> f32 {
+        let p = self.to_voxel.transform_point3(p);
+        let base = p.floor();
+        let f = p - base;
+        let base = base.as_ivec3();
+        let mut density = 0.;
+        for corner in 0..8 {
+            let offset = IVec3::new(corner >> 2, (corner >> 1) & 1, corner & 1);
+            let w = Vec3::select(offset.cmpeq(IVec3::ONE), f, 1. - f);
+            density += self.value(base + offset) * w.x * w.y * w.z;
+        }
+        density
+    }
+}
+
+/// A `DensityGrid` placed in the scene, usually smoke or clouds loaded from an OpenVDB
+/// file, marched like the `CloudLayer`. Scene files refer to the grid by `path` and
+/// `grid_name`, and it is loaded again from there, which needs the `vdb` feature.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "VolumeFile", into = "VolumeFile")]
+pub struct Volume {
+    pub grid: Arc<DensityGrid>,
+    pub path: String,
+    /// Grid read from the file, the first float grid when empty.
+    pub grid_name: String,
+    /// Where the origin of the grid world space goes in the scene.
+    pub position: Vec3,
+    pub scale: f32,
+    /// Extinction per unit of distance where the grid holds 1.
+    pub density: f32,
+    pub color: Vec3,
+    /// Samples along view rays.
+    pub steps: u32,
+    /// Samples along the rays towards the sun.
+    pub light_steps: u32,
+}
+
+/// How a `Volume` is written in scene files.
+#[derive(Serialize, Deserialize)]
+struct VolumeFile {
+    path: String,
+    #[serde(default)]
+    grid_name: String,
+    position: Vec3,
+    scale: f32,
+    density: f32,
+    color: Vec3,
+    steps: u32,
+    light_steps: u32,
+}
+
+impl TryFrom<VolumeFile> for Volume {
+    type Error = AppError;
+
+    fn try_from(file: VolumeFile) -> Result<Self, Self::Error> {
+        Ok(Volume {
+            position: file.position,
+            scale: file.scale,
+            density: file.density,
+            color: file.color,
+            steps: file.steps,
+            light_steps: file.light_steps,
+            ..Volume::load(file.path, file.grid_name)?
+        })
+    }
+}
+
+impl From<Volume> for VolumeFile {
+    fn from(volume: Volume) -> Self {
+        VolumeFile {
+            path: volume.path,
+            grid_name: volume.grid_name,
+            position: volume.position,
+            scale: volume.scale,
+            density: volume.density,
+            color: volume.color,
+            steps: volume.steps,
+            light_steps: volume.light_steps,
+        }
+    }
+}
+
+impl Volume {
+    pub fn new(grid: DensityGrid) -> Volume {
+        Volume {
+            grid: Arc::new(grid),
+            path: String::new(),
+            grid_name: String::new(),
+            position: Vec3::ZERO,
+            scale: 1.,
+            density: 1.,
+            color: Vec3::ONE,
+            steps: 64,
+            light_steps: 8,
+        }
+    }
+
+    /// Loads the float grid `grid_name` of an OpenVDB file, or its first one when empty.
+    #[cfg(feature = "vdb")]
+    pub fn load(path: impl Into<String>, grid_name: impl Into<String>) -> Result<Volume, AppError> {
+        let (path, grid_name) = (path.into(), grid_name.into());
+        let grid = crate::utils::vdb::VdbUtils::load_grid(&path, &grid_name)?;
+        Ok(Volume {
+            path,
+            grid_name,
+            ..Volume::new(grid)
+        })
+    }
+
+    /// Fails: reading OpenVDB files needs the `vdb` feature.
+    #[cfg(not(feature = "vdb"))]
+    pub fn load(
+        path: impl Into<String>,
+        _grid_name: impl Into<String>,
+    ) -> Result<Volume, AppError> {
+        Err(AppError::VolumeLoad {
+            path: path.into(),
+            message: "built without the vdb feature".to_string(),
+        })
+    }
+
+    /// `p` in the grid world space.
+    fn local(&self, p: Vec3) -> Vec3 {
+        (p - self.position) / self.scale
+    }
+
+    /// Box around the volume in the scene.
+    pub fn bounds(&self) -> Aabb {
+        let bounds = self.grid.bounds();
+        Aabb::new(
+            bounds.min * self.scale + self.position,
+            bounds.max * self.scale + self.position,
+        )
+    }
+}
+
+/// A participating medium marched along the rays crossing it and lit by the sun with the
+/// Beer-Powder approximation: the clouds and the loaded volumes.
+pub trait Medium {
+    /// Extinction per unit of distance at `p`.
+    fn density_at(&self, p: Vec3) -> f32;
+
+    /// Distances along `ray` where it is inside the medium, up to `distance`.
+    fn span(&self, ray: &Ray, distance: f32) -> Option<(f32, f32)>;
+
+    /// Length of the steps towards the sun, along `to_sun`.
+    fn light_step(&self, to_sun: Vec3) -> f32;
+
+    fn color(&self) -> Vec3;
+
+    /// Samples along view rays and along the rays towards the sun.
+    fn steps(&self) -> (u32, u32);
+
+    /// Light scattered towards the ray origin by the medium over the first `distance` of
+    /// `ray`, and the fraction of the light from behind it that gets through.
+    fn segment(&self, ray: &Ray, distance: f32, light: &Light, ambient: Vec3) -> (Vec3, f32) {
+        let Some((t0, t1)) = self.span(ray, distance) else {
+            return (Vec3::ZERO, 1.);
+        };
+
+        let (steps, light_steps) = self.steps();
+        let to_sun = -light.direction.normalize();
+        let light_step = self.light_step(to_sun);
+        // Henyey-Greenstein forward scattering, brightening the medium around the sun.
+        let g = 0.6;
+        let cos = ray.direction.normalize().dot(to_sun);
+        let phase = (1. - g * g) / (4. * PI * (1. + g * g - 2. * g * cos).powf(1.5));
+
+        let steps = steps.max(1);
+        let dt = (t1 - t0) / steps as f32;
+        let mut transmittance = 1.;
+        let mut scattered = Vec3::ZERO;
+        for i in 0..steps {
+            let p = ray.at(t0 + (i as f32 + 0.5) * dt);
+            let sigma = self.density_at(p);
+            if sigma <= 0. {
+                continue;
+            }
+
+            let depth: f32 = (1..=light_steps)
+                .map(|j| self.density_at(p + to_sun * light_step * j as f32) * light_step)
+                .sum();
+            let beer_powder = 2. * (-depth).exp() * (1. - (-2. * depth).exp());
+            let sun = light.power * beer_powder * phase * 4. * PI;
+
+            scattered += transmittance * sigma * dt * self.color() * (sun + ambient);
+            transmittance *= (-sigma * dt).exp();
+            if transmittance < 0.01 {
+                transmittance = 0.;
+                break;
+            }
+        }
+        (scattered, transmittance)
+    }
+
+    /// Fraction of the light that crosses the medium over the first `distance` of `ray`.
+    fn transmittance(&self, ray: &Ray, distance: f32) -> f32 {
+        let Some((t0, t1)) = self.span(ray, distance) else {
+            return 1.;
+        };
+        let steps = self.steps().0.max(1);
+        let dt = (t1 - t0) / steps as f32;
+        let depth: f32 = (0..steps)
+            .map(|i| self.density_at(ray.at(t0 + (i as f32 + 0.5) * dt)) * dt)
+            .sum();
+        (-depth).exp()
+    }
+
+    fn apply(&self, color: Vec3, ray: &Ray, distance: f32, light: &Light, ambient: Vec3) -> Vec3 {
+        let (scattered, transmittance) = self.segment(ray, distance, light, ambient);
+        color * transmittance + scattered
+    }
+}
+
+impl Medium for Volume {
+    fn density_at(&self, p: Vec3) -> f32 {
+        self.grid.sample(self.local(p)).max(0.) * self.density
+    }
+
+    fn span(&self, ray: &Ray, distance: f32) -> Option<(f32, f32)> {
+        let (t0, t1) = self.bounds().hit(ray, distance)?;
+        (t0 < t1).then_some((t0, t1))
+    }
+
+    fn light_step(&self, _to_sun: Vec3) -> f32 {
+        self.bounds().extent().length() / self.light_steps.max(1) as f32
+    }
+
+    fn color(&self) -> Vec3 {
+        self.color
+    }
+
+    fn steps(&self) -> (u32, u32) {
+        (self.steps, self.light_steps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{vec3, Affine3A, IVec3, Vec3};
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::accel::Accel;
+    use crate::ray::Sampler;
+    use crate::scene::{PixelCache, Scene};
+
+    /// A cube of 16 voxels of 1 from the origin, its voxels 0.5 wide.
+    fn cube() -> Volume {
+        let to_world = Affine3A::from_scale(Vec3::splat(0.5));
+        let mut grid = DensityGrid::new(IVec3::ZERO, IVec3::splat(15), to_world);
+        for i in 0..16 * 16 * 16 {
+            grid.set(IVec3::new(i / 256, i / 16 % 16, i % 16), 1.);
+        }
+        Volume::new(grid)
+    }
+
+    #[test]
+    fn rays_are_dimmed_by_the_density_they_cross() {
+        let ray = |origin: Vec3| Ray {
+            origin,
+            direction: Vec3::X,
+            ..Default::default()
+        };
+        let volume = cube();
+        // Voxels blend into the empty ones around over half a voxel each way.
+        let expected = (-8f32).exp();
+        let through = volume.transmittance(&ray(vec3(-10., 3.75, 3.75)), f32::INFINITY);
+        assert!((through - expected).abs() < 1e-3, "{}", through);
+        assert_eq!(
+            volume.transmittance(&ray(vec3(-10., 20., 3.75)), f32::INFINITY),
+            1.
+        );
+        assert_eq!(volume.transmittance(&ray(vec3(-10., 3.75, 3.75)), 5.), 1.);
+    }
+
+    #[test]
+    fn scenes_are_seen_through_their_volumes() {
+        let mut scene = Scene {
+            light: Light {
+                direction: vec3(0., -1., 0.),
+                ..Default::default()
+            },
+            ambient_color: Vec3::ONE,
+            max_ray_bounces: 1,
+            volumes: vec![Volume {
+                density: 0.25,
+                color: Vec3::ZERO,
+                position: vec3(-4., -4., 10.),
+                ..cube()
+            }],
+            ..Default::default()
+        };
+        scene.compile(Accel::Linear);
+        let mut rnd = Sampler::seed_from_u64(1);
+        let ray = Ray {
+            direction: Vec3::Z,
+            ..Default::default()
+        };
+        let radiance = scene
+            .pixel(ray, &mut rnd, &mut PixelCache::default())
+            .truncate();
+        let expected = Vec3::splat((-2f32).exp());
+        assert!(
+            (radiance - expected).abs().max_element() < 1e-3,
+            "{}",
+            radiance
+        );
+    }
+}
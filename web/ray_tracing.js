@@ -0,0 +1,108 @@
+// Draws the WebAssembly build of the renderer into a canvas, sharpening a little more on
+// every animation frame. Build the module with
+//
+//   cargo build --release --lib --no-default-features --target wasm32-unknown-unknown
+//
+// and serve target/wasm32-unknown-unknown/release/ray_tracing.wasm next to the page:
+//
+//   import { RayTracer } from "./ray_tracing.js";
+//   const sceneJson = await (await fetch("scene.json")).text();
+//   const tracer = await RayTracer.load("ray_tracing.wasm", sceneJson, canvas);
+//   tracer.setCamera([0, 1, 4], [0, 0, 0], 45);
+//   tracer.start();
+//
+// The calls are the C interface of include/ray_tracing.h. Rendering runs on the page's
+// thread, one sample per pixel per animation frame; textures and meshes referenced by
+// path cannot be read in a browser, so scenes have to be self-contained.
+
+export class RayTracer {
+  static async load(wasmUrl, sceneJson, canvas) {
+    const imports = { env: { rt_now: () => performance.now() } };
+    const { instance } = await WebAssembly.instantiateStreaming(fetch(wasmUrl), imports);
+    return new RayTracer(instance.exports, sceneJson, canvas);
+  }
+
+  constructor(exports, sceneJson, canvas) {
+    this.rt = exports;
+    this.canvas = canvas;
+    this.context = canvas.getContext("2d");
+    this.running = false;
+
+    const bytes = new TextEncoder().encode(sceneJson);
+    const text = this.rt.rt_alloc(bytes.length);
+    new Uint8Array(this.rt.memory.buffer, text, bytes.length).set(bytes);
+    this.renderer = this.rt.rt_renderer_parse(text, bytes.length, canvas.width, canvas.height);
+    this.rt.rt_free(text, bytes.length);
+    if (this.renderer === 0) {
+      throw new Error(this.lastError());
+    }
+    this.allocatePixels();
+  }
+
+  allocatePixels() {
+    this.width = this.canvas.width;
+    this.height = this.canvas.height;
+    this.length = this.width * this.height * 4;
+    this.pixels = this.rt.rt_alloc(this.length);
+  }
+
+  lastError() {
+    const start = this.rt.rt_last_error();
+    const memory = new Uint8Array(this.rt.memory.buffer);
+    const end = memory.indexOf(0, start);
+    return new TextDecoder().decode(memory.subarray(start, end));
+  }
+
+  // Places the camera at `position` looking at `target`, both [x, y, z], with a vertical
+  // field of view of `fov` degrees.
+  setCamera(position, target, fov) {
+    this.rt.rt_set_camera(this.renderer, ...position, ...target, fov);
+  }
+
+  // Follows the canvas size, restarting the image.
+  resize() {
+    this.rt.rt_free(this.pixels, this.length);
+    if (this.rt.rt_resize(this.renderer, this.canvas.width, this.canvas.height) < 0) {
+      throw new Error(this.lastError());
+    }
+    this.allocatePixels();
+  }
+
+  // Traces one more sample per pixel and draws the image. False once the scene's frame
+  // limit is reached.
+  frame() {
+    const status = this.rt.rt_render_frame(this.renderer, this.pixels, this.length);
+    if (status < 0) {
+      throw new Error(this.lastError());
+    }
+    if (status > 0) {
+      // The memory may have grown since the last frame, detaching older views of it.
+      const rgba = new Uint8ClampedArray(this.rt.memory.buffer, this.pixels, this.length);
+      this.context.putImageData(new ImageData(rgba, this.width, this.height), 0, 0);
+    }
+    return status > 0;
+  }
+
+  start() {
+    this.running = true;
+    const loop = () => {
+      if (this.running && this.frame()) {
+        requestAnimationFrame(loop);
+      } else {
+        this.running = false;
+      }
+    };
+    requestAnimationFrame(loop);
+  }
+
+  stop() {
+    this.running = false;
+  }
+
+  free() {
+    this.stop();
+    this.rt.rt_free(this.pixels, this.length);
+    this.rt.rt_renderer_free(this.renderer);
+    this.renderer = 0;
+  }
+}